@@ -0,0 +1,375 @@
+//! An async facade of `MiniLsm`.
+//!
+//! Reads and writes may block on disk I/O and on the storage locks, so the facade runs each operation on a dedicated
+//! I/O thread pool and returns a future that completes when the operation is done. The futures do not depend on a
+//! particular executor. Background flush and compaction keep running in the threads of `MiniLsm`.
+
+use std::{
+    collections::VecDeque,
+    future::Future,
+    ops::Bound,
+    panic::AssertUnwindSafe,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, Waker},
+    thread::JoinHandle,
+};
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use futures_core::Stream;
+use parking_lot::Mutex;
+
+use crate::{
+    iterators::StorageIterator,
+    lsm_storage::{MiniLsm, WriteBatchRecord},
+    mvcc::txn::{IsolationLevel, Transaction, TxnIterator},
+};
+
+/// A scan reads this many entries per task on the I/O thread pool.
+const SCAN_BATCH_SIZE: usize = 128;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// The threads that run the blocking operations of the async facade.
+struct IoPool {
+    sender: Option<crossbeam_channel::Sender<Job>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl IoPool {
+    fn new(num_threads: usize) -> Result<Self> {
+        let (sender, receiver) = crossbeam_channel::unbounded::<Job>();
+        let mut threads = Vec::with_capacity(num_threads);
+        for i in 0..num_threads.max(1) {
+            let receiver = receiver.clone();
+            let thread = std::thread::Builder::new()
+                .name(format!("mini-lsm-io-{}", i))
+                .spawn(move || {
+                    while let Ok(job) = receiver.recv() {
+                        // a panicking job fails its future, but should not take down the thread
+                        let _ = std::panic::catch_unwind(AssertUnwindSafe(job));
+                    }
+                })?;
+            threads.push(thread);
+        }
+        Ok(Self {
+            sender: Some(sender),
+            threads,
+        })
+    }
+
+    fn spawn<T: Send + 'static>(
+        &self,
+        f: impl FnOnce() -> Result<T> + Send + 'static,
+    ) -> IoFuture<T> {
+        let shared = Arc::new(Mutex::new(Shared {
+            result: None,
+            waker: None,
+        }));
+        let completer = Completer {
+            shared: Some(shared.clone()),
+        };
+        let job = Box::new(move || completer.complete(f()));
+        // if the pool is gone, the job is dropped and fails the future
+        let _ = self.sender.as_ref().unwrap().send(job);
+        IoFuture { shared }
+    }
+}
+
+impl Drop for IoPool {
+    fn drop(&mut self) {
+        // the threads finish the queued jobs and exit once the channel is closed
+        self.sender.take();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+struct Shared<T> {
+    result: Option<Result<T>>,
+    waker: Option<Waker>,
+}
+
+/// A future of an operation running on the I/O thread pool.
+pub struct IoFuture<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+impl<T> Future for IoFuture<T> {
+    type Output = Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<T>> {
+        let mut shared = self.shared.lock();
+        match shared.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                shared.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Completes an `IoFuture`. If dropped before completing, e.g. because the job panicked, the future fails.
+struct Completer<T> {
+    shared: Option<Arc<Mutex<Shared<T>>>>,
+}
+
+impl<T> Completer<T> {
+    fn complete(mut self, result: Result<T>) {
+        self.set(result);
+    }
+
+    fn set(&mut self, result: Result<T>) {
+        if let Some(shared) = self.shared.take() {
+            let waker = {
+                let mut shared = shared.lock();
+                shared.result = Some(result);
+                shared.waker.take()
+            };
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl<T> Drop for Completer<T> {
+    fn drop(&mut self) {
+        self.set(Err(anyhow!("the I/O task did not complete")));
+    }
+}
+
+/// An async facade of `MiniLsm`, see the module docs.
+#[derive(Clone)]
+pub struct AsyncMiniLsm {
+    storage: Arc<MiniLsm>,
+    pool: Arc<IoPool>,
+}
+
+impl AsyncMiniLsm {
+    /// Run the operations on `storage` with `num_threads` I/O threads.
+    pub fn new(storage: Arc<MiniLsm>, num_threads: usize) -> Result<Self> {
+        Ok(Self {
+            storage,
+            pool: Arc::new(IoPool::new(num_threads)?),
+        })
+    }
+
+    pub fn storage(&self) -> &Arc<MiniLsm> {
+        &self.storage
+    }
+
+    pub fn get(&self, key: &[u8]) -> IoFuture<Option<Bytes>> {
+        let storage = self.storage.clone();
+        let key = Bytes::copy_from_slice(key);
+        self.pool.spawn(move || storage.get(&key))
+    }
+
+    pub fn put(&self, key: &[u8], value: &[u8]) -> IoFuture<()> {
+        self.write_batch(vec![WriteBatchRecord::Put(
+            Bytes::copy_from_slice(key),
+            Bytes::copy_from_slice(value),
+        )])
+    }
+
+    pub fn delete(&self, key: &[u8]) -> IoFuture<()> {
+        self.write_batch(vec![WriteBatchRecord::Del(Bytes::copy_from_slice(key))])
+    }
+
+    pub fn write_batch(&self, batch: Vec<WriteBatchRecord<Bytes>>) -> IoFuture<()> {
+        let storage = self.storage.clone();
+        self.pool.spawn(move || storage.write_batch(&batch))
+    }
+
+    pub fn scan(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> ScanStream {
+        let storage = self.storage.clone();
+        let lower = lower.map(Bytes::copy_from_slice);
+        let upper = upper.map(Bytes::copy_from_slice);
+        ScanStream::new(
+            self.pool.clone(),
+            Box::new(move || {
+                storage.scan(
+                    lower.as_ref().map(|x| x.as_ref()),
+                    upper.as_ref().map(|x| x.as_ref()),
+                )
+            }),
+        )
+    }
+
+    pub fn new_txn(&self) -> Result<AsyncTransaction> {
+        Ok(self.wrap_txn(self.storage.new_txn()?))
+    }
+
+    pub fn new_txn_with_isolation(&self, isolation: IsolationLevel) -> Result<AsyncTransaction> {
+        Ok(self.wrap_txn(self.storage.new_txn_with_isolation(isolation)?))
+    }
+
+    pub fn new_pessimistic_txn(&self) -> Result<AsyncTransaction> {
+        Ok(self.wrap_txn(self.storage.new_pessimistic_txn()?))
+    }
+
+    fn wrap_txn(&self, txn: Arc<Transaction>) -> AsyncTransaction {
+        AsyncTransaction {
+            txn,
+            pool: self.pool.clone(),
+        }
+    }
+}
+
+/// An async facade of a `Transaction`. Writes are async as well, as they may spill to disk or wait for a key lock.
+pub struct AsyncTransaction {
+    txn: Arc<Transaction>,
+    pool: Arc<IoPool>,
+}
+
+impl AsyncTransaction {
+    pub fn txn(&self) -> &Arc<Transaction> {
+        &self.txn
+    }
+
+    pub fn get(&self, key: &[u8]) -> IoFuture<Option<Bytes>> {
+        let txn = self.txn.clone();
+        let key = Bytes::copy_from_slice(key);
+        self.pool.spawn(move || txn.get(&key))
+    }
+
+    pub fn get_for_update(&self, key: &[u8]) -> IoFuture<Option<Bytes>> {
+        let txn = self.txn.clone();
+        let key = Bytes::copy_from_slice(key);
+        self.pool.spawn(move || txn.get_for_update(&key))
+    }
+
+    pub fn scan(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> ScanStream {
+        let txn = self.txn.clone();
+        let lower = lower.map(Bytes::copy_from_slice);
+        let upper = upper.map(Bytes::copy_from_slice);
+        ScanStream::new(
+            self.pool.clone(),
+            Box::new(move || {
+                txn.scan(
+                    lower.as_ref().map(|x| x.as_ref()),
+                    upper.as_ref().map(|x| x.as_ref()),
+                )
+            }),
+        )
+    }
+
+    pub fn put(&self, key: &[u8], value: &[u8]) -> IoFuture<()> {
+        let txn = self.txn.clone();
+        let key = Bytes::copy_from_slice(key);
+        let value = Bytes::copy_from_slice(value);
+        self.pool.spawn(move || {
+            txn.put(&key, &value);
+            Ok(())
+        })
+    }
+
+    pub fn delete(&self, key: &[u8]) -> IoFuture<()> {
+        let txn = self.txn.clone();
+        let key = Bytes::copy_from_slice(key);
+        self.pool.spawn(move || {
+            txn.delete(&key);
+            Ok(())
+        })
+    }
+
+    pub fn commit(&self) -> IoFuture<()> {
+        let txn = self.txn.clone();
+        self.pool.spawn(move || txn.commit())
+    }
+
+    pub fn rollback(&self) -> IoFuture<()> {
+        let txn = self.txn.clone();
+        self.pool.spawn(move || txn.rollback())
+    }
+}
+
+enum ScanSource {
+    Create(Box<dyn FnOnce() -> Result<TxnIterator> + Send>),
+    Iter(Box<TxnIterator>),
+}
+
+/// The iterator after reading a batch, and the batch.
+type ScanBatch = (Box<TxnIterator>, Vec<(Bytes, Bytes)>);
+
+/// A stream of the key-value pairs of a scan. The iterator is created and advanced on the I/O thread pool, a batch
+/// of entries at a time.
+pub struct ScanStream {
+    pool: Arc<IoPool>,
+    /// `None` while a batch is being read.
+    source: Option<ScanSource>,
+    pending: Option<IoFuture<ScanBatch>>,
+    buffer: VecDeque<(Bytes, Bytes)>,
+    done: bool,
+}
+
+impl ScanStream {
+    fn new(pool: Arc<IoPool>, create: Box<dyn FnOnce() -> Result<TxnIterator> + Send>) -> Self {
+        Self {
+            pool,
+            source: Some(ScanSource::Create(create)),
+            pending: None,
+            buffer: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    fn read_batch(&mut self) {
+        let source = self.source.take().unwrap();
+        self.pending = Some(self.pool.spawn(move || {
+            let mut iter = match source {
+                ScanSource::Create(create) => Box::new(create()?),
+                ScanSource::Iter(iter) => iter,
+            };
+            let mut batch = Vec::with_capacity(SCAN_BATCH_SIZE);
+            while iter.is_valid() && batch.len() < SCAN_BATCH_SIZE {
+                batch.push((
+                    Bytes::copy_from_slice(iter.key()),
+                    Bytes::copy_from_slice(iter.value()),
+                ));
+                iter.next()?;
+            }
+            Ok((iter, batch))
+        }));
+    }
+}
+
+impl Stream for ScanStream {
+    type Item = Result<(Bytes, Bytes)>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(entry) = this.buffer.pop_front() {
+                return Poll::Ready(Some(Ok(entry)));
+            }
+            if this.done {
+                return Poll::Ready(None);
+            }
+            let Some(pending) = &mut this.pending else {
+                this.read_batch();
+                continue;
+            };
+            match Pin::new(pending).poll(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Ok((iter, batch))) => {
+                    this.pending = None;
+                    if iter.is_valid() {
+                        this.source = Some(ScanSource::Iter(iter));
+                    } else {
+                        this.done = true;
+                    }
+                    this.buffer.extend(batch);
+                }
+                Poll::Ready(Err(e)) => {
+                    this.pending = None;
+                    this.done = true;
+                    return Poll::Ready(Some(Err(e)));
+                }
+            }
+        }
+    }
+}
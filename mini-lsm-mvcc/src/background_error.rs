@@ -0,0 +1,182 @@
+//! Errors of the flushes and compactions.
+//!
+//! A background error is either retryable, and the scheduler runs the same job again after a delay, or fatal. After
+//! a fatal error the engine is read-only: the writes return the error, and the flushes and compactions are skipped
+//! until `MiniLsm::resume` succeeds. Retryable errors that keep coming back are treated as fatal, so that a problem
+//! that does not go away on its own is not retried forever.
+
+use std::fmt;
+use std::io::ErrorKind;
+
+use anyhow::{bail, Result};
+
+use crate::background_scheduler::JobOutcome;
+use crate::event_listener::BackgroundErrorReason;
+use crate::lsm_storage::LsmStorageInner;
+
+/// The number of retryable errors in a row after which the engine gives up and becomes read-only.
+const MAX_CONSECUTIVE_RETRIES: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackgroundErrorSeverity {
+    /// A transient problem, e.g. an interrupted system call. The work is retried.
+    Retryable,
+    /// A problem that needs an operator, e.g. a full disk or a corrupted SST. The engine becomes read-only.
+    Fatal,
+}
+
+impl BackgroundErrorSeverity {
+    /// Classify an error by the I/O error that caused it. Errors not caused by I/O, like a corruption, are fatal.
+    pub fn of(error: &anyhow::Error) -> Self {
+        let Some(io_error) = error
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<std::io::Error>())
+        else {
+            return BackgroundErrorSeverity::Fatal;
+        };
+        match io_error.kind() {
+            ErrorKind::StorageFull
+            | ErrorKind::QuotaExceeded
+            | ErrorKind::ReadOnlyFilesystem
+            | ErrorKind::PermissionDenied
+            | ErrorKind::NotFound
+            | ErrorKind::InvalidData
+            | ErrorKind::UnexpectedEof => BackgroundErrorSeverity::Fatal,
+            _ => BackgroundErrorSeverity::Retryable,
+        }
+    }
+}
+
+/// The error that made the engine read-only.
+#[derive(Debug, Clone)]
+pub struct BackgroundError {
+    pub reason: BackgroundErrorReason,
+    pub severity: BackgroundErrorSeverity,
+    /// The error with its causes.
+    pub message: String,
+}
+
+impl fmt::Display for BackgroundError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} failed: {}", self.reason, self.message)
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct BackgroundErrorState {
+    /// The fatal error, if the engine is read-only.
+    error: Option<BackgroundError>,
+    consecutive_flush_errors: usize,
+    consecutive_compaction_errors: usize,
+}
+
+impl BackgroundErrorState {
+    fn consecutive_errors(&mut self, reason: BackgroundErrorReason) -> &mut usize {
+        match reason {
+            BackgroundErrorReason::Flush => &mut self.consecutive_flush_errors,
+            BackgroundErrorReason::Compaction => &mut self.consecutive_compaction_errors,
+        }
+    }
+}
+
+impl LsmStorageInner {
+    /// Run a piece of background work, unless the engine is read-only, and record its error. The job returns whether
+    /// there may be more work to do.
+    pub(crate) fn run_background_job(
+        &self,
+        reason: BackgroundErrorReason,
+        job: impl FnOnce() -> Result<bool>,
+    ) -> JobOutcome {
+        if self.background_error().is_some() {
+            return JobOutcome::Done;
+        }
+        match job() {
+            Ok(more) => {
+                *self.background_error.lock().consecutive_errors(reason) = 0;
+                if more {
+                    JobOutcome::Again
+                } else {
+                    JobOutcome::Done
+                }
+            }
+            Err(e) => match self.record_background_error(reason, &e) {
+                BackgroundErrorSeverity::Retryable => JobOutcome::RetryLater,
+                BackgroundErrorSeverity::Fatal => JobOutcome::Done,
+            },
+        }
+    }
+
+    /// Record the error of a piece of background work, and make the engine read-only if it is fatal.
+    pub(crate) fn record_background_error(
+        &self,
+        reason: BackgroundErrorReason,
+        error: &anyhow::Error,
+    ) -> BackgroundErrorSeverity {
+        let mut severity = BackgroundErrorSeverity::of(error);
+        {
+            let mut state = self.background_error.lock();
+            let consecutive_errors = state.consecutive_errors(reason);
+            *consecutive_errors += 1;
+            if *consecutive_errors >= MAX_CONSECUTIVE_RETRIES {
+                severity = BackgroundErrorSeverity::Fatal;
+            }
+            if severity == BackgroundErrorSeverity::Fatal && state.error.is_none() {
+                state.error = Some(BackgroundError {
+                    reason,
+                    severity,
+                    message: format!("{:#}", error),
+                });
+            }
+        }
+        self.notify_listeners(|listener| listener.on_background_error(reason, severity, error));
+        severity
+    }
+
+    /// The fatal error that made the engine read-only, if any.
+    pub(crate) fn background_error(&self) -> Option<BackgroundError> {
+        self.background_error.lock().error.clone()
+    }
+
+    /// Fail if the engine is read-only after a fatal background error.
+    pub(crate) fn check_writable(&self) -> Result<()> {
+        if let Some(error) = self.background_error() {
+            bail!(
+                "the database is read-only after a background error: {}",
+                error
+            );
+        }
+        Ok(())
+    }
+
+    /// Retry the background work that failed, and take writes again if it succeeds.
+    pub(crate) fn resume(&self) -> Result<()> {
+        let Some(error) = self.background_error() else {
+            return Ok(());
+        };
+        let result = match error.reason {
+            // flush what the failed flush could not, so that the memtables are not over their limit
+            BackgroundErrorReason::Flush => self.flush_imm_memtables(),
+            BackgroundErrorReason::Compaction => self.trigger_compaction().map(|_| ()),
+        };
+        let mut state = self.background_error.lock();
+        match result {
+            Ok(()) => {
+                *state = BackgroundErrorState::default();
+                drop(state);
+                self.notify_listeners(|listener| listener.on_background_error_resumed(&error));
+                // catch up on the work skipped while the engine was read-only
+                self.schedule_flush();
+                self.schedule_compaction();
+                Ok(())
+            }
+            Err(e) => {
+                state.error = Some(BackgroundError {
+                    reason: error.reason,
+                    severity: BackgroundErrorSeverity::Fatal,
+                    message: format!("{:#}", e),
+                });
+                Err(e.context("failed to resume"))
+            }
+        }
+    }
+}
@@ -0,0 +1,369 @@
+//! Thread pools running the flushes and compactions of storage engines.
+//!
+//! A scheduler has a high-priority pool for flushes and a low-priority pool for compactions, so that a long
+//! compaction never holds up the flush a stalled writer waits for. Engines can share a scheduler with
+//! `LsmStorageOptions::background_scheduler` to bound the background threads of a process. An engine without one
+//! creates a scheduler of its own with one thread in each pool.
+//!
+//! The pools do not poll. An engine schedules a flush when a memtable is sealed or the write buffer manager is over
+//! its budget, and a compaction when a flush or another compaction changed its SSTs. A job that failed with a
+//! retryable error is scheduled again after `RETRY_DELAY`. The flushes of an engine run on one thread at a time, and
+//! so do its compactions.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Weak};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use parking_lot::{Condvar, Mutex};
+
+use crate::compact::CompactionOptions;
+use crate::event_listener::BackgroundErrorReason;
+use crate::lsm_storage::LsmStorageInner;
+
+/// How long a job that failed with a retryable error waits before it runs again.
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobPriority {
+    /// Flushes, which free the memtables writers may be waiting for.
+    High,
+    /// Compactions.
+    Low,
+}
+
+/// What the scheduler does with a job after it ran.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum JobOutcome {
+    Done,
+    /// The job did some work and there may be more, so it runs again right away.
+    Again,
+    /// The job failed with a retryable error and runs again after `RETRY_DELAY`.
+    RetryLater,
+}
+
+struct Job {
+    engine: Weak<LsmStorageInner>,
+    priority: JobPriority,
+}
+
+#[derive(Default)]
+struct PoolQueue {
+    ready: VecDeque<Job>,
+    /// The jobs to run again after a retryable error, with the time they are due.
+    delayed: Vec<(Instant, Job)>,
+    shutdown: bool,
+}
+
+#[derive(Default)]
+struct PoolShared {
+    queue: Mutex<PoolQueue>,
+    cv: Condvar,
+}
+
+impl PoolShared {
+    fn push(&self, job: Job, delay: Option<Duration>) {
+        let mut queue = self.queue.lock();
+        match delay {
+            Some(delay) => queue.delayed.push((Instant::now() + delay, job)),
+            None => queue.ready.push_back(job),
+        }
+        self.cv.notify_one();
+    }
+
+    /// Wait for the next job that is due. Returns None once the pool shuts down.
+    fn pop(&self) -> Option<Job> {
+        let mut queue = self.queue.lock();
+        loop {
+            if queue.shutdown {
+                return None;
+            }
+            let now = Instant::now();
+            if let Some(idx) = queue.delayed.iter().position(|(due, _)| *due <= now) {
+                let (_, job) = queue.delayed.swap_remove(idx);
+                queue.ready.push_back(job);
+            }
+            if let Some(job) = queue.ready.pop_front() {
+                return Some(job);
+            }
+            match queue.delayed.iter().map(|(due, _)| *due).min() {
+                Some(due) => {
+                    self.cv.wait_until(&mut queue, due);
+                }
+                None => self.cv.wait(&mut queue),
+            }
+        }
+    }
+
+    fn shutdown(&self) {
+        let mut queue = self.queue.lock();
+        queue.shutdown = true;
+        queue.ready.clear();
+        queue.delayed.clear();
+        self.cv.notify_all();
+    }
+}
+
+struct Pool {
+    shared: Arc<PoolShared>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Pool {
+    fn start(name: &str, num_threads: usize) -> Self {
+        assert!(num_threads > 0, "a pool needs at least one thread");
+        let shared = Arc::new(PoolShared::default());
+        let threads = (0..num_threads)
+            .map(|idx| {
+                let shared = shared.clone();
+                std::thread::Builder::new()
+                    .name(format!("{}-{}", name, idx))
+                    .spawn(move || {
+                        while let Some(job) = shared.pop() {
+                            if let Some(engine) = job.engine.upgrade() {
+                                engine.run_scheduled_job(job.priority);
+                            }
+                        }
+                    })
+                    .expect("failed to spawn a background thread")
+            })
+            .collect();
+        Self { shared, threads }
+    }
+
+    fn stop(&mut self) {
+        self.shared.shutdown();
+        let current = std::thread::current().id();
+        for thread in self.threads.drain(..) {
+            // the last engine using the scheduler may be dropped by one of its own jobs
+            if thread.thread().id() != current {
+                thread.join().ok();
+            }
+        }
+    }
+}
+
+/// A high-priority pool for flushes and a low-priority pool for compactions, shareable across storage engines.
+pub struct BackgroundScheduler {
+    flush_pool: Pool,
+    compaction_pool: Pool,
+}
+
+impl BackgroundScheduler {
+    /// Start a scheduler with `flush_threads` threads for the flushes and `compaction_threads` threads for the
+    /// compactions. Both must be at least 1.
+    pub fn new(flush_threads: usize, compaction_threads: usize) -> Self {
+        Self {
+            flush_pool: Pool::start("mini-lsm-flush", flush_threads),
+            compaction_pool: Pool::start("mini-lsm-compaction", compaction_threads),
+        }
+    }
+
+    pub fn num_threads(&self, priority: JobPriority) -> usize {
+        self.pool(priority).threads.len()
+    }
+
+    fn pool(&self, priority: JobPriority) -> &Pool {
+        match priority {
+            JobPriority::High => &self.flush_pool,
+            JobPriority::Low => &self.compaction_pool,
+        }
+    }
+}
+
+impl Drop for BackgroundScheduler {
+    fn drop(&mut self) {
+        self.flush_pool.stop();
+        self.compaction_pool.stop();
+    }
+}
+
+impl fmt::Debug for BackgroundScheduler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BackgroundScheduler")
+            .field("flush_threads", &self.flush_pool.threads.len())
+            .field("compaction_threads", &self.compaction_pool.threads.len())
+            .finish()
+    }
+}
+
+#[derive(Default)]
+struct JobState {
+    /// In the queue of the pool, maybe waiting for a retry.
+    queued: bool,
+    running: bool,
+    /// Scheduled while running, so it runs once more when it finishes.
+    rerun: bool,
+}
+
+#[derive(Default)]
+struct BackgroundWorkState {
+    flush: JobState,
+    compaction: JobState,
+    /// The number of `pause_background_work` calls not undone yet.
+    paused: usize,
+    /// Set once the engine closes. No job runs afterwards.
+    stopped: bool,
+}
+
+impl BackgroundWorkState {
+    fn job(&mut self, priority: JobPriority) -> &mut JobState {
+        match priority {
+            JobPriority::High => &mut self.flush,
+            JobPriority::Low => &mut self.compaction,
+        }
+    }
+}
+
+/// The flushes and compactions of one engine on a scheduler.
+pub(crate) struct BackgroundWork {
+    engine: Weak<LsmStorageInner>,
+    scheduler: Arc<BackgroundScheduler>,
+    state: Mutex<BackgroundWorkState>,
+    /// Notified whenever a job finishes.
+    idle: Condvar,
+}
+
+impl BackgroundWork {
+    /// Queue a job, unless it is queued already. A job that is running runs once more instead.
+    fn schedule(&self, priority: JobPriority, delay: Option<Duration>) {
+        let mut state = self.state.lock();
+        if state.stopped {
+            return;
+        }
+        let job = state.job(priority);
+        if job.running {
+            job.rerun = true;
+        } else if !job.queued {
+            job.queued = true;
+            let job = Job {
+                engine: self.engine.clone(),
+                priority,
+            };
+            self.scheduler.pool(priority).shared.push(job, delay);
+        }
+    }
+
+    /// Wait for the running jobs to finish.
+    fn wait_idle(&self) {
+        let mut state = self.state.lock();
+        while state.flush.running || state.compaction.running {
+            self.idle.wait(&mut state);
+        }
+    }
+}
+
+impl LsmStorageInner {
+    /// Run the flushes and compactions of the engine on `scheduler` from now on.
+    pub(crate) fn start_background_work(self: &Arc<Self>, scheduler: Arc<BackgroundScheduler>) {
+        let work = BackgroundWork {
+            engine: Arc::downgrade(self),
+            scheduler,
+            state: Mutex::new(BackgroundWorkState::default()),
+            idle: Condvar::new(),
+        };
+        if self.background_work.set(work).is_err() {
+            panic!("the background work is started already");
+        }
+        // the engine may have recovered immutable memtables and SSTs waiting for a compaction
+        self.schedule_flush();
+        self.schedule_compaction();
+    }
+
+    /// Stop scheduling the flushes and compactions of the engine, and wait for the running ones if `wait` is set.
+    pub(crate) fn stop_background_work(&self, wait: bool) {
+        if let Some(work) = self.background_work.get() {
+            work.state.lock().stopped = true;
+            if wait {
+                work.wait_idle();
+            }
+        }
+    }
+
+    pub(crate) fn schedule_flush(&self) {
+        if let Some(work) = self.background_work.get() {
+            work.schedule(JobPriority::High, None);
+        }
+    }
+
+    pub(crate) fn schedule_compaction(&self) {
+        if matches!(
+            self.options.compaction_options,
+            CompactionOptions::NoCompaction
+        ) {
+            return;
+        }
+        if let Some(work) = self.background_work.get() {
+            work.schedule(JobPriority::Low, None);
+        }
+    }
+
+    /// Stop the flushes and compactions of the engine until `continue_background_work`, and wait for the running
+    /// ones. The calls nest. Must not be called from an event listener, which may run on a job.
+    pub(crate) fn pause_background_work(&self) {
+        if let Some(work) = self.background_work.get() {
+            work.state.lock().paused += 1;
+            work.wait_idle();
+        }
+    }
+
+    /// Undo a `pause_background_work`, and catch up on the work skipped in the meantime once no pause is left.
+    pub(crate) fn continue_background_work(&self) -> Result<()> {
+        let Some(work) = self.background_work.get() else {
+            bail!("the background work is not started");
+        };
+        {
+            let mut state = work.state.lock();
+            if state.paused == 0 {
+                bail!("the background work is not paused");
+            }
+            state.paused -= 1;
+            if state.paused > 0 {
+                return Ok(());
+            }
+        }
+        self.schedule_flush();
+        self.schedule_compaction();
+        Ok(())
+    }
+
+    fn run_scheduled_job(&self, priority: JobPriority) {
+        let work = self
+            .background_work
+            .get()
+            .expect("a job of an engine without background work");
+        {
+            let mut state = work.state.lock();
+            let skip = state.paused > 0 || state.stopped;
+            let job = state.job(priority);
+            job.queued = false;
+            if skip {
+                return;
+            }
+            job.running = true;
+        }
+        let outcome = match priority {
+            JobPriority::High => self.run_background_job(BackgroundErrorReason::Flush, || {
+                self.trigger_flush().map(|()| false)
+            }),
+            JobPriority::Low => self.run_background_job(BackgroundErrorReason::Compaction, || {
+                self.trigger_compaction()
+            }),
+        };
+        let rerun = {
+            let mut state = work.state.lock();
+            let job = state.job(priority);
+            job.running = false;
+            std::mem::take(&mut job.rerun)
+        };
+        work.idle.notify_all();
+        match outcome {
+            JobOutcome::Again => work.schedule(priority, None),
+            JobOutcome::RetryLater => work.schedule(priority, Some(RETRY_DELAY)),
+            JobOutcome::Done if rerun => work.schedule(priority, None),
+            JobOutcome::Done => {}
+        }
+    }
+}
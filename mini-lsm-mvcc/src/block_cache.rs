@@ -0,0 +1,405 @@
+//! A sharded block cache that charges each block by its size in bytes.
+//!
+//! The cache is split into shards, each with its own lock and its own share of the capacity, and evicts with either
+//! LRU or CLOCK. Blocks inserted with `CachePriority::High` are only evicted once no low-priority block is left in the
+//! shard, as long as they take no more than `high_priority_ratio` of it. One cache can be shared by several
+//! `MiniLsm` instances through `BlockCache::share`, which gives each of them a separate key space.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use anyhow::Result;
+use parking_lot::Mutex;
+
+use crate::block::{Block, SIZEOF_U16};
+use crate::statistics::{Statistics, Ticker};
+
+/// The key of a cached block: the SST id and the index of the block in the SST.
+pub type BlockCacheKey = (usize, usize);
+
+/// The key of a block in a shard, namespaced by the `BlockCache` handle that inserted it.
+type ShardKey = (u64, usize, usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CacheEvictionPolicy {
+    /// Evict the least recently used block.
+    #[default]
+    Lru,
+    /// Evict the first block the clock hand finds without its reference bit set, clearing the bits it passes.
+    Clock,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CachePriority {
+    #[default]
+    Low,
+    /// Kept over low-priority blocks, e.g. for hot blocks that should stay pinned in the cache.
+    High,
+}
+
+impl CachePriority {
+    fn pool(self) -> usize {
+        match self {
+            CachePriority::Low => 0,
+            CachePriority::High => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BlockCacheOptions {
+    /// The capacity of the cache in bytes.
+    pub capacity: usize,
+    /// The number of shards. Each shard holds `capacity / num_shards` bytes.
+    pub num_shards: usize,
+    pub eviction_policy: CacheEvictionPolicy,
+    /// The fraction of a shard that high-priority blocks can take. Beyond it, they are evicted before low-priority
+    /// blocks.
+    pub high_priority_ratio: f64,
+}
+
+impl BlockCacheOptions {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            num_shards: 16,
+            eviction_policy: CacheEvictionPolicy::Lru,
+            high_priority_ratio: 0.5,
+        }
+    }
+}
+
+/// A point-in-time view of the counters of a block cache, covering every handle that shares it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BlockCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub inserts: u64,
+    pub evictions: u64,
+    /// The bytes charged for the blocks in the cache.
+    pub usage: usize,
+    pub capacity: usize,
+}
+
+/// The bytes a block is charged in the cache.
+fn charge_of(block: &Block) -> usize {
+    std::mem::size_of::<Block>() + block.data.len() + block.offsets.len() * SIZEOF_U16
+}
+
+struct CacheEntry {
+    block: Arc<Block>,
+    charge: usize,
+    priority: CachePriority,
+    /// The position in the LRU list, or the generation of the entry in the clock ring.
+    position: u64,
+    referenced: bool,
+}
+
+struct Shard {
+    policy: CacheEvictionPolicy,
+    capacity: usize,
+    high_priority_capacity: usize,
+    /// The usage of the low and high priority pools.
+    usage: [usize; 2],
+    entries: HashMap<ShardKey, CacheEntry>,
+    /// The LRU list of each pool, from the least to the most recently used.
+    lru: [BTreeMap<u64, ShardKey>; 2],
+    /// The clock ring of each pool. Removed or replaced entries stay in the ring until the hand skips them.
+    clock: [VecDeque<(ShardKey, u64)>; 2],
+    next_position: u64,
+}
+
+impl Shard {
+    fn new(policy: CacheEvictionPolicy, capacity: usize, high_priority_ratio: f64) -> Self {
+        Self {
+            policy,
+            capacity,
+            high_priority_capacity: (capacity as f64 * high_priority_ratio) as usize,
+            usage: [0; 2],
+            entries: HashMap::new(),
+            lru: Default::default(),
+            clock: Default::default(),
+            next_position: 0,
+        }
+    }
+
+    fn next_position(&mut self) -> u64 {
+        self.next_position += 1;
+        self.next_position
+    }
+
+    fn get(&mut self, key: &ShardKey) -> Option<Arc<Block>> {
+        let position = match self.policy {
+            CacheEvictionPolicy::Lru => Some(self.next_position()),
+            CacheEvictionPolicy::Clock => None,
+        };
+        let entry = self.entries.get_mut(key)?;
+        match position {
+            Some(position) => {
+                let pool = entry.priority.pool();
+                self.lru[pool].remove(&entry.position);
+                self.lru[pool].insert(position, *key);
+                entry.position = position;
+            }
+            None => entry.referenced = true,
+        }
+        Some(entry.block.clone())
+    }
+
+    fn remove(&mut self, key: &ShardKey) -> Option<CacheEntry> {
+        let entry = self.entries.remove(key)?;
+        let pool = entry.priority.pool();
+        self.usage[pool] -= entry.charge;
+        if self.policy == CacheEvictionPolicy::Lru {
+            self.lru[pool].remove(&entry.position);
+        }
+        Some(entry)
+    }
+
+    /// Insert the block and return the number of blocks evicted for it. A block larger than the shard is not cached.
+    fn insert(&mut self, key: ShardKey, block: Arc<Block>, priority: CachePriority) -> u64 {
+        let charge = charge_of(&block);
+        self.remove(&key);
+        if charge > self.capacity {
+            return 0;
+        }
+        let position = self.next_position();
+        let pool = priority.pool();
+        match self.policy {
+            CacheEvictionPolicy::Lru => {
+                self.lru[pool].insert(position, key);
+            }
+            CacheEvictionPolicy::Clock => self.clock[pool].push_back((key, position)),
+        }
+        self.entries.insert(
+            key,
+            CacheEntry {
+                block,
+                charge,
+                priority,
+                position,
+                referenced: false,
+            },
+        );
+        self.usage[pool] += charge;
+
+        let mut evicted = 0;
+        while self.usage[0] + self.usage[1] > self.capacity {
+            let high = CachePriority::High.pool();
+            let victim_pool =
+                if self.usage[0] == 0 || self.usage[high] > self.high_priority_capacity {
+                    high
+                } else {
+                    CachePriority::Low.pool()
+                };
+            self.evict_one(victim_pool);
+            evicted += 1;
+        }
+        evicted
+    }
+
+    /// Evict one block from a pool that is not empty.
+    fn evict_one(&mut self, pool: usize) {
+        let victim = match self.policy {
+            CacheEvictionPolicy::Lru => self.lru[pool].pop_first().map(|(_, key)| key),
+            CacheEvictionPolicy::Clock => loop {
+                let Some((key, position)) = self.clock[pool].pop_front() else {
+                    break None;
+                };
+                let Some(entry) = self.entries.get_mut(&key) else {
+                    continue;
+                };
+                if entry.position != position || entry.priority.pool() != pool {
+                    continue;
+                }
+                if entry.referenced {
+                    entry.referenced = false;
+                    self.clock[pool].push_back((key, position));
+                    continue;
+                }
+                break Some(key);
+            },
+        };
+        let key = victim.expect("evicting from an empty pool");
+        self.remove(&key);
+    }
+}
+
+struct SharedBlockCache {
+    options: BlockCacheOptions,
+    shards: Vec<Mutex<Shard>>,
+    next_namespace: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    inserts: AtomicU64,
+    evictions: AtomicU64,
+}
+
+/// A handle to a block cache. Handles created with `share` use the same shards and capacity, but keep their blocks
+/// apart, so that storage engines with overlapping SST ids can share one cache.
+pub struct BlockCache {
+    shared: Arc<SharedBlockCache>,
+    namespace: u64,
+    /// The engine statistics that count the hits and misses of this handle.
+    statistics: Option<Arc<Statistics>>,
+}
+
+impl BlockCache {
+    /// Create an LRU block cache holding up to `capacity` bytes of blocks.
+    pub fn new(capacity: usize) -> Self {
+        Self::with_options(BlockCacheOptions::new(capacity))
+    }
+
+    pub fn with_options(options: BlockCacheOptions) -> Self {
+        let num_shards = options.num_shards.max(1);
+        let shard_capacity = options.capacity / num_shards;
+        let shards = (0..num_shards)
+            .map(|_| {
+                Mutex::new(Shard::new(
+                    options.eviction_policy,
+                    shard_capacity,
+                    options.high_priority_ratio,
+                ))
+            })
+            .collect();
+        Self {
+            shared: Arc::new(SharedBlockCache {
+                options,
+                shards,
+                next_namespace: AtomicU64::new(1),
+                hits: AtomicU64::new(0),
+                misses: AtomicU64::new(0),
+                inserts: AtomicU64::new(0),
+                evictions: AtomicU64::new(0),
+            }),
+            namespace: 0,
+            statistics: None,
+        }
+    }
+
+    /// Create a handle to the same cache with its own key space.
+    pub fn share(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+            namespace: self.shared.next_namespace.fetch_add(1, Ordering::Relaxed),
+            statistics: None,
+        }
+    }
+
+    /// Count the hits and misses of this handle in the statistics of an engine as well.
+    pub fn with_statistics(mut self, statistics: Arc<Statistics>) -> Self {
+        self.statistics = Some(statistics);
+        self
+    }
+
+    pub fn options(&self) -> &BlockCacheOptions {
+        &self.shared.options
+    }
+
+    fn shard_key(&self, key: &BlockCacheKey) -> ShardKey {
+        (self.namespace, key.0, key.1)
+    }
+
+    fn shard(&self, key: &ShardKey) -> &Mutex<Shard> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let shards = &self.shared.shards;
+        &shards[hasher.finish() as usize % shards.len()]
+    }
+
+    pub fn get(&self, key: &BlockCacheKey) -> Option<Arc<Block>> {
+        let key = self.shard_key(key);
+        let block = self.shard(&key).lock().get(&key);
+        let (counter, ticker) = if block.is_some() {
+            (&self.shared.hits, Ticker::BlockCacheHit)
+        } else {
+            (&self.shared.misses, Ticker::BlockCacheMiss)
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        if let Some(statistics) = &self.statistics {
+            statistics.record(ticker, 1);
+        }
+        block
+    }
+
+    /// Whether the block is in the cache. Unlike `get`, this neither counts as an access nor updates the stats.
+    pub fn contains_key(&self, key: &BlockCacheKey) -> bool {
+        let key = self.shard_key(key);
+        self.shard(&key).lock().entries.contains_key(&key)
+    }
+
+    pub fn insert(&self, key: BlockCacheKey, block: Arc<Block>) {
+        self.insert_with_priority(key, block, CachePriority::Low)
+    }
+
+    pub fn insert_with_priority(
+        &self,
+        key: BlockCacheKey,
+        block: Arc<Block>,
+        priority: CachePriority,
+    ) {
+        let key = self.shard_key(&key);
+        let evicted = self.shard(&key).lock().insert(key, block, priority);
+        self.shared.inserts.fetch_add(1, Ordering::Relaxed);
+        self.shared.evictions.fetch_add(evicted, Ordering::Relaxed);
+    }
+
+    /// Get the block, or load it with `init` and insert it with low priority if it is not in the cache.
+    pub fn try_get_with(
+        &self,
+        key: BlockCacheKey,
+        init: impl FnOnce() -> Result<Arc<Block>>,
+    ) -> Result<Arc<Block>> {
+        if let Some(block) = self.get(&key) {
+            return Ok(block);
+        }
+        let block = init()?;
+        self.insert(key, block.clone());
+        Ok(block)
+    }
+
+    pub fn remove(&self, key: &BlockCacheKey) -> Option<Arc<Block>> {
+        let key = self.shard_key(key);
+        self.shard(&key)
+            .lock()
+            .remove(&key)
+            .map(|entry| entry.block)
+    }
+
+    /// The bytes charged for the blocks in the cache, including the blocks of the other handles sharing it.
+    pub fn usage(&self) -> usize {
+        self.shared
+            .shards
+            .iter()
+            .map(|shard| {
+                let shard = shard.lock();
+                shard.usage[0] + shard.usage[1]
+            })
+            .sum()
+    }
+
+    pub fn stats(&self) -> BlockCacheStats {
+        BlockCacheStats {
+            hits: self.shared.hits.load(Ordering::Relaxed),
+            misses: self.shared.misses.load(Ordering::Relaxed),
+            inserts: self.shared.inserts.load(Ordering::Relaxed),
+            evictions: self.shared.evictions.load(Ordering::Relaxed),
+            usage: self.usage(),
+            capacity: self.shared.options.capacity,
+        }
+    }
+}
+
+impl fmt::Debug for BlockCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockCache")
+            .field("namespace", &self.namespace)
+            .field("options", &self.shared.options)
+            .field("usage", &self.usage())
+            .finish()
+    }
+}
@@ -4,7 +4,7 @@ mod tiered;
 
 use std::collections::HashSet;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::Instant;
 
 use anyhow::Result;
 pub use leveled::{LeveledCompactionController, LeveledCompactionOptions, LeveledCompactionTask};
@@ -14,6 +14,7 @@ pub use simple_leveled::{
 };
 pub use tiered::{TieredCompactionController, TieredCompactionOptions, TieredCompactionTask};
 
+use crate::event_listener::{CompactionBeginInfo, CompactionCompletedInfo, SstDeletedInfo};
 use crate::iterators::concat_iterator::SstConcatIterator;
 use crate::iterators::merge_iterator::MergeIterator;
 use crate::iterators::two_merge_iterator::TwoMergeIterator;
@@ -21,9 +22,10 @@ use crate::iterators::StorageIterator;
 use crate::key::KeySlice;
 use crate::lsm_storage::{CompactionFilter, LsmStorageInner, LsmStorageState};
 use crate::manifest::ManifestRecord;
+use crate::statistics::{HistogramType, Ticker};
 use crate::table::{SsTable, SsTableBuilder, SsTableIterator};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum CompactionTask {
     Leveled(LeveledCompactionTask),
     Tiered(TieredCompactionTask),
@@ -35,6 +37,31 @@ pub enum CompactionTask {
 }
 
 impl CompactionTask {
+    /// The ids of the SSTs the task reads.
+    pub fn input_sst_ids(&self) -> Vec<usize> {
+        match self {
+            CompactionTask::ForceFullCompaction {
+                l0_sstables,
+                l1_sstables,
+            } => [l0_sstables.as_slice(), l1_sstables].concat(),
+            CompactionTask::Leveled(task) => [
+                task.upper_level_sst_ids.as_slice(),
+                &task.lower_level_sst_ids,
+            ]
+            .concat(),
+            CompactionTask::Simple(task) => [
+                task.upper_level_sst_ids.as_slice(),
+                &task.lower_level_sst_ids,
+            ]
+            .concat(),
+            CompactionTask::Tiered(task) => task
+                .tiers
+                .iter()
+                .flat_map(|(_, ids)| ids.iter().copied())
+                .collect(),
+        }
+    }
+
     fn compact_to_bottom_level(&self) -> bool {
         match self {
             CompactionTask::ForceFullCompaction { .. } => true,
@@ -173,9 +200,10 @@ impl LsmStorageInner {
             if builder_inner.estimated_size() >= self.options.target_sst_size && !same_as_last_key {
                 let sst_id = self.next_sst_id();
                 let old_builder = builder.take().unwrap();
-                let sst = Arc::new(old_builder.build(
+                let sst = Arc::new(old_builder.build_with_table_cache(
                     sst_id,
                     Some(self.block_cache.clone()),
+                    self.table_cache.clone(),
                     self.path_of_sst(sst_id),
                 )?);
                 new_sst.push(sst);
@@ -194,9 +222,10 @@ impl LsmStorageInner {
         }
         if let Some(builder) = builder {
             let sst_id = self.next_sst_id(); // lock dropped here
-            let sst = Arc::new(builder.build(
+            let sst = Arc::new(builder.build_with_table_cache(
                 sst_id,
                 Some(self.block_cache.clone()),
+                self.table_cache.clone(),
                 self.path_of_sst(sst_id),
             )?);
             new_sst.push(sst);
@@ -315,15 +344,21 @@ impl LsmStorageInner {
 
         println!("force full compaction: {:?}", compaction_task);
 
+        let begin_info = self.begin_compaction(&compaction_task, &snapshot);
+        let start = Instant::now();
         let sstables = self.compact(&compaction_task)?;
+        let output_bytes = sstables.iter().map(|sst| sst.table_size()).sum();
+        let sst_metas = sstables.iter().map(|sst| sst.meta()).collect();
         let mut ids = Vec::with_capacity(sstables.len());
 
-        {
+        let ssts_to_remove = {
             let state_lock = self.state_lock.lock();
             let mut state = self.state.read().as_ref().clone();
+            let mut ssts_to_remove = Vec::with_capacity(l0_sstables.len() + l1_sstables.len());
             for sst in l0_sstables.iter().chain(l1_sstables.iter()) {
                 let result = state.sstables.remove(sst);
                 assert!(result.is_some());
+                ssts_to_remove.push(result.unwrap());
             }
             for new_sst in sstables {
                 ids.push(new_sst.sst_id());
@@ -342,21 +377,92 @@ impl LsmStorageInner {
             assert!(l0_sstables_map.is_empty());
             *self.state.write() = Arc::new(state);
             self.sync_dir()?;
+            self.manifest
+                .as_ref()
+                .unwrap()
+                .add_record(&state_lock, ManifestRecord::NewSsts(sst_metas))?;
             self.manifest.as_ref().unwrap().add_record(
                 &state_lock,
                 ManifestRecord::Compaction(compaction_task, ids.clone()),
             )?;
-        }
-        for sst in l0_sstables.iter().chain(l1_sstables.iter()) {
-            std::fs::remove_file(self.path_of_sst(*sst))?;
-        }
+            ssts_to_remove
+        };
+        self.remove_sst_files(ssts_to_remove)?;
 
         println!("force full compaction done, new SSTs: {:?}", ids);
+        self.complete_compaction(begin_info, ids, output_bytes, start);
 
         Ok(())
     }
 
-    fn trigger_compaction(&self) -> Result<()> {
+    /// Tell the listeners about a compaction about to run on the SSTs in `snapshot`.
+    fn begin_compaction(
+        &self,
+        task: &CompactionTask,
+        snapshot: &LsmStorageState,
+    ) -> CompactionBeginInfo {
+        let input_sst_ids = task.input_sst_ids();
+        let input_bytes = input_sst_ids
+            .iter()
+            .map(|id| snapshot.sstables[id].table_size())
+            .sum();
+        let info = CompactionBeginInfo {
+            task: task.clone(),
+            input_sst_ids,
+            input_bytes,
+        };
+        self.notify_listeners(|listener| listener.on_compaction_begin(&info));
+        info
+    }
+
+    fn complete_compaction(
+        &self,
+        begin_info: CompactionBeginInfo,
+        output_sst_ids: Vec<usize>,
+        output_bytes: u64,
+        start: Instant,
+    ) {
+        let info = CompactionCompletedInfo {
+            task: begin_info.task,
+            input_sst_ids: begin_info.input_sst_ids,
+            output_sst_ids,
+            input_bytes: begin_info.input_bytes,
+            output_bytes,
+            duration: start.elapsed(),
+        };
+        self.statistics.record(Ticker::Compactions, 1);
+        self.statistics
+            .record(Ticker::CompactionBytesRead, info.input_bytes);
+        self.statistics
+            .record(Ticker::CompactionBytesWritten, info.output_bytes);
+        self.statistics
+            .record_duration(HistogramType::CompactionMicros, info.duration);
+        self.notify_listeners(|listener| listener.on_compaction_completed(&info));
+    }
+
+    /// Delete the files of SSTs removed from the state. An SST still referenced elsewhere, e.g. by a snapshot or an
+    /// iterator, keeps its reader open so that it can still be read.
+    fn remove_sst_files(&self, ssts: Vec<Arc<SsTable>>) -> Result<()> {
+        for sst in ssts {
+            // No one can get the SST from the state anymore, so a count of 1 means it is only referenced here.
+            if Arc::strong_count(&sst) > 1 {
+                sst.pin_reader()?;
+            }
+            self.table_cache.evict(sst.sst_id());
+            let path = self.path_of_sst(sst.sst_id());
+            std::fs::remove_file(&path)?;
+            self.notify_listeners(|listener| {
+                listener.on_sst_deleted(&SstDeletedInfo {
+                    sst_id: sst.sst_id(),
+                    path: path.clone(),
+                })
+            });
+        }
+        Ok(())
+    }
+
+    /// Run a compaction task if the controller has one. Returns whether it ran one.
+    pub(crate) fn trigger_compaction(&self) -> Result<bool> {
         let snapshot = {
             let state = self.state.read();
             state.clone()
@@ -365,12 +471,18 @@ impl LsmStorageInner {
             .compaction_controller
             .generate_compaction_task(&snapshot);
         let Some(task) = task else {
-            return Ok(());
+            *self.compaction_backlog.lock() = (0, 0);
+            return Ok(false);
         };
         self.dump_structure();
         println!("running compaction task: {:?}", task);
+        let begin_info = self.begin_compaction(&task, &snapshot);
+        *self.compaction_backlog.lock() = (begin_info.input_sst_ids.len(), begin_info.input_bytes);
+        let start = Instant::now();
         let sstables = self.compact(&task)?;
+        let output_bytes = sstables.iter().map(|sst| sst.table_size()).sum();
         let output = sstables.iter().map(|x| x.sst_id()).collect::<Vec<_>>();
+        let sst_metas = sstables.iter().map(|sst| sst.meta()).collect();
         let ssts_to_remove = {
             let state_lock = self.state_lock.lock();
             let mut snapshot = self.state.read().as_ref().clone();
@@ -394,6 +506,8 @@ impl LsmStorageInner {
             *state = Arc::new(snapshot);
             drop(state);
             self.sync_dir()?;
+            self.manifest()
+                .add_record(&state_lock, ManifestRecord::NewSsts(sst_metas))?;
             self.manifest()
                 .add_record(&state_lock, ManifestRecord::Compaction(task, new_sst_ids))?;
             ssts_to_remove
@@ -404,67 +518,27 @@ impl LsmStorageInner {
             output.len(),
             output
         );
-        for sst in ssts_to_remove {
-            std::fs::remove_file(self.path_of_sst(sst.sst_id()))?;
-        }
+        self.remove_sst_files(ssts_to_remove)?;
         self.sync_dir()?;
+        self.complete_compaction(begin_info, output, output_bytes, start);
 
-        Ok(())
+        Ok(true)
     }
 
-    pub(crate) fn spawn_compaction_thread(
-        self: &Arc<Self>,
-        rx: crossbeam_channel::Receiver<()>,
-    ) -> Result<Option<std::thread::JoinHandle<()>>> {
-        if let CompactionOptions::Leveled(_)
-        | CompactionOptions::Simple(_)
-        | CompactionOptions::Tiered(_) = self.options.compaction_options
-        {
-            let this = self.clone();
-            let handle = std::thread::spawn(move || {
-                let ticker = crossbeam_channel::tick(Duration::from_millis(50));
-                loop {
-                    crossbeam_channel::select! {
-                        recv(ticker) -> _ => if let Err(e) = this.trigger_compaction() {
-                            eprintln!("compaction failed: {}", e);
-                        },
-                        recv(rx) -> _ => return
-                    }
-                }
-            });
-            return Ok(Some(handle));
+    pub(crate) fn trigger_flush(&self) -> Result<()> {
+        while self.state.read().imm_memtables.len() >= self.options.num_memtable_limit {
+            self.force_flush_next_imm_memtable()?;
         }
-        Ok(None)
-    }
 
-    fn trigger_flush(&self) -> Result<()> {
-        let res = {
-            let state = self.state.read();
-            state.imm_memtables.len() >= self.options.num_memtable_limit
-        };
-        if res {
-            self.force_flush_next_imm_memtable()?;
+        // flush the immutable memtables while the memtables sharing the write buffer are over the budget
+        if let Some(write_buffer_manager) = &self.options.write_buffer_manager {
+            while write_buffer_manager.should_flush_immutable()
+                && !self.state.read().imm_memtables.is_empty()
+            {
+                self.force_flush_next_imm_memtable()?;
+            }
         }
 
         Ok(())
     }
-
-    pub(crate) fn spawn_flush_thread(
-        self: &Arc<Self>,
-        rx: crossbeam_channel::Receiver<()>,
-    ) -> Result<Option<std::thread::JoinHandle<()>>> {
-        let this = self.clone();
-        let handle = std::thread::spawn(move || {
-            let ticker = crossbeam_channel::tick(Duration::from_millis(50));
-            loop {
-                crossbeam_channel::select! {
-                    recv(ticker) -> _ => if let Err(e) = this.trigger_flush() {
-                        eprintln!("flush failed: {}", e);
-                    },
-                    recv(rx) -> _ => return
-                }
-            }
-        });
-        Ok(Some(handle))
-    }
 }
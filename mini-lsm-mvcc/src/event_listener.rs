@@ -0,0 +1,145 @@
+//! Callbacks for the background work of a storage engine.
+//!
+//! The listeners are registered with `LsmStorageOptions::event_listeners` when the engine opens. A callback runs on
+//! the thread doing the work, in some cases while the engine holds its state lock, so it should return quickly and
+//! must not flush or compact the engine itself. Work that takes longer, like shipping a new SST to a backup, belongs
+//! on a thread of the listener.
+
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use bytes::Bytes;
+
+use crate::background_error::{BackgroundError, BackgroundErrorSeverity};
+use crate::compact::CompactionTask;
+use crate::lsm_storage::LsmStorageInner;
+
+/// A memtable that stopped taking writes and waits for its flush.
+#[derive(Debug, Clone)]
+pub struct MemTableSealedInfo {
+    pub memtable_id: usize,
+    pub approximate_size: usize,
+    /// The number of immutable memtables, including this one.
+    pub num_imm_memtables: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct FlushBeginInfo {
+    /// The id of the memtable, which is also the id of the SST it is flushed to.
+    pub sst_id: usize,
+    pub memtable_size: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct FlushCompletedInfo {
+    pub sst_id: usize,
+    pub file_size: u64,
+    /// The first and last user key in the SST.
+    pub first_key: Bytes,
+    pub last_key: Bytes,
+    pub duration: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct CompactionBeginInfo {
+    pub task: CompactionTask,
+    pub input_sst_ids: Vec<usize>,
+    pub input_bytes: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct CompactionCompletedInfo {
+    pub task: CompactionTask,
+    pub input_sst_ids: Vec<usize>,
+    pub output_sst_ids: Vec<usize>,
+    pub input_bytes: u64,
+    pub output_bytes: u64,
+    pub duration: Duration,
+}
+
+/// An SST file removed after a compaction. A reader that still uses the SST keeps its file open.
+#[derive(Debug, Clone)]
+pub struct SstDeletedInfo {
+    pub sst_id: usize,
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteStallCondition {
+    Normal,
+    /// Writes wait for the memtables sharing the write buffer manager to be flushed.
+    Stopped,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteStallInfo {
+    pub previous: WriteStallCondition,
+    pub current: WriteStallCondition,
+}
+
+/// The background work that failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackgroundErrorReason {
+    Flush,
+    Compaction,
+}
+
+/// Callbacks for the background work of a storage engine. Every callback does nothing by default.
+pub trait EventListener: Send + Sync {
+    fn on_memtable_sealed(&self, _info: &MemTableSealedInfo) {}
+
+    /// Called before the memtable is written to an SST.
+    fn on_flush_begin(&self, _info: &FlushBeginInfo) {}
+
+    /// Called once the SST is in the manifest and the WAL of the memtable can go away.
+    fn on_flush_completed(&self, _info: &FlushCompletedInfo) {}
+
+    fn on_compaction_begin(&self, _info: &CompactionBeginInfo) {}
+
+    /// Called once the output SSTs replaced the input SSTs, and the files of the inputs are deleted.
+    fn on_compaction_completed(&self, _info: &CompactionCompletedInfo) {}
+
+    fn on_sst_deleted(&self, _info: &SstDeletedInfo) {}
+
+    fn on_stall_conditions_changed(&self, _info: &WriteStallInfo) {}
+
+    /// Called for every error of the scheduled flushes and compactions. The engine is read-only after a fatal error.
+    fn on_background_error(
+        &self,
+        _reason: BackgroundErrorReason,
+        _severity: BackgroundErrorSeverity,
+        _error: &anyhow::Error,
+    ) {
+    }
+
+    /// Called once `MiniLsm::resume` redid the failed work and the engine takes writes again.
+    fn on_background_error_resumed(&self, _error: &BackgroundError) {}
+
+    /// Called when a replication leader fails to accept a follower, or its stream to a follower breaks.
+    fn on_replication_error(&self, _error: &anyhow::Error) {}
+}
+
+impl fmt::Debug for dyn EventListener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EventListener")
+    }
+}
+
+impl LsmStorageInner {
+    pub(crate) fn notify_listeners(&self, f: impl Fn(&dyn EventListener)) {
+        for listener in &self.options.event_listeners {
+            f(listener.as_ref());
+        }
+    }
+
+    /// Tell the listeners about a change of the write stall condition of this engine.
+    pub(crate) fn set_write_stall(&self, current: WriteStallCondition) {
+        let previous = std::mem::replace(&mut *self.write_stall.lock(), current);
+        if previous != current {
+            self.notify_listeners(|listener| {
+                listener.on_stall_conditions_changed(&WriteStallInfo { previous, current })
+            });
+        }
+    }
+}
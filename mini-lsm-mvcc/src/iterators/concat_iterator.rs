@@ -4,7 +4,7 @@ use anyhow::Result;
 
 use crate::{
     key::KeySlice,
-    table::{SsTable, SsTableIterator},
+    table::{SsTable, SsTableIterator, TableReadOptions},
 };
 
 use super::StorageIterator;
@@ -15,6 +15,7 @@ pub struct SstConcatIterator {
     current: Option<SsTableIterator>,
     next_sst_idx: usize,
     sstables: Vec<Arc<SsTable>>,
+    options: TableReadOptions,
 }
 
 impl SstConcatIterator {
@@ -30,26 +31,44 @@ impl SstConcatIterator {
     }
 
     pub fn create_and_seek_to_first(sstables: Vec<Arc<SsTable>>) -> Result<Self> {
+        Self::create_and_seek_to_first_with_options(sstables, TableReadOptions::default())
+    }
+
+    pub fn create_and_seek_to_first_with_options(
+        sstables: Vec<Arc<SsTable>>,
+        options: TableReadOptions,
+    ) -> Result<Self> {
         Self::check_sst_valid(&sstables);
         if sstables.is_empty() {
             return Ok(Self {
                 current: None,
                 next_sst_idx: 0,
                 sstables,
+                options,
             });
         }
         let mut iter = Self {
-            current: Some(SsTableIterator::create_and_seek_to_first(
+            current: Some(SsTableIterator::create_and_seek_to_first_with_options(
                 sstables[0].clone(),
+                options.clone(),
             )?),
             next_sst_idx: 1,
             sstables,
+            options,
         };
         iter.move_until_valid()?;
         Ok(iter)
     }
 
     pub fn create_and_seek_to_key(sstables: Vec<Arc<SsTable>>, key: KeySlice) -> Result<Self> {
+        Self::create_and_seek_to_key_with_options(sstables, key, TableReadOptions::default())
+    }
+
+    pub fn create_and_seek_to_key_with_options(
+        sstables: Vec<Arc<SsTable>>,
+        key: KeySlice,
+        options: TableReadOptions,
+    ) -> Result<Self> {
         Self::check_sst_valid(&sstables);
         let idx: usize = sstables
             .partition_point(|table| table.first_key().as_key_slice() <= key)
@@ -59,15 +78,18 @@ impl SstConcatIterator {
                 current: None,
                 next_sst_idx: sstables.len(),
                 sstables,
+                options,
             });
         }
         let mut iter = Self {
-            current: Some(SsTableIterator::create_and_seek_to_key(
+            current: Some(SsTableIterator::create_and_seek_to_key_with_options(
                 sstables[idx].clone(),
                 key,
+                options.clone(),
             )?),
             next_sst_idx: idx + 1,
             sstables,
+            options,
         };
         iter.move_until_valid()?;
         Ok(iter)
@@ -81,8 +103,9 @@ impl SstConcatIterator {
             if self.next_sst_idx >= self.sstables.len() {
                 self.current = None;
             } else {
-                self.current = Some(SsTableIterator::create_and_seek_to_first(
+                self.current = Some(SsTableIterator::create_and_seek_to_first_with_options(
                     self.sstables[self.next_sst_idx].clone(),
+                    self.options.clone(),
                 )?);
                 self.next_sst_idx += 1;
             }
@@ -94,7 +117,7 @@ impl SstConcatIterator {
 impl StorageIterator for SstConcatIterator {
     type KeyType<'a> = KeySlice<'a>;
 
-    fn key(&self) -> KeySlice {
+    fn key(&self) -> KeySlice<'_> {
         self.current.as_ref().unwrap().key()
     }
 
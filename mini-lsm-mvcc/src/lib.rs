@@ -1,15 +1,26 @@
+pub mod async_lsm;
+pub mod background_error;
+pub mod background_scheduler;
 pub mod block;
+pub mod block_cache;
 pub mod compact;
 pub mod debug;
+pub mod event_listener;
 pub mod iterators;
 pub mod key;
 pub mod lsm_iterator;
 pub mod lsm_storage;
 pub mod manifest;
 pub mod mem_table;
+pub mod metrics;
 pub mod mvcc;
+pub mod replication;
+pub mod row_cache;
+pub mod statistics;
 pub mod table;
+pub mod table_cache;
 pub mod wal;
+pub mod write_buffer_manager;
 
 #[cfg(test)]
 mod tests;
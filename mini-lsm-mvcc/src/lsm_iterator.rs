@@ -1,4 +1,6 @@
 use std::ops::Bound;
+use std::sync::Arc;
+use std::time::Instant;
 
 use anyhow::{bail, Result};
 use bytes::Bytes;
@@ -8,6 +10,7 @@ use crate::iterators::merge_iterator::MergeIterator;
 use crate::iterators::two_merge_iterator::TwoMergeIterator;
 use crate::iterators::StorageIterator;
 use crate::mem_table::MemTableIterator;
+use crate::statistics::{HistogramType, Statistics};
 use crate::table::SsTableIterator;
 
 /// Represents the internal type for an LSM iterator. This type will be changed across the tutorial for multiple times.
@@ -22,6 +25,8 @@ pub struct LsmIterator {
     is_valid: bool,
     read_ts: u64,
     prev_key: Vec<u8>,
+    /// Where to record the time of the scan, and when it started. Taken once the scan ends.
+    scan_timer: Option<(Arc<Statistics>, Instant)>,
 }
 
 impl LsmIterator {
@@ -36,11 +41,27 @@ impl LsmIterator {
             end_bound,
             read_ts,
             prev_key: Vec::new(),
+            scan_timer: None,
         };
         iter.move_to_key()?;
         Ok(iter)
     }
 
+    /// Record the time from `start` until the iterator is exhausted or dropped as a scan.
+    pub(crate) fn with_scan_timer(mut self, statistics: Arc<Statistics>, start: Instant) -> Self {
+        self.scan_timer = Some((statistics, start));
+        if !self.is_valid {
+            self.finish_scan();
+        }
+        self
+    }
+
+    fn finish_scan(&mut self) {
+        if let Some((statistics, start)) = self.scan_timer.take() {
+            statistics.record_elapsed(HistogramType::ScanMicros, start);
+        }
+    }
+
     fn next_inner(&mut self) -> Result<()> {
         self.inner.next()?;
         if !self.inner.is_valid() {
@@ -103,6 +124,9 @@ impl StorageIterator for LsmIterator {
     fn next(&mut self) -> Result<()> {
         self.next_inner()?;
         self.move_to_key()?;
+        if !self.is_valid {
+            self.finish_scan();
+        }
         Ok(())
     }
 
@@ -111,6 +135,12 @@ impl StorageIterator for LsmIterator {
     }
 }
 
+impl Drop for LsmIterator {
+    fn drop(&mut self) {
+        self.finish_scan();
+    }
+}
+
 /// A wrapper around existing iterator, will prevent users from calling `next` when the iterator is
 /// invalid. If an iterator is already invalid, `next` does not do anything. If `next` returns an error,
 /// `is_valid` should return false, and `next` should always return an error.
@@ -129,7 +159,10 @@ impl<I: StorageIterator> FusedIterator<I> {
 }
 
 impl<I: StorageIterator> StorageIterator for FusedIterator<I> {
-    type KeyType<'a> = I::KeyType<'a> where Self: 'a;
+    type KeyType<'a>
+        = I::KeyType<'a>
+    where
+        Self: 'a;
 
     fn is_valid(&self) -> bool {
         !self.has_errored && self.iter.is_valid()
@@ -1,32 +1,49 @@
-use std::collections::{BTreeSet, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fs::File;
+use std::net::SocketAddr;
 use std::ops::Bound;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::AtomicUsize;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use bytes::Bytes;
 use parking_lot::{Mutex, MutexGuard, RwLock};
 
-use crate::block::Block;
+use crate::background_error::{BackgroundError, BackgroundErrorState};
+use crate::background_scheduler::{BackgroundScheduler, BackgroundWork};
 use crate::compact::{
     CompactionController, CompactionOptions, LeveledCompactionController, LeveledCompactionOptions,
     SimpleLeveledCompactionController, SimpleLeveledCompactionOptions, TieredCompactionController,
 };
+use crate::event_listener::{
+    EventListener, FlushBeginInfo, FlushCompletedInfo, MemTableSealedInfo, WriteStallCondition,
+};
 use crate::iterators::concat_iterator::SstConcatIterator;
 use crate::iterators::merge_iterator::MergeIterator;
 use crate::iterators::two_merge_iterator::TwoMergeIterator;
 use crate::iterators::StorageIterator;
-use crate::key::{self, KeySlice};
+use crate::key::{self, KeyBytes, KeySlice};
 use crate::lsm_iterator::{FusedIterator, LsmIterator};
 use crate::manifest::{Manifest, ManifestRecord};
-use crate::mem_table::{map_bound, map_key_bound_plus_ts, MemTable};
-use crate::mvcc::txn::{Transaction, TxnIterator};
-use crate::mvcc::LsmMvccInner;
-use crate::table::{FileObject, SsTable, SsTableBuilder, SsTableIterator};
-
-pub type BlockCache = moka::sync::Cache<(usize, usize), Arc<Block>>;
+use crate::mem_table::{map_bound, map_key_bound_plus_ts, MemTable, MemTableKind};
+use crate::metrics::MetricsServer;
+use crate::mvcc::snapshot::{NamedSnapshotInfo, Snapshot, SnapshotIterator};
+use crate::mvcc::txn::{ConflictSets, IsolationLevel, PreparedTxn, Transaction, TxnIterator};
+use crate::mvcc::{CommittedTxnData, LsmMvccInner};
+use crate::replication::{ReplicationAddr, ReplicationHub, ReplicationLeader};
+use crate::row_cache::{RowCache, RowCacheStats};
+use crate::statistics::{HistogramType, Statistics, Ticker};
+use crate::table::{FileObject, SsTable, SsTableBuilder, SsTableIterator, TableReadOptions};
+use crate::table_cache::TableCache;
+use crate::wal::Wal;
+use crate::write_buffer_manager::WriteBufferManager;
+
+pub use crate::block_cache::BlockCache;
+
+/// The size of the block cache of a storage engine that does not share one, in bytes.
+const DEFAULT_BLOCK_CACHE_SIZE: usize = 256 << 20;
 
 /// Represents the state of the storage engine.
 #[derive(Clone)]
@@ -61,7 +78,10 @@ impl LsmStorageState {
             CompactionOptions::NoCompaction => vec![(1, Vec::new())],
         };
         Self {
-            memtable: Arc::new(MemTable::create(0)),
+            memtable: Arc::new(
+                MemTable::new(0, options.memtable_kind, None)
+                    .with_write_buffer_manager(options.write_buffer_manager.clone()),
+            ),
             imm_memtables: Vec::new(),
             l0_sstables: Vec::new(),
             levels,
@@ -80,7 +100,52 @@ pub struct LsmStorageOptions {
     pub num_memtable_limit: usize,
     pub compaction_options: CompactionOptions,
     pub enable_wal: bool,
+    /// Directory of the WAL segments. Defaults to the DB directory.
+    pub wal_dir: Option<PathBuf>,
+    /// Size of a WAL segment in bytes. The WAL rotates to a new segment when it is full.
+    pub wal_segment_size: usize,
+    /// The isolation level of `new_txn` and of the reads and writes outside a transaction: serializable if set,
+    /// snapshot isolation otherwise.
     pub serializable: bool,
+    /// Keep old versions for time-travel reads with `get_at` and `scan_at`. Without it, compaction only keeps the
+    /// versions visible to live transactions and snapshots.
+    pub history_retention: Option<HistoryRetention>,
+    /// How long a pessimistic transaction waits for a key lock before giving up.
+    pub lock_timeout: Duration,
+    /// A transaction spills its buffered writes to disk once they exceed this many bytes.
+    pub txn_spill_threshold: usize,
+    /// A block cache shared with other storage engines. Each engine gets its own handle of it with `share`, so that
+    /// their SST ids do not collide. Without it, the engine creates a cache of its own.
+    pub block_cache: Option<Arc<BlockCache>>,
+    /// The maximum number of SST files the table cache keeps open.
+    pub max_open_files: usize,
+    /// The bytes of block indexes and bloom filters the table cache keeps in memory.
+    pub table_cache_size: usize,
+    /// The bytes of the row cache, which answers point lookups of hot keys without reading the memtables and SSTs.
+    /// 0 disables it.
+    pub row_cache_size: usize,
+    /// The data structure of the memtables.
+    pub memtable_kind: MemTableKind,
+    /// A budget for the memtables shared with other storage engines. Without it, each engine only bounds its
+    /// memtables by `target_sst_size` and `num_memtable_limit`.
+    pub write_buffer_manager: Option<Arc<WriteBufferManager>>,
+    /// The local address to serve the engine metrics on, in the Prometheus text format at `/metrics`. None disables
+    /// the endpoint.
+    pub metrics_addr: Option<SocketAddr>,
+    /// Callbacks for the flushes, compactions, write stalls and background errors of the engine.
+    pub event_listeners: Vec<Arc<dyn EventListener>>,
+    /// The thread pools for the flushes and compactions, shared with other storage engines. Without it, the engine
+    /// starts a scheduler of its own with one flush thread and one compaction thread.
+    pub background_scheduler: Option<Arc<BackgroundScheduler>>,
+}
+
+/// How much history compaction keeps besides the versions pinned by live readers.
+#[derive(Debug, Clone)]
+pub enum HistoryRetention {
+    /// Keep the versions needed to read the state as of this long ago.
+    Duration(Duration),
+    /// Keep the versions needed to read at any of the last N commit timestamps.
+    Timestamps(u64),
 }
 
 impl LsmStorageOptions {
@@ -90,8 +155,22 @@ impl LsmStorageOptions {
             target_sst_size: 2 << 20,
             compaction_options: CompactionOptions::NoCompaction,
             enable_wal: false,
+            wal_dir: None,
+            wal_segment_size: 4 << 20,
             num_memtable_limit: 50,
             serializable: false,
+            history_retention: None,
+            lock_timeout: Duration::from_secs(1),
+            txn_spill_threshold: 64 << 20,
+            block_cache: None,
+            max_open_files: 1024,
+            table_cache_size: 64 << 20,
+            row_cache_size: 0,
+            memtable_kind: MemTableKind::default(),
+            write_buffer_manager: None,
+            metrics_addr: None,
+            event_listeners: Vec::new(),
+            background_scheduler: None,
         }
     }
 
@@ -101,8 +180,22 @@ impl LsmStorageOptions {
             target_sst_size: 2 << 20,
             compaction_options: CompactionOptions::NoCompaction,
             enable_wal: false,
+            wal_dir: None,
+            wal_segment_size: 4 << 20,
             num_memtable_limit: 2,
             serializable: false,
+            history_retention: None,
+            lock_timeout: Duration::from_secs(1),
+            txn_spill_threshold: 64 << 20,
+            block_cache: None,
+            max_open_files: 1024,
+            table_cache_size: 64 << 20,
+            row_cache_size: 0,
+            memtable_kind: MemTableKind::default(),
+            write_buffer_manager: None,
+            metrics_addr: None,
+            event_listeners: Vec::new(),
+            background_scheduler: None,
         }
     }
 
@@ -112,8 +205,50 @@ impl LsmStorageOptions {
             target_sst_size: 1 << 20, // 1MB
             compaction_options,
             enable_wal: false,
+            wal_dir: None,
+            wal_segment_size: 4 << 20,
             num_memtable_limit: 2,
             serializable: false,
+            history_retention: None,
+            lock_timeout: Duration::from_secs(1),
+            txn_spill_threshold: 64 << 20,
+            block_cache: None,
+            max_open_files: 1024,
+            table_cache_size: 64 << 20,
+            row_cache_size: 0,
+            memtable_kind: MemTableKind::default(),
+            write_buffer_manager: None,
+            metrics_addr: None,
+            event_listeners: Vec::new(),
+            background_scheduler: None,
+        }
+    }
+}
+
+/// The options of an engine outside the tests: a 2MB memtable, up to 3 memtables in memory and no compaction.
+impl Default for LsmStorageOptions {
+    fn default() -> Self {
+        Self {
+            block_size: 4096,
+            target_sst_size: 2 << 20,
+            compaction_options: CompactionOptions::NoCompaction,
+            enable_wal: false,
+            wal_dir: None,
+            wal_segment_size: 4 << 20,
+            num_memtable_limit: 3,
+            serializable: false,
+            history_retention: None,
+            lock_timeout: Duration::from_secs(1),
+            txn_spill_threshold: 64 << 20,
+            block_cache: None,
+            max_open_files: 1024,
+            table_cache_size: 64 << 20,
+            row_cache_size: 0,
+            memtable_kind: MemTableKind::default(),
+            write_buffer_manager: None,
+            metrics_addr: None,
+            event_listeners: Vec::new(),
+            background_scheduler: None,
         }
     }
 }
@@ -149,6 +284,76 @@ fn key_within(user_key: &[u8], table_begin: KeySlice, table_end: KeySlice) -> bo
     table_begin.key_ref() <= user_key && user_key <= table_end.key_ref()
 }
 
+/// The keys a batch writes.
+fn write_set_of<T: AsRef<[u8]>>(batch: &[WriteBatchRecord<T>]) -> BTreeSet<Bytes> {
+    batch
+        .iter()
+        .map(|record| match record {
+            WriteBatchRecord::Put(key, _) | WriteBatchRecord::Del(key) => {
+                Bytes::copy_from_slice(key.as_ref())
+            }
+        })
+        .collect()
+}
+
+/// Whether the key range and the bloom filter of the table allow it to contain `key`. The bloom filter checks are
+/// counted in `statistics`, if given.
+fn may_contain_key(key: &[u8], table: &SsTable, statistics: Option<&Statistics>) -> Result<bool> {
+    if key_within(
+        key,
+        table.first_key().as_key_slice(),
+        table.last_key().as_key_slice(),
+    ) {
+        let Some(may_contain) = table.check_bloom(key)? else {
+            return Ok(true);
+        };
+        if let Some(statistics) = statistics {
+            statistics.record(Ticker::BloomChecked, 1);
+            if !may_contain {
+                statistics.record(Ticker::BloomUseful, 1);
+            }
+        }
+        return Ok(may_contain);
+    }
+    Ok(false)
+}
+
+/// Options for a single read through `MiniLsm::get_with_options` or `MiniLsm::scan_with_options`.
+#[derive(Clone)]
+pub struct ReadOptions {
+    /// Read at the timestamp of this snapshot instead of the latest commit ts.
+    pub snapshot: Option<Arc<Snapshot>>,
+    /// The maximum number of bytes a scan reads ahead of the current SST block. 0 disables readahead.
+    pub readahead_size: usize,
+    /// Whether the blocks read by a scan are added to the block cache. A long scan that is not repeated can turn it
+    /// off to keep the cache for other reads.
+    pub fill_cache: bool,
+    /// Read ahead in the background while the scan goes through the blocks read before.
+    pub background_readahead: bool,
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        let table_options = TableReadOptions::default();
+        Self {
+            snapshot: None,
+            readahead_size: table_options.readahead_size,
+            fill_cache: table_options.fill_cache,
+            background_readahead: table_options.background_readahead,
+        }
+    }
+}
+
+impl ReadOptions {
+    fn table_read_options(&self) -> TableReadOptions {
+        TableReadOptions {
+            readahead_size: self.readahead_size,
+            fill_cache: self.fill_cache,
+            background_readahead: self.background_readahead,
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum CompactionFilter {
     Prefix(Bytes),
@@ -160,52 +365,46 @@ pub(crate) struct LsmStorageInner {
     pub(crate) state_lock: Mutex<()>,
     path: PathBuf,
     pub(crate) block_cache: Arc<BlockCache>,
+    pub(crate) table_cache: Arc<TableCache>,
+    pub(crate) row_cache: Option<RowCache>,
     next_sst_id: AtomicUsize,
     pub(crate) options: Arc<LsmStorageOptions>,
     pub(crate) compaction_controller: CompactionController,
     pub(crate) manifest: Option<Manifest>,
+    /// The WAL shared by all memtables, if WAL is enabled.
+    pub(crate) wal: Option<Arc<Wal>>,
     pub(crate) mvcc: Option<LsmMvccInner>,
     pub(crate) compaction_filters: Arc<Mutex<Vec<CompactionFilter>>>,
+    pub(crate) replication: ReplicationHub,
+    /// Transactions prepared for a two-phase commit and not decided yet, by name.
+    pub(crate) prepared_txns: Mutex<BTreeMap<String, PreparedTxn>>,
+    pub(crate) statistics: Arc<Statistics>,
+    /// The number and size of the SSTs the last scheduled compaction picked.
+    pub(crate) compaction_backlog: Mutex<(usize, u64)>,
+    /// The last write stall condition told to the event listeners.
+    pub(crate) write_stall: Mutex<WriteStallCondition>,
+    pub(crate) background_error: Mutex<BackgroundErrorState>,
+    /// The flushes and compactions of the engine, once `MiniLsm::open` started them.
+    pub(crate) background_work: OnceLock<BackgroundWork>,
 }
 
 /// A thin wrapper for `LsmStorageInner` and the user interface for MiniLSM.
 pub struct MiniLsm {
     pub(crate) inner: Arc<LsmStorageInner>,
-    /// Notifies the L0 flush thread to stop working. (In week 1 day 6)
-    flush_notifier: crossbeam_channel::Sender<()>,
-    /// The handle for the flush thread. (In week 1 day 6)
-    flush_thread: Mutex<Option<std::thread::JoinHandle<()>>>,
-    /// Notifies the compaction thread to stop working. (In week 2)
-    compaction_notifier: crossbeam_channel::Sender<()>,
-    /// The handle for the compaction thread. (In week 2)
-    compaction_thread: Mutex<Option<std::thread::JoinHandle<()>>>,
+    /// Serves the metrics if `metrics_addr` is set.
+    metrics_server: Option<MetricsServer>,
 }
 
 impl Drop for MiniLsm {
     fn drop(&mut self) {
-        self.compaction_notifier.send(()).ok();
-        self.flush_notifier.send(()).ok();
+        self.inner.stop_background_work(false);
     }
 }
 
 impl MiniLsm {
     pub fn close(&self) -> Result<()> {
         self.inner.sync_dir()?;
-        self.compaction_notifier.send(()).ok();
-        self.flush_notifier.send(()).ok();
-
-        let mut compaction_thread = self.compaction_thread.lock();
-        if let Some(compaction_thread) = compaction_thread.take() {
-            compaction_thread
-                .join()
-                .map_err(|e| anyhow::anyhow!("{:?}", e))?;
-        }
-        let mut flush_thread = self.flush_thread.lock();
-        if let Some(flush_thread) = flush_thread.take() {
-            flush_thread
-                .join()
-                .map_err(|e| anyhow::anyhow!("{:?}", e))?;
-        }
+        self.inner.stop_background_work(true);
 
         if self.inner.options.enable_wal {
             self.inner.sync()?;
@@ -215,18 +414,17 @@ impl MiniLsm {
 
         // create memtable and skip updating manifest
         if !self.inner.state.read().memtable.is_empty() {
-            self.inner
-                .freeze_memtable_with_memtable(Arc::new(MemTable::create(
+            self.inner.freeze_memtable_with_memtable(Arc::new(
+                MemTable::new(
                     self.inner.next_sst_id(),
-                )))?;
+                    self.inner.options.memtable_kind,
+                    None,
+                )
+                .with_write_buffer_manager(self.inner.options.write_buffer_manager.clone()),
+            ))?;
         }
 
-        while {
-            let snapshot = self.inner.state.read();
-            !snapshot.imm_memtables.is_empty()
-        } {
-            self.inner.force_flush_next_imm_memtable()?;
-        }
+        self.inner.flush_imm_memtables()?;
         self.inner.sync_dir()?;
 
         Ok(())
@@ -236,16 +434,22 @@ impl MiniLsm {
     /// not exist.
     pub fn open(path: impl AsRef<Path>, options: LsmStorageOptions) -> Result<Arc<Self>> {
         let inner = Arc::new(LsmStorageInner::open(path, options)?);
-        let (tx1, rx) = crossbeam_channel::unbounded();
-        let compaction_thread = inner.spawn_compaction_thread(rx)?;
-        let (tx2, rx) = crossbeam_channel::unbounded();
-        let flush_thread = inner.spawn_flush_thread(rx)?;
+        let scheduler = inner
+            .options
+            .background_scheduler
+            .clone()
+            .unwrap_or_else(|| Arc::new(BackgroundScheduler::new(1, 1)));
+        inner.start_background_work(scheduler);
+        if let Some(write_buffer_manager) = &inner.options.write_buffer_manager {
+            write_buffer_manager.register(&inner);
+        }
+        let metrics_server = match inner.options.metrics_addr {
+            Some(addr) => Some(MetricsServer::start(inner.clone(), addr)?),
+            None => None,
+        };
         Ok(Arc::new(Self {
             inner,
-            flush_notifier: tx2,
-            flush_thread: Mutex::new(flush_thread),
-            compaction_notifier: tx1,
-            compaction_thread: Mutex::new(compaction_thread),
+            metrics_server,
         }))
     }
 
@@ -257,6 +461,62 @@ impl MiniLsm {
         self.inner.get(key)
     }
 
+    pub fn multi_get(&self, keys: &[&[u8]]) -> Result<Vec<Option<Bytes>>> {
+        self.inner.multi_get(keys)
+    }
+
+    /// The block cache of this engine. Its stats cover the other engines sharing the cache as well.
+    pub fn block_cache(&self) -> &Arc<BlockCache> {
+        &self.inner.block_cache
+    }
+
+    pub fn table_cache(&self) -> &Arc<TableCache> {
+        &self.inner.table_cache
+    }
+
+    /// The stats of the row cache, if it is enabled.
+    pub fn row_cache_stats(&self) -> Option<RowCacheStats> {
+        self.inner.row_cache.as_ref().map(RowCache::stats)
+    }
+
+    /// The tickers and latency histograms of this engine. Call `Statistics::reset` to start counting again.
+    pub fn statistics(&self) -> &Arc<Statistics> {
+        &self.inner.statistics
+    }
+
+    /// The fatal error of a flush or a compaction that made the database read-only, if any.
+    pub fn background_error(&self) -> Option<BackgroundError> {
+        self.inner.background_error()
+    }
+
+    /// Retry the flush or compaction that made the database read-only, once the problem behind it is fixed. The
+    /// database takes writes again if the retry succeeds.
+    pub fn resume(&self) -> Result<()> {
+        self.inner.resume()
+    }
+
+    /// Stop the flushes and compactions of the engine, waiting for the running ones, until
+    /// `continue_background_work`. Writes go on meanwhile until the memtables are over their limits.
+    pub fn pause_background_work(&self) {
+        self.inner.pause_background_work()
+    }
+
+    /// Undo a `pause_background_work`. The flushes and compactions start again once every pause is undone.
+    pub fn continue_background_work(&self) -> Result<()> {
+        self.inner.continue_background_work()
+    }
+
+    /// The metrics of this engine in the Prometheus text format, as served on `metrics_addr`.
+    pub fn prometheus_metrics(&self) -> String {
+        self.inner.render_metrics()
+    }
+
+    /// The address the metrics endpoint listens on, if it is enabled. This resolves a port 0 binding to the actual
+    /// port.
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics_server.as_ref().map(MetricsServer::addr)
+    }
+
     pub fn write_batch<T: AsRef<[u8]>>(&self, batch: &[WriteBatchRecord<T>]) -> Result<()> {
         self.inner.write_batch(batch)
     }
@@ -277,10 +537,83 @@ impl MiniLsm {
         self.inner.new_txn()
     }
 
+    pub fn new_txn_with_isolation(&self, isolation: IsolationLevel) -> Result<Arc<Transaction>> {
+        self.inner.new_txn_with_isolation(isolation)
+    }
+
+    pub fn new_pessimistic_txn(&self) -> Result<Arc<Transaction>> {
+        self.inner.new_pessimistic_txn()
+    }
+
+    /// Names of the transactions prepared with `Transaction::prepare` and not decided yet, including those recovered
+    /// from the WAL.
+    pub fn prepared_txns(&self) -> Vec<String> {
+        self.inner.prepared_txns.lock().keys().cloned().collect()
+    }
+
+    pub fn commit_prepared(&self, name: &str) -> Result<()> {
+        self.inner.commit_prepared(name)
+    }
+
+    pub fn rollback_prepared(&self, name: &str) -> Result<()> {
+        self.inner.rollback_prepared(name)
+    }
+
+    /// Take a read-only snapshot at the latest commit ts.
+    pub fn snapshot(&self) -> Arc<Snapshot> {
+        self.inner.snapshot()
+    }
+
+    pub fn get_with_options(&self, key: &[u8], options: &ReadOptions) -> Result<Option<Bytes>> {
+        self.inner.get_with_options(key, options)
+    }
+
+    pub fn scan_with_options(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        options: &ReadOptions,
+    ) -> Result<SnapshotIterator> {
+        self.inner.scan_with_options(lower, upper, options)
+    }
+
     pub fn scan(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<TxnIterator> {
         self.inner.scan(lower, upper)
     }
 
+    /// Create a durable snapshot at the latest commit ts. It is kept across restarts until released. Returns its ts.
+    pub fn create_named_snapshot(&self, name: &str) -> Result<u64> {
+        self.inner.create_named_snapshot(name)
+    }
+
+    pub fn release_named_snapshot(&self, name: &str) -> Result<()> {
+        self.inner.release_named_snapshot(name)
+    }
+
+    pub fn list_named_snapshots(&self) -> Vec<NamedSnapshotInfo> {
+        self.inner.list_named_snapshots()
+    }
+
+    /// Open a read-only view of a named snapshot.
+    pub fn named_snapshot(&self, name: &str) -> Result<Arc<Snapshot>> {
+        self.inner.named_snapshot(name)
+    }
+
+    /// Read a key as of a past commit ts within the retained history.
+    pub fn get_at(&self, key: &[u8], ts: u64) -> Result<Option<Bytes>> {
+        self.inner.get_at(key, ts)
+    }
+
+    /// Scan as of a past commit ts within the retained history.
+    pub fn scan_at(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        ts: u64,
+    ) -> Result<SnapshotIterator> {
+        self.inner.scan_at(lower, upper, ts)
+    }
+
     /// Only call this in test cases due to race conditions
     pub fn force_flush(&self) -> Result<()> {
         if !self.inner.state.read().memtable.is_empty() {
@@ -296,6 +629,12 @@ impl MiniLsm {
     pub fn force_full_compaction(&self) -> Result<()> {
         self.inner.force_full_compaction()
     }
+
+    /// Start serving replication streams on `addr`. Followers connecting to it bootstrap from a checkpoint of this
+    /// database and then receive every batch committed afterwards.
+    pub fn serve_replication(&self, addr: ReplicationAddr) -> Result<ReplicationLeader> {
+        ReplicationLeader::start(self.inner.clone(), addr)
+    }
 }
 
 impl LsmStorageInner {
@@ -318,7 +657,18 @@ impl LsmStorageInner {
         let mut state = LsmStorageState::create(&options);
         let path = path.as_ref();
         let mut next_sst_id = 1;
-        let block_cache = Arc::new(BlockCache::new(1 << 20)); // 4GB block cache,
+        let statistics = Arc::new(Statistics::new());
+        let block_cache = Arc::new(
+            match &options.block_cache {
+                Some(block_cache) => block_cache.share(),
+                None => BlockCache::new(DEFAULT_BLOCK_CACHE_SIZE),
+            }
+            .with_statistics(statistics.clone()),
+        );
+        let table_cache = Arc::new(TableCache::new(
+            options.max_open_files,
+            options.table_cache_size,
+        ));
         let manifest;
 
         let compaction_controller = match &options.compaction_options {
@@ -337,20 +687,37 @@ impl LsmStorageInner {
         if !path.exists() {
             std::fs::create_dir_all(path).context("failed to create DB dir")?;
         }
+        // writes spilled by transactions that did not commit before a crash
+        let txn_spill_dir = Self::path_of_txn_spill_dir_static(path);
+        if txn_spill_dir.exists() {
+            std::fs::remove_dir_all(&txn_spill_dir).context("failed to remove txn spill dir")?;
+        }
         let manifest_path = path.join("MANIFEST");
+        let wal_dir = options.wal_dir.as_deref().unwrap_or(path);
         let mut last_commit_ts = 0;
+        let mut wal = None;
+        let mut named_snapshots = BTreeMap::new();
+        let mut prepared_txns = BTreeMap::new();
         if !manifest_path.exists() {
             if options.enable_wal {
-                state.memtable = Arc::new(MemTable::create_with_wal(
-                    state.memtable.id(),
-                    Self::path_of_wal_static(path, state.memtable.id()),
-                )?);
+                let (new_wal, _) = Wal::recover(wal_dir, options.wal_segment_size, |_| false)?;
+                let new_wal = Arc::new(new_wal.with_statistics(statistics.clone()));
+                state.memtable = Arc::new(
+                    MemTable::new(
+                        state.memtable.id(),
+                        options.memtable_kind,
+                        Some(new_wal.clone()),
+                    )
+                    .with_write_buffer_manager(options.write_buffer_manager.clone()),
+                );
+                wal = Some(new_wal);
             }
             manifest = Manifest::create(&manifest_path).context("failed to create manifest")?;
             manifest.add_record_when_init(ManifestRecord::NewMemtable(state.memtable.id()))?;
         } else {
             let (m, records) = Manifest::recover(&manifest_path)?;
             let mut memtables = BTreeSet::new();
+            let mut sst_metas = HashMap::new();
             for record in records {
                 match record {
                     ManifestRecord::Flush(sst_id) => {
@@ -375,6 +742,27 @@ impl LsmStorageInner {
                         next_sst_id =
                             next_sst_id.max(output.iter().max().copied().unwrap_or_default());
                     }
+                    ManifestRecord::CreateSnapshot {
+                        name,
+                        ts,
+                        created_at_ms,
+                    } => {
+                        let created_at = UNIX_EPOCH + Duration::from_millis(created_at_ms);
+                        named_snapshots.insert(
+                            name.clone(),
+                            NamedSnapshotInfo {
+                                name,
+                                ts,
+                                created_at,
+                            },
+                        );
+                    }
+                    ManifestRecord::ReleaseSnapshot(name) => {
+                        named_snapshots.remove(&name);
+                    }
+                    ManifestRecord::NewSsts(metas) => {
+                        sst_metas.extend(metas.into_iter().map(|meta| (meta.id, meta)));
+                    }
                 }
             }
 
@@ -386,12 +774,23 @@ impl LsmStorageInner {
                 .chain(state.levels.iter().flat_map(|(_, files)| files))
             {
                 let table_id = *table_id;
-                let sst = SsTable::open(
-                    table_id,
-                    Some(block_cache.clone()),
-                    FileObject::open(&Self::path_of_sst_static(path, table_id))
-                        .context("failed to open SST")?,
-                )?;
+                let sst_path = Self::path_of_sst_static(path, table_id);
+                // the file is only opened on the first read if the manifest has the metadata of the SST
+                let sst = match sst_metas.get(&table_id) {
+                    Some(meta) => SsTable::from_meta_with_table_cache(
+                        meta,
+                        Some(block_cache.clone()),
+                        table_cache.clone(),
+                        sst_path,
+                    ),
+                    None => SsTable::open_with_table_cache(
+                        table_id,
+                        Some(block_cache.clone()),
+                        table_cache.clone(),
+                        sst_path,
+                    )
+                    .context("failed to open SST")?,
+                };
                 last_commit_ts = last_commit_ts.max(sst.max_ts());
                 state.sstables.insert(table_id, Arc::new(sst));
                 sst_cnt += 1;
@@ -416,46 +815,106 @@ impl LsmStorageInner {
 
             // recover memtables
             if options.enable_wal {
-                let mut wal_cnt = 0;
+                let (recovered_wal, mut recovery) =
+                    Wal::recover(wal_dir, options.wal_segment_size, |id| {
+                        memtables.contains(&id)
+                    })?;
+                let recovered_wal = Arc::new(recovered_wal.with_statistics(statistics.clone()));
+                // the WAL files of each memtable, left by a version before the memtables shared a WAL
+                recovered_wal.import_legacy_wals(
+                    path,
+                    |id| memtables.contains(&id),
+                    &mut recovery,
+                )?;
                 for id in memtables.iter() {
-                    let memtable =
-                        MemTable::recover_from_wal(*id, Self::path_of_wal_static(path, *id))?;
-                    let max_ts = memtable
-                        .map
-                        .iter()
-                        .map(|x| x.key().ts())
-                        .max()
-                        .unwrap_or_default();
-                    last_commit_ts = last_commit_ts.max(max_ts);
+                    let memtable = MemTable::recover_from_wal(
+                        *id,
+                        options.memtable_kind,
+                        recovered_wal.clone(),
+                        recovery.memtables.remove(id).unwrap_or_default(),
+                    )
+                    .with_write_buffer_manager(options.write_buffer_manager.clone());
+                    memtable.freeze();
+                    memtable.for_each(|key, _| last_commit_ts = last_commit_ts.max(key.ts()));
                     if !memtable.is_empty() {
                         state.imm_memtables.insert(0, Arc::new(memtable));
-                        wal_cnt += 1;
                     }
                 }
-                println!("{} WALs recovered", wal_cnt);
-                state.memtable = Arc::new(MemTable::create_with_wal(
-                    next_sst_id,
-                    Self::path_of_wal_static(path, next_sst_id),
-                )?);
+                for (name, record) in recovery.prepared {
+                    let conflict_sets = ConflictSets {
+                        write_set: record.kv_pairs.iter().map(|(key, _)| key.clone()).collect(),
+                        read_set: record.read_set,
+                        read_ranges: record.read_ranges,
+                    };
+                    prepared_txns.insert(
+                        name,
+                        PreparedTxn {
+                            txn_id: 0,
+                            read_ts: 0,
+                            batch: record.kv_pairs,
+                            conflict_sets,
+                            locked_keys: BTreeSet::new(),
+                        },
+                    );
+                }
+                state.memtable = Arc::new(
+                    MemTable::new(
+                        next_sst_id,
+                        options.memtable_kind,
+                        Some(recovered_wal.clone()),
+                    )
+                    .with_write_buffer_manager(options.write_buffer_manager.clone()),
+                );
+                // empty memtables are dropped, so their segments can go away
+                for id in memtables.iter() {
+                    if !state
+                        .imm_memtables
+                        .iter()
+                        .any(|memtable| memtable.id() == *id)
+                    {
+                        recovered_wal.memtable_flushed(*id)?;
+                    }
+                }
+                recovered_wal.finish_recovery()?;
+                wal = Some(recovered_wal);
             } else {
-                state.memtable = Arc::new(MemTable::create(next_sst_id));
+                state.memtable = Arc::new(
+                    MemTable::new(next_sst_id, options.memtable_kind, None)
+                        .with_write_buffer_manager(options.write_buffer_manager.clone()),
+                );
             }
             m.add_record_when_init(ManifestRecord::NewMemtable(state.memtable.id()))?;
             next_sst_id += 1;
             manifest = m;
         };
 
+        // never reuse the ts of a snapshot, even if the data written before it is lost
+        for snapshot in named_snapshots.values() {
+            last_commit_ts = last_commit_ts.max(snapshot.ts);
+        }
+        let mvcc = LsmMvccInner::new(last_commit_ts, options.history_retention.clone());
+        mvcc.restore_named_snapshots(named_snapshots);
         let storage = Self {
             state: Arc::new(RwLock::new(Arc::new(state))),
             state_lock: Mutex::new(()),
             path: path.to_path_buf(),
             block_cache,
+            table_cache,
+            row_cache: (options.row_cache_size > 0).then(|| RowCache::new(options.row_cache_size)),
             next_sst_id: AtomicUsize::new(next_sst_id),
             compaction_controller,
             manifest: Some(manifest),
+            wal,
             options: options.into(),
-            mvcc: Some(LsmMvccInner::new(last_commit_ts)),
+            mvcc: Some(mvcc),
             compaction_filters: Arc::new(Mutex::new(Vec::new())),
+            replication: ReplicationHub::default(),
+            prepared_txns: Mutex::new(prepared_txns),
+            statistics,
+            compaction_backlog: Mutex::new((0, 0)),
+            write_stall: Mutex::new(WriteStallCondition::Normal),
+            background_error: Mutex::new(BackgroundErrorState::default()),
+            background_work: OnceLock::new(),
         };
         storage.sync_dir()?;
 
@@ -473,11 +932,189 @@ impl LsmStorageInner {
 
     /// Get a key from the storage. In day 7, this can be further optimized by using a bloom filter.
     pub fn get(self: &Arc<Self>, key: &[u8]) -> Result<Option<Bytes>> {
-        let txn = self.mvcc().new_txn(self.clone(), self.options.serializable);
+        let txn = self.mvcc().new_txn(self.clone(), self.default_isolation());
         txn.get(key)
     }
 
+    /// Get several keys at the same read ts. If SST reads can run in parallel, the blocks that may contain the keys
+    /// are read together before looking up the keys.
+    pub fn multi_get(self: &Arc<Self>, keys: &[&[u8]]) -> Result<Vec<Option<Bytes>>> {
+        let txn = self.mvcc().new_txn(self.clone(), self.default_isolation());
+        if FileObject::supports_batched_reads() {
+            self.prefetch_blocks_for_keys(keys)?;
+        }
+        keys.iter().map(|key| txn.get(key)).collect()
+    }
+
+    fn prefetch_blocks_for_keys(&self, keys: &[&[u8]]) -> Result<()> {
+        let snapshot = {
+            let guard = self.state.read();
+            Arc::clone(&guard)
+        };
+        let mut blocks = BTreeSet::new();
+        for key in keys {
+            let sst_ids = snapshot
+                .l0_sstables
+                .iter()
+                .chain(snapshot.levels.iter().flat_map(|(_, ids)| ids.iter()));
+            for sst_id in sst_ids {
+                let table = &snapshot.sstables[sst_id];
+                // not counted, the lookup of the key checks the filter again
+                if may_contain_key(key, table, None)? {
+                    let block_idx =
+                        table.find_block_idx(KeySlice::from_slice(key, key::TS_RANGE_BEGIN))?;
+                    blocks.insert((*sst_id, block_idx));
+                }
+            }
+        }
+        let blocks = blocks
+            .into_iter()
+            .map(|(sst_id, block_idx)| (snapshot.sstables[&sst_id].as_ref(), block_idx))
+            .collect::<Vec<_>>();
+        SsTable::read_blocks_cached(&blocks)?;
+        Ok(())
+    }
+
+    pub fn snapshot(self: &Arc<Self>) -> Arc<Snapshot> {
+        self.mvcc().new_snapshot(self.clone())
+    }
+
+    pub fn get_with_options(
+        self: &Arc<Self>,
+        key: &[u8],
+        options: &ReadOptions,
+    ) -> Result<Option<Bytes>> {
+        match &options.snapshot {
+            Some(snapshot) => snapshot.get(key),
+            None => self.get(key),
+        }
+    }
+
+    pub fn scan_with_options(
+        self: &Arc<Self>,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        options: &ReadOptions,
+    ) -> Result<SnapshotIterator> {
+        let snapshot = match &options.snapshot {
+            Some(snapshot) => snapshot.clone(),
+            None => self.snapshot(),
+        };
+        snapshot.scan_with_table_options(lower, upper, &options.table_read_options())
+    }
+
+    pub fn create_named_snapshot(&self, name: &str) -> Result<u64> {
+        let state_lock = self.state_lock.lock();
+        let mut named_snapshots = self.mvcc().named_snapshots.lock();
+        if named_snapshots.contains_key(name) {
+            bail!("snapshot {} already exists", name);
+        }
+        let ts = {
+            let mut ts = self.mvcc().ts.lock();
+            let read_ts = ts.0;
+            ts.1.add_reader(read_ts);
+            read_ts
+        };
+        let created_at = SystemTime::now();
+        let record = ManifestRecord::CreateSnapshot {
+            name: name.to_string(),
+            ts,
+            created_at_ms: created_at.duration_since(UNIX_EPOCH)?.as_millis() as u64,
+        };
+        if let Err(e) = self.manifest().add_record(&state_lock, record) {
+            self.mvcc().ts.lock().1.remove_reader(ts);
+            return Err(e);
+        }
+        named_snapshots.insert(
+            name.to_string(),
+            NamedSnapshotInfo {
+                name: name.to_string(),
+                ts,
+                created_at,
+            },
+        );
+        Ok(ts)
+    }
+
+    pub fn release_named_snapshot(&self, name: &str) -> Result<()> {
+        let state_lock = self.state_lock.lock();
+        let mut named_snapshots = self.mvcc().named_snapshots.lock();
+        let Some(snapshot) = named_snapshots.get(name) else {
+            bail!("snapshot {} does not exist", name);
+        };
+        let ts = snapshot.ts;
+        self.manifest().add_record(
+            &state_lock,
+            ManifestRecord::ReleaseSnapshot(name.to_string()),
+        )?;
+        named_snapshots.remove(name);
+        self.mvcc().ts.lock().1.remove_reader(ts);
+        Ok(())
+    }
+
+    pub fn list_named_snapshots(&self) -> Vec<NamedSnapshotInfo> {
+        self.mvcc()
+            .named_snapshots
+            .lock()
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    pub fn named_snapshot(self: &Arc<Self>, name: &str) -> Result<Arc<Snapshot>> {
+        let Some(ts) = self
+            .mvcc()
+            .named_snapshots
+            .lock()
+            .get(name)
+            .map(|snapshot| snapshot.ts)
+        else {
+            bail!("snapshot {} does not exist", name);
+        };
+        self.mvcc().new_snapshot_at(self.clone(), ts)
+    }
+
+    pub fn get_at(self: &Arc<Self>, key: &[u8], ts: u64) -> Result<Option<Bytes>> {
+        self.mvcc().new_snapshot_at(self.clone(), ts)?.get(key)
+    }
+
+    pub fn scan_at(
+        self: &Arc<Self>,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        ts: u64,
+    ) -> Result<SnapshotIterator> {
+        self.mvcc()
+            .new_snapshot_at(self.clone(), ts)?
+            .scan(lower, upper)
+    }
+
     pub(crate) fn get_with_ts(&self, key: &[u8], read_ts: u64) -> Result<Option<Bytes>> {
+        let start = Instant::now();
+        let value = self.get_with_ts_cached(key, read_ts)?;
+        self.statistics.record(Ticker::KeysRead, 1);
+        if let Some(value) = &value {
+            self.statistics
+                .record(Ticker::BytesRead, value.len() as u64);
+        }
+        self.statistics
+            .record_elapsed(HistogramType::GetMicros, start);
+        Ok(value)
+    }
+
+    fn get_with_ts_cached(&self, key: &[u8], read_ts: u64) -> Result<Option<Bytes>> {
+        let Some(row_cache) = &self.row_cache else {
+            return self.get_with_ts_uncached(key, read_ts);
+        };
+        if let Some(value) = row_cache.get(key, read_ts) {
+            return Ok(value);
+        }
+        let value = self.get_with_ts_uncached(key, read_ts)?;
+        row_cache.insert(key, value.clone(), read_ts);
+        Ok(value)
+    }
+
+    fn get_with_ts_uncached(&self, key: &[u8], read_ts: u64) -> Result<Option<Bytes>> {
         let snapshot = {
             let guard = self.state.read();
             Arc::clone(&guard)
@@ -498,26 +1135,9 @@ impl LsmStorageInner {
 
         let mut l0_iters = Vec::with_capacity(snapshot.l0_sstables.len());
 
-        let keep_table = |key: &[u8], table: &SsTable| {
-            if key_within(
-                key,
-                table.first_key().as_key_slice(),
-                table.last_key().as_key_slice(),
-            ) {
-                if let Some(bloom) = &table.bloom {
-                    if bloom.may_contain(farmhash::fingerprint32(key)) {
-                        return true;
-                    }
-                } else {
-                    return true;
-                }
-            }
-            false
-        };
-
         for table in snapshot.l0_sstables.iter() {
             let table = snapshot.sstables[table].clone();
-            if keep_table(key, &table) {
+            if may_contain_key(key, &table, Some(&self.statistics))? {
                 l0_iters.push(Box::new(SsTableIterator::create_and_seek_to_key(
                     table,
                     KeySlice::from_slice(key, key::TS_RANGE_BEGIN),
@@ -530,7 +1150,7 @@ impl LsmStorageInner {
             let mut level_ssts = Vec::with_capacity(level_sst_ids.len());
             for table in level_sst_ids {
                 let table = snapshot.sstables[table].clone();
-                if keep_table(key, &table) {
+                if may_contain_key(key, &table, Some(&self.statistics))? {
                     level_ssts.push(table);
                 }
             }
@@ -557,19 +1177,196 @@ impl LsmStorageInner {
     }
 
     pub fn write_batch_inner<T: AsRef<[u8]>>(&self, batch: &[WriteBatchRecord<T>]) -> Result<u64> {
+        let start = Instant::now();
+        let ts = {
+            let _lck = self.mvcc().write_lock.lock();
+            if !self.prepared_txns.lock().is_empty() {
+                self.check_prepared_reads(&write_set_of(batch))?;
+            }
+            let ts = self.mvcc().latest_commit_ts() + 1;
+            self.write_batch_at_ts(batch, ts)?;
+            self.replication.publish(ts, batch);
+            // a write outside a transaction is a conflict for some of the transactions running concurrently,
+            // recorded before the write lock is released since their conflict checks run under it
+            if self.mvcc().records_plain_writes() {
+                self.mvcc().record_committed_txn(CommittedTxnData {
+                    write_set: write_set_of(batch),
+                    read_ts: ts - 1,
+                    commit_ts: ts,
+                });
+            }
+            ts
+        };
+        self.statistics
+            .record_elapsed(HistogramType::WriteMicros, start);
+        Ok(ts)
+    }
+
+    /// Write the chunks returned by `next_chunk` at a single commit ts, until it returns `None`, so that a large
+    /// batch never has to be in memory at once. `check` runs under the write lock before the first chunk. Readers see
+    /// the records only after all chunks are written, and followers receive them as one batch then. If a chunk
+    /// fails, the keys written so far are restored at the commit ts, which is never used again.
+    pub(crate) fn write_batch_chunks<T: AsRef<[u8]>>(
+        &self,
+        check: impl FnOnce() -> Result<()>,
+        mut next_chunk: impl FnMut() -> Result<Option<Vec<WriteBatchRecord<T>>>>,
+    ) -> Result<u64> {
         let _lck = self.mvcc().write_lock.lock();
+        check()?;
         let ts = self.mvcc().latest_commit_ts() + 1;
+        // a follower cannot connect while the write lock is held
+        let mut replicated = (self.replication.num_subscribers() > 0).then(Vec::new);
+        let mut written_keys = BTreeSet::new();
+        let mut write_chunks = || -> Result<()> {
+            while let Some(chunk) = next_chunk()? {
+                // before the write, which may fail after some of the records are in the memtable
+                written_keys.extend(write_set_of(&chunk));
+                self.write_records_at_ts(&chunk, ts)?;
+                if let Some(replicated) = &mut replicated {
+                    replicated.extend(ReplicationHub::entries(ts, &chunk));
+                }
+            }
+            Ok(())
+        };
+        if let Err(e) = write_chunks() {
+            if written_keys.is_empty() {
+                return Err(e);
+            }
+            return Err(self.abort_batch_at_ts(&written_keys, ts, e));
+        }
+        if let Some(replicated) = replicated {
+            self.replication.publish_entries(ts, replicated);
+        }
+        self.mvcc().update_commit_ts(ts);
+        Ok(ts)
+    }
+
+    /// Undo a batch that failed after some of its records were written at `ts`, and return the error. The caller
+    /// must hold the MVCC write lock.
+    fn abort_batch_at_ts(
+        &self,
+        keys: &BTreeSet<Bytes>,
+        ts: u64,
+        e: anyhow::Error,
+    ) -> anyhow::Error {
+        // The records at ts are in the memtable, and maybe in an SST already, so they cannot be removed. Write the
+        // values before ts over them instead, and skip ts, so that no later batch makes them visible.
+        match self.restore_keys_at_ts(keys, ts) {
+            Ok(()) => {
+                self.mvcc().update_commit_ts(ts);
+                e
+            }
+            Err(restore_error) => e.context(format!(
+                "failed to restore the keys written at ts {}: {:#}",
+                ts, restore_error
+            )),
+        }
+    }
+
+    /// Write the values the keys had before `ts` at `ts`. The caller must hold the MVCC write lock.
+    fn restore_keys_at_ts(&self, keys: &BTreeSet<Bytes>, ts: u64) -> Result<()> {
+        let mut batch = Vec::with_capacity(keys.len());
+        for key in keys {
+            match self.get_with_ts(key, ts - 1)? {
+                Some(value) => batch.push(WriteBatchRecord::Put(key.clone(), value)),
+                None => batch.push(WriteBatchRecord::Del(key.clone())),
+            }
+        }
+        // the engine may have become read-only, which is what failed the batch
+        self.insert_records_at_ts(&batch, ts)
+    }
+
+    /// Apply a batch committed on the replication leader, keeping the leader's commit ts.
+    pub(crate) fn apply_replicated_batch(
+        &self,
+        ts: u64,
+        entries: &[(KeyBytes, Bytes)],
+    ) -> Result<()> {
+        let _lck = self.mvcc().write_lock.lock();
+        let applied_ts = self.mvcc().latest_commit_ts();
+        if ts < applied_ts {
+            bail!(
+                "replicated batch at ts {} is behind the applied ts {}",
+                ts,
+                applied_ts
+            );
+        }
+        let batch = entries
+            .iter()
+            .map(|(key, value)| {
+                if value.is_empty() {
+                    WriteBatchRecord::Del(key.key_ref())
+                } else {
+                    WriteBatchRecord::Put(key.key_ref(), &value[..])
+                }
+            })
+            .collect::<Vec<_>>();
+        self.write_batch_at_ts(&batch, ts)
+    }
+
+    /// Write a batch at the given commit ts. The caller must hold the MVCC write lock.
+    fn write_batch_at_ts<T: AsRef<[u8]>>(
+        &self,
+        batch: &[WriteBatchRecord<T>],
+        ts: u64,
+    ) -> Result<()> {
+        self.write_records_at_ts(batch, ts)?;
+        self.mvcc().update_commit_ts(ts);
+        Ok(())
+    }
+
+    /// Write records at the given ts without making them visible. The caller must hold the MVCC write lock.
+    fn write_records_at_ts<T: AsRef<[u8]>>(
+        &self,
+        batch: &[WriteBatchRecord<T>],
+        ts: u64,
+    ) -> Result<()> {
+        self.check_writable()?;
+        if let Some(write_buffer_manager) = &self.options.write_buffer_manager {
+            if write_buffer_manager.should_stall() {
+                self.set_write_stall(WriteStallCondition::Stopped);
+                let stalled = write_buffer_manager.maybe_stall(|| self.check_writable());
+                self.set_write_stall(WriteStallCondition::Normal);
+                stalled?;
+            }
+        }
+        self.insert_records_at_ts(batch, ts)?;
+        self.statistics
+            .record(Ticker::KeysWritten, batch.len() as u64);
+        if let Some(write_buffer_manager) = &self.options.write_buffer_manager {
+            if write_buffer_manager.should_flush() {
+                write_buffer_manager.flush_largest_memtable()?;
+            }
+            if write_buffer_manager.should_flush_immutable() {
+                write_buffer_manager.schedule_flushes();
+            }
+        }
+        Ok(())
+    }
+
+    /// Put records into the memtable at the given ts, freezing it when it is full.
+    fn insert_records_at_ts<T: AsRef<[u8]>>(
+        &self,
+        batch: &[WriteBatchRecord<T>],
+        ts: u64,
+    ) -> Result<()> {
         for record in batch {
             match record {
                 WriteBatchRecord::Del(key) => {
                     let key = key.as_ref();
                     assert!(!key.is_empty(), "key cannot be empty");
+                    self.statistics
+                        .record(Ticker::BytesWritten, key.len() as u64);
                     let size;
                     {
                         let guard = self.state.read();
                         guard.memtable.put(KeySlice::from_slice(key, ts), b"")?;
                         size = guard.memtable.approximate_size();
                     }
+                    // after the write is in the memtable, so that a lookup cached before is dropped
+                    if let Some(row_cache) = &self.row_cache {
+                        row_cache.invalidate(key, ts);
+                    }
                     self.try_freeze(size)?;
                 }
                 WriteBatchRecord::Put(key, value) => {
@@ -577,18 +1374,22 @@ impl LsmStorageInner {
                     let value = value.as_ref();
                     assert!(!key.is_empty(), "key cannot be empty");
                     assert!(!value.is_empty(), "value cannot be empty");
+                    self.statistics
+                        .record(Ticker::BytesWritten, (key.len() + value.len()) as u64);
                     let size;
                     {
                         let guard = self.state.read();
                         guard.memtable.put(KeySlice::from_slice(key, ts), value)?;
                         size = guard.memtable.approximate_size();
                     }
+                    if let Some(row_cache) = &self.row_cache {
+                        row_cache.invalidate(key, ts);
+                    }
                     self.try_freeze(size)?;
                 }
             }
         }
-        self.mvcc().update_commit_ts(ts);
-        Ok(ts)
+        Ok(())
     }
 
     pub fn write_batch<T: AsRef<[u8]>>(
@@ -598,7 +1399,7 @@ impl LsmStorageInner {
         if !self.options.serializable {
             self.write_batch_inner(batch)?;
         } else {
-            let txn = self.mvcc().new_txn(self.clone(), self.options.serializable);
+            let txn = self.mvcc().new_txn(self.clone(), self.default_isolation());
             for record in batch {
                 match record {
                     WriteBatchRecord::Del(key) => {
@@ -619,7 +1420,7 @@ impl LsmStorageInner {
         if !self.options.serializable {
             self.write_batch_inner(&[WriteBatchRecord::Put(key, value)])?;
         } else {
-            let txn = self.mvcc().new_txn(self.clone(), self.options.serializable);
+            let txn = self.mvcc().new_txn(self.clone(), self.default_isolation());
             txn.put(key, value);
             txn.commit()?;
         }
@@ -631,7 +1432,7 @@ impl LsmStorageInner {
         if !self.options.serializable {
             self.write_batch_inner(&[WriteBatchRecord::Del(key)])?;
         } else {
-            let txn = self.mvcc().new_txn(self.clone(), self.options.serializable);
+            let txn = self.mvcc().new_txn(self.clone(), self.default_isolation());
             txn.delete(key);
             txn.commit()?;
         }
@@ -651,6 +1452,16 @@ impl LsmStorageInner {
         Ok(())
     }
 
+    /// Freeze the memtable for the write buffer manager, unless it is empty. Returns whether it was frozen.
+    pub(crate) fn freeze_memtable_for_write_buffer(&self) -> Result<bool> {
+        let state_lock = self.state_lock.lock();
+        if self.state.read().memtable.is_empty() {
+            return Ok(false);
+        }
+        self.force_freeze_memtable(&state_lock)?;
+        Ok(true)
+    }
+
     pub(crate) fn path_of_sst_static(path: impl AsRef<Path>, id: usize) -> PathBuf {
         path.as_ref().join(format!("{:05}.sst", id))
     }
@@ -659,12 +1470,12 @@ impl LsmStorageInner {
         Self::path_of_sst_static(&self.path, id)
     }
 
-    pub(crate) fn path_of_wal_static(path: impl AsRef<Path>, id: usize) -> PathBuf {
-        path.as_ref().join(format!("{:05}.wal", id))
+    pub(crate) fn path_of_txn_spill_dir_static(path: impl AsRef<Path>) -> PathBuf {
+        path.as_ref().join("txn_spill")
     }
 
-    pub(crate) fn path_of_wal(&self, id: usize) -> PathBuf {
-        Self::path_of_wal_static(&self.path, id)
+    pub(crate) fn path_of_txn_spill_dir(&self) -> PathBuf {
+        Self::path_of_txn_spill_dir_static(&self.path)
     }
 
     pub(super) fn sync_dir(&self) -> Result<()> {
@@ -680,10 +1491,20 @@ impl LsmStorageInner {
         // Add the memtable to the immutable memtables.
         snapshot.imm_memtables.insert(0, old_memtable.clone());
         // Update the snapshot.
+        let num_imm_memtables = snapshot.imm_memtables.len();
         *guard = Arc::new(snapshot);
 
         drop(guard);
+        old_memtable.freeze();
         old_memtable.sync_wal()?;
+        self.notify_listeners(|listener| {
+            listener.on_memtable_sealed(&MemTableSealedInfo {
+                memtable_id: old_memtable.id(),
+                approximate_size: old_memtable.approximate_size(),
+                num_imm_memtables,
+            })
+        });
+        self.schedule_flush();
 
         Ok(())
     }
@@ -691,14 +1512,10 @@ impl LsmStorageInner {
     /// Force freeze the current memtable to an immutable memtable
     pub fn force_freeze_memtable(&self, state_lock_observer: &MutexGuard<'_, ()>) -> Result<()> {
         let memtable_id = self.next_sst_id();
-        let memtable = if self.options.enable_wal {
-            Arc::new(MemTable::create_with_wal(
-                memtable_id,
-                self.path_of_wal(memtable_id),
-            )?)
-        } else {
-            Arc::new(MemTable::create(memtable_id))
-        };
+        let memtable = Arc::new(
+            MemTable::new(memtable_id, self.options.memtable_kind, self.wal.clone())
+                .with_write_buffer_manager(self.options.write_buffer_manager.clone()),
+        );
 
         self.freeze_memtable_with_memtable(memtable)?;
 
@@ -711,9 +1528,18 @@ impl LsmStorageInner {
         Ok(())
     }
 
+    /// Flush all the immutable memtables, the earliest first.
+    pub(crate) fn flush_imm_memtables(&self) -> Result<()> {
+        while !self.state.read().imm_memtables.is_empty() {
+            self.force_flush_next_imm_memtable()?;
+        }
+        Ok(())
+    }
+
     /// Force flush the earliest-created immutable memtable to disk
     pub fn force_flush_next_imm_memtable(&self) -> Result<()> {
         let state_lock = self.state_lock.lock();
+        let start = Instant::now();
 
         let flush_memtable;
 
@@ -725,16 +1551,31 @@ impl LsmStorageInner {
                 .expect("no imm memtables!")
                 .clone();
         }
+        self.notify_listeners(|listener| {
+            listener.on_flush_begin(&FlushBeginInfo {
+                sst_id: flush_memtable.id(),
+                memtable_size: flush_memtable.approximate_size(),
+            })
+        });
 
         let mut builder = SsTableBuilder::new(self.options.block_size);
         flush_memtable.flush(&mut builder)?;
         let sst_id = flush_memtable.id();
-        let sst = Arc::new(builder.build(
+        let sst = Arc::new(builder.build_with_table_cache(
             sst_id,
             Some(self.block_cache.clone()),
+            self.table_cache.clone(),
             self.path_of_sst(sst_id),
         )?);
+        let mut flush_info = FlushCompletedInfo {
+            sst_id,
+            file_size: sst.table_size(),
+            first_key: Bytes::copy_from_slice(sst.first_key().key_ref()),
+            last_key: Bytes::copy_from_slice(sst.last_key().key_ref()),
+            duration: Duration::ZERO,
+        };
 
+        let sst_meta = sst.meta();
         // Add the flushed L0 table to the list.
         {
             let mut guard = self.state.write();
@@ -751,34 +1592,155 @@ impl LsmStorageInner {
                 snapshot.levels.insert(0, (sst_id, vec![sst_id]));
             }
             println!("flushed {}.sst with size={}", sst_id, sst.table_size());
+            self.statistics.record(Ticker::Flushes, 1);
+            self.statistics
+                .record(Ticker::FlushBytesWritten, sst.table_size());
             snapshot.sstables.insert(sst_id, sst);
             // Update the snapshot.
             *guard = Arc::new(snapshot);
         }
 
-        if self.options.enable_wal {
-            std::fs::remove_file(self.path_of_wal(sst_id))?;
-        }
-
+        self.manifest()
+            .add_record(&state_lock, ManifestRecord::NewSsts(vec![sst_meta]))?;
         self.manifest()
             .add_record(&state_lock, ManifestRecord::Flush(sst_id))?;
 
+        if let Some(wal) = &self.wal {
+            wal.memtable_flushed(sst_id)?;
+        }
+
         self.sync_dir()?;
+        drop(state_lock);
+        flush_info.duration = start.elapsed();
+        self.statistics
+            .record_duration(HistogramType::FlushMicros, flush_info.duration);
+        self.notify_listeners(|listener| listener.on_flush_completed(&flush_info));
+        self.schedule_compaction();
 
         Ok(())
     }
 
     pub fn new_txn(self: &Arc<Self>) -> Result<Arc<Transaction>> {
-        Ok(self.mvcc().new_txn(self.clone(), self.options.serializable))
+        Ok(self.mvcc().new_txn(self.clone(), self.default_isolation()))
+    }
+
+    pub fn new_txn_with_isolation(
+        self: &Arc<Self>,
+        isolation: IsolationLevel,
+    ) -> Result<Arc<Transaction>> {
+        Ok(self.mvcc().new_txn(self.clone(), isolation))
+    }
+
+    fn default_isolation(&self) -> IsolationLevel {
+        if self.options.serializable {
+            IsolationLevel::Serializable
+        } else {
+            IsolationLevel::Snapshot
+        }
+    }
+
+    pub fn new_pessimistic_txn(self: &Arc<Self>) -> Result<Arc<Transaction>> {
+        Ok(self.mvcc().new_pessimistic_txn(self.clone()))
+    }
+
+    pub fn commit_prepared(&self, name: &str) -> Result<()> {
+        let _commit_lock = self.mvcc().commit_lock.lock();
+        // taken before the prepared txns, which the writes check under the write lock
+        let write_lock = self.mvcc().write_lock.lock();
+        let mut prepared_txns = self.prepared_txns.lock();
+        let Some(txn) = prepared_txns.get(name) else {
+            bail!("prepared txn {} does not exist", name);
+        };
+        let batch = txn
+            .batch
+            .iter()
+            .map(|(key, value)| {
+                if value.is_empty() {
+                    WriteBatchRecord::Del(key.clone())
+                } else {
+                    WriteBatchRecord::Put(key.clone(), value.clone())
+                }
+            })
+            .collect::<Vec<_>>();
+        let (ts, size) = {
+            self.check_writable()?;
+            let ts = self.mvcc().latest_commit_ts() + 1;
+            let data = txn
+                .batch
+                .iter()
+                .map(|(key, value)| (KeySlice::from_slice(key, ts), &value[..]))
+                .collect::<Vec<_>>();
+            let result = {
+                // The WAL has the write set in the prepare record, so the data and the commit decision go to the WAL
+                // as one record. No memtable is frozen before that record is written, so that the write set of an
+                // undecided txn never gets into an SST.
+                let _state_lock = self.state_lock.lock();
+                let guard = self.state.read();
+                guard
+                    .memtable
+                    .put_prepared(name, &data, ts)
+                    .map(|()| guard.memtable.approximate_size())
+            };
+            for (key, value) in &txn.batch {
+                if let Some(row_cache) = &self.row_cache {
+                    row_cache.invalidate(key, ts);
+                }
+                self.statistics
+                    .record(Ticker::BytesWritten, (key.len() + value.len()) as u64);
+            }
+            let size = match result {
+                Ok(size) => size,
+                Err(e) => {
+                    let keys = txn.batch.iter().map(|(key, _)| key.clone()).collect();
+                    return Err(self.abort_batch_at_ts(&keys, ts, e));
+                }
+            };
+            self.statistics
+                .record(Ticker::KeysWritten, txn.batch.len() as u64);
+            self.mvcc().update_commit_ts(ts);
+            self.replication.publish(ts, &batch);
+            (ts, size)
+        };
+        let txn = prepared_txns.remove(name).unwrap();
+        drop(prepared_txns);
+        drop(write_lock);
+        self.mvcc().record_committed_txn(CommittedTxnData {
+            write_set: txn.batch.into_iter().map(|(key, _)| key).collect(),
+            read_ts: txn.read_ts,
+            commit_ts: ts,
+        });
+        self.mvcc().lock_manager.unlock(txn.txn_id, txn.locked_keys);
+        // the txn is committed even if the memtable cannot be frozen
+        self.try_freeze(size)
+    }
+
+    /// Check that a write of `write_set` touches no key a prepared txn has read and no range it has scanned. The
+    /// prepared txn passed its serializable check at prepare, so it must still read the same when it commits. The
+    /// caller should hold the MVCC write lock.
+    pub(crate) fn check_prepared_reads(&self, write_set: &BTreeSet<Bytes>) -> Result<()> {
+        for (name, txn) in self.prepared_txns.lock().iter() {
+            if txn.conflict_sets.conflicts_with(write_set) {
+                self.statistics.record(Ticker::TxnConflicts, 1);
+                bail!("write to a key read by prepared txn {}", name);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn rollback_prepared(&self, name: &str) -> Result<()> {
+        let mut prepared_txns = self.prepared_txns.lock();
+        if !prepared_txns.contains_key(name) {
+            bail!("prepared txn {} does not exist", name);
+        }
+        self.wal.as_ref().unwrap().rollback_prepared(name)?;
+        let txn = prepared_txns.remove(name).unwrap();
+        self.mvcc().lock_manager.unlock(txn.txn_id, txn.locked_keys);
+        Ok(())
     }
 
     /// Create an iterator over a range of keys.
-    pub fn scan<'a>(
-        self: &'a Arc<Self>,
-        lower: Bound<&[u8]>,
-        upper: Bound<&[u8]>,
-    ) -> Result<TxnIterator> {
-        let txn = self.mvcc().new_txn(self.clone(), self.options.serializable);
+    pub fn scan(self: &Arc<Self>, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<TxnIterator> {
+        let txn = self.mvcc().new_txn(self.clone(), self.default_isolation());
         txn.scan(lower, upper)
     }
 
@@ -788,6 +1750,30 @@ impl LsmStorageInner {
         upper: Bound<&[u8]>,
         read_ts: u64,
     ) -> Result<FusedIterator<LsmIterator>> {
+        self.scan_with_ts_and_options(lower, upper, read_ts, &TableReadOptions::default())
+    }
+
+    pub(crate) fn scan_with_ts_and_options(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        read_ts: u64,
+        options: &TableReadOptions,
+    ) -> Result<FusedIterator<LsmIterator>> {
+        let start = Instant::now();
+        let iter = self.create_scan_iterator(lower, upper, read_ts, options)?;
+        Ok(FusedIterator::new(
+            iter.with_scan_timer(self.statistics.clone(), start),
+        ))
+    }
+
+    fn create_scan_iterator(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        read_ts: u64,
+        options: &TableReadOptions,
+    ) -> Result<LsmIterator> {
         let snapshot = {
             let guard = self.state.read();
             Arc::clone(&guard)
@@ -816,21 +1802,26 @@ impl LsmStorageInner {
                 table.last_key().as_key_slice(),
             ) {
                 let iter = match lower {
-                    Bound::Included(key) => SsTableIterator::create_and_seek_to_key(
+                    Bound::Included(key) => SsTableIterator::create_and_seek_to_key_with_options(
                         table,
                         KeySlice::from_slice(key, key::TS_RANGE_BEGIN),
+                        options.clone(),
                     )?,
                     Bound::Excluded(key) => {
-                        let mut iter = SsTableIterator::create_and_seek_to_key(
+                        let mut iter = SsTableIterator::create_and_seek_to_key_with_options(
                             table,
                             KeySlice::from_slice(key, key::TS_RANGE_BEGIN),
+                            options.clone(),
                         )?;
                         while iter.is_valid() && iter.key().key_ref() == key {
                             iter.next()?;
                         }
                         iter
                     }
-                    Bound::Unbounded => SsTableIterator::create_and_seek_to_first(table)?,
+                    Bound::Unbounded => SsTableIterator::create_and_seek_to_first_with_options(
+                        table,
+                        options.clone(),
+                    )?,
                 };
 
                 table_iters.push(Box::new(iter));
@@ -854,21 +1845,26 @@ impl LsmStorageInner {
             }
 
             let level_iter = match lower {
-                Bound::Included(key) => SstConcatIterator::create_and_seek_to_key(
+                Bound::Included(key) => SstConcatIterator::create_and_seek_to_key_with_options(
                     level_ssts,
                     KeySlice::from_slice(key, key::TS_RANGE_BEGIN),
+                    options.clone(),
                 )?,
                 Bound::Excluded(key) => {
-                    let mut iter = SstConcatIterator::create_and_seek_to_key(
+                    let mut iter = SstConcatIterator::create_and_seek_to_key_with_options(
                         level_ssts,
                         KeySlice::from_slice(key, key::TS_RANGE_BEGIN),
+                        options.clone(),
                     )?;
                     while iter.is_valid() && iter.key().key_ref() == key {
                         iter.next()?;
                     }
                     iter
                 }
-                Bound::Unbounded => SstConcatIterator::create_and_seek_to_first(level_ssts)?,
+                Bound::Unbounded => SstConcatIterator::create_and_seek_to_first_with_options(
+                    level_ssts,
+                    options.clone(),
+                )?,
             };
             level_iters.push(Box::new(level_iter));
         }
@@ -876,10 +1872,6 @@ impl LsmStorageInner {
         let iter = TwoMergeIterator::create(memtable_iter, l0_iter)?;
         let iter = TwoMergeIterator::create(iter, MergeIterator::create(level_iters))?;
 
-        Ok(FusedIterator::new(LsmIterator::new(
-            iter,
-            map_bound(upper),
-            read_ts,
-        )?))
+        LsmIterator::new(iter, map_bound(upper), read_ts)
     }
 }
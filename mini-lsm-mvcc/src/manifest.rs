@@ -19,6 +19,30 @@ pub enum ManifestRecord {
     Flush(usize),
     NewMemtable(usize),
     Compaction(CompactionTask, Vec<usize>),
+    /// A named snapshot at `ts`, created at `created_at_ms` milliseconds since the Unix epoch.
+    CreateSnapshot {
+        name: String,
+        ts: u64,
+        created_at_ms: u64,
+    },
+    ReleaseSnapshot(String),
+    /// The metadata of the SSTs added by the `Flush` or `Compaction` record that follows. A manifest written before
+    /// this record existed does not have it, and those SSTs are read to get their metadata instead.
+    NewSsts(Vec<SstMeta>),
+}
+
+/// What an SST opened through the table cache keeps in memory, so that it can be opened without reading the file.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SstMeta {
+    pub id: usize,
+    pub file_size: u64,
+    pub block_meta_offset: usize,
+    pub num_of_blocks: usize,
+    /// The first key and its ts.
+    pub first_key: (Vec<u8>, u64),
+    /// The last key and its ts.
+    pub last_key: (Vec<u8>, u64),
+    pub max_ts: u64,
 }
 
 impl Manifest {
@@ -64,6 +88,15 @@ impl Manifest {
         ))
     }
 
+    /// Read the whole manifest file, e.g., to ship it to a replication follower.
+    pub fn read_all(&self) -> Result<Vec<u8>> {
+        use std::os::unix::fs::FileExt;
+        let file = self.file.lock();
+        let mut buf = vec![0; file.metadata()?.len() as usize];
+        file.read_exact_at(&mut buf, 0)?;
+        Ok(buf)
+    }
+
     pub fn add_record(
         &self,
         _state_lock_observer: &MutexGuard<()>,
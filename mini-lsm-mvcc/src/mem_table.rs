@@ -1,6 +1,5 @@
 use std::ops::Bound;
-use std::path::Path;
-use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
 use std::sync::Arc;
 
 use anyhow::Result;
@@ -13,16 +12,102 @@ use crate::iterators::StorageIterator;
 use crate::key::{KeyBytes, KeySlice, TS_DEFAULT};
 use crate::table::SsTableBuilder;
 use crate::wal::Wal;
+use crate::write_buffer_manager::WriteBufferManager;
 
-/// A basic mem-table based on crossbeam-skiplist.
+mod arena_skiplist;
+mod hash_prefix;
+mod vector;
+
+pub use arena_skiplist::{ArenaSkipList, ArenaSkipListIterator};
+pub use hash_prefix::{HashPrefixIterator, HashPrefixRep};
+pub use vector::{VectorIterator, VectorRep};
+
+/// The data structure holding the entries of a mem-table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MemTableKind {
+    /// A crossbeam `SkipMap`, with each key and value allocated separately. Its size is estimated from the lengths of
+    /// the keys and values.
+    #[default]
+    SkipMap,
+    /// A skiplist allocated in an arena. Its size is the exact memory taken by the arena.
+    ArenaSkipList,
+    /// An append-only vector, sorted once when the mem-table is frozen. Writes are cheap, but reads of the mutable
+    /// mem-table scan or sort the whole vector, which suits bulk loads.
+    Vector,
+    /// A hash table from the first `prefix_len` bytes of the keys to a `SkipMap` of the keys with that prefix. Gets
+    /// only search the skiplist of their prefix, while scans sort the prefixes first.
+    HashPrefix { prefix_len: usize },
+}
+
+/// The data structure holding the entries of a mem-table. Writes with the same key and ts replace each other.
+pub trait MemTableRep: Send + Sync {
+    fn insert(&self, key: KeySlice, value: &[u8]);
+
+    fn get(&self, key: KeySlice) -> Option<Bytes>;
+
+    /// Get an iterator over a range of keys.
+    fn scan(self: Arc<Self>, lower: Bound<KeySlice>, upper: Bound<KeySlice>) -> MemTableIterator;
+
+    /// Call `f` on every entry in key order.
+    fn for_each(&self, f: &mut dyn FnMut(KeySlice, &[u8]));
+
+    fn is_empty(&self) -> bool;
+
+    /// The exact bytes taken by the entries, if the representation keeps track of them.
+    fn memory_usage(&self) -> Option<usize> {
+        None
+    }
+
+    /// Called once the mem-table becomes immutable.
+    fn freeze(&self) {}
+}
+
+impl MemTableRep for SkipMap<KeyBytes, Bytes> {
+    fn insert(&self, key: KeySlice, value: &[u8]) {
+        SkipMap::insert(
+            self,
+            key.to_key_vec().into_key_bytes(),
+            Bytes::copy_from_slice(value),
+        );
+    }
+
+    fn get(&self, key: KeySlice) -> Option<Bytes> {
+        let key_bytes = KeyBytes::from_bytes_with_ts(
+            Bytes::from_static(unsafe { std::mem::transmute::<&[u8], &[u8]>(key.key_ref()) }),
+            key.ts(),
+        );
+        SkipMap::get(self, &key_bytes).map(|e| e.value().clone())
+    }
+
+    fn scan(self: Arc<Self>, lower: Bound<KeySlice>, upper: Bound<KeySlice>) -> MemTableIterator {
+        MemTableIterator::SkipMap(SkipMapIterator::scan(self, lower, upper))
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(KeySlice, &[u8])) {
+        for entry in self.iter() {
+            f(entry.key().as_key_slice(), entry.value());
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        SkipMap::is_empty(self)
+    }
+}
+
+/// A basic mem-table based on crossbeam-skiplist, or on another `MemTableRep` picked by `MemTableKind`.
 ///
 /// An initial implementation of memtable is part of week 1, day 1. It will be incrementally implemented in other
 /// chapters of week 1 and week 2.
 pub struct MemTable {
-    pub(crate) map: Arc<SkipMap<KeyBytes, Bytes>>,
-    wal: Option<Wal>,
+    rep: Arc<dyn MemTableRep>,
+    kind: MemTableKind,
+    wal: Option<Arc<Wal>>,
     id: usize,
     approximate_size: Arc<AtomicUsize>,
+    write_buffer_manager: Option<Arc<WriteBufferManager>>,
+    /// The bytes charged to the write buffer manager.
+    charged: AtomicUsize,
+    frozen: AtomicBool,
 }
 
 /// Create a bound of `Bytes` from a bound of `&[u8]`.
@@ -50,7 +135,7 @@ pub(crate) fn map_key_bound(bound: Bound<KeySlice>) -> Bound<KeyBytes> {
 }
 
 /// Create a bound of `Bytes` from a bound of `KeySlice`.
-pub(crate) fn map_key_bound_plus_ts(bound: Bound<&[u8]>, ts: u64) -> Bound<KeySlice> {
+pub(crate) fn map_key_bound_plus_ts(bound: Bound<&[u8]>, ts: u64) -> Bound<KeySlice<'_>> {
     match bound {
         Bound::Included(x) => Bound::Included(KeySlice::from_slice(x, ts)),
         Bound::Excluded(x) => Bound::Excluded(KeySlice::from_slice(x, ts)),
@@ -61,42 +146,88 @@ pub(crate) fn map_key_bound_plus_ts(bound: Bound<&[u8]>, ts: u64) -> Bound<KeySl
 impl MemTable {
     /// Create a new mem-table.
     pub fn create(id: usize) -> Self {
+        Self::new(id, MemTableKind::default(), None)
+    }
+
+    /// Create a new mem-table that logs to the shared WAL
+    pub fn create_with_wal(id: usize, wal: Arc<Wal>) -> Self {
+        Self::new(id, MemTableKind::default(), Some(wal))
+    }
+
+    /// Create a new mem-table of the given kind, logging to the shared WAL if there is one.
+    pub fn new(id: usize, kind: MemTableKind, wal: Option<Arc<Wal>>) -> Self {
+        if let Some(wal) = &wal {
+            wal.add_memtable(id);
+        }
+        let rep: Arc<dyn MemTableRep> = match kind {
+            MemTableKind::SkipMap => Arc::new(SkipMap::new()),
+            MemTableKind::ArenaSkipList => Arc::new(ArenaSkipList::new()),
+            MemTableKind::Vector => Arc::new(VectorRep::new()),
+            MemTableKind::HashPrefix { prefix_len } => Arc::new(HashPrefixRep::new(prefix_len)),
+        };
         Self {
             id,
-            map: Arc::new(SkipMap::new()),
-            wal: None,
+            rep,
+            kind,
+            wal,
             approximate_size: Arc::new(AtomicUsize::new(0)),
+            write_buffer_manager: None,
+            charged: AtomicUsize::new(0),
+            frozen: AtomicBool::new(false),
         }
     }
 
-    /// Create a new mem-table with WAL
-    pub fn create_with_wal(id: usize, path: impl AsRef<Path>) -> Result<Self> {
-        Ok(Self {
-            id,
-            map: Arc::new(SkipMap::new()),
-            wal: Some(Wal::create(path.as_ref())?),
-            approximate_size: Arc::new(AtomicUsize::new(0)),
-        })
+    /// Charge the size of the mem-table to a write buffer manager until it is dropped.
+    pub fn with_write_buffer_manager(mut self, manager: Option<Arc<WriteBufferManager>>) -> Self {
+        self.write_buffer_manager = manager;
+        self.charge_write_buffer();
+        self
     }
 
-    /// Create a memtable from WAL
-    pub fn recover_from_wal(id: usize, path: impl AsRef<Path>) -> Result<Self> {
-        let map = Arc::new(SkipMap::new());
-        Ok(Self {
-            id,
-            wal: Some(Wal::recover(path.as_ref(), &map)?),
-            map,
-            approximate_size: Arc::new(AtomicUsize::new(0)),
-        })
+    fn charge_write_buffer(&self) {
+        if let Some(manager) = &self.write_buffer_manager {
+            let size = self.approximate_size();
+            let charged = self
+                .charged
+                .fetch_max(size, std::sync::atomic::Ordering::Relaxed);
+            if size > charged {
+                manager.reserve(size - charged);
+            }
+        }
+    }
+
+    /// Create a memtable from the records recovered from the shared WAL
+    pub fn recover_from_wal(
+        id: usize,
+        kind: MemTableKind,
+        wal: Arc<Wal>,
+        map: SkipMap<KeyBytes, Bytes>,
+    ) -> Self {
+        let mut memtable = Self::new(id, kind, Some(wal));
+        if kind == MemTableKind::SkipMap {
+            memtable.rep = Arc::new(map);
+        } else {
+            for entry in map.iter() {
+                memtable
+                    .rep
+                    .insert(entry.key().as_key_slice(), entry.value());
+            }
+        }
+        let mut size = 0;
+        memtable.for_each(|key, value| size += key.raw_len() + value.len());
+        memtable
+            .approximate_size
+            .store(size, std::sync::atomic::Ordering::Relaxed);
+        memtable
+    }
+
+    pub fn kind(&self) -> MemTableKind {
+        self.kind
     }
 
     /// Get a value by key. Should not be used in week 3.
     pub fn get(&self, key: KeySlice) -> Option<Bytes> {
-        let key_bytes = KeyBytes::from_bytes_with_ts(
-            Bytes::from_static(unsafe { std::mem::transmute::<&[u8], &[u8]>(key.key_ref()) }),
-            key.ts(),
-        );
-        self.map.get(&key_bytes).map(|e| e.value().clone())
+        self.rep.get(key)
     }
 
     pub fn for_testing_put_slice(&self, key: &[u8], value: &[u8]) -> Result<()> {
@@ -129,20 +260,37 @@ impl MemTable {
 
     /// Implement this in week 3, day 5.
     pub fn put_batch(&self, data: &[(KeySlice, &[u8])]) -> Result<()> {
+        self.insert_batch(data);
+        if let Some(ref wal) = self.wal {
+            wal.put_batch(self.id, data)?;
+        }
+        Ok(())
+    }
+
+    /// Put the write set of the prepared transaction `name` at its commit ts. The WAL only gets the commit decision,
+    /// as it has the write set in the prepare record already.
+    pub(crate) fn put_prepared(
+        &self,
+        name: &str,
+        data: &[(KeySlice, &[u8])],
+        commit_ts: u64,
+    ) -> Result<()> {
+        self.insert_batch(data);
+        if let Some(ref wal) = self.wal {
+            wal.commit_prepared(name, self.id, commit_ts)?;
+        }
+        Ok(())
+    }
+
+    fn insert_batch(&self, data: &[(KeySlice, &[u8])]) {
         let mut estimated_size = 0;
         for (key, value) in data {
             estimated_size += key.raw_len() + value.len();
-            self.map.insert(
-                key.to_key_vec().into_key_bytes(),
-                Bytes::copy_from_slice(value),
-            );
+            self.rep.insert(*key, value);
         }
         self.approximate_size
             .fetch_add(estimated_size, std::sync::atomic::Ordering::Relaxed);
-        if let Some(ref wal) = self.wal {
-            wal.put_batch(data)?;
-        }
-        Ok(())
+        self.charge_write_buffer();
     }
 
     pub fn sync_wal(&self) -> Result<()> {
@@ -154,22 +302,27 @@ impl MemTable {
 
     /// Get an iterator over a range of keys.
     pub fn scan(&self, lower: Bound<KeySlice>, upper: Bound<KeySlice>) -> MemTableIterator {
-        let (lower, upper) = (map_key_bound(lower), map_key_bound(upper));
-        let mut iter = MemTableIteratorBuilder {
-            map: self.map.clone(),
-            iter_builder: |map| map.range((lower, upper)),
-            item: (KeyBytes::new(), Bytes::new()),
+        self.rep.clone().scan(lower, upper)
+    }
+
+    /// Call `f` on every entry in key order. For an arena skiplist, the keys and values are read in place.
+    pub(crate) fn for_each(&self, mut f: impl FnMut(KeySlice, &[u8])) {
+        self.rep.for_each(&mut f)
+    }
+
+    /// Mark the mem-table as immutable, e.g. to sort a vector mem-table before it is read and flushed.
+    pub fn freeze(&self) {
+        self.rep.freeze();
+        if let Some(manager) = &self.write_buffer_manager {
+            if !self.frozen.swap(true, std::sync::atomic::Ordering::Relaxed) {
+                manager.mark_immutable(self.charged.load(std::sync::atomic::Ordering::Relaxed));
+            }
         }
-        .build();
-        iter.next().unwrap();
-        iter
     }
 
     /// Flush the mem-table to SSTable. Implement in week 1 day 6.
     pub fn flush(&self, builder: &mut SsTableBuilder) -> Result<()> {
-        for entry in self.map.iter() {
-            builder.add(entry.key().as_key_slice(), &entry.value()[..]);
-        }
+        self.for_each(|key, value| builder.add(key, value));
         Ok(())
     }
 
@@ -177,14 +330,25 @@ impl MemTable {
         self.id
     }
 
+    /// The size of the keys and values, or the exact memory taken by an arena skiplist.
     pub fn approximate_size(&self) -> usize {
-        self.approximate_size
-            .load(std::sync::atomic::Ordering::Relaxed)
+        self.rep.memory_usage().unwrap_or_else(|| {
+            self.approximate_size
+                .load(std::sync::atomic::Ordering::Relaxed)
+        })
     }
 
     /// Only use this function when closing the database
     pub fn is_empty(&self) -> bool {
-        self.map.is_empty()
+        self.rep.is_empty()
+    }
+}
+
+impl Drop for MemTable {
+    fn drop(&mut self) {
+        if let Some(manager) = &self.write_buffer_manager {
+            manager.free(*self.charged.get_mut(), !*self.frozen.get_mut());
+        }
     }
 }
 
@@ -201,10 +365,10 @@ type SkipMapRangeIter<'a> = crossbeam_skiplist::map::Range<
 ///
 /// This is part of week 1, day 2.
 #[self_referencing]
-pub struct MemTableIterator {
+pub struct SkipMapIterator {
     /// Stores a reference to the skipmap.
     map: Arc<SkipMap<KeyBytes, Bytes>>,
-    /// Stores a skipmap iterator that refers to the lifetime of `MemTableIterator` itself.
+    /// Stores a skipmap iterator that refers to the lifetime of `SkipMapIterator` itself.
     #[borrows(map)]
     #[not_covariant]
     iter: SkipMapRangeIter<'this>,
@@ -212,7 +376,23 @@ pub struct MemTableIterator {
     item: (KeyBytes, Bytes),
 }
 
-impl MemTableIterator {
+impl SkipMapIterator {
+    fn scan(
+        map: Arc<SkipMap<KeyBytes, Bytes>>,
+        lower: Bound<KeySlice>,
+        upper: Bound<KeySlice>,
+    ) -> Self {
+        let (lower, upper) = (map_key_bound(lower), map_key_bound(upper));
+        let mut iter = SkipMapIteratorBuilder {
+            map,
+            iter_builder: |map| map.range((lower, upper)),
+            item: (KeyBytes::new(), Bytes::new()),
+        }
+        .build();
+        iter.next().unwrap();
+        iter
+    }
+
     fn entry_to_item(entry: Option<Entry<'_, KeyBytes, Bytes>>) -> (KeyBytes, Bytes) {
         entry
             .map(|x| (x.key().clone(), x.value().clone()))
@@ -220,14 +400,14 @@ impl MemTableIterator {
     }
 }
 
-impl StorageIterator for MemTableIterator {
+impl StorageIterator for SkipMapIterator {
     type KeyType<'a> = KeySlice<'a>;
 
     fn value(&self) -> &[u8] {
         &self.borrow_item().1[..]
     }
 
-    fn key(&self) -> KeySlice {
+    fn key(&self) -> KeySlice<'_> {
         self.borrow_item().0.as_key_slice()
     }
 
@@ -236,8 +416,56 @@ impl StorageIterator for MemTableIterator {
     }
 
     fn next(&mut self) -> Result<()> {
-        let entry = self.with_iter_mut(|iter| MemTableIterator::entry_to_item(iter.next()));
+        let entry = self.with_iter_mut(|iter| SkipMapIterator::entry_to_item(iter.next()));
         self.with_mut(|x| *x.item = entry);
         Ok(())
     }
 }
+
+/// An iterator over a range of a mem-table.
+pub enum MemTableIterator {
+    SkipMap(SkipMapIterator),
+    Arena(ArenaSkipListIterator),
+    Vector(VectorIterator),
+    HashPrefix(HashPrefixIterator),
+}
+
+impl StorageIterator for MemTableIterator {
+    type KeyType<'a> = KeySlice<'a>;
+
+    fn value(&self) -> &[u8] {
+        match self {
+            Self::SkipMap(iter) => iter.value(),
+            Self::Arena(iter) => iter.value(),
+            Self::Vector(iter) => iter.value(),
+            Self::HashPrefix(iter) => iter.value(),
+        }
+    }
+
+    fn key(&self) -> KeySlice<'_> {
+        match self {
+            Self::SkipMap(iter) => iter.key(),
+            Self::Arena(iter) => iter.key(),
+            Self::Vector(iter) => iter.key(),
+            Self::HashPrefix(iter) => iter.key(),
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        match self {
+            Self::SkipMap(iter) => iter.is_valid(),
+            Self::Arena(iter) => iter.is_valid(),
+            Self::Vector(iter) => iter.is_valid(),
+            Self::HashPrefix(iter) => iter.is_valid(),
+        }
+    }
+
+    fn next(&mut self) -> Result<()> {
+        match self {
+            Self::SkipMap(iter) => iter.next(),
+            Self::Arena(iter) => iter.next(),
+            Self::Vector(iter) => iter.next(),
+            Self::HashPrefix(iter) => iter.next(),
+        }
+    }
+}
@@ -0,0 +1,427 @@
+//! A concurrent skiplist whose nodes, keys and values are allocated in an arena.
+//!
+//! The arena hands out memory from large chunks that never move until the skiplist is dropped, so that nodes can link
+//! to each other with plain pointers and a flush reads the entries from a few contiguous chunks. Nodes are never
+//! removed. Inserts link a node level by level with a CAS, from the bottom level up, and readers follow the links
+//! without locking. Writing a key with the same ts again swaps the value of its node.
+
+use std::ops::Bound;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use anyhow::Result;
+use bytes::Bytes;
+use parking_lot::Mutex;
+
+use crate::iterators::StorageIterator;
+use crate::key::{KeyBytes, KeySlice};
+
+use super::{MemTableIterator, MemTableRep};
+
+const MAX_HEIGHT: usize = 12;
+/// A node gets one more level with a probability of 1 / `BRANCHING`.
+const BRANCHING: u32 = 4;
+const CHUNK_SIZE: usize = 1 << 20;
+const ALIGN: usize = std::mem::align_of::<u64>();
+
+/// Chunks of 8-byte aligned memory. Allocated memory is zeroed and stays in place until the arena is dropped.
+struct Arena {
+    chunks: Mutex<ArenaChunks>,
+    /// The bytes handed out by `alloc`, including the padding for alignment.
+    allocated: AtomicUsize,
+}
+
+struct ArenaChunks {
+    chunks: Vec<Box<[u64]>>,
+    /// The start of the free space of the last chunk, and how many bytes are left in it.
+    free: *mut u8,
+    remaining: usize,
+}
+
+impl Arena {
+    fn new() -> Self {
+        Self {
+            chunks: Mutex::new(ArenaChunks {
+                chunks: Vec::new(),
+                free: ptr::null_mut(),
+                remaining: 0,
+            }),
+            allocated: AtomicUsize::new(0),
+        }
+    }
+
+    fn alloc(&self, size: usize) -> *mut u8 {
+        let size = size.next_multiple_of(ALIGN);
+        let mut chunks = self.chunks.lock();
+        if chunks.remaining < size {
+            let mut chunk = vec![0u64; size.max(CHUNK_SIZE) / ALIGN].into_boxed_slice();
+            let start = chunk.as_mut_ptr() as *mut u8;
+            if size >= CHUNK_SIZE {
+                // a large allocation gets a chunk of its own, keeping the free space of the current one
+                chunks.chunks.push(chunk);
+                self.allocated.fetch_add(size, Ordering::Relaxed);
+                return start;
+            }
+            chunks.chunks.push(chunk);
+            chunks.free = start;
+            chunks.remaining = CHUNK_SIZE;
+        }
+        let result = chunks.free;
+        // SAFETY: `size` bytes are left in the chunk `free` points into.
+        chunks.free = unsafe { chunks.free.add(size) };
+        chunks.remaining -= size;
+        self.allocated.fetch_add(size, Ordering::Relaxed);
+        result
+    }
+
+    fn allocated(&self) -> usize {
+        self.allocated.load(Ordering::Relaxed)
+    }
+}
+
+/// The header of a node. It is followed in the arena by `height` links and the key.
+#[repr(C)]
+struct Node {
+    /// The value, as a `u32` length followed by the bytes.
+    value: AtomicPtr<u8>,
+    ts: u64,
+    key_len: u32,
+    height: u32,
+}
+
+const NODE_HEADER_SIZE: usize = std::mem::size_of::<Node>();
+const LINK_SIZE: usize = std::mem::size_of::<AtomicPtr<Node>>();
+
+/// Accessors of a node, which must be allocated by the arena of a live skiplist.
+impl Node {
+    unsafe fn link<'a>(node: *const Node, level: usize) -> &'a AtomicPtr<Node> {
+        debug_assert!(level < (*node).height as usize);
+        &*((node as *const u8).add(NODE_HEADER_SIZE + level * LINK_SIZE) as *const AtomicPtr<Node>)
+    }
+
+    unsafe fn next(node: *const Node, level: usize) -> *mut Node {
+        Self::link(node, level).load(Ordering::Acquire)
+    }
+
+    unsafe fn key<'a>(node: *const Node) -> KeySlice<'a> {
+        let height = (*node).height as usize;
+        let key = std::slice::from_raw_parts(
+            (node as *const u8).add(NODE_HEADER_SIZE + height * LINK_SIZE),
+            (*node).key_len as usize,
+        );
+        KeySlice::from_slice(key, (*node).ts)
+    }
+
+    unsafe fn value<'a>(node: *const Node) -> &'a [u8] {
+        let value = (*node).value.load(Ordering::Acquire);
+        let len = u32::from_le_bytes(*(value as *const [u8; 4]));
+        std::slice::from_raw_parts(value.add(4), len as usize)
+    }
+}
+
+pub struct ArenaSkipList {
+    arena: Arena,
+    head: *mut Node,
+    /// The number of levels in use.
+    height: AtomicUsize,
+    len: AtomicUsize,
+}
+
+// SAFETY: the nodes are only modified through atomics, and the arena lives as long as the skiplist.
+unsafe impl Send for ArenaSkipList {}
+unsafe impl Sync for ArenaSkipList {}
+
+impl Default for ArenaSkipList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ArenaSkipList {
+    pub fn new() -> Self {
+        let arena = Arena::new();
+        let head = Self::alloc_node(&arena, KeySlice::from_slice(&[], 0), MAX_HEIGHT);
+        Self {
+            arena,
+            head,
+            height: AtomicUsize::new(1),
+            len: AtomicUsize::new(0),
+        }
+    }
+
+    fn alloc_node(arena: &Arena, key: KeySlice, height: usize) -> *mut Node {
+        let key_ref = key.key_ref();
+        let node = arena.alloc(NODE_HEADER_SIZE + height * LINK_SIZE + key_ref.len()) as *mut Node;
+        // SAFETY: the memory is zeroed, which is a null value and null links, and large enough for the key.
+        unsafe {
+            (*node).ts = key.ts();
+            (*node).key_len = key_ref.len() as u32;
+            (*node).height = height as u32;
+            ptr::copy_nonoverlapping(
+                key_ref.as_ptr(),
+                (node as *mut u8).add(NODE_HEADER_SIZE + height * LINK_SIZE),
+                key_ref.len(),
+            );
+        }
+        node
+    }
+
+    fn alloc_value(&self, value: &[u8]) -> *mut u8 {
+        let ptr = self.arena.alloc(4 + value.len());
+        // SAFETY: the allocation holds the length and the value.
+        unsafe {
+            ptr::copy_nonoverlapping((value.len() as u32).to_le_bytes().as_ptr(), ptr, 4);
+            ptr::copy_nonoverlapping(value.as_ptr(), ptr.add(4), value.len());
+        }
+        ptr
+    }
+
+    fn random_height() -> usize {
+        let mut height = 1;
+        while height < MAX_HEIGHT && rand::random::<u32>().is_multiple_of(BRANCHING) {
+            height += 1;
+        }
+        height
+    }
+
+    /// Starting from `before` at `level`, find the last node before `key` and the first node not before it.
+    fn find_splice_for_level(
+        &self,
+        key: KeySlice,
+        mut before: *mut Node,
+        level: usize,
+    ) -> (*mut Node, *mut Node) {
+        loop {
+            // SAFETY: `before` is the head or a node of this skiplist.
+            let next = unsafe { Node::next(before, level) };
+            if next.is_null() || unsafe { Node::key(next) } >= key {
+                return (before, next);
+            }
+            before = next;
+        }
+    }
+
+    /// The first node not before `key`, or null.
+    fn seek(&self, key: KeySlice) -> *mut Node {
+        let mut before = self.head;
+        let mut next = ptr::null_mut();
+        for level in (0..self.height.load(Ordering::Acquire)).rev() {
+            (before, next) = self.find_splice_for_level(key, before, level);
+        }
+        next
+    }
+
+    fn first(&self) -> *mut Node {
+        // SAFETY: the head has all the levels.
+        unsafe { Node::next(self.head, 0) }
+    }
+
+    pub fn insert(&self, key: KeySlice, value: &[u8]) {
+        let value_ptr = self.alloc_value(value);
+        let mut list_height = self.height.load(Ordering::Acquire);
+        let mut splice = [(self.head, ptr::null_mut()); MAX_HEIGHT];
+        let mut before = self.head;
+        for level in (0..list_height).rev() {
+            splice[level] = self.find_splice_for_level(key, before, level);
+            before = splice[level].0;
+        }
+        let next = splice[0].1;
+        // SAFETY: all the nodes come from this skiplist.
+        unsafe {
+            if !next.is_null() && Node::key(next) == key {
+                (*next).value.store(value_ptr, Ordering::Release);
+                return;
+            }
+        }
+
+        let height = Self::random_height();
+        while height > list_height {
+            match self.height.compare_exchange_weak(
+                list_height,
+                height,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => break,
+                Err(current) => list_height = current,
+            }
+        }
+        let node = Self::alloc_node(&self.arena, key, height);
+        // SAFETY: the node is not linked yet, and every node linked to comes from this skiplist.
+        unsafe {
+            (*node).value.store(value_ptr, Ordering::Relaxed);
+            for (level, splice) in splice.iter_mut().enumerate().take(height) {
+                loop {
+                    let (before, next) = *splice;
+                    Node::link(node, level).store(next, Ordering::Relaxed);
+                    if Node::link(before, level)
+                        .compare_exchange(next, node, Ordering::AcqRel, Ordering::Acquire)
+                        .is_ok()
+                    {
+                        break;
+                    }
+                    // another insert linked a node in between, find the splice again from the same node
+                    *splice = self.find_splice_for_level(key, before, level);
+                    let next = splice.1;
+                    if level == 0 && !next.is_null() && Node::key(next) == key {
+                        // the same key was inserted concurrently, and this node is left unlinked
+                        (*next).value.store(value_ptr, Ordering::Release);
+                        return;
+                    }
+                }
+            }
+        }
+        self.len.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self, key: KeySlice) -> Option<&[u8]> {
+        let node = self.seek(key);
+        // SAFETY: the node comes from this skiplist, which outlives the value.
+        unsafe { (!node.is_null() && Node::key(node) == key).then(|| Node::value(node)) }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.first().is_null()
+    }
+
+    /// The bytes taken by the nodes, the keys and the values, including the values replaced by later writes.
+    pub fn memory_usage(&self) -> usize {
+        self.arena.allocated()
+    }
+
+    /// Call `f` on every entry in key order.
+    pub fn for_each(&self, mut f: impl FnMut(KeySlice, &[u8])) {
+        let mut node = self.first();
+        while !node.is_null() {
+            // SAFETY: the node comes from this skiplist.
+            unsafe {
+                f(Node::key(node), Node::value(node));
+                node = Node::next(node, 0);
+            }
+        }
+    }
+
+    /// An iterator over a range of the skiplist, which keeps the skiplist alive.
+    pub fn range(
+        self: &Arc<Self>,
+        lower: Bound<KeySlice>,
+        upper: Bound<KeySlice>,
+    ) -> ArenaSkipListIterator {
+        let node = match lower {
+            Bound::Included(key) => self.seek(key),
+            Bound::Excluded(key) => {
+                let node = self.seek(key);
+                // SAFETY: the node comes from this skiplist.
+                unsafe {
+                    if !node.is_null() && Node::key(node) == key {
+                        Node::next(node, 0)
+                    } else {
+                        node
+                    }
+                }
+            }
+            Bound::Unbounded => self.first(),
+        };
+        let upper = match upper {
+            Bound::Included(key) => Bound::Included(key.to_key_vec().into_key_bytes()),
+            Bound::Excluded(key) => Bound::Excluded(key.to_key_vec().into_key_bytes()),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let mut iter = ArenaSkipListIterator {
+            _list: self.clone(),
+            node,
+            upper,
+        };
+        iter.check_upper_bound();
+        iter
+    }
+}
+
+impl MemTableRep for ArenaSkipList {
+    fn insert(&self, key: KeySlice, value: &[u8]) {
+        ArenaSkipList::insert(self, key, value);
+    }
+
+    fn get(&self, key: KeySlice) -> Option<Bytes> {
+        ArenaSkipList::get(self, key).map(Bytes::copy_from_slice)
+    }
+
+    fn scan(self: Arc<Self>, lower: Bound<KeySlice>, upper: Bound<KeySlice>) -> MemTableIterator {
+        MemTableIterator::Arena(self.range(lower, upper))
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(KeySlice, &[u8])) {
+        ArenaSkipList::for_each(self, f);
+    }
+
+    fn is_empty(&self) -> bool {
+        ArenaSkipList::is_empty(self)
+    }
+
+    fn memory_usage(&self) -> Option<usize> {
+        Some(ArenaSkipList::memory_usage(self))
+    }
+}
+
+pub struct ArenaSkipListIterator {
+    /// Keeps the nodes alive.
+    _list: Arc<ArenaSkipList>,
+    /// The current node, or null once the iterator is exhausted.
+    node: *mut Node,
+    upper: Bound<KeyBytes>,
+}
+
+// SAFETY: the iterator only reads the nodes of the skiplist it keeps alive.
+unsafe impl Send for ArenaSkipListIterator {}
+
+impl ArenaSkipListIterator {
+    fn check_upper_bound(&mut self) {
+        if self.node.is_null() {
+            return;
+        }
+        // SAFETY: the node comes from `self._list`.
+        let key = unsafe { Node::key(self.node) };
+        let in_range = match &self.upper {
+            Bound::Included(upper) => key <= upper.as_key_slice(),
+            Bound::Excluded(upper) => key < upper.as_key_slice(),
+            Bound::Unbounded => true,
+        };
+        if !in_range {
+            self.node = ptr::null_mut();
+        }
+    }
+}
+
+impl StorageIterator for ArenaSkipListIterator {
+    type KeyType<'a> = KeySlice<'a>;
+
+    fn value(&self) -> &[u8] {
+        debug_assert!(!self.node.is_null());
+        // SAFETY: the node comes from `self._list`, which lives as long as the iterator.
+        unsafe { Node::value(self.node) }
+    }
+
+    fn key(&self) -> KeySlice<'_> {
+        debug_assert!(!self.node.is_null());
+        // SAFETY: the node comes from `self._list`, which lives as long as the iterator.
+        unsafe { Node::key(self.node) }
+    }
+
+    fn is_valid(&self) -> bool {
+        !self.node.is_null()
+    }
+
+    fn next(&mut self) -> Result<()> {
+        if !self.node.is_null() {
+            // SAFETY: the node comes from `self._list`.
+            self.node = unsafe { Node::next(self.node, 0) };
+            self.check_upper_bound();
+        }
+        Ok(())
+    }
+}
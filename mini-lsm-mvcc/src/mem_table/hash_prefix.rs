@@ -0,0 +1,148 @@
+//! A mem-table that hashes the prefixes of the keys to a `SkipMap` per prefix.
+//!
+//! The prefix of a key is its first `prefix_len` bytes, or the whole key if it is shorter. All the keys with a prefix
+//! sort between the prefix and the next prefix, so a scan goes through the skiplists one after another in the order
+//! of their prefixes.
+
+use std::collections::HashMap;
+use std::ops::Bound;
+use std::sync::Arc;
+
+use anyhow::Result;
+use bytes::Bytes;
+use crossbeam_skiplist::SkipMap;
+use parking_lot::RwLock;
+
+use crate::iterators::StorageIterator;
+use crate::key::{KeyBytes, KeySlice};
+
+use super::{MemTableIterator, MemTableRep, SkipMapIterator};
+
+type Bucket = Arc<SkipMap<KeyBytes, Bytes>>;
+
+pub struct HashPrefixRep {
+    prefix_len: usize,
+    buckets: RwLock<HashMap<Bytes, Bucket>>,
+}
+
+impl HashPrefixRep {
+    pub fn new(prefix_len: usize) -> Self {
+        Self {
+            prefix_len,
+            buckets: RwLock::new(HashMap::new()),
+        }
+    }
+
+    fn prefix<'a>(&self, key: &'a [u8]) -> &'a [u8] {
+        &key[..key.len().min(self.prefix_len)]
+    }
+
+    fn bucket(&self, key: &[u8]) -> Option<Bucket> {
+        self.buckets.read().get(self.prefix(key)).cloned()
+    }
+
+    /// The buckets that may hold keys in the range, in key order.
+    fn sorted_buckets(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Vec<Bucket> {
+        let lower = match lower {
+            Bound::Included(key) | Bound::Excluded(key) => Some(self.prefix(key)),
+            Bound::Unbounded => None,
+        };
+        let upper = match upper {
+            Bound::Included(key) | Bound::Excluded(key) => Some(key),
+            Bound::Unbounded => None,
+        };
+        let mut buckets = self
+            .buckets
+            .read()
+            .iter()
+            .filter(|(prefix, _)| lower.is_none_or(|lower| prefix.as_ref() >= lower))
+            .filter(|(prefix, _)| upper.is_none_or(|upper| prefix.as_ref() <= upper))
+            .map(|(prefix, bucket)| (prefix.clone(), bucket.clone()))
+            .collect::<Vec<_>>();
+        buckets.sort_by(|a, b| a.0.cmp(&b.0));
+        buckets.into_iter().map(|(_, bucket)| bucket).collect()
+    }
+}
+
+impl MemTableRep for HashPrefixRep {
+    fn insert(&self, key: KeySlice, value: &[u8]) {
+        let bucket = match self.bucket(key.key_ref()) {
+            Some(bucket) => bucket,
+            None => self
+                .buckets
+                .write()
+                .entry(Bytes::copy_from_slice(self.prefix(key.key_ref())))
+                .or_default()
+                .clone(),
+        };
+        MemTableRep::insert(bucket.as_ref(), key, value);
+    }
+
+    fn get(&self, key: KeySlice) -> Option<Bytes> {
+        MemTableRep::get(self.bucket(key.key_ref())?.as_ref(), key)
+    }
+
+    fn scan(self: Arc<Self>, lower: Bound<KeySlice>, upper: Bound<KeySlice>) -> MemTableIterator {
+        let buckets = self.sorted_buckets(
+            lower.map(|key| key.key_ref()),
+            upper.map(|key| key.key_ref()),
+        );
+        let mut iter = HashPrefixIterator {
+            iters: buckets
+                .into_iter()
+                .map(|bucket| SkipMapIterator::scan(bucket, lower, upper))
+                .collect(),
+            idx: 0,
+        };
+        iter.skip_exhausted();
+        MemTableIterator::HashPrefix(iter)
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(KeySlice, &[u8])) {
+        for bucket in self.sorted_buckets(Bound::Unbounded, Bound::Unbounded) {
+            MemTableRep::for_each(bucket.as_ref(), f);
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buckets.read().is_empty()
+    }
+}
+
+/// An iterator over the skiplists of a hash-prefix mem-table, one after another.
+pub struct HashPrefixIterator {
+    iters: Vec<SkipMapIterator>,
+    idx: usize,
+}
+
+impl HashPrefixIterator {
+    fn skip_exhausted(&mut self) {
+        while self.idx < self.iters.len() && !self.iters[self.idx].is_valid() {
+            self.idx += 1;
+        }
+    }
+}
+
+impl StorageIterator for HashPrefixIterator {
+    type KeyType<'a> = KeySlice<'a>;
+
+    fn value(&self) -> &[u8] {
+        self.iters[self.idx].value()
+    }
+
+    fn key(&self) -> KeySlice<'_> {
+        self.iters[self.idx].key()
+    }
+
+    fn is_valid(&self) -> bool {
+        self.idx < self.iters.len()
+    }
+
+    fn next(&mut self) -> Result<()> {
+        if self.idx < self.iters.len() {
+            self.iters[self.idx].next()?;
+            self.skip_exhausted();
+        }
+        Ok(())
+    }
+}
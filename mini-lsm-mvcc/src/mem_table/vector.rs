@@ -0,0 +1,221 @@
+//! A mem-table that appends the writes to a vector and sorts it once, when the mem-table is frozen.
+//!
+//! Until then, a get, and a scan of the versions of a single key, search the vector from the newest write backwards.
+//! Other scans read a sorted copy of the vector, which is kept until the next write.
+
+use std::ops::Bound;
+use std::sync::Arc;
+
+use anyhow::Result;
+use bytes::Bytes;
+use parking_lot::RwLock;
+
+use crate::iterators::StorageIterator;
+use crate::key::{KeyBytes, KeySlice};
+
+use super::{MemTableIterator, MemTableRep};
+
+type Entries = Vec<(KeyBytes, Bytes)>;
+
+struct VectorState {
+    /// The entries in the order of the writes, or in key order once sorted.
+    entries: Arc<Entries>,
+    sorted: bool,
+    /// A sorted copy of the entries, if no write came in since the last scan made it.
+    sorted_copy: Option<Arc<Entries>>,
+}
+
+pub struct VectorRep {
+    state: RwLock<VectorState>,
+}
+
+impl Default for VectorRep {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Sort the entries by key, keeping only the newest write of each key and ts.
+fn sort_entries(entries: &mut Entries) {
+    entries.reverse();
+    // the sort is stable, so the newest write comes first among the writes of the same key
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    entries.dedup_by(|later, earlier| later.0 == earlier.0);
+}
+
+impl VectorRep {
+    pub fn new() -> Self {
+        Self {
+            state: RwLock::new(VectorState {
+                entries: Arc::new(Vec::new()),
+                sorted: true,
+                sorted_copy: None,
+            }),
+        }
+    }
+
+    /// The entries in key order, sorting a copy of them if the mem-table is not frozen yet and was written since the
+    /// last sort.
+    fn sorted_entries(&self) -> Arc<Entries> {
+        let state = self.state.read();
+        if state.sorted {
+            return state.entries.clone();
+        }
+        if let Some(sorted_copy) = &state.sorted_copy {
+            return sorted_copy.clone();
+        }
+        let mut entries = state.entries.as_ref().clone();
+        drop(state);
+        let num_entries = entries.len();
+        sort_entries(&mut entries);
+        let entries = Arc::new(entries);
+        let mut state = self.state.write();
+        // the vector only grows, so the same length means no write came in during the sort
+        if state.entries.len() == num_entries {
+            state.sorted_copy = Some(entries.clone());
+        }
+        entries
+    }
+
+    /// The versions of `key` in key order, without sorting the other entries.
+    fn sorted_versions_of(&self, key: &[u8]) -> Arc<Entries> {
+        let state = self.state.read();
+        if state.sorted {
+            return state.entries.clone();
+        }
+        if let Some(sorted_copy) = &state.sorted_copy {
+            return sorted_copy.clone();
+        }
+        let mut entries = state
+            .entries
+            .iter()
+            .filter(|(entry, _)| entry.key_ref() == key)
+            .cloned()
+            .collect();
+        drop(state);
+        sort_entries(&mut entries);
+        Arc::new(entries)
+    }
+}
+
+/// The key of a scan over the versions of a single key, such as a point lookup.
+fn single_key<'a>(lower: Bound<KeySlice<'a>>, upper: Bound<KeySlice<'a>>) -> Option<&'a [u8]> {
+    match (lower, upper) {
+        (
+            Bound::Included(lower) | Bound::Excluded(lower),
+            Bound::Included(upper) | Bound::Excluded(upper),
+        ) if lower.key_ref() == upper.key_ref() => Some(lower.key_ref()),
+        _ => None,
+    }
+}
+
+impl MemTableRep for VectorRep {
+    fn insert(&self, key: KeySlice, value: &[u8]) {
+        let mut state = self.state.write();
+        Arc::make_mut(&mut state.entries).push((
+            key.to_key_vec().into_key_bytes(),
+            Bytes::copy_from_slice(value),
+        ));
+        state.sorted = false;
+        state.sorted_copy = None;
+    }
+
+    fn get(&self, key: KeySlice) -> Option<Bytes> {
+        let state = self.state.read();
+        if state.sorted {
+            let idx = state
+                .entries
+                .binary_search_by(|(entry, _)| entry.as_key_slice().cmp(&key))
+                .ok()?;
+            Some(state.entries[idx].1.clone())
+        } else {
+            state
+                .entries
+                .iter()
+                .rev()
+                .find(|(entry, _)| entry.as_key_slice() == key)
+                .map(|(_, value)| value.clone())
+        }
+    }
+
+    fn scan(self: Arc<Self>, lower: Bound<KeySlice>, upper: Bound<KeySlice>) -> MemTableIterator {
+        let entries = match single_key(lower, upper) {
+            Some(key) => self.sorted_versions_of(key),
+            None => self.sorted_entries(),
+        };
+        let idx = match lower {
+            Bound::Included(key) => {
+                entries.partition_point(|(entry, _)| entry.as_key_slice() < key)
+            }
+            Bound::Excluded(key) => {
+                entries.partition_point(|(entry, _)| entry.as_key_slice() <= key)
+            }
+            Bound::Unbounded => 0,
+        };
+        let end = match upper {
+            Bound::Included(key) => {
+                entries.partition_point(|(entry, _)| entry.as_key_slice() <= key)
+            }
+            Bound::Excluded(key) => {
+                entries.partition_point(|(entry, _)| entry.as_key_slice() < key)
+            }
+            Bound::Unbounded => entries.len(),
+        };
+        MemTableIterator::Vector(VectorIterator {
+            entries,
+            idx,
+            end: end.max(idx),
+        })
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(KeySlice, &[u8])) {
+        for (key, value) in self.sorted_entries().iter() {
+            f(key.as_key_slice(), value);
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.state.read().entries.is_empty()
+    }
+
+    fn freeze(&self) {
+        let mut state = self.state.write();
+        if !state.sorted {
+            match state.sorted_copy.take() {
+                Some(sorted_copy) => state.entries = sorted_copy,
+                None => sort_entries(Arc::make_mut(&mut state.entries)),
+            }
+            state.sorted = true;
+        }
+    }
+}
+
+/// An iterator over a sorted snapshot of a vector mem-table.
+pub struct VectorIterator {
+    entries: Arc<Entries>,
+    idx: usize,
+    end: usize,
+}
+
+impl StorageIterator for VectorIterator {
+    type KeyType<'a> = KeySlice<'a>;
+
+    fn value(&self) -> &[u8] {
+        &self.entries[self.idx].1
+    }
+
+    fn key(&self) -> KeySlice<'_> {
+        self.entries[self.idx].0.as_key_slice()
+    }
+
+    fn is_valid(&self) -> bool {
+        self.idx < self.end
+    }
+
+    fn next(&mut self) -> Result<()> {
+        if self.idx < self.end {
+            self.idx += 1;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,325 @@
+//! Engine metrics in the Prometheus text exposition format, optionally served over HTTP.
+//!
+//! The endpoint only answers `GET /metrics`, one request per connection. Scrapes are rare, so the connections are
+//! served one at a time by the thread that accepts them.
+
+use std::fmt::{self, Write as _};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+use crate::lsm_storage::LsmStorageInner;
+use crate::row_cache::RowCache;
+use crate::statistics::{HistogramType, Ticker};
+
+/// How long the endpoint waits for a scraper to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+/// The longest request the endpoint reads. Anything after the request line is ignored anyway.
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Writes metric families, each with its help and type line.
+struct MetricsWriter {
+    out: String,
+}
+
+impl MetricsWriter {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        writeln!(self.out, "# HELP mini_lsm_{name} {help}").unwrap();
+        writeln!(self.out, "# TYPE mini_lsm_{name} {kind}").unwrap();
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl fmt::Display) {
+        write!(self.out, "mini_lsm_{name}").unwrap();
+        if !labels.is_empty() {
+            let labels = labels
+                .iter()
+                .map(|(label, value)| format!("{label}=\"{value}\""))
+                .collect::<Vec<_>>();
+            write!(self.out, "{{{}}}", labels.join(",")).unwrap();
+        }
+        writeln!(self.out, " {value}").unwrap();
+    }
+
+    fn gauge(&mut self, name: &str, help: &str, value: impl fmt::Display) {
+        self.family(name, "gauge", help);
+        self.sample(name, &[], value);
+    }
+
+    fn counter(&mut self, name: &str, help: &str, value: u64) {
+        let name = format!("{name}_total");
+        self.family(&name, "counter", help);
+        self.sample(&name, &[], value);
+    }
+}
+
+impl LsmStorageInner {
+    /// Render the metrics of the engine in the Prometheus text format.
+    pub(crate) fn render_metrics(&self) -> String {
+        let mut w = MetricsWriter { out: String::new() };
+        let snapshot = self.state.read().clone();
+
+        let levels = std::iter::once((0, &snapshot.l0_sstables))
+            .chain(snapshot.levels.iter().map(|(level, ids)| (*level, ids)))
+            .map(|(level, ids)| {
+                let size = ids
+                    .iter()
+                    .map(|id| snapshot.sstables[id].table_size())
+                    .sum::<u64>();
+                (level.to_string(), ids.len(), size)
+            })
+            .collect::<Vec<_>>();
+        w.family("level_ssts", "gauge", "The number of SSTs in a level.");
+        for (level, count, _) in &levels {
+            w.sample("level_ssts", &[("level", level)], count);
+        }
+        w.family("level_bytes", "gauge", "The size of the SSTs in a level.");
+        for (level, _, size) in &levels {
+            w.sample("level_bytes", &[("level", level)], size);
+        }
+
+        w.family("memtables", "gauge", "The number of memtables.");
+        w.sample("memtables", &[("state", "mutable")], 1);
+        w.sample(
+            "memtables",
+            &[("state", "immutable")],
+            snapshot.imm_memtables.len(),
+        );
+        w.family(
+            "memtable_bytes",
+            "gauge",
+            "The approximate size of the memtables.",
+        );
+        w.sample(
+            "memtable_bytes",
+            &[("state", "mutable")],
+            snapshot.memtable.approximate_size(),
+        );
+        w.sample(
+            "memtable_bytes",
+            &[("state", "immutable")],
+            snapshot
+                .imm_memtables
+                .iter()
+                .map(|memtable| memtable.approximate_size())
+                .sum::<usize>(),
+        );
+        drop(snapshot);
+
+        let (pending_ssts, pending_bytes) = *self.compaction_backlog.lock();
+        w.gauge(
+            "compaction_pending_ssts",
+            "The number of SSTs the next compaction reads.",
+            pending_ssts,
+        );
+        w.gauge(
+            "compaction_pending_bytes",
+            "The size of the SSTs the next compaction reads.",
+            pending_bytes,
+        );
+
+        if let Some(mvcc) = &self.mvcc {
+            w.gauge(
+                "latest_commit_ts",
+                "The ts of the latest commit.",
+                mvcc.latest_commit_ts(),
+            );
+            w.gauge(
+                "mvcc_watermark",
+                "The ts below which compaction may drop old versions.",
+                mvcc.watermark(),
+            );
+        }
+
+        let block_cache = self.block_cache.stats();
+        w.counter(
+            "block_cache_hits",
+            "The lookups served by the shared block cache.",
+            block_cache.hits,
+        );
+        w.counter(
+            "block_cache_misses",
+            "The lookups that missed the shared block cache.",
+            block_cache.misses,
+        );
+        w.counter(
+            "block_cache_evictions",
+            "The blocks evicted from the shared block cache.",
+            block_cache.evictions,
+        );
+        w.gauge(
+            "block_cache_usage_bytes",
+            "The bytes of the blocks in the shared block cache.",
+            block_cache.usage,
+        );
+        w.gauge(
+            "block_cache_capacity_bytes",
+            "The capacity of the shared block cache.",
+            block_cache.capacity,
+        );
+
+        let table_cache = self.table_cache.stats();
+        w.counter(
+            "table_cache_hits",
+            "The SST lookups served by the table cache.",
+            table_cache.hits,
+        );
+        w.counter(
+            "table_cache_misses",
+            "The SST lookups that opened the SST.",
+            table_cache.misses,
+        );
+        w.gauge(
+            "table_cache_open_files",
+            "The SST files the table cache keeps open.",
+            table_cache.open_files,
+        );
+        w.gauge(
+            "table_cache_usage_bytes",
+            "The bytes of the indexes and bloom filters in the table cache.",
+            table_cache.usage,
+        );
+
+        if let Some(row_cache) = self.row_cache.as_ref().map(RowCache::stats) {
+            w.counter(
+                "row_cache_hits",
+                "The point lookups served by the row cache.",
+                row_cache.hits,
+            );
+            w.counter(
+                "row_cache_misses",
+                "The point lookups that missed the row cache.",
+                row_cache.misses,
+            );
+            w.gauge(
+                "row_cache_usage_bytes",
+                "The bytes of the rows in the row cache.",
+                row_cache.usage,
+            );
+        }
+
+        for ticker in Ticker::ALL {
+            let name = ticker.name().replace('.', "_");
+            w.counter(
+                &name,
+                &format!("The {} ticker of the engine statistics.", ticker.name()),
+                self.statistics.ticker(ticker),
+            );
+        }
+        for histogram in HistogramType::ALL {
+            let name = histogram.name().replace('.', "_");
+            let data = self.statistics.histogram(histogram);
+            w.family(
+                &name,
+                "summary",
+                &format!(
+                    "The {} histogram of the engine statistics.",
+                    histogram.name()
+                ),
+            );
+            for (quantile, value) in [("0.5", data.p50), ("0.95", data.p95), ("0.99", data.p99)] {
+                w.sample(&name, &[("quantile", quantile)], value);
+            }
+            w.sample(&format!("{name}_sum"), &[], data.sum);
+            w.sample(&format!("{name}_count"), &[], data.count);
+        }
+
+        w.out
+    }
+}
+
+/// Serves the metrics of an engine over HTTP. Stops when dropped.
+pub struct MetricsServer {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    handle: Option<std::thread::JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    pub(crate) fn start(inner: Arc<LsmStorageInner>, addr: SocketAddr) -> Result<Self> {
+        let listener = TcpListener::bind(addr).context("failed to bind metrics address")?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                let ticker = crossbeam_channel::tick(Duration::from_millis(50));
+                while !stop.load(Ordering::SeqCst) {
+                    match listener.accept() {
+                        Ok((stream, _)) => {
+                            if serve_scrape(&inner, stream).is_err() {
+                                inner.statistics.record(Ticker::MetricsScrapeErrors, 1);
+                            }
+                        }
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                            ticker.recv().ok();
+                        }
+                        Err(_) => {
+                            inner.statistics.record(Ticker::MetricsScrapeErrors, 1);
+                            ticker.recv().ok();
+                        }
+                    }
+                }
+            })
+        };
+        Ok(Self {
+            addr,
+            stop,
+            handle: Some(handle),
+        })
+    }
+
+    /// The address the endpoint listens on. This resolves a port 0 binding to the actual port.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            handle.join().ok();
+        }
+    }
+}
+
+fn serve_scrape(inner: &LsmStorageInner, mut stream: TcpStream) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST_SIZE {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+    let (status, content_type, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => (
+            "200 OK",
+            "text/plain; version=0.0.4",
+            inner.render_metrics(),
+        ),
+        (Some("GET"), _) => ("404 Not Found", "text/plain", "not found\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "method not allowed\n".to_string(),
+        ),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()?;
+    Ok(())
+}
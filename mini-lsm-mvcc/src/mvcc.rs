@@ -1,74 +1,275 @@
 #![allow(unused_variables)] // TODO(you): remove this lint after implementing this mod
 #![allow(dead_code)] // TODO(you): remove this lint after implementing this mod
 
+pub(crate) mod lock_manager;
+pub mod snapshot;
+pub(crate) mod spill;
 pub mod txn;
 pub mod watermark;
 
 use std::{
-    collections::{BTreeMap, HashSet},
-    sync::{atomic::AtomicBool, Arc},
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
+use anyhow::{bail, Result};
+use bytes::Bytes;
 use crossbeam_skiplist::SkipMap;
-use parking_lot::Mutex;
+use parking_lot::{Mutex, RwLock};
 
-use crate::lsm_storage::LsmStorageInner;
+use crate::lsm_storage::{HistoryRetention, LsmStorageInner};
 
-use self::{txn::Transaction, watermark::Watermark};
+use self::{
+    lock_manager::LockManager,
+    snapshot::{NamedSnapshotInfo, Snapshot},
+    spill::SpilledRuns,
+    txn::{ConflictSets, IsolationLevel, PessimisticState, Transaction},
+    watermark::Watermark,
+};
 
 pub(crate) struct CommittedTxnData {
-    pub(crate) key_hashes: HashSet<u32>,
+    pub(crate) write_set: BTreeSet<Bytes>,
     #[allow(dead_code)]
     pub(crate) read_ts: u64,
     #[allow(dead_code)]
     pub(crate) commit_ts: u64,
 }
 
+/// The live transactions whose commit checks the commits since their read_ts, which are the serializable and the
+/// pessimistic ones.
+#[derive(Default)]
+pub(crate) struct ActiveTxns {
+    /// Their read_ts. Unlike the watermark of the readers, it leaves out the other transactions and the snapshots,
+    /// which need no records in `committed_txns`. The writes outside a transaction are only recorded there while it
+    /// is not empty.
+    read_ts: Watermark,
+}
+
+/// Drop the commit times older than `duration`, except the last of them, which is the state of the storage at the
+/// cutoff.
+fn trim_commit_times(commit_times: &mut VecDeque<(u64, Instant)>, duration: Duration) {
+    let Some(cutoff) = Instant::now().checked_sub(duration) else {
+        return;
+    };
+    while commit_times.len() >= 2 && commit_times[1].1 <= cutoff {
+        commit_times.pop_front();
+    }
+}
+
 pub(crate) struct LsmMvccInner {
     pub(crate) write_lock: Mutex<()>,
     pub(crate) commit_lock: Mutex<()>,
     pub(crate) ts: Arc<Mutex<(u64, Watermark)>>,
     pub(crate) committed_txns: Arc<Mutex<BTreeMap<u64, CommittedTxnData>>>,
+    pub(crate) active_txns: Mutex<ActiveTxns>,
+    pub(crate) history_retention: Option<HistoryRetention>,
+    /// Commit ts and the time it was committed, oldest first. Only tracked with a duration-based retention.
+    commit_times: Mutex<VecDeque<(u64, Instant)>>,
+    /// Named snapshots by name. Each of them holds a reader in the watermark.
+    pub(crate) named_snapshots: Mutex<BTreeMap<String, NamedSnapshotInfo>>,
+    pub(crate) lock_manager: LockManager,
+    next_txn_id: AtomicU64,
 }
 
 impl LsmMvccInner {
-    pub fn new(initial_ts: u64) -> Self {
+    pub fn new(initial_ts: u64, history_retention: Option<HistoryRetention>) -> Self {
+        // We do not know when the recovered data was committed, so it is retained for one window after opening.
+        let commit_times = VecDeque::from([(initial_ts, Instant::now())]);
         Self {
             write_lock: Mutex::new(()),
             commit_lock: Mutex::new(()),
             ts: Arc::new(Mutex::new((initial_ts, Watermark::new()))),
             committed_txns: Arc::new(Mutex::new(BTreeMap::new())),
+            active_txns: Mutex::new(ActiveTxns::default()),
+            history_retention,
+            commit_times: Mutex::new(commit_times),
+            named_snapshots: Mutex::new(BTreeMap::new()),
+            lock_manager: LockManager::default(),
+            next_txn_id: AtomicU64::new(1),
         }
     }
 
+    /// Pin the named snapshots recovered from the manifest.
+    pub(crate) fn restore_named_snapshots(&self, snapshots: BTreeMap<String, NamedSnapshotInfo>) {
+        let mut ts = self.ts.lock();
+        for snapshot in snapshots.values() {
+            ts.1.add_reader(snapshot.ts);
+        }
+        *self.named_snapshots.lock() = snapshots;
+    }
+
     pub fn latest_commit_ts(&self) -> u64 {
         self.ts.lock().0
     }
 
     pub fn update_commit_ts(&self, ts: u64) {
-        self.ts.lock().0 = ts;
+        let mut ts_guard = self.ts.lock();
+        ts_guard.0 = ts;
+        if let Some(HistoryRetention::Duration(duration)) = self.history_retention {
+            let mut commit_times = self.commit_times.lock();
+            commit_times.push_back((ts, Instant::now()));
+            // trimmed here as well, since nothing may read the watermark, e.g. without compaction
+            trim_commit_times(&mut commit_times, duration);
+        }
+    }
+
+    pub(crate) fn num_commit_times(&self) -> usize {
+        self.commit_times.lock().len()
+    }
+
+    /// The oldest ts kept by the history retention policy. The caller should hold the ts lock.
+    fn history_floor(&self, latest_commit_ts: u64) -> u64 {
+        match self.history_retention {
+            None => latest_commit_ts,
+            Some(HistoryRetention::Timestamps(n)) => latest_commit_ts.saturating_sub(n),
+            Some(HistoryRetention::Duration(duration)) => {
+                let mut commit_times = self.commit_times.lock();
+                trim_commit_times(&mut commit_times, duration);
+                commit_times.front().map_or(latest_commit_ts, |(ts, _)| *ts)
+            }
+        }
     }
 
     /// All ts (strictly) below this ts can be garbage collected.
     pub fn watermark(&self) -> u64 {
         let ts = self.ts.lock();
-        ts.1.watermark().unwrap_or(ts.0)
+        let floor = self.history_floor(ts.0);
+        ts.1.watermark()
+            .map_or(floor, |watermark| watermark.min(floor))
+    }
+
+    /// Record the keys written at a commit ts for the conflict checks of the transactions running concurrently, and
+    /// drop the records no live transaction can conflict with.
+    pub(crate) fn record_committed_txn(&self, txn_data: CommittedTxnData) {
+        let mut committed_txns = self.committed_txns.lock();
+        let old_data = committed_txns.insert(txn_data.commit_ts, txn_data);
+        assert!(old_data.is_none());
+
+        // remove unneeded txn data, a txn only conflicts with the commits after its read_ts, and a txn starting later
+        // reads at the latest commit ts or after it
+        let latest_commit_ts = self.latest_commit_ts();
+        let watermark = self
+            .active_txns
+            .lock()
+            .read_ts
+            .watermark()
+            .unwrap_or(latest_commit_ts);
+        while let Some(entry) = committed_txns.first_entry() {
+            if *entry.key() <= watermark {
+                entry.remove();
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Whether a write outside a transaction committed by now should be recorded in `committed_txns`.
+    pub(crate) fn records_plain_writes(&self) -> bool {
+        self.active_txns.lock().read_ts.watermark().is_some()
     }
 
-    pub fn new_txn(&self, inner: Arc<LsmStorageInner>, serializable: bool) -> Arc<Transaction> {
+    /// Forget a transaction that ends.
+    pub(crate) fn remove_txn(&self, txn: &Transaction) {
+        if txn.checks_commits() {
+            self.active_txns.lock().read_ts.remove_reader(txn.read_ts);
+        }
+        if txn.pins_read_ts() {
+            self.ts.lock().1.remove_reader(txn.read_ts);
+        }
+    }
+
+    pub fn new_snapshot(&self, inner: Arc<LsmStorageInner>) -> Arc<Snapshot> {
         let mut ts = self.ts.lock();
         let read_ts = ts.0;
         ts.1.add_reader(read_ts);
-        Arc::new(Transaction {
+        Arc::new(Snapshot { inner, read_ts })
+    }
+
+    /// Take a snapshot at a past ts. Fails if the versions at that ts may have been garbage collected.
+    pub fn new_snapshot_at(
+        &self,
+        inner: Arc<LsmStorageInner>,
+        read_ts: u64,
+    ) -> Result<Arc<Snapshot>> {
+        let mut ts = self.ts.lock();
+        if read_ts > ts.0 {
+            bail!(
+                "read ts {} is newer than the latest commit ts {}",
+                read_ts,
+                ts.0
+            );
+        }
+        let floor = self.history_floor(ts.0);
+        let watermark =
+            ts.1.watermark()
+                .map_or(floor, |watermark| watermark.min(floor));
+        if read_ts < watermark {
+            bail!(
+                "read ts {} is older than the retained history (starts at {})",
+                read_ts,
+                watermark
+            );
+        }
+        ts.1.add_reader(read_ts);
+        Ok(Arc::new(Snapshot { inner, read_ts }))
+    }
+
+    pub fn new_txn(
+        &self,
+        inner: Arc<LsmStorageInner>,
+        isolation: IsolationLevel,
+    ) -> Arc<Transaction> {
+        self.create_txn(inner, isolation, None)
+    }
+
+    /// A pessimistic transaction locks the keys it writes or reads with `get_for_update`, so other pessimistic
+    /// transactions cannot write them until it ends. Its commit only fails if an optimistic transaction or a write
+    /// outside a transaction wrote one of its keys after it was locked. It reads a snapshot like a snapshot isolation
+    /// transaction.
+    pub fn new_pessimistic_txn(&self, inner: Arc<LsmStorageInner>) -> Arc<Transaction> {
+        self.create_txn(
             inner,
+            IsolationLevel::Snapshot,
+            Some(Mutex::new(PessimisticState::default())),
+        )
+    }
+
+    fn create_txn(
+        &self,
+        inner: Arc<LsmStorageInner>,
+        isolation: IsolationLevel,
+        pessimistic: Option<Mutex<PessimisticState>>,
+    ) -> Arc<Transaction> {
+        let txn_id = self.next_txn_id.fetch_add(1, Ordering::SeqCst);
+        let spilled = SpilledRuns::new(inner.path_of_txn_spill_dir(), txn_id);
+        let mut ts = self.ts.lock();
+        let read_ts = ts.0;
+        let txn = Arc::new(Transaction {
+            inner,
+            txn_id,
             read_ts,
-            local_storage: Arc::new(SkipMap::new()),
+            isolation,
+            local_storage: RwLock::new(Arc::new(SkipMap::new())),
+            buffered_size: AtomicUsize::new(0),
+            spilled: Mutex::new(spilled),
             committed: Arc::new(AtomicBool::new(false)),
-            key_hashes: if serializable {
-                Some(Mutex::new((HashSet::new(), HashSet::new())))
-            } else {
-                None
-            },
-        })
+            conflict_sets: Mutex::new(ConflictSets::default()),
+            pessimistic,
+            savepoints: Mutex::new(Vec::new()),
+            rolled_back: AtomicBool::new(false),
+            write_error: Mutex::new(None),
+        });
+        if txn.pins_read_ts() {
+            ts.1.add_reader(read_ts);
+        }
+        // under the ts lock, so that a write committed after read_ts sees the txn
+        if txn.checks_commits() {
+            self.active_txns.lock().read_ts.add_reader(read_ts);
+        }
+        txn
     }
 }
@@ -0,0 +1,92 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use parking_lot::{Condvar, Mutex};
+
+/// Exclusive per-key locks for pessimistic transactions.
+///
+/// A transaction waits for at most one lock at a time, so the waits-for graph has at most one outgoing edge per
+/// transaction and a deadlock is found by following the edges from the lock owner.
+#[derive(Default)]
+pub(crate) struct LockManager {
+    state: Mutex<LockState>,
+    released: Condvar,
+}
+
+#[derive(Default)]
+struct LockState {
+    /// Key -> id of the transaction holding the lock.
+    owners: HashMap<Bytes, u64>,
+    /// Id of a waiting transaction -> id of the transaction it waits for.
+    waits_for: HashMap<u64, u64>,
+}
+
+impl LockState {
+    /// Whether `txn_id` waiting for `owner` closes a cycle in the waits-for graph.
+    fn is_deadlock(&self, txn_id: u64, owner: u64) -> bool {
+        let mut current = owner;
+        // a cycle without `txn_id` cannot exist, but do not loop forever if it does
+        for _ in 0..=self.waits_for.len() {
+            if current == txn_id {
+                return true;
+            }
+            match self.waits_for.get(&current) {
+                Some(next) => current = *next,
+                None => return false,
+            }
+        }
+        false
+    }
+}
+
+impl LockManager {
+    /// Acquire the lock on `key` for `txn_id`, waiting for at most `timeout`. Fails immediately if waiting would
+    /// cause a deadlock.
+    pub fn lock(&self, txn_id: u64, key: &Bytes, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock();
+        loop {
+            let owner = match state.owners.get(key) {
+                None => {
+                    state.owners.insert(key.clone(), txn_id);
+                    state.waits_for.remove(&txn_id);
+                    return Ok(());
+                }
+                Some(owner) if *owner == txn_id => {
+                    state.waits_for.remove(&txn_id);
+                    return Ok(());
+                }
+                Some(owner) => *owner,
+            };
+            if state.is_deadlock(txn_id, owner) {
+                state.waits_for.remove(&txn_id);
+                bail!("deadlock detected when locking key {:?}", key);
+            }
+            state.waits_for.insert(txn_id, owner);
+            if self.released.wait_until(&mut state, deadline).timed_out() {
+                state.waits_for.remove(&txn_id);
+                bail!("lock wait timeout on key {:?}", key);
+            }
+        }
+    }
+
+    /// Release the locks of `txn_id` on `keys`.
+    pub fn unlock(&self, txn_id: u64, keys: impl IntoIterator<Item = Bytes>) {
+        let mut state = self.state.lock();
+        for key in keys {
+            if state.owners.get(&key) == Some(&txn_id) {
+                state.owners.remove(&key);
+            }
+        }
+        drop(state);
+        self.released.notify_all();
+    }
+
+    pub fn num_locked_keys(&self) -> usize {
+        self.state.lock().owners.len()
+    }
+}
@@ -0,0 +1,106 @@
+use std::{
+    ops::Bound,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Result;
+use bytes::Bytes;
+
+use crate::{
+    iterators::StorageIterator,
+    lsm_iterator::{FusedIterator, LsmIterator},
+    lsm_storage::LsmStorageInner,
+    table::TableReadOptions,
+};
+
+/// A read-only view of the storage at a fixed read_ts. Unlike a transaction, a snapshot has no write buffer and does
+/// not track read sets. The read_ts is pinned in the watermark until the snapshot is dropped, so that compaction
+/// keeps the versions it can see.
+pub struct Snapshot {
+    pub(crate) inner: Arc<LsmStorageInner>,
+    pub(crate) read_ts: u64,
+}
+
+impl Snapshot {
+    pub fn read_ts(&self) -> u64 {
+        self.read_ts
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        self.inner.get_with_ts(key, self.read_ts)
+    }
+
+    pub fn scan(
+        self: &Arc<Self>,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Result<SnapshotIterator> {
+        self.scan_with_table_options(lower, upper, &TableReadOptions::default())
+    }
+
+    pub(crate) fn scan_with_table_options(
+        self: &Arc<Self>,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        options: &TableReadOptions,
+    ) -> Result<SnapshotIterator> {
+        Ok(SnapshotIterator {
+            iter: self
+                .inner
+                .scan_with_ts_and_options(lower, upper, self.read_ts, options)?,
+            _snapshot: self.clone(),
+        })
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.inner.mvcc().ts.lock().1.remove_reader(self.read_ts)
+    }
+}
+
+/// A named snapshot recorded in the manifest. It holds back the watermark, also across restarts, until it is
+/// released with `MiniLsm::release_named_snapshot`.
+#[derive(Debug, Clone)]
+pub struct NamedSnapshotInfo {
+    pub name: String,
+    pub ts: u64,
+    pub created_at: SystemTime,
+}
+
+impl NamedSnapshotInfo {
+    pub fn age(&self) -> Duration {
+        self.created_at.elapsed().unwrap_or_default()
+    }
+}
+
+/// An iterator over a snapshot. Keeps the snapshot alive while iterating.
+pub struct SnapshotIterator {
+    _snapshot: Arc<Snapshot>,
+    iter: FusedIterator<LsmIterator>,
+}
+
+impl StorageIterator for SnapshotIterator {
+    type KeyType<'a> = &'a [u8];
+
+    fn value(&self) -> &[u8] {
+        self.iter.value()
+    }
+
+    fn key(&self) -> &[u8] {
+        self.iter.key()
+    }
+
+    fn is_valid(&self) -> bool {
+        self.iter.is_valid()
+    }
+
+    fn next(&mut self) -> Result<()> {
+        self.iter.next()
+    }
+
+    fn num_active_iterators(&self) -> usize {
+        self.iter.num_active_iterators()
+    }
+}
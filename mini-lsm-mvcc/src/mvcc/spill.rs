@@ -0,0 +1,167 @@
+use std::{
+    ops::Bound,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use crossbeam_skiplist::SkipMap;
+
+use crate::{
+    iterators::{merge_iterator::MergeIterator, StorageIterator},
+    key::{KeySlice, TS_RANGE_BEGIN, TS_RANGE_END},
+    table::{SsTable, SsTableBuilder, SsTableIterator},
+};
+
+/// The writes a transaction spilled to disk, as sorted runs in the SST format. All keys of a run have the index of
+/// the run (starting from 1) as their ts, so that for the same key the newest run comes first in a merge. Empty
+/// values are deletes, as in the transaction's local storage. The files are removed when this is dropped.
+pub(crate) struct SpilledRuns {
+    dir: PathBuf,
+    txn_id: u64,
+    runs: Vec<Arc<SsTable>>,
+}
+
+impl SpilledRuns {
+    pub fn new(dir: impl AsRef<Path>, txn_id: u64) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+            txn_id,
+            runs: Vec::new(),
+        }
+    }
+
+    fn path_of_run(&self, idx: usize) -> PathBuf {
+        self.dir.join(format!("{}-{}.sst", self.txn_id, idx))
+    }
+
+    pub fn num_runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    /// Write the entries of a transaction's local storage as a new run.
+    pub fn spill(&mut self, block_size: usize, entries: &SkipMap<Bytes, Bytes>) -> Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let ts = self.runs.len() as u64 + 1;
+        let mut builder = SsTableBuilder::new(block_size);
+        for entry in entries.iter() {
+            builder.add(KeySlice::from_slice(entry.key(), ts), entry.value());
+        }
+        std::fs::create_dir_all(&self.dir).context("failed to create txn spill dir")?;
+        let path = self.path_of_run(self.runs.len());
+        let table = builder.build(0, None, path)?;
+        self.runs.push(Arc::new(table));
+        Ok(())
+    }
+
+    /// Get the newest spilled value of a key, which is empty if the key is deleted.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        for table in self.runs.iter().rev() {
+            if key < table.first_key().key_ref() || key > table.last_key().key_ref() {
+                continue;
+            }
+            if !table.may_contain(key)? {
+                continue;
+            }
+            let iter = SsTableIterator::create_and_seek_to_key(
+                table.clone(),
+                KeySlice::from_slice(key, TS_RANGE_BEGIN),
+            )?;
+            if iter.is_valid() && iter.key().key_ref() == key {
+                return Ok(Some(Bytes::copy_from_slice(iter.value())));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn scan(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<SpilledRunsIterator> {
+        let mut iters = Vec::with_capacity(self.runs.len());
+        for table in self.runs.iter().rev() {
+            let iter = match lower {
+                Bound::Included(key) => SsTableIterator::create_and_seek_to_key(
+                    table.clone(),
+                    KeySlice::from_slice(key, TS_RANGE_BEGIN),
+                )?,
+                Bound::Excluded(key) => SsTableIterator::create_and_seek_to_key(
+                    table.clone(),
+                    KeySlice::from_slice(key, TS_RANGE_END),
+                )?,
+                Bound::Unbounded => SsTableIterator::create_and_seek_to_first(table.clone())?,
+            };
+            iters.push(Box::new(iter));
+        }
+        let mut iter = SpilledRunsIterator {
+            iter: MergeIterator::create(iters),
+            upper: upper.map(Bytes::copy_from_slice),
+            prev_key: Vec::new(),
+        };
+        if let Bound::Excluded(key) = lower {
+            while iter.iter.is_valid() && iter.iter.key().key_ref() == key {
+                iter.iter.next()?;
+            }
+        }
+        Ok(iter)
+    }
+
+    /// Remove the runs spilled after the first `num_runs` runs.
+    pub fn truncate(&mut self, num_runs: usize) {
+        while self.runs.len() > num_runs {
+            self.runs.pop();
+            let _ = std::fs::remove_file(self.path_of_run(self.runs.len()));
+        }
+    }
+}
+
+impl Drop for SpilledRuns {
+    fn drop(&mut self) {
+        self.truncate(0);
+    }
+}
+
+/// Iterates over the newest value of each key in the spilled runs, deletes included.
+pub struct SpilledRunsIterator {
+    iter: MergeIterator<SsTableIterator>,
+    upper: Bound<Bytes>,
+    prev_key: Vec<u8>,
+}
+
+impl StorageIterator for SpilledRunsIterator {
+    type KeyType<'a> = &'a [u8];
+
+    fn value(&self) -> &[u8] {
+        self.iter.value()
+    }
+
+    fn key(&self) -> &[u8] {
+        self.iter.key().key_ref()
+    }
+
+    fn is_valid(&self) -> bool {
+        if !self.iter.is_valid() {
+            return false;
+        }
+        match &self.upper {
+            Bound::Included(key) => self.key() <= key.as_ref(),
+            Bound::Excluded(key) => self.key() < key.as_ref(),
+            Bound::Unbounded => true,
+        }
+    }
+
+    fn next(&mut self) -> Result<()> {
+        self.prev_key.clear();
+        self.prev_key.extend(self.iter.key().key_ref());
+        self.iter.next()?;
+        // skip the older versions of the same key
+        while self.iter.is_valid() && self.iter.key().key_ref() == self.prev_key {
+            self.iter.next()?;
+        }
+        Ok(())
+    }
+
+    fn num_active_iterators(&self) -> usize {
+        self.iter.num_active_iterators()
+    }
+}
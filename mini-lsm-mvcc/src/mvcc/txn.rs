@@ -1,174 +1,678 @@
 use std::{
-    collections::HashSet,
+    collections::{BTreeMap, BTreeSet},
     ops::Bound,
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
         Arc,
     },
+    time::Instant,
 };
 
 use anyhow::{bail, Result};
 use bytes::Bytes;
 use crossbeam_skiplist::{map::Entry, SkipMap};
 use ouroboros::self_referencing;
-use parking_lot::Mutex;
+use parking_lot::{Mutex, RwLock};
 
 use crate::{
     iterators::{two_merge_iterator::TwoMergeIterator, StorageIterator},
     lsm_iterator::{FusedIterator, LsmIterator},
     lsm_storage::{LsmStorageInner, WriteBatchRecord},
     mem_table::map_bound,
-    mvcc::CommittedTxnData,
+    mvcc::{
+        spill::{SpilledRuns, SpilledRunsIterator},
+        CommittedTxnData,
+    },
+    statistics::{HistogramType, Ticker},
+    wal::{PreparedRecord, Wal},
 };
 
+/// Commit writes the buffered data in chunks of about this many bytes.
+const COMMIT_CHUNK_SIZE: usize = 1 << 20;
+
+/// How much a transaction is isolated from the transactions committing while it runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IsolationLevel {
+    /// Every `get` and `scan` reads the latest committed data. No conflict check at commit.
+    ReadCommitted,
+    /// Reads see the data committed before the transaction started. Commit does not check the transactions that
+    /// committed in the meantime, the last writer wins.
+    #[default]
+    Snapshot,
+    /// Snapshot reads, and commit fails if a transaction or a write outside one that committed in the meantime wrote
+    /// a key this transaction has read, or a key in a range it has scanned.
+    Serializable,
+}
+
+/// The keys a transaction has written, and for a serializable transaction the keys it has read and the key ranges
+/// it has scanned.
+#[derive(Default, Clone)]
+pub(crate) struct ConflictSets {
+    pub(crate) write_set: BTreeSet<Bytes>,
+    pub(crate) read_set: BTreeSet<Bytes>,
+    pub(crate) read_ranges: Vec<(Bound<Bytes>, Bound<Bytes>)>,
+}
+
+impl ConflictSets {
+    /// Whether a transaction that committed `write_set` wrote anything this transaction has written.
+    pub(crate) fn write_conflicts_with(&self, write_set: &BTreeSet<Bytes>) -> bool {
+        if self.write_set.len() <= write_set.len() {
+            self.write_set.iter().any(|key| write_set.contains(key))
+        } else {
+            write_set.iter().any(|key| self.write_set.contains(key))
+        }
+    }
+
+    /// Whether a transaction that committed `write_set` wrote anything this transaction has read.
+    pub(crate) fn conflicts_with(&self, write_set: &BTreeSet<Bytes>) -> bool {
+        let point_conflict = if self.read_set.len() <= write_set.len() {
+            self.read_set.iter().any(|key| write_set.contains(key))
+        } else {
+            write_set.iter().any(|key| self.read_set.contains(key))
+        };
+        point_conflict
+            || self
+                .read_ranges
+                .iter()
+                .any(|(lower, upper)| range_overlaps(write_set, lower, upper))
+    }
+}
+
+fn range_overlaps(keys: &BTreeSet<Bytes>, lower: &Bound<Bytes>, upper: &Bound<Bytes>) -> bool {
+    let lower = map_bytes_bound(lower);
+    let upper = map_bytes_bound(upper);
+    // `BTreeSet::range` panics on an inverted range, which matches nothing anyway
+    let is_empty = match (lower, upper) {
+        (Bound::Included(l), Bound::Included(u)) => l > u,
+        (Bound::Included(l) | Bound::Excluded(l), Bound::Included(u) | Bound::Excluded(u)) => {
+            l >= u
+        }
+        _ => false,
+    };
+    if is_empty {
+        return false;
+    }
+    keys.range::<[u8], _>((lower, upper)).next().is_some()
+}
+
+fn map_bytes_bound(bound: &Bound<Bytes>) -> Bound<&[u8]> {
+    match bound {
+        Bound::Included(x) => Bound::Included(x),
+        Bound::Excluded(x) => Bound::Excluded(x),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+fn to_bytes_bound(bound: Bound<&[u8]>) -> Bound<Bytes> {
+    match bound {
+        Bound::Included(x) => Bound::Included(Bytes::copy_from_slice(x)),
+        Bound::Excluded(x) => Bound::Excluded(Bytes::copy_from_slice(x)),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+/// The keys locked by a pessimistic transaction.
+#[derive(Default)]
+pub(crate) struct PessimisticState {
+    pub(crate) locked_keys: BTreeSet<Bytes>,
+    /// The latest commit ts when each key was locked. The locks keep other pessimistic transactions from writing the
+    /// keys, but not the optimistic transactions and the writes outside a transaction, so their writes after this ts
+    /// are conflicts.
+    pub(crate) lock_ts: BTreeMap<Bytes, u64>,
+}
+
+/// The state to restore when rolling back to a savepoint.
+///
+/// If the local storage was empty when the savepoint was set, the savepoint records the number of spilled runs and
+/// rolling back drops the later runs and the local storage. Otherwise, it keeps an undo log of the local storage, and
+/// the txn does not spill until the savepoint is gone.
+pub(crate) struct Savepoint {
+    num_runs: Option<usize>,
+    /// The previous values of the keys written after the savepoint, in write order. `None` if the key was not
+    /// written by the txn before.
+    undo_log: Vec<(Bytes, Option<Bytes>)>,
+    conflict_sets: ConflictSets,
+}
+
+/// A transaction prepared for a two-phase commit. Recovered transactions have no locks and a read_ts of 0.
+pub(crate) struct PreparedTxn {
+    pub(crate) txn_id: u64,
+    pub(crate) read_ts: u64,
+    /// An empty value is a delete.
+    pub(crate) batch: Vec<(Bytes, Bytes)>,
+    /// The keys of the batch, and the keys read and the ranges scanned by a serializable txn, which no other write
+    /// may touch until the txn is decided.
+    pub(crate) conflict_sets: ConflictSets,
+    pub(crate) locked_keys: BTreeSet<Bytes>,
+}
+
 pub struct Transaction {
+    pub(crate) txn_id: u64,
     pub(crate) read_ts: u64,
+    pub(crate) isolation: IsolationLevel,
     pub(crate) inner: Arc<LsmStorageInner>,
-    pub(crate) local_storage: Arc<SkipMap<Bytes, Bytes>>,
+    /// The buffered writes that are not spilled yet. Replaced by an empty map after a spill.
+    pub(crate) local_storage: RwLock<Arc<SkipMap<Bytes, Bytes>>>,
+    /// Approximate size of the local storage.
+    pub(crate) buffered_size: AtomicUsize,
+    pub(crate) spilled: Mutex<SpilledRuns>,
     pub(crate) committed: Arc<AtomicBool>,
-    /// Write set and read set
-    pub(crate) key_hashes: Option<Mutex<(HashSet<u32>, HashSet<u32>)>>,
+    pub(crate) conflict_sets: Mutex<ConflictSets>,
+    /// Only tracked for pessimistic transactions.
+    pub(crate) pessimistic: Option<Mutex<PessimisticState>>,
+    pub(crate) savepoints: Mutex<Vec<Savepoint>>,
+    pub(crate) rolled_back: AtomicBool,
+    /// The first error of `put` or `delete`, which cannot fail, reported at commit.
+    pub(crate) write_error: Mutex<Option<anyhow::Error>>,
 }
 
 impl Transaction {
-    pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
-        if self.committed.load(Ordering::SeqCst) {
-            panic!("cannot operate on committed txn!");
-        }
-        if let Some(guard) = &self.key_hashes {
-            let mut guard = guard.lock();
-            let (_, read_set) = &mut *guard;
-            read_set.insert(farmhash::hash32(key));
+    pub fn txn_id(&self) -> u64 {
+        self.txn_id
+    }
+
+    /// Lock the key if this is a pessimistic transaction.
+    fn lock_key(&self, key: &[u8]) -> Result<()> {
+        let Some(pessimistic) = &self.pessimistic else {
+            return Ok(());
+        };
+        let mut pessimistic = pessimistic.lock();
+        if pessimistic.locked_keys.contains(key) {
+            return Ok(());
         }
-        if let Some(entry) = self.local_storage.get(key) {
-            if entry.value().is_empty() {
-                return Ok(None);
-            } else {
-                return Ok(Some(entry.value().clone()));
-            }
+        let key = Bytes::copy_from_slice(key);
+        self.inner
+            .mvcc()
+            .lock_manager
+            .lock(self.txn_id, &key, self.inner.options.lock_timeout)?;
+        pessimistic
+            .lock_ts
+            .insert(key.clone(), self.inner.mvcc().latest_commit_ts());
+        pessimistic.locked_keys.insert(key);
+        Ok(())
+    }
+
+    pub fn isolation(&self) -> IsolationLevel {
+        self.isolation
+    }
+
+    /// The ts to read at, which moves forward with each read in a read committed transaction.
+    fn visible_ts(&self) -> u64 {
+        match self.isolation {
+            IsolationLevel::ReadCommitted => self.inner.mvcc().latest_commit_ts(),
+            IsolationLevel::Snapshot | IsolationLevel::Serializable => self.read_ts,
         }
-        self.inner.get_with_ts(key, self.read_ts)
     }
 
-    pub fn scan(self: &Arc<Self>, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<TxnIterator> {
-        if self.committed.load(Ordering::SeqCst) {
-            panic!("cannot operate on committed txn!");
+    /// Whether the commit checks the commits since read_ts, the writes outside a transaction included.
+    pub(crate) fn checks_commits(&self) -> bool {
+        self.isolation == IsolationLevel::Serializable || self.pessimistic.is_some()
+    }
+
+    /// Whether the versions at read_ts must be kept until the txn ends. A read committed txn always reads at the
+    /// latest commit ts, which garbage collection never removes.
+    pub(crate) fn pins_read_ts(&self) -> bool {
+        self.isolation != IsolationLevel::ReadCommitted
+    }
+
+    /// The read set and the scanned ranges are only needed for the serializable check.
+    fn tracks_reads(&self) -> bool {
+        self.isolation == IsolationLevel::Serializable
+    }
+
+    fn local(&self) -> Arc<SkipMap<Bytes, Bytes>> {
+        self.local_storage.read().clone()
+    }
+
+    /// Get the value written by the txn, which is empty if the key is deleted.
+    fn get_buffered(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        if let Some(entry) = self.local().get(key) {
+            return Ok(Some(entry.value().clone()));
         }
+        self.spilled.lock().get(key)
+    }
+
+    /// Iterate over the writes of the txn, deletes included.
+    fn buffer_iter(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<TxnBufferIterator> {
         let mut local_iter = TxnLocalIteratorBuilder {
-            map: self.local_storage.clone(),
+            map: self.local(),
             iter_builder: |map| map.range((map_bound(lower), map_bound(upper))),
             item: (Bytes::new(), Bytes::new()),
         }
         .build();
         let entry = local_iter.with_iter_mut(|iter| TxnLocalIterator::entry_to_item(iter.next()));
         local_iter.with_mut(|x| *x.item = entry);
+        let spilled_iter = self.spilled.lock().scan(lower, upper)?;
+        TwoMergeIterator::create(local_iter, spilled_iter)
+    }
+
+    /// Fail if the txn is committed, prepared or rolled back.
+    fn check_active(&self) -> Result<()> {
+        if self.rolled_back.load(Ordering::SeqCst) {
+            bail!("txn is rolled back");
+        }
+        if self.committed.load(Ordering::SeqCst) {
+            bail!("txn is committed");
+        }
+        Ok(())
+    }
+
+    /// Mark the txn as committed before it commits or prepares, so that it cannot be used afterwards.
+    fn finish(&self) -> Result<()> {
+        if self
+            .committed
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            self.check_active()?;
+        }
+        Ok(())
+    }
+
+    /// `put` and `delete` cannot fail, so an error is reported at commit.
+    fn set_write_error(&self, e: anyhow::Error) {
+        self.write_error.lock().get_or_insert(e);
+    }
+
+    fn unlock_all(&self) {
+        if let Some(pessimistic) = &self.pessimistic {
+            let mut pessimistic = pessimistic.lock();
+            pessimistic.lock_ts.clear();
+            let locked_keys = std::mem::take(&mut pessimistic.locked_keys);
+            self.inner
+                .mvcc()
+                .lock_manager
+                .unlock(self.txn_id, locked_keys);
+        }
+    }
+
+    /// Read a key for a later write. In a pessimistic transaction, this locks the key and returns the latest
+    /// committed value instead of the one at read_ts, so the key cannot change until the transaction ends. In an
+    /// optimistic transaction, this is the same as `get`.
+    pub fn get_for_update(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        if self.pessimistic.is_none() {
+            return self.get(key);
+        }
+        self.check_active()?;
+        self.lock_key(key)?;
+        if let Some(value) = self.get_buffered(key)? {
+            if value.is_empty() {
+                return Ok(None);
+            } else {
+                return Ok(Some(value));
+            }
+        }
+        self.inner
+            .get_with_ts(key, self.inner.mvcc().latest_commit_ts())
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        self.check_active()?;
+        if self.tracks_reads() {
+            self.conflict_sets
+                .lock()
+                .read_set
+                .insert(Bytes::copy_from_slice(key));
+        }
+        if let Some(value) = self.get_buffered(key)? {
+            if value.is_empty() {
+                return Ok(None);
+            } else {
+                return Ok(Some(value));
+            }
+        }
+        self.inner.get_with_ts(key, self.visible_ts())
+    }
+
+    pub fn scan(self: &Arc<Self>, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<TxnIterator> {
+        self.check_active()?;
+        // Track the whole range instead of the returned keys, so that a concurrent insert into the range (a phantom)
+        // is a conflict as well.
+        if self.tracks_reads() {
+            self.conflict_sets
+                .lock()
+                .read_ranges
+                .push((to_bytes_bound(lower), to_bytes_bound(upper)));
+        }
 
         TxnIterator::create(
             self.clone(),
             TwoMergeIterator::create(
-                local_iter,
-                self.inner.scan_with_ts(lower, upper, self.read_ts)?,
+                self.buffer_iter(lower, upper)?,
+                self.inner.scan_with_ts(lower, upper, self.visible_ts())?,
             )?,
         )
     }
 
     pub fn put(&self, key: &[u8], value: &[u8]) {
-        if self.committed.load(Ordering::SeqCst) {
-            panic!("cannot operate on committed txn!");
+        if let Err(e) = self.check_active() {
+            self.set_write_error(e);
+            return;
         }
-        self.local_storage
-            .insert(Bytes::copy_from_slice(key), Bytes::copy_from_slice(value));
-        if let Some(key_hashes) = &self.key_hashes {
-            let mut key_hashes = key_hashes.lock();
-            let (write_hashes, _) = &mut *key_hashes;
-            write_hashes.insert(farmhash::hash32(key));
+        if let Err(e) = self.lock_key(key) {
+            self.set_write_error(e);
+            return;
         }
+        if let Err(e) = self.write_local(key, Bytes::copy_from_slice(value)) {
+            self.set_write_error(e);
+        }
+        self.conflict_sets
+            .lock()
+            .write_set
+            .insert(Bytes::copy_from_slice(key));
     }
 
     pub fn delete(&self, key: &[u8]) {
-        if self.committed.load(Ordering::SeqCst) {
-            panic!("cannot operate on committed txn!");
+        if let Err(e) = self.check_active() {
+            self.set_write_error(e);
+            return;
         }
-        self.local_storage
-            .insert(Bytes::copy_from_slice(key), Bytes::new());
-        if let Some(key_hashes) = &self.key_hashes {
-            let mut key_hashes = key_hashes.lock();
-            let (write_hashes, _) = &mut *key_hashes;
-            write_hashes.insert(farmhash::hash32(key));
+        if let Err(e) = self.lock_key(key) {
+            self.set_write_error(e);
+            return;
         }
+        if let Err(e) = self.write_local(key, Bytes::new()) {
+            self.set_write_error(e);
+        }
+        self.conflict_sets
+            .lock()
+            .write_set
+            .insert(Bytes::copy_from_slice(key));
     }
 
-    pub fn commit(&self) -> Result<()> {
-        self.committed
-            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
-            .expect("cannot operate on committed txn!");
-        let _commit_lock = self.inner.mvcc().commit_lock.lock();
-        let serializability_check;
-        if let Some(guard) = &self.key_hashes {
-            let guard = guard.lock();
-            let (write_set, read_set) = &*guard;
-            println!(
-                "commit txn: write_set: {:?}, read_set: {:?}",
-                write_set, read_set
-            );
-            if !write_set.is_empty() {
-                let committed_txns = self.inner.mvcc().committed_txns.lock();
-                for (_, txn_data) in committed_txns.range((self.read_ts + 1)..) {
-                    for key_hash in read_set {
-                        if txn_data.key_hashes.contains(key_hash) {
-                            bail!("serializable check failed");
-                        }
-                    }
-                }
+    fn write_local(&self, key: &[u8], value: Bytes) -> Result<()> {
+        let key = Bytes::copy_from_slice(key);
+        let mut savepoints = self.savepoints.lock();
+        let local = self.local();
+        if let Some(savepoint) = savepoints.last_mut() {
+            if savepoint.num_runs.is_none() {
+                let previous = local.get(&key).map(|entry| entry.value().clone());
+                savepoint.undo_log.push((key.clone(), previous));
             }
-            serializability_check = true;
-        } else {
-            serializability_check = false;
         }
-        let batch = self
-            .local_storage
+        let size = key.len() + value.len();
+        let buffered_size = self.buffered_size.fetch_add(size, Ordering::SeqCst) + size;
+        local.insert(key, value);
+        if buffered_size > self.inner.options.txn_spill_threshold
+            && savepoints
+                .iter()
+                .all(|savepoint| savepoint.num_runs.is_some())
+        {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// Move the local storage to a new spilled run. The caller should hold the savepoints lock, so that no write
+    /// comes in between.
+    fn spill(&self) -> Result<()> {
+        let local = self.local();
+        self.spilled
+            .lock()
+            .spill(self.inner.options.block_size, &local)?;
+        *self.local_storage.write() = Arc::new(SkipMap::new());
+        self.buffered_size.store(0, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Mark the current state of the txn, so that later writes can be undone with `rollback_to_savepoint`.
+    /// Savepoints can be nested.
+    pub fn set_savepoint(&self) -> Result<()> {
+        self.check_active()?;
+        let mut savepoints = self.savepoints.lock();
+        let can_spill = savepoints
             .iter()
-            .map(|entry| {
-                if entry.value().is_empty() {
-                    WriteBatchRecord::Del(entry.key().clone())
-                } else {
-                    WriteBatchRecord::Put(entry.key().clone(), entry.value().clone())
-                }
-            })
-            .collect::<Vec<_>>();
-        let ts = self.inner.write_batch_inner(&batch)?;
-        if serializability_check {
-            let mut committed_txns = self.inner.mvcc().committed_txns.lock();
-            let mut key_hashes = self.key_hashes.as_ref().unwrap().lock();
-            let (write_set, _) = &mut *key_hashes;
-
-            let old_data = committed_txns.insert(
-                ts,
-                CommittedTxnData {
-                    key_hashes: std::mem::take(write_set),
-                    read_ts: self.read_ts,
-                    commit_ts: ts,
-                },
-            );
-            assert!(old_data.is_none());
-
-            // remove unneeded txn data
-            let watermark = self.inner.mvcc().watermark();
-            while let Some(entry) = committed_txns.first_entry() {
-                if *entry.key() < watermark {
-                    entry.remove();
-                } else {
-                    break;
+            .all(|savepoint| savepoint.num_runs.is_some());
+        // Once a txn has spilled, spill at the savepoint so that it can keep spilling afterwards.
+        let num_runs =
+            if self.local().is_empty() || (can_spill && self.spilled.lock().num_runs() > 0) {
+                self.spill()?;
+                Some(self.spilled.lock().num_runs())
+            } else {
+                None
+            };
+        let conflict_sets = self.conflict_sets.lock().clone();
+        savepoints.push(Savepoint {
+            num_runs,
+            undo_log: Vec::new(),
+            conflict_sets,
+        });
+        Ok(())
+    }
+
+    /// Undo the writes since the latest savepoint and remove the savepoint. The read and write sets are
+    /// restored as well. Keys locked by a pessimistic txn stay locked until the txn ends.
+    pub fn rollback_to_savepoint(&self) -> Result<()> {
+        self.check_active()?;
+        let mut savepoints = self.savepoints.lock();
+        let Some(savepoint) = savepoints.pop() else {
+            bail!("no savepoint to roll back to");
+        };
+        if let Some(num_runs) = savepoint.num_runs {
+            self.spilled.lock().truncate(num_runs);
+            *self.local_storage.write() = Arc::new(SkipMap::new());
+            self.buffered_size.store(0, Ordering::SeqCst);
+        } else {
+            let local = self.local();
+            for (key, previous) in savepoint.undo_log.into_iter().rev() {
+                // in reverse order, the local value is the one this entry of the undo log wrote
+                let written = local.get(&key).map_or(0, |entry| entry.value().len());
+                self.buffered_size
+                    .fetch_sub(key.len() + written, Ordering::SeqCst);
+                match previous {
+                    Some(value) => {
+                        local.insert(key, value);
+                    }
+                    None => {
+                        local.remove(&key);
+                    }
                 }
             }
         }
+        *self.conflict_sets.lock() = savepoint.conflict_sets;
+        Ok(())
+    }
+
+    /// Abandon the txn, discarding its writes and releasing its locks. Rolling back twice is a no-op, and rolling
+    /// back a committed txn fails.
+    pub fn rollback(&self) -> Result<()> {
+        if self.rolled_back.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        if self
+            .committed
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            bail!("cannot roll back a committed txn");
+        }
+        self.rolled_back.store(true, Ordering::SeqCst);
+        self.savepoints.lock().clear();
+        *self.local_storage.write() = Arc::new(SkipMap::new());
+        self.spilled.lock().truncate(0);
+        self.unlock_all();
+        Ok(())
+    }
+
+    /// Check that the txn can commit. The caller should hold the commit lock and the write lock, so that no write
+    /// commits between the check and the commit of this txn.
+    fn check_before_commit(&self) -> Result<()> {
+        // not taken, since a txn that fails to prepare stays usable
+        if let Some(e) = &*self.write_error.lock() {
+            bail!("failed to write to the txn: {:#}", e);
+        }
+        self.check_prepared_conflicts()?;
+        if let Some(pessimistic) = &self.pessimistic {
+            return self.check_lock_conflicts(&pessimistic.lock());
+        }
+        if self.isolation != IsolationLevel::Serializable {
+            return Ok(());
+        }
+        let conflict_sets = self.conflict_sets.lock();
+        // a read-only txn never conflicts
+        if conflict_sets.write_set.is_empty() {
+            return Ok(());
+        }
+        let committed_txns = self.inner.mvcc().committed_txns.lock();
+        for (_, txn_data) in committed_txns.range((self.read_ts + 1)..) {
+            if conflict_sets.conflicts_with(&txn_data.write_set) {
+                self.inner.statistics.record(Ticker::TxnConflicts, 1);
+                bail!("serializable check failed");
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that no prepared txn has read a key this txn writes, and, unless this txn is read committed, that no
+    /// prepared txn writes one. The prepared txn commits later, and would overwrite the key without having seen this
+    /// write.
+    fn check_prepared_conflicts(&self) -> Result<()> {
+        let conflict_sets = self.conflict_sets.lock();
+        if conflict_sets.write_set.is_empty() {
+            return Ok(());
+        }
+        self.inner.check_prepared_reads(&conflict_sets.write_set)?;
+        if self.isolation == IsolationLevel::ReadCommitted {
+            return Ok(());
+        }
+        for (name, txn) in self.inner.prepared_txns.lock().iter() {
+            if txn.conflict_sets.write_conflicts_with(&conflict_sets.write_set) {
+                self.inner.statistics.record(Ticker::TxnConflicts, 1);
+                bail!("write-write conflict with prepared txn {}", name);
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that no write that does not take the key locks overwrote a key of a pessimistic txn after the txn
+    /// locked it, so that the commit does not lose that update.
+    fn check_lock_conflicts(&self, pessimistic: &PessimisticState) -> Result<()> {
+        let conflict_sets = self.conflict_sets.lock();
+        let committed_txns = self.inner.mvcc().committed_txns.lock();
+        for key in &conflict_sets.write_set {
+            let Some(lock_ts) = pessimistic.lock_ts.get(key) else {
+                continue;
+            };
+            if committed_txns
+                .range((lock_ts + 1)..)
+                .any(|(_, txn_data)| txn_data.write_set.contains(key))
+            {
+                self.inner.statistics.record(Ticker::TxnConflicts, 1);
+                bail!("write-write conflict");
+            }
+        }
+        Ok(())
+    }
+
+    /// Persist the write set to the WAL as a prepared transaction named `name`, the first phase of a two-phase
+    /// commit. The prepared transaction survives restarts until it is decided with `MiniLsm::commit_prepared` or
+    /// `MiniLsm::rollback_prepared`. Keys locked by a pessimistic txn stay locked until then, and the commit of any
+    /// other txn writing one of the keys fails. The serializable check runs here, not at `commit_prepared`, so any
+    /// write to a key a serializable txn has read, or into a range it has scanned, fails until then as well.
+    ///
+    /// If the txn cannot be prepared, it stays usable, so that it can be rolled back or prepared again.
+    pub fn prepare(&self, name: &str) -> Result<()> {
+        self.check_active()?;
+        let Some(wal) = &self.inner.wal else {
+            bail!("two-phase commit requires the WAL");
+        };
+        self.inner.check_writable()?;
+        let _commit_lock = self.inner.mvcc().commit_lock.lock();
+        let _lck = self.inner.mvcc().write_lock.lock();
+        // no write to the txn can come in while it is being prepared
+        self.finish()?;
+        let result = self.prepare_locked(wal, name);
+        if result.is_err() {
+            self.committed.store(false, Ordering::SeqCst);
+        }
+        result
+    }
+
+    /// Prepare the txn. The caller should hold the commit lock and the write lock.
+    fn prepare_locked(&self, wal: &Wal, name: &str) -> Result<()> {
+        if self.inner.prepared_txns.lock().contains_key(name) {
+            bail!("prepared txn {} already exists", name);
+        }
+        self.check_before_commit()?;
+        let mut iter = self.buffer_iter(Bound::Unbounded, Bound::Unbounded)?;
+        let mut record = PreparedRecord::default();
+        while iter.is_valid() {
+            record.kv_pairs.push((
+                Bytes::copy_from_slice(iter.key()),
+                Bytes::copy_from_slice(iter.value()),
+            ));
+            iter.next()?;
+        }
+        let conflict_sets = self.conflict_sets.lock().clone();
+        record.read_set = conflict_sets.read_set.clone();
+        record.read_ranges = conflict_sets.read_ranges.clone();
+        wal.prepare(name, &record)?;
+        let locked_keys = match &self.pessimistic {
+            Some(pessimistic) => {
+                let mut pessimistic = pessimistic.lock();
+                pessimistic.lock_ts.clear();
+                std::mem::take(&mut pessimistic.locked_keys)
+            }
+            None => BTreeSet::new(),
+        };
+        self.inner.prepared_txns.lock().insert(
+            name.to_string(),
+            PreparedTxn {
+                txn_id: self.txn_id,
+                read_ts: self.read_ts,
+                batch: record.kv_pairs,
+                conflict_sets,
+                locked_keys,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn commit(&self) -> Result<()> {
+        self.finish()?;
+        let start = Instant::now();
+        let _commit_lock = self.inner.mvcc().commit_lock.lock();
+        // stream the writes, which may be spilled to disk, instead of collecting them into a single batch
+        let mut iter = self.buffer_iter(Bound::Unbounded, Bound::Unbounded)?;
+        let result = self.inner.write_batch_chunks(
+            || self.check_before_commit(),
+            || {
+                let mut chunk = Vec::new();
+                let mut chunk_size = 0;
+                while iter.is_valid() && chunk_size < COMMIT_CHUNK_SIZE {
+                    let key = Bytes::copy_from_slice(iter.key());
+                    let value = Bytes::copy_from_slice(iter.value());
+                    chunk_size += key.len() + value.len();
+                    if value.is_empty() {
+                        chunk.push(WriteBatchRecord::Del(key));
+                    } else {
+                        chunk.push(WriteBatchRecord::Put(key, value));
+                    }
+                    iter.next()?;
+                }
+                Ok((!chunk.is_empty()).then_some(chunk))
+            },
+        );
+        // the txn cannot be used after a failed commit, so it does not need its locks either
+        self.unlock_all();
+        let ts = result?;
+        let write_set = std::mem::take(&mut self.conflict_sets.lock().write_set);
+        self.inner.mvcc().record_committed_txn(CommittedTxnData {
+            write_set,
+            read_ts: self.read_ts,
+            commit_ts: ts,
+        });
+        self.inner
+            .statistics
+            .record_elapsed(HistogramType::CommitMicros, start);
         Ok(())
     }
 }
 
 impl Drop for Transaction {
     fn drop(&mut self) {
-        self.inner.mvcc().ts.lock().1.remove_reader(self.read_ts)
+        self.unlock_all();
+        self.inner.mvcc().remove_txn(self);
     }
 }
 
@@ -217,21 +721,21 @@ impl StorageIterator for TxnLocalIterator {
     }
 }
 
+/// The writes of a transaction, in its local storage and spilled to disk.
+pub type TxnBufferIterator = TwoMergeIterator<TxnLocalIterator, SpilledRunsIterator>;
+
 pub struct TxnIterator {
     txn: Arc<Transaction>,
-    iter: TwoMergeIterator<TxnLocalIterator, FusedIterator<LsmIterator>>,
+    iter: TwoMergeIterator<TxnBufferIterator, FusedIterator<LsmIterator>>,
 }
 
 impl TxnIterator {
     pub fn create(
         txn: Arc<Transaction>,
-        iter: TwoMergeIterator<TxnLocalIterator, FusedIterator<LsmIterator>>,
+        iter: TwoMergeIterator<TxnBufferIterator, FusedIterator<LsmIterator>>,
     ) -> Result<Self> {
         let mut iter = Self { txn, iter };
         iter.skip_deletes()?;
-        if iter.is_valid() {
-            iter.add_to_read_set(iter.key());
-        }
         Ok(iter)
     }
 
@@ -241,18 +745,13 @@ impl TxnIterator {
         }
         Ok(())
     }
-
-    fn add_to_read_set(&self, key: &[u8]) {
-        if let Some(guard) = &self.txn.key_hashes {
-            let mut guard = guard.lock();
-            let (_, read_set) = &mut *guard;
-            read_set.insert(farmhash::hash32(key));
-        }
-    }
 }
 
 impl StorageIterator for TxnIterator {
-    type KeyType<'a> = &'a [u8] where Self: 'a;
+    type KeyType<'a>
+        = &'a [u8]
+    where
+        Self: 'a;
 
     fn value(&self) -> &[u8] {
         self.iter.value()
@@ -269,9 +768,6 @@ impl StorageIterator for TxnIterator {
     fn next(&mut self) -> Result<()> {
         self.iter.next()?;
         self.skip_deletes()?;
-        if self.is_valid() {
-            self.add_to_read_set(self.key());
-        }
         Ok(())
     }
 
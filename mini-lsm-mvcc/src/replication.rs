@@ -0,0 +1,626 @@
+//! Leader/follower replication over a local socket.
+//!
+//! A follower bootstraps from a checkpoint of the leader (the manifest, every live SST and the content of the
+//! memtables), and then applies the write batches committed on the leader in commit_ts order. The follower keeps its
+//! own copy of the SSTs and runs its own flush and compaction, but it never accepts writes from users.
+//!
+//! Every message on the wire is framed as `| tag (u8) | len (u64) | payload | checksum (u32) |`.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::ops::Bound;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use bytes::{Buf, BufMut, Bytes};
+use parking_lot::Mutex;
+
+use crate::key::KeyBytes;
+use crate::lsm_storage::{LsmStorageInner, LsmStorageOptions, MiniLsm, WriteBatchRecord};
+use crate::mvcc::txn::TxnIterator;
+use crate::table::SsTable;
+
+const TAG_MANIFEST: u8 = 1;
+const TAG_SST: u8 = 2;
+const TAG_MEMTABLE: u8 = 3;
+const TAG_CHECKPOINT_END: u8 = 4;
+const TAG_BATCH: u8 = 5;
+const TAG_HEARTBEAT: u8 = 6;
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The address a replication leader listens on.
+#[derive(Debug, Clone)]
+pub enum ReplicationAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+enum Connection {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Connection {
+    fn connect(addr: &ReplicationAddr) -> Result<Self> {
+        Ok(match addr {
+            ReplicationAddr::Tcp(addr) => {
+                let stream = TcpStream::connect(addr).context("failed to connect to leader")?;
+                stream.set_nodelay(true)?;
+                Connection::Tcp(stream)
+            }
+            ReplicationAddr::Unix(path) => {
+                Connection::Unix(UnixStream::connect(path).context("failed to connect to leader")?)
+            }
+        })
+    }
+
+    fn try_clone(&self) -> Result<Self> {
+        Ok(match self {
+            Connection::Tcp(stream) => Connection::Tcp(stream.try_clone()?),
+            Connection::Unix(stream) => Connection::Unix(stream.try_clone()?),
+        })
+    }
+
+    fn shutdown(&self) {
+        match self {
+            Connection::Tcp(stream) => stream.shutdown(Shutdown::Both).ok(),
+            Connection::Unix(stream) => stream.shutdown(Shutdown::Both).ok(),
+        };
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.read(buf),
+            Connection::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.write(buf),
+            Connection::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.flush(),
+            Connection::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    fn bind(addr: &ReplicationAddr) -> Result<Self> {
+        let listener = match addr {
+            ReplicationAddr::Tcp(addr) => Listener::Tcp(TcpListener::bind(addr)?),
+            ReplicationAddr::Unix(path) => Listener::Unix(UnixListener::bind(path)?, path.clone()),
+        };
+        match &listener {
+            Listener::Tcp(listener) => listener.set_nonblocking(true)?,
+            Listener::Unix(listener, _) => listener.set_nonblocking(true)?,
+        }
+        Ok(listener)
+    }
+
+    fn local_addr(&self) -> Result<ReplicationAddr> {
+        Ok(match self {
+            Listener::Tcp(listener) => ReplicationAddr::Tcp(listener.local_addr()?),
+            Listener::Unix(_, path) => ReplicationAddr::Unix(path.clone()),
+        })
+    }
+
+    /// Accepts a connection if there is one pending. The accepted connection is always in blocking mode.
+    fn try_accept(&self) -> Result<Option<Connection>> {
+        let conn = match self {
+            Listener::Tcp(listener) => listener.accept().map(|(stream, _)| {
+                stream.set_nodelay(true).ok();
+                stream
+                    .set_nonblocking(false)
+                    .map(|_| Connection::Tcp(stream))
+            }),
+            Listener::Unix(listener, _) => listener.accept().map(|(stream, _)| {
+                stream
+                    .set_nonblocking(false)
+                    .map(|_| Connection::Unix(stream))
+            }),
+        };
+        match conn {
+            Ok(conn) => Ok(Some(conn?)),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            std::fs::remove_file(path).ok();
+        }
+    }
+}
+
+fn write_frame(w: &mut impl Write, tag: u8, payload: &[u8]) -> Result<()> {
+    w.write_all(&[tag])?;
+    w.write_all(&(payload.len() as u64).to_be_bytes())?;
+    w.write_all(payload)?;
+    w.write_all(&crc32fast::hash(payload).to_be_bytes())?;
+    Ok(())
+}
+
+fn read_frame(r: &mut impl Read) -> Result<(u8, Vec<u8>)> {
+    let mut header = [0; 9];
+    r.read_exact(&mut header)?;
+    let tag = header[0];
+    let len = (&header[1..]).get_u64() as usize;
+    let mut payload = vec![0; len];
+    r.read_exact(&mut payload)?;
+    let mut checksum = [0; 4];
+    r.read_exact(&mut checksum)?;
+    if u32::from_be_bytes(checksum) != crc32fast::hash(&payload) {
+        bail!("replication frame checksum mismatched");
+    }
+    Ok((tag, payload))
+}
+
+/// Encodes key-value pairs in the same layout as a WAL batch, `| key_len | key | ts | value_len | value |`.
+fn encode_entries(entries: &[(KeyBytes, Bytes)], buf: &mut Vec<u8>) {
+    for (key, value) in entries {
+        buf.put_u16(key.key_len() as u16);
+        buf.put_slice(key.key_ref());
+        buf.put_u64(key.ts());
+        buf.put_u16(value.len() as u16);
+        buf.put_slice(value);
+    }
+}
+
+fn decode_entries(mut buf: &[u8]) -> Vec<(KeyBytes, Bytes)> {
+    let mut entries = Vec::new();
+    while buf.has_remaining() {
+        let key_len = buf.get_u16() as usize;
+        let key = buf.copy_to_bytes(key_len);
+        let ts = buf.get_u64();
+        let value_len = buf.get_u16() as usize;
+        let value = buf.copy_to_bytes(value_len);
+        entries.push((KeyBytes::from_bytes_with_ts(key, ts), value));
+    }
+    entries
+}
+
+/// A batch committed on the leader.
+pub(crate) struct ReplicatedBatch {
+    commit_ts: u64,
+    entries: Vec<(KeyBytes, Bytes)>,
+}
+
+/// Fan-out of committed write batches to the connected followers.
+#[derive(Default)]
+pub(crate) struct ReplicationHub {
+    subscribers: Mutex<Vec<crossbeam_channel::Sender<Arc<ReplicatedBatch>>>>,
+}
+
+impl ReplicationHub {
+    /// Publish a committed batch. Must be called with the MVCC write lock held so that followers observe batches in
+    /// commit_ts order.
+    pub(crate) fn publish<T: AsRef<[u8]>>(&self, commit_ts: u64, batch: &[WriteBatchRecord<T>]) {
+        if self.num_subscribers() == 0 {
+            return;
+        }
+        self.publish_entries(commit_ts, Self::entries(commit_ts, batch));
+    }
+
+    /// The entries a follower applies for the records of a batch.
+    pub(crate) fn entries<T: AsRef<[u8]>>(
+        commit_ts: u64,
+        batch: &[WriteBatchRecord<T>],
+    ) -> Vec<(KeyBytes, Bytes)> {
+        batch
+            .iter()
+            .map(|record| match record {
+                WriteBatchRecord::Put(key, value) => (
+                    KeyBytes::from_bytes_with_ts(Bytes::copy_from_slice(key.as_ref()), commit_ts),
+                    Bytes::copy_from_slice(value.as_ref()),
+                ),
+                WriteBatchRecord::Del(key) => (
+                    KeyBytes::from_bytes_with_ts(Bytes::copy_from_slice(key.as_ref()), commit_ts),
+                    Bytes::new(),
+                ),
+            })
+            .collect()
+    }
+
+    /// Publish the entries of a committed batch, see `publish`.
+    pub(crate) fn publish_entries(&self, commit_ts: u64, entries: Vec<(KeyBytes, Bytes)>) {
+        let mut subscribers = self.subscribers.lock();
+        if subscribers.is_empty() {
+            return;
+        }
+        let batch = Arc::new(ReplicatedBatch { commit_ts, entries });
+        // followers that went away have dropped their receivers
+        subscribers.retain(|tx| tx.send(batch.clone()).is_ok());
+    }
+
+    fn subscribe(&self) -> crossbeam_channel::Receiver<Arc<ReplicatedBatch>> {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        self.subscribers.lock().push(tx);
+        rx
+    }
+
+    pub(crate) fn num_subscribers(&self) -> usize {
+        self.subscribers.lock().len()
+    }
+}
+
+/// A consistent view of the leader that a follower can bootstrap from.
+struct Checkpoint {
+    manifest: Vec<u8>,
+    sstables: Vec<Arc<SsTable>>,
+    memtable_entries: Vec<(KeyBytes, Bytes)>,
+    commit_ts: u64,
+    /// Receives every batch committed after `commit_ts`.
+    rx: crossbeam_channel::Receiver<Arc<ReplicatedBatch>>,
+}
+
+impl LsmStorageInner {
+    fn create_checkpoint(&self) -> Result<Checkpoint> {
+        // Blocks writers and flushes so that the manifest, the SSTs, the memtables and the subscription all
+        // correspond to the same commit_ts.
+        let _write_lock = self.mvcc().write_lock.lock();
+        let _state_lock = self.state_lock.lock();
+        let snapshot = self.state.read().clone();
+        let manifest = self.manifest().read_all()?;
+        // SST files stay readable through their open handles even if compaction removes them later.
+        let sstables = snapshot
+            .l0_sstables
+            .iter()
+            .chain(snapshot.levels.iter().flat_map(|(_, files)| files))
+            .map(|id| snapshot.sstables[id].clone())
+            .collect();
+        let mut memtable_entries = Vec::new();
+        for memtable in std::iter::once(&snapshot.memtable).chain(snapshot.imm_memtables.iter()) {
+            memtable.for_each(|key, value| {
+                memtable_entries.push((
+                    key.to_key_vec().into_key_bytes(),
+                    Bytes::copy_from_slice(value),
+                ))
+            });
+        }
+        Ok(Checkpoint {
+            manifest,
+            sstables,
+            memtable_entries,
+            commit_ts: self.mvcc().latest_commit_ts(),
+            rx: self.replication.subscribe(),
+        })
+    }
+
+    fn report_replication_error(&self, error: &anyhow::Error) {
+        self.notify_listeners(|listener| listener.on_replication_error(error));
+    }
+
+    fn serve_follower(&self, conn: Connection, stop: &AtomicBool) -> Result<()> {
+        let checkpoint = self.create_checkpoint()?;
+        let mut w = BufWriter::new(conn);
+        write_frame(&mut w, TAG_MANIFEST, &checkpoint.manifest)?;
+        for sst in &checkpoint.sstables {
+            let mut buf = Vec::with_capacity(sst.table_size() as usize + 8);
+            buf.put_u64(sst.sst_id() as u64);
+            buf.extend(sst.reader()?.file().read(0, sst.table_size())?);
+            write_frame(&mut w, TAG_SST, &buf)?;
+        }
+        let mut buf = Vec::new();
+        encode_entries(&checkpoint.memtable_entries, &mut buf);
+        write_frame(&mut w, TAG_MEMTABLE, &buf)?;
+        write_frame(
+            &mut w,
+            TAG_CHECKPOINT_END,
+            &checkpoint.commit_ts.to_be_bytes(),
+        )?;
+        w.flush()?;
+
+        let ticker = crossbeam_channel::tick(HEARTBEAT_INTERVAL);
+        while !stop.load(Ordering::SeqCst) {
+            crossbeam_channel::select! {
+                recv(checkpoint.rx) -> batch => {
+                    let Ok(batch) = batch else { return Ok(()) };
+                    let mut buf = Vec::new();
+                    buf.put_u64(batch.commit_ts);
+                    encode_entries(&batch.entries, &mut buf);
+                    write_frame(&mut w, TAG_BATCH, &buf)?;
+                    // batch up everything that is already queued before flushing the socket
+                    if checkpoint.rx.is_empty() {
+                        w.flush()?;
+                    }
+                },
+                recv(ticker) -> _ => {
+                    write_frame(&mut w, TAG_HEARTBEAT, &self.mvcc().latest_commit_ts().to_be_bytes())?;
+                    w.flush()?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Serves replication streams to followers. Stops accepting and disconnects all followers when dropped, and waits for
+/// the streams to end.
+pub struct ReplicationLeader {
+    addr: ReplicationAddr,
+    inner: Arc<LsmStorageInner>,
+    stop: Arc<AtomicBool>,
+    handle: Option<std::thread::JoinHandle<()>>,
+}
+
+impl ReplicationLeader {
+    pub(crate) fn start(inner: Arc<LsmStorageInner>, addr: ReplicationAddr) -> Result<Self> {
+        let listener = Listener::bind(&addr).context("failed to bind replication address")?;
+        let addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let inner = inner.clone();
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                let ticker = crossbeam_channel::tick(Duration::from_millis(50));
+                // the connection of each stream, to unblock its writes when the leader stops
+                let mut streams: Vec<(Connection, std::thread::JoinHandle<()>)> = Vec::new();
+                while !stop.load(Ordering::SeqCst) {
+                    streams.retain(|(_, handle)| !handle.is_finished());
+                    match listener.try_accept() {
+                        Ok(Some(conn)) => {
+                            let stream_conn = match conn.try_clone() {
+                                Ok(stream_conn) => stream_conn,
+                                Err(e) => {
+                                    inner.report_replication_error(
+                                        &e.context("failed to accept follower"),
+                                    );
+                                    continue;
+                                }
+                            };
+                            let inner = inner.clone();
+                            let stop = stop.clone();
+                            let handle = std::thread::spawn(move || {
+                                if let Err(e) = inner.serve_follower(conn, &stop) {
+                                    // the streams break when the leader stops
+                                    if !stop.load(Ordering::SeqCst) {
+                                        inner.report_replication_error(
+                                            &e.context("replication stream closed"),
+                                        );
+                                    }
+                                }
+                            });
+                            streams.push((stream_conn, handle));
+                        }
+                        Ok(None) => {
+                            ticker.recv().ok();
+                        }
+                        Err(e) => {
+                            inner.report_replication_error(&e.context("failed to accept follower"));
+                            ticker.recv().ok();
+                        }
+                    }
+                }
+                for (conn, _) in &streams {
+                    conn.shutdown();
+                }
+                for (_, handle) in streams {
+                    handle.join().ok();
+                }
+            })
+        };
+        Ok(Self {
+            addr,
+            inner,
+            stop,
+            handle: Some(handle),
+        })
+    }
+
+    /// The address followers should connect to. For TCP, this resolves a port 0 binding to the actual port.
+    pub fn addr(&self) -> &ReplicationAddr {
+        &self.addr
+    }
+
+    /// Number of followers currently streaming from this leader.
+    pub fn num_followers(&self) -> usize {
+        self.inner.replication.num_subscribers()
+    }
+}
+
+impl Drop for ReplicationLeader {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            handle.join().ok();
+        }
+    }
+}
+
+/// How far a follower is behind its leader.
+#[derive(Debug, Clone, Copy)]
+pub struct ReplicationLag {
+    /// The commit_ts of the latest batch applied on the follower. Reads are served at this timestamp.
+    pub applied_ts: u64,
+    /// The latest commit_ts the follower has heard of from the leader.
+    pub leader_commit_ts: u64,
+    /// Time since the follower last heard from the leader.
+    pub since_last_message: Duration,
+}
+
+impl ReplicationLag {
+    /// Number of commits the follower is behind.
+    pub fn lag_ts(&self) -> u64 {
+        self.leader_commit_ts.saturating_sub(self.applied_ts)
+    }
+}
+
+struct FollowerProgress {
+    leader_commit_ts: AtomicU64,
+    last_message: Mutex<Instant>,
+    error: Mutex<Option<String>>,
+}
+
+/// A read-only hot standby of a `MiniLsm` leader.
+pub struct MiniLsmFollower {
+    db: Arc<MiniLsm>,
+    conn: Connection,
+    progress: Arc<FollowerProgress>,
+    handle: Mutex<Option<std::thread::JoinHandle<()>>>,
+}
+
+impl MiniLsmFollower {
+    /// Bootstrap a follower in `path` from the leader at `addr` and start applying its write batches.
+    ///
+    /// `path` must not contain a database. The follower does not write a WAL: after a restart it has to be
+    /// bootstrapped again from the leader.
+    pub fn connect(
+        path: impl AsRef<Path>,
+        options: LsmStorageOptions,
+        addr: &ReplicationAddr,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        if path.join("MANIFEST").exists() {
+            bail!("cannot bootstrap a follower into an existing database");
+        }
+        std::fs::create_dir_all(path).context("failed to create DB dir")?;
+
+        let conn = Connection::connect(addr)?;
+        let mut r = BufReader::new(conn.try_clone()?);
+        let mut memtable_entries = Vec::new();
+        let commit_ts = loop {
+            let (tag, payload) = read_frame(&mut r)?;
+            match tag {
+                TAG_MANIFEST => std::fs::write(path.join("MANIFEST"), payload)?,
+                TAG_SST => {
+                    let mut buf = &payload[..];
+                    let id = buf.get_u64() as usize;
+                    std::fs::write(LsmStorageInner::path_of_sst_static(path, id), buf)?;
+                }
+                TAG_MEMTABLE => memtable_entries = decode_entries(&payload),
+                TAG_CHECKPOINT_END => break (&payload[..]).get_u64(),
+                tag => bail!("unexpected replication frame {} during bootstrap", tag),
+            }
+        };
+        File::open(path)?.sync_all()?;
+
+        let db = MiniLsm::open(
+            path,
+            LsmStorageOptions {
+                enable_wal: false,
+                ..options
+            },
+        )?;
+        let mut batches = BTreeMap::<u64, Vec<_>>::new();
+        for (key, value) in memtable_entries {
+            batches.entry(key.ts()).or_default().push((key, value));
+        }
+        for (ts, entries) in batches {
+            db.inner.apply_replicated_batch(ts, &entries)?;
+        }
+        db.inner.apply_replicated_batch(commit_ts, &[])?;
+
+        let progress = Arc::new(FollowerProgress {
+            leader_commit_ts: AtomicU64::new(commit_ts),
+            last_message: Mutex::new(Instant::now()),
+            error: Mutex::new(None),
+        });
+        let handle = {
+            let inner = db.inner.clone();
+            let progress = progress.clone();
+            std::thread::spawn(move || {
+                if let Err(e) = Self::apply_stream(&inner, &mut r, &progress) {
+                    *progress.error.lock() = Some(e.to_string());
+                }
+            })
+        };
+
+        Ok(Self {
+            db,
+            conn,
+            progress,
+            handle: Mutex::new(Some(handle)),
+        })
+    }
+
+    fn apply_stream(
+        inner: &LsmStorageInner,
+        r: &mut impl Read,
+        progress: &FollowerProgress,
+    ) -> Result<()> {
+        loop {
+            let (tag, payload) = read_frame(r)?;
+            *progress.last_message.lock() = Instant::now();
+            let mut buf = &payload[..];
+            match tag {
+                TAG_BATCH => {
+                    let ts = buf.get_u64();
+                    inner.apply_replicated_batch(ts, &decode_entries(buf))?;
+                    progress.leader_commit_ts.fetch_max(ts, Ordering::SeqCst);
+                }
+                TAG_HEARTBEAT => {
+                    progress
+                        .leader_commit_ts
+                        .fetch_max(buf.get_u64(), Ordering::SeqCst);
+                }
+                tag => bail!("unexpected replication frame {}", tag),
+            }
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        self.db.get(key)
+    }
+
+    pub fn scan(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<TxnIterator> {
+        self.db.scan(lower, upper)
+    }
+
+    /// The commit_ts reads are currently served at.
+    pub fn applied_ts(&self) -> u64 {
+        self.db.inner.mvcc().latest_commit_ts()
+    }
+
+    pub fn lag(&self) -> ReplicationLag {
+        ReplicationLag {
+            applied_ts: self.applied_ts(),
+            leader_commit_ts: self.progress.leader_commit_ts.load(Ordering::SeqCst),
+            since_last_message: self.progress.last_message.lock().elapsed(),
+        }
+    }
+
+    /// Returns the error that stopped replication, if any. The follower keeps serving reads at its last applied
+    /// timestamp after replication stops.
+    pub fn replication_error(&self) -> Option<String> {
+        self.progress.error.lock().clone()
+    }
+
+    pub fn close(&self) -> Result<()> {
+        self.conn.shutdown();
+        if let Some(handle) = self.handle.lock().take() {
+            handle.join().map_err(|e| anyhow::anyhow!("{:?}", e))?;
+        }
+        self.db.close()
+    }
+}
+
+impl Drop for MiniLsmFollower {
+    fn drop(&mut self) {
+        self.conn.shutdown();
+    }
+}
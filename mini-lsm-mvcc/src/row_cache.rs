@@ -0,0 +1,188 @@
+//! A cache of point lookup results, keyed by user key.
+//!
+//! An entry holds the value of a key as read at some read ts, or the lack of one, and answers the lookups of the key
+//! at that ts or later. Every write to the key invalidates it once it is in the memtable but before it becomes
+//! visible, so that an entry never hides a newer version. A lookup result is only cached if no write to its shard newer than its read ts was
+//! invalidated before, since the lookup may have missed that write.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+
+const NUM_SHARDS: usize = 16;
+
+/// The bytes charged for an entry besides its key and value.
+const ENTRY_OVERHEAD: usize = 64;
+
+/// A point-in-time view of the counters of a row cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RowCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub inserts: u64,
+    pub invalidations: u64,
+    pub evictions: u64,
+    /// The bytes charged for the entries in the cache.
+    pub usage: usize,
+    pub capacity: usize,
+}
+
+impl RowCacheStats {
+    /// The fraction of the lookups answered by the cache.
+    pub fn hit_rate(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            0.0
+        } else {
+            self.hits as f64 / lookups as f64
+        }
+    }
+}
+
+struct RowCacheEntry {
+    /// The value of the key, or `None` if it does not exist at `read_ts`.
+    value: Option<Bytes>,
+    read_ts: u64,
+    charge: usize,
+    position: u64,
+}
+
+#[derive(Default)]
+struct Shard {
+    entries: HashMap<Bytes, RowCacheEntry>,
+    /// The keys from the least to the most recently used.
+    lru: BTreeMap<u64, Bytes>,
+    usage: usize,
+    next_position: u64,
+    /// The largest ts of the writes that invalidated a key of this shard.
+    max_invalidated_ts: u64,
+}
+
+impl Shard {
+    fn remove(&mut self, key: &[u8]) -> bool {
+        let Some(entry) = self.entries.remove(key) else {
+            return false;
+        };
+        self.lru.remove(&entry.position);
+        self.usage -= entry.charge;
+        true
+    }
+}
+
+pub struct RowCache {
+    capacity: usize,
+    shards: Vec<Mutex<Shard>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    inserts: AtomicU64,
+    invalidations: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl RowCache {
+    /// Create a row cache holding up to `capacity` bytes of keys and values.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            shards: (0..NUM_SHARDS).map(|_| Mutex::default()).collect(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            inserts: AtomicU64::new(0),
+            invalidations: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    fn shard(&self, key: &[u8]) -> &Mutex<Shard> {
+        &self.shards[farmhash::hash64(key) as usize % NUM_SHARDS]
+    }
+
+    /// Look up the value of a key at `read_ts`. Returns `None` on a miss and `Some(None)` if the key is cached as not
+    /// existing.
+    pub fn get(&self, key: &[u8], read_ts: u64) -> Option<Option<Bytes>> {
+        let mut guard = self.shard(key).lock();
+        let shard = &mut *guard;
+        shard.next_position += 1;
+        let position = shard.next_position;
+        let value = match shard.entries.get_mut(key) {
+            Some(entry) if entry.read_ts <= read_ts => {
+                let old_position = std::mem::replace(&mut entry.position, position);
+                let value = entry.value.clone();
+                let key = shard.lru.remove(&old_position).unwrap();
+                shard.lru.insert(position, key);
+                Some(value)
+            }
+            _ => None,
+        };
+        drop(guard);
+        let counter = if value.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        value
+    }
+
+    /// Cache the value of a key read at `read_ts`, unless a write the read may have missed was invalidated already.
+    pub fn insert(&self, key: &[u8], value: Option<Bytes>, read_ts: u64) {
+        let charge = key.len() + value.as_ref().map_or(0, |value| value.len()) + ENTRY_OVERHEAD;
+        let shard_capacity = self.capacity / NUM_SHARDS;
+        if charge > shard_capacity {
+            return;
+        }
+        let mut shard = self.shard(key).lock();
+        if shard.max_invalidated_ts > read_ts {
+            return;
+        }
+        shard.remove(key);
+        shard.next_position += 1;
+        let position = shard.next_position;
+        let key = Bytes::copy_from_slice(key);
+        shard.lru.insert(position, key.clone());
+        shard.entries.insert(
+            key,
+            RowCacheEntry {
+                value,
+                read_ts,
+                charge,
+                position,
+            },
+        );
+        shard.usage += charge;
+        let mut evicted = 0;
+        while shard.usage > shard_capacity {
+            let (_, victim) = shard.lru.pop_first().unwrap();
+            let entry = shard.entries.remove(&victim).unwrap();
+            shard.usage -= entry.charge;
+            evicted += 1;
+        }
+        drop(shard);
+        self.inserts.fetch_add(1, Ordering::Relaxed);
+        self.evictions.fetch_add(evicted, Ordering::Relaxed);
+    }
+
+    /// Drop the cached value of a key written at `ts`. Must be called after the write is in the memtable and before it
+    /// becomes visible.
+    pub fn invalidate(&self, key: &[u8], ts: u64) {
+        let mut shard = self.shard(key).lock();
+        shard.max_invalidated_ts = shard.max_invalidated_ts.max(ts);
+        if shard.remove(key) {
+            self.invalidations.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn stats(&self) -> RowCacheStats {
+        RowCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            inserts: self.inserts.load(Ordering::Relaxed),
+            invalidations: self.invalidations.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            usage: self.shards.iter().map(|shard| shard.lock().usage).sum(),
+            capacity: self.capacity,
+        }
+    }
+}
@@ -0,0 +1,317 @@
+//! Counters and latency histograms of a storage engine.
+//!
+//! A ticker counts events or bytes. A histogram keeps the distribution of a value, usually a latency in
+//! microseconds, in buckets of powers of two, so percentiles are estimated within a factor of two.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// A counter of the engine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Ticker {
+    /// The bytes of the keys and values written by users.
+    BytesWritten,
+    KeysWritten,
+    /// The bytes appended to the WAL.
+    WalBytesWritten,
+    /// The bytes of the SSTs written by flushes.
+    FlushBytesWritten,
+    /// The bytes of the SSTs read by compactions.
+    CompactionBytesRead,
+    /// The bytes of the SSTs written by compactions.
+    CompactionBytesWritten,
+    /// The bytes of the values returned by point lookups.
+    BytesRead,
+    KeysRead,
+    BlockCacheHit,
+    BlockCacheMiss,
+    /// The lookups that checked the bloom filter of an SST.
+    BloomChecked,
+    /// The lookups for which the bloom filter ruled out an SST.
+    BloomUseful,
+    Flushes,
+    Compactions,
+    /// The transactions that failed to commit because of a conflict with another transaction.
+    TxnConflicts,
+    /// The connections to the metrics endpoint that could not be accepted or answered.
+    MetricsScrapeErrors,
+}
+
+impl Ticker {
+    pub const ALL: [Ticker; 16] = [
+        Ticker::BytesWritten,
+        Ticker::KeysWritten,
+        Ticker::WalBytesWritten,
+        Ticker::FlushBytesWritten,
+        Ticker::CompactionBytesRead,
+        Ticker::CompactionBytesWritten,
+        Ticker::BytesRead,
+        Ticker::KeysRead,
+        Ticker::BlockCacheHit,
+        Ticker::BlockCacheMiss,
+        Ticker::BloomChecked,
+        Ticker::BloomUseful,
+        Ticker::Flushes,
+        Ticker::Compactions,
+        Ticker::TxnConflicts,
+        Ticker::MetricsScrapeErrors,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Ticker::BytesWritten => "bytes.written",
+            Ticker::KeysWritten => "keys.written",
+            Ticker::WalBytesWritten => "wal.bytes.written",
+            Ticker::FlushBytesWritten => "flush.bytes.written",
+            Ticker::CompactionBytesRead => "compaction.bytes.read",
+            Ticker::CompactionBytesWritten => "compaction.bytes.written",
+            Ticker::BytesRead => "bytes.read",
+            Ticker::KeysRead => "keys.read",
+            Ticker::BlockCacheHit => "block.cache.hit",
+            Ticker::BlockCacheMiss => "block.cache.miss",
+            Ticker::BloomChecked => "bloom.filter.checked",
+            Ticker::BloomUseful => "bloom.filter.useful",
+            Ticker::Flushes => "flush.count",
+            Ticker::Compactions => "compaction.count",
+            Ticker::TxnConflicts => "txn.conflicts",
+            Ticker::MetricsScrapeErrors => "metrics.scrape.errors",
+        }
+    }
+}
+
+/// A distribution kept by the engine. All of them are in microseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HistogramType {
+    GetMicros,
+    /// The time of a scan, from creating the iterator until it is exhausted or dropped.
+    ScanMicros,
+    /// The time to write a batch outside a transaction.
+    WriteMicros,
+    /// The time to commit a transaction, including the conflict check.
+    CommitMicros,
+    FlushMicros,
+    CompactionMicros,
+}
+
+impl HistogramType {
+    pub const ALL: [HistogramType; 6] = [
+        HistogramType::GetMicros,
+        HistogramType::ScanMicros,
+        HistogramType::WriteMicros,
+        HistogramType::CommitMicros,
+        HistogramType::FlushMicros,
+        HistogramType::CompactionMicros,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            HistogramType::GetMicros => "get.micros",
+            HistogramType::ScanMicros => "scan.micros",
+            HistogramType::WriteMicros => "write.micros",
+            HistogramType::CommitMicros => "commit.micros",
+            HistogramType::FlushMicros => "flush.micros",
+            HistogramType::CompactionMicros => "compaction.micros",
+        }
+    }
+}
+
+/// Bucket 0 holds 0, and bucket `i` holds the values in `[2^(i-1), 2^i)`.
+const NUM_BUCKETS: usize = 65;
+
+fn bucket_of(value: u64) -> usize {
+    (u64::BITS - value.leading_zeros()) as usize
+}
+
+/// The lower and upper bound of the values in a bucket.
+fn bucket_range(bucket: usize) -> (u64, u64) {
+    match bucket {
+        0 => (0, 0),
+        64 => (1 << 63, u64::MAX),
+        _ => (1 << (bucket - 1), (1 << bucket) - 1),
+    }
+}
+
+struct Histogram {
+    buckets: [AtomicU64; NUM_BUCKETS],
+    count: AtomicU64,
+    sum: AtomicU64,
+    min: AtomicU64,
+    max: AtomicU64,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+            min: AtomicU64::new(u64::MAX),
+            max: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, value: u64) {
+        self.buckets[bucket_of(value)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+        self.min.fetch_min(value, Ordering::Relaxed);
+        self.max.fetch_max(value, Ordering::Relaxed);
+    }
+
+    fn reset(&self) {
+        for bucket in &self.buckets {
+            bucket.store(0, Ordering::Relaxed);
+        }
+        self.count.store(0, Ordering::Relaxed);
+        self.sum.store(0, Ordering::Relaxed);
+        self.min.store(u64::MAX, Ordering::Relaxed);
+        self.max.store(0, Ordering::Relaxed);
+    }
+
+    fn data(&self) -> HistogramData {
+        let buckets = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect::<Vec<_>>();
+        let count = buckets.iter().sum::<u64>();
+        if count == 0 {
+            return HistogramData::default();
+        }
+        let min = self.min.load(Ordering::Relaxed);
+        let max = self.max.load(Ordering::Relaxed);
+        let percentile = |p: f64| {
+            let rank = p / 100.0 * count as f64;
+            let mut seen = 0;
+            for (bucket, &bucket_count) in buckets.iter().enumerate() {
+                if bucket_count == 0 || ((seen + bucket_count) as f64) < rank {
+                    seen += bucket_count;
+                    continue;
+                }
+                // interpolate within the bucket, which is as precise as the bucket allows
+                let (low, high) = bucket_range(bucket);
+                let fraction = (rank - seen as f64) / bucket_count as f64;
+                let value = low as f64 + (high - low) as f64 * fraction;
+                return (value as u64).clamp(min, max);
+            }
+            max
+        };
+        HistogramData {
+            count,
+            sum: self.sum.load(Ordering::Relaxed),
+            min,
+            max,
+            p50: percentile(50.0),
+            p95: percentile(95.0),
+            p99: percentile(99.0),
+        }
+    }
+}
+
+/// A point-in-time view of a histogram.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct HistogramData {
+    pub count: u64,
+    pub sum: u64,
+    pub min: u64,
+    pub max: u64,
+    pub p50: u64,
+    pub p95: u64,
+    pub p99: u64,
+}
+
+impl HistogramData {
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.sum as f64 / self.count as f64
+        }
+    }
+}
+
+/// The tickers and histograms of a storage engine, read through `MiniLsm::statistics`.
+pub struct Statistics {
+    tickers: [AtomicU64; Ticker::ALL.len()],
+    histograms: [Histogram; HistogramType::ALL.len()],
+}
+
+impl Default for Statistics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Statistics {
+    pub fn new() -> Self {
+        Self {
+            tickers: std::array::from_fn(|_| AtomicU64::new(0)),
+            histograms: std::array::from_fn(|_| Histogram::new()),
+        }
+    }
+
+    pub fn record(&self, ticker: Ticker, count: u64) {
+        self.tickers[ticker as usize].fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn ticker(&self, ticker: Ticker) -> u64 {
+        self.tickers[ticker as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn record_value(&self, histogram: HistogramType, value: u64) {
+        self.histograms[histogram as usize].record(value);
+    }
+
+    /// Record the time since `start` in microseconds.
+    pub fn record_elapsed(&self, histogram: HistogramType, start: Instant) {
+        self.record_duration(histogram, start.elapsed());
+    }
+
+    pub fn record_duration(&self, histogram: HistogramType, duration: Duration) {
+        self.record_value(histogram, duration.as_micros() as u64);
+    }
+
+    pub fn histogram(&self, histogram: HistogramType) -> HistogramData {
+        self.histograms[histogram as usize].data()
+    }
+
+    /// Set all the tickers and histograms back to zero.
+    pub fn reset(&self) {
+        for ticker in &self.tickers {
+            ticker.store(0, Ordering::Relaxed);
+        }
+        for histogram in &self.histograms {
+            histogram.reset();
+        }
+    }
+}
+
+impl fmt::Display for Statistics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for ticker in Ticker::ALL {
+            writeln!(f, "{} COUNT : {}", ticker.name(), self.ticker(ticker))?;
+        }
+        for histogram in HistogramType::ALL {
+            let data = self.histogram(histogram);
+            writeln!(
+                f,
+                "{} P50 : {} P95 : {} P99 : {} MAX : {} COUNT : {} SUM : {}",
+                histogram.name(),
+                data.p50,
+                data.p95,
+                data.p99,
+                data.max,
+                data.count,
+                data.sum
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Statistics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Statistics").finish_non_exhaustive()
+    }
+}
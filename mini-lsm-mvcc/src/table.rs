@@ -1,19 +1,23 @@
 pub(crate) mod bloom;
 mod builder;
 mod iterator;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
 
 use std::fs::File;
-use std::path::Path;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
 
-use anyhow::{anyhow, bail, Result};
+use anyhow::{bail, Result};
 pub use builder::SsTableBuilder;
-use bytes::{Buf, BufMut};
-pub use iterator::SsTableIterator;
+use bytes::{Buf, BufMut, Bytes};
+pub use iterator::{SsTableIterator, TableReadOptions};
 
 use crate::block::Block;
 use crate::key::{KeyBytes, KeySlice};
 use crate::lsm_storage::BlockCache;
+use crate::manifest::SstMeta;
+use crate::table_cache::TableCache;
 
 use self::bloom::Bloom;
 
@@ -107,6 +111,37 @@ impl FileObject {
         Ok(data)
     }
 
+    /// Read `(file, offset, len)` ranges, possibly of different files. With the `io-uring` feature on Linux, the
+    /// reads are submitted together and run in parallel. Otherwise, or if io_uring is not available, they are read
+    /// one by one.
+    pub fn read_batch(reqs: &[(&FileObject, u64, u64)]) -> Result<Vec<Vec<u8>>> {
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        {
+            let files = reqs
+                .iter()
+                .map(|(file, offset, len)| (file.0.as_ref().unwrap(), *offset, *len as usize))
+                .collect::<Vec<_>>();
+            if let Some(result) = uring::read_batch(&files) {
+                return result;
+            }
+        }
+        reqs.iter()
+            .map(|(file, offset, len)| file.read(*offset, *len))
+            .collect()
+    }
+
+    /// Whether `read_batch` runs the reads in parallel, so that reading ahead is worthwhile.
+    pub fn supports_batched_reads() -> bool {
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        {
+            uring::is_available()
+        }
+        #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+        {
+            false
+        }
+    }
+
     pub fn size(&self) -> u64 {
         self.1
     }
@@ -128,11 +163,114 @@ impl FileObject {
     }
 }
 
+/// The parts of an SST needed to read its blocks, as held by the table cache: the file, the block index and the
+/// bloom filter.
+pub struct TableReader {
+    pub(crate) file: FileObject,
+    pub(crate) block_meta: Vec<BlockMeta>,
+    pub(crate) block_meta_offset: usize,
+    pub(crate) bloom: Option<Bloom>,
+}
+
+impl TableReader {
+    /// Read the block index and the bloom filter of an SST file. Also returns the max ts of the SST.
+    pub(crate) fn open(file: FileObject) -> Result<(Self, u64)> {
+        let len = file.size();
+        let raw_bloom_offset = file.read(len - 4, 4)?;
+        let bloom_offset = (&raw_bloom_offset[..]).get_u32() as u64;
+        let raw_bloom = file.read(bloom_offset, len - 4 - bloom_offset)?;
+        let bloom_filter = Bloom::decode(&raw_bloom)?;
+        let raw_meta_offset = file.read(bloom_offset - 4, 4)?;
+        let block_meta_offset = (&raw_meta_offset[..]).get_u32() as u64;
+        let raw_meta = file.read(block_meta_offset, bloom_offset - 4 - block_meta_offset)?;
+        let (block_meta, max_ts) = BlockMeta::decode_block_meta(&raw_meta[..])?;
+        Ok((
+            Self {
+                file,
+                block_meta,
+                block_meta_offset: block_meta_offset as usize,
+                bloom: Some(bloom_filter),
+            },
+            max_ts,
+        ))
+    }
+
+    /// The bytes of memory taken by the block index and the bloom filter.
+    pub fn charge(&self) -> usize {
+        let meta = self
+            .block_meta
+            .iter()
+            .map(|meta| {
+                std::mem::size_of::<BlockMeta>()
+                    + meta.first_key.raw_len()
+                    + meta.last_key.raw_len()
+            })
+            .sum::<usize>();
+        meta + self.bloom.as_ref().map_or(0, |bloom| bloom.filter.len())
+    }
+}
+
+/// The file, the block index and the bloom filter of an SST, either kept with the SST or held by the table cache.
+pub enum TableReaderRef<'a> {
+    Resident(&'a SsTable),
+    Cached(Arc<TableReader>),
+}
+
+impl TableReaderRef<'_> {
+    pub fn file(&self) -> &FileObject {
+        match self {
+            TableReaderRef::Resident(table) => &table.file,
+            TableReaderRef::Cached(reader) => &reader.file,
+        }
+    }
+
+    pub fn block_meta(&self) -> &[BlockMeta] {
+        match self {
+            TableReaderRef::Resident(table) => &table.block_meta,
+            TableReaderRef::Cached(reader) => &reader.block_meta,
+        }
+    }
+
+    fn block_meta_offset(&self) -> usize {
+        match self {
+            TableReaderRef::Resident(table) => table.block_meta_offset,
+            TableReaderRef::Cached(reader) => reader.block_meta_offset,
+        }
+    }
+
+    pub fn bloom(&self) -> Option<&Bloom> {
+        match self {
+            TableReaderRef::Resident(table) => table.bloom.as_ref(),
+            TableReaderRef::Cached(reader) => reader.bloom.as_ref(),
+        }
+    }
+
+    /// The offset and the length of a block with its checksum.
+    fn block_range(&self, block_idx: usize) -> (u64, u64) {
+        let block_meta = self.block_meta();
+        let offset = block_meta[block_idx].offset;
+        let offset_end = block_meta
+            .get(block_idx + 1)
+            .map_or(self.block_meta_offset(), |x| x.offset);
+        (offset as u64, (offset_end - offset) as u64)
+    }
+}
+
+/// How an SST opened through the table cache gets its reader back.
+struct CachedReader {
+    table_cache: Arc<TableCache>,
+    path: PathBuf,
+    num_of_blocks: usize,
+    /// Set by `pin_reader` to keep the file open after it is deleted.
+    pinned: OnceLock<Arc<TableReader>>,
+}
+
 /// An SSTable.
 pub struct SsTable {
-    /// The actual storage unit of SsTable, the format is as above.
+    /// The actual storage unit of SsTable, the format is as above. Not open if the SST is opened through the table
+    /// cache.
     pub(crate) file: FileObject,
-    /// The meta blocks that hold info for data blocks.
+    /// The meta blocks that hold info for data blocks. Empty if the SST is opened through the table cache.
     pub(crate) block_meta: Vec<BlockMeta>,
     /// The offset that indicates the start point of meta blocks in `file`.
     pub(crate) block_meta_offset: usize,
@@ -142,6 +280,8 @@ pub struct SsTable {
     last_key: KeyBytes,
     pub(crate) bloom: Option<Bloom>,
     max_ts: u64,
+    /// Set if the reader is held by the table cache instead of the fields above.
+    cached_reader: Option<CachedReader>,
 }
 impl SsTable {
     #[cfg(test)]
@@ -149,28 +289,142 @@ impl SsTable {
         Self::open(0, None, file)
     }
 
+    /// Create an SST that keeps its reader.
+    pub(crate) fn from_reader(
+        id: usize,
+        block_cache: Option<Arc<BlockCache>>,
+        reader: TableReader,
+        max_ts: u64,
+    ) -> Self {
+        Self {
+            file: reader.file,
+            first_key: reader.block_meta.first().unwrap().first_key.clone(),
+            last_key: reader.block_meta.last().unwrap().last_key.clone(),
+            block_meta: reader.block_meta,
+            block_meta_offset: reader.block_meta_offset,
+            id,
+            block_cache,
+            bloom: reader.bloom,
+            max_ts,
+            cached_reader: None,
+        }
+    }
+
     /// Open SSTable from a file.
     pub fn open(id: usize, block_cache: Option<Arc<BlockCache>>, file: FileObject) -> Result<Self> {
-        let len = file.size();
-        let raw_bloom_offset = file.read(len - 4, 4)?;
-        let bloom_offset = (&raw_bloom_offset[..]).get_u32() as u64;
-        let raw_bloom = file.read(bloom_offset, len - 4 - bloom_offset)?;
-        let bloom_filter = Bloom::decode(&raw_bloom)?;
-        let raw_meta_offset = file.read(bloom_offset - 4, 4)?;
-        let block_meta_offset = (&raw_meta_offset[..]).get_u32() as u64;
-        let raw_meta = file.read(block_meta_offset, bloom_offset - 4 - block_meta_offset)?;
-        let (block_meta, max_ts) = BlockMeta::decode_block_meta(&raw_meta[..])?;
-        Ok(Self {
-            file,
-            first_key: block_meta.first().unwrap().first_key.clone(),
-            last_key: block_meta.last().unwrap().last_key.clone(),
-            block_meta,
-            block_meta_offset: block_meta_offset as usize,
+        let (reader, max_ts) = TableReader::open(file)?;
+        Ok(Self::from_reader(id, block_cache, reader, max_ts))
+    }
+
+    /// Create an SST whose reader is held by the table cache. Only its key range and max ts are kept with it.
+    fn with_table_cache(
+        id: usize,
+        block_cache: Option<Arc<BlockCache>>,
+        table_cache: Arc<TableCache>,
+        path: PathBuf,
+        reader: &TableReader,
+        max_ts: u64,
+    ) -> Self {
+        Self {
+            file: FileObject(None, reader.file.size()),
+            block_meta: vec![],
+            block_meta_offset: reader.block_meta_offset,
+            id,
+            block_cache,
+            first_key: reader.block_meta.first().unwrap().first_key.clone(),
+            last_key: reader.block_meta.last().unwrap().last_key.clone(),
+            bloom: None,
+            max_ts,
+            cached_reader: Some(CachedReader {
+                table_cache,
+                path,
+                num_of_blocks: reader.block_meta.len(),
+                pinned: OnceLock::new(),
+            }),
+        }
+    }
+
+    /// Open SSTable from a file through the table cache. The file, the block index and the bloom filter are loaded
+    /// again on demand once the table cache evicts them.
+    pub fn open_with_table_cache(
+        id: usize,
+        block_cache: Option<Arc<BlockCache>>,
+        table_cache: Arc<TableCache>,
+        path: impl AsRef<Path>,
+    ) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let (reader, max_ts) = table_cache.get_or_open(id, &path)?;
+        Ok(Self::with_table_cache(
+            id,
+            block_cache,
+            table_cache,
+            path,
+            &reader,
+            max_ts,
+        ))
+    }
+
+    /// Create an SST whose reader is held by the table cache from the metadata recorded in the manifest, without
+    /// opening the file.
+    pub(crate) fn from_meta_with_table_cache(
+        meta: &SstMeta,
+        block_cache: Option<Arc<BlockCache>>,
+        table_cache: Arc<TableCache>,
+        path: impl AsRef<Path>,
+    ) -> Self {
+        let key = |(key, ts): &(Vec<u8>, u64)| {
+            KeyBytes::from_bytes_with_ts(Bytes::copy_from_slice(key), *ts)
+        };
+        Self {
+            file: FileObject(None, meta.file_size),
+            block_meta: vec![],
+            block_meta_offset: meta.block_meta_offset,
+            id: meta.id,
+            block_cache,
+            first_key: key(&meta.first_key),
+            last_key: key(&meta.last_key),
+            bloom: None,
+            max_ts: meta.max_ts,
+            cached_reader: Some(CachedReader {
+                table_cache,
+                path: path.as_ref().to_path_buf(),
+                num_of_blocks: meta.num_of_blocks,
+                pinned: OnceLock::new(),
+            }),
+        }
+    }
+
+    /// The metadata to record in the manifest, see `from_meta_with_table_cache`.
+    pub(crate) fn meta(&self) -> SstMeta {
+        SstMeta {
+            id: self.id,
+            file_size: self.table_size(),
+            block_meta_offset: self.block_meta_offset,
+            num_of_blocks: self.num_of_blocks(),
+            first_key: (self.first_key.key_ref().to_vec(), self.first_key.ts()),
+            last_key: (self.last_key.key_ref().to_vec(), self.last_key.ts()),
+            max_ts: self.max_ts,
+        }
+    }
+
+    /// Create an SST from a reader that was just built, adding the reader to the table cache.
+    pub(crate) fn from_reader_with_table_cache(
+        id: usize,
+        block_cache: Option<Arc<BlockCache>>,
+        table_cache: Arc<TableCache>,
+        path: impl AsRef<Path>,
+        reader: TableReader,
+        max_ts: u64,
+    ) -> Self {
+        let reader = table_cache.insert(id, reader, max_ts);
+        Self::with_table_cache(
             id,
             block_cache,
-            bloom: Some(bloom_filter),
+            table_cache,
+            path.as_ref().to_path_buf(),
+            &reader,
             max_ts,
-        })
+        )
     }
 
     /// Create a mock SST with only first key + last key metadata
@@ -190,20 +444,56 @@ impl SsTable {
             last_key,
             bloom: None,
             max_ts: 0,
+            cached_reader: None,
         }
     }
 
-    /// Read a block from the disk.
-    pub fn read_block(&self, block_idx: usize) -> Result<Arc<Block>> {
-        let offset = self.block_meta[block_idx].offset;
-        let offset_end = self
-            .block_meta
-            .get(block_idx + 1)
-            .map_or(self.block_meta_offset, |x| x.offset);
-        let block_len = offset_end - offset - 4;
-        let block_data_with_chksum: Vec<u8> = self
-            .file
-            .read(offset as u64, (offset_end - offset) as u64)?;
+    /// Get the file, the block index and the bloom filter of the SST, opening them through the table cache if they
+    /// are not open.
+    pub fn reader(&self) -> Result<TableReaderRef<'_>> {
+        let Some(cached) = &self.cached_reader else {
+            return Ok(TableReaderRef::Resident(self));
+        };
+        if let Some(reader) = cached.pinned.get() {
+            return Ok(TableReaderRef::Cached(reader.clone()));
+        }
+        let result = cached.table_cache.get_or_open(self.id, &cached.path);
+        // The SST may have been pinned and its file deleted since the check above. Then the file is gone, or a reader
+        // opened before the deletion was put back into the cache, where no one else would remove it.
+        if let Some(reader) = cached.pinned.get() {
+            cached.table_cache.evict(self.id);
+            return Ok(TableReaderRef::Cached(reader.clone()));
+        }
+        let (reader, _) = result?;
+        Ok(TableReaderRef::Cached(reader))
+    }
+
+    /// Keep the reader open for the lifetime of the SST, so that it can still be read after its file is deleted.
+    pub(crate) fn pin_reader(&self) -> Result<()> {
+        if let Some(cached) = &self.cached_reader {
+            if cached.pinned.get().is_none() {
+                let (reader, _) = cached.table_cache.get_or_open(self.id, &cached.path)?;
+                cached.pinned.set(reader).ok();
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the bloom filter allows the SST to contain the key.
+    pub fn may_contain(&self, key: &[u8]) -> Result<bool> {
+        Ok(self.check_bloom(key)?.unwrap_or(true))
+    }
+
+    /// Check the key against the bloom filter, if the SST has one.
+    pub fn check_bloom(&self, key: &[u8]) -> Result<Option<bool>> {
+        Ok(self
+            .reader()?
+            .bloom()
+            .map(|bloom| bloom.may_contain(farmhash::fingerprint32(key))))
+    }
+
+    fn decode_block(block_data_with_chksum: &[u8]) -> Result<Arc<Block>> {
+        let block_len = block_data_with_chksum.len() - 4;
         let block_data = &block_data_with_chksum[..block_len];
         let checksum = (&block_data_with_chksum[block_len..]).get_u32();
         if checksum != crc32fast::hash(block_data) {
@@ -212,28 +502,126 @@ impl SsTable {
         Ok(Arc::new(Block::decode(block_data)))
     }
 
+    /// Read a block from the disk.
+    pub fn read_block(&self, block_idx: usize) -> Result<Arc<Block>> {
+        let reader = self.reader()?;
+        let (offset, len) = reader.block_range(block_idx);
+        Self::decode_block(&reader.file().read(offset, len)?)
+    }
+
+    /// Read blocks of one or more SSTs, with block cache. The blocks that are not cached are read with a single
+    /// `FileObject::read_batch`.
+    pub fn read_blocks_cached(blocks: &[(&SsTable, usize)]) -> Result<Vec<Arc<Block>>> {
+        let mut result = Vec::with_capacity(blocks.len());
+        let mut misses = Vec::new();
+        for (idx, (table, block_idx)) in blocks.iter().enumerate() {
+            let cached = table
+                .block_cache
+                .as_ref()
+                .and_then(|block_cache| block_cache.get(&(table.id, *block_idx)));
+            if cached.is_none() {
+                misses.push(idx);
+            }
+            result.push(cached);
+        }
+        if !misses.is_empty() {
+            let readers = misses
+                .iter()
+                .map(|idx| blocks[*idx].0.reader())
+                .collect::<Result<Vec<_>>>()?;
+            let reqs = misses
+                .iter()
+                .zip(&readers)
+                .map(|(idx, reader)| {
+                    let (offset, len) = reader.block_range(blocks[*idx].1);
+                    (reader.file(), offset, len)
+                })
+                .collect::<Vec<_>>();
+            for (idx, data) in misses.into_iter().zip(FileObject::read_batch(&reqs)?) {
+                let (table, block_idx) = blocks[idx];
+                let block = Self::decode_block(&data)?;
+                if let Some(block_cache) = &table.block_cache {
+                    block_cache.insert((table.id, block_idx), block.clone());
+                }
+                result[idx] = Some(block);
+            }
+        }
+        Ok(result.into_iter().map(Option::unwrap).collect())
+    }
+
+    /// Read the blocks in `start..end` with a single read of the range of the file, with block cache. Blocks read
+    /// from the disk are added to the cache if `fill_cache` is set.
+    pub fn read_block_range(
+        &self,
+        start: usize,
+        end: usize,
+        fill_cache: bool,
+    ) -> Result<Vec<Arc<Block>>> {
+        if let Some(block_cache) = &self.block_cache {
+            let cached = (start..end)
+                .map_while(|block_idx| block_cache.get(&(self.id, block_idx)))
+                .collect::<Vec<_>>();
+            if cached.len() == end - start {
+                return Ok(cached);
+            }
+        }
+        let reader = self.reader()?;
+        let offset = reader.block_range(start).0;
+        let (last_offset, last_len) = reader.block_range(end - 1);
+        let data = reader.file().read(offset, last_offset + last_len - offset)?;
+        let mut blocks = Vec::with_capacity(end - start);
+        for block_idx in start..end {
+            let (block_offset, block_len) = reader.block_range(block_idx);
+            let begin = (block_offset - offset) as usize;
+            let block = Self::decode_block(&data[begin..begin + block_len as usize])?;
+            if let (Some(block_cache), true) = (&self.block_cache, fill_cache) {
+                block_cache.insert((self.id, block_idx), block.clone());
+            }
+            blocks.push(block);
+        }
+        Ok(blocks)
+    }
+
+    /// Read a block from disk, with block cache. A block read from the disk is only added to the cache if
+    /// `fill_cache` is set.
+    pub fn read_block_with_options(&self, block_idx: usize, fill_cache: bool) -> Result<Arc<Block>> {
+        if fill_cache {
+            return self.read_block_cached(block_idx);
+        }
+        if let Some(block) = self
+            .block_cache
+            .as_ref()
+            .and_then(|block_cache| block_cache.get(&(self.id, block_idx)))
+        {
+            return Ok(block);
+        }
+        self.read_block(block_idx)
+    }
+
     /// Read a block from disk, with block cache.
     pub fn read_block_cached(&self, block_idx: usize) -> Result<Arc<Block>> {
         if let Some(ref block_cache) = self.block_cache {
-            let blk = block_cache
-                .try_get_with((self.id, block_idx), || self.read_block(block_idx))
-                .map_err(|e| anyhow!("{}", e))?;
-            Ok(blk)
+            block_cache.try_get_with((self.id, block_idx), || self.read_block(block_idx))
         } else {
             self.read_block(block_idx)
         }
     }
 
     /// Find the block that may contain `key`.
-    pub fn find_block_idx(&self, key: KeySlice) -> usize {
-        self.block_meta
+    pub fn find_block_idx(&self, key: KeySlice) -> Result<usize> {
+        Ok(self
+            .reader()?
+            .block_meta()
             .partition_point(|meta| meta.first_key.as_key_slice() <= key)
-            .saturating_sub(1)
+            .saturating_sub(1))
     }
 
     /// Get number of data blocks.
     pub fn num_of_blocks(&self) -> usize {
-        self.block_meta.len()
+        match &self.cached_reader {
+            Some(cached) => cached.num_of_blocks,
+            None => self.block_meta.len(),
+        }
     }
 
     pub fn first_key(&self) -> &KeyBytes {
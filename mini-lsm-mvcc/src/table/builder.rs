@@ -5,10 +5,11 @@ use anyhow::Result;
 use bytes::BufMut;
 
 use super::bloom::Bloom;
-use super::{BlockMeta, FileObject, SsTable};
+use super::{BlockMeta, FileObject, SsTable, TableReader};
 use crate::block::BlockBuilder;
 use crate::key::{KeySlice, KeyVec};
 use crate::lsm_storage::BlockCache;
+use crate::table_cache::TableCache;
 
 /// Builds an SSTable from key-value pairs.
 pub struct SsTableBuilder {
@@ -80,13 +81,8 @@ impl SsTableBuilder {
         self.data.put_u32(checksum);
     }
 
-    /// Builds the SSTable and writes it to the given path. Use the `FileObject` structure to manipulate the disk objects.
-    pub fn build(
-        mut self,
-        id: usize,
-        block_cache: Option<Arc<BlockCache>>,
-        path: impl AsRef<Path>,
-    ) -> Result<SsTable> {
+    /// Writes the SSTable to the given path and returns its reader and max ts.
+    fn write(mut self, path: &Path) -> Result<(TableReader, u64)> {
         self.finish_block();
         let mut buf = self.data;
         let meta_offset = buf.len();
@@ -99,18 +95,46 @@ impl SsTableBuilder {
         let bloom_offset = buf.len();
         bloom.encode(&mut buf);
         buf.put_u32(bloom_offset as u32);
-        let file = FileObject::create(path.as_ref(), buf)?;
-        Ok(SsTable {
+        let file = FileObject::create(path, buf)?;
+        Ok((
+            TableReader {
+                file,
+                block_meta: self.meta,
+                block_meta_offset: meta_offset,
+                bloom: Some(bloom),
+            },
+            self.max_ts,
+        ))
+    }
+
+    /// Builds the SSTable and writes it to the given path. Use the `FileObject` structure to manipulate the disk objects.
+    pub fn build(
+        self,
+        id: usize,
+        block_cache: Option<Arc<BlockCache>>,
+        path: impl AsRef<Path>,
+    ) -> Result<SsTable> {
+        let (reader, max_ts) = self.write(path.as_ref())?;
+        Ok(SsTable::from_reader(id, block_cache, reader, max_ts))
+    }
+
+    /// Builds the SSTable like `build`, with its file, block index and bloom filter held by the table cache.
+    pub fn build_with_table_cache(
+        self,
+        id: usize,
+        block_cache: Option<Arc<BlockCache>>,
+        table_cache: Arc<TableCache>,
+        path: impl AsRef<Path>,
+    ) -> Result<SsTable> {
+        let (reader, max_ts) = self.write(path.as_ref())?;
+        Ok(SsTable::from_reader_with_table_cache(
             id,
-            file,
-            first_key: self.meta.first().unwrap().first_key.clone(),
-            last_key: self.meta.last().unwrap().last_key.clone(),
-            block_meta: self.meta,
-            block_meta_offset: meta_offset,
             block_cache,
-            bloom: Some(bloom),
-            max_ts: self.max_ts,
-        })
+            table_cache,
+            path,
+            reader,
+            max_ts,
+        ))
     }
 
     #[cfg(test)]
@@ -1,55 +1,144 @@
-use std::sync::Arc;
+use std::collections::VecDeque;
+use std::sync::{Arc, OnceLock};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 
 use super::SsTable;
-use crate::block::BlockIterator;
+use crate::block::{Block, BlockIterator};
 use crate::iterators::StorageIterator;
 use crate::key::KeySlice;
 
+/// The number of sequential block reads after which a scan starts to read ahead.
+const READAHEAD_TRIGGER: usize = 2;
+
+/// The size of the first readahead. Each readahead doubles the size, up to `TableReadOptions::readahead_size`.
+const INITIAL_READAHEAD_SIZE: usize = 16 * 1024;
+
+/// How an iterator reads the blocks of an SST.
+#[derive(Clone, Debug)]
+pub struct TableReadOptions {
+    /// The maximum number of bytes to read ahead of a sequential scan. 0 disables readahead.
+    pub readahead_size: usize,
+    /// Whether the blocks read by the iterator are added to the block cache.
+    pub fill_cache: bool,
+    /// Read the next readahead window in the background while the iterator goes through the current one.
+    pub background_readahead: bool,
+}
+
+impl Default for TableReadOptions {
+    fn default() -> Self {
+        Self {
+            readahead_size: 256 * 1024,
+            fill_cache: true,
+            background_readahead: false,
+        }
+    }
+}
+
+type ReadaheadJob = Box<dyn FnOnce() + Send>;
+
+/// The threads that read ahead in the background, shared by all iterators.
+fn readahead_pool() -> &'static crossbeam_channel::Sender<ReadaheadJob> {
+    static POOL: OnceLock<crossbeam_channel::Sender<ReadaheadJob>> = OnceLock::new();
+    POOL.get_or_init(|| {
+        let (sender, receiver) = crossbeam_channel::unbounded::<ReadaheadJob>();
+        for i in 0..2 {
+            let receiver = receiver.clone();
+            std::thread::Builder::new()
+                .name(format!("mini-lsm-readahead-{}", i))
+                .spawn(move || {
+                    while let Ok(job) = receiver.recv() {
+                        job();
+                    }
+                })
+                .expect("failed to spawn readahead thread");
+        }
+        sender
+    })
+}
+
+/// The readahead state of an iterator. Reset on every seek.
+#[derive(Default)]
+struct Readahead {
+    /// The number of blocks read in sequence since the last seek.
+    sequential_reads: usize,
+    /// The size of the last readahead window.
+    window_size: usize,
+    /// The blocks after `blk_idx` that are already read.
+    prefetched: VecDeque<Arc<Block>>,
+    /// The blocks being read in the background, which come after `prefetched`.
+    pending: Option<crossbeam_channel::Receiver<Result<Vec<Arc<Block>>>>>,
+    /// The index of the first block that is neither prefetched nor pending.
+    next_unread: usize,
+}
+
 /// An iterator over the contents of an SSTable.
 pub struct SsTableIterator {
     table: Arc<SsTable>,
     blk_iter: BlockIterator,
     blk_idx: usize,
+    options: TableReadOptions,
+    readahead: Readahead,
 }
 
 impl SsTableIterator {
-    fn seek_to_first_inner(table: &Arc<SsTable>) -> Result<(usize, BlockIterator)> {
+    fn seek_to_first_inner(
+        table: &Arc<SsTable>,
+        options: &TableReadOptions,
+    ) -> Result<(usize, BlockIterator)> {
         Ok((
             0,
-            BlockIterator::create_and_seek_to_first(table.read_block_cached(0)?),
+            BlockIterator::create_and_seek_to_first(
+                table.read_block_with_options(0, options.fill_cache)?,
+            ),
         ))
     }
 
     /// Create a new iterator and seek to the first key-value pair.
     pub fn create_and_seek_to_first(table: Arc<SsTable>) -> Result<Self> {
-        let (blk_idx, blk_iter) = Self::seek_to_first_inner(&table)?;
+        Self::create_and_seek_to_first_with_options(table, TableReadOptions::default())
+    }
+
+    pub fn create_and_seek_to_first_with_options(
+        table: Arc<SsTable>,
+        options: TableReadOptions,
+    ) -> Result<Self> {
+        let (blk_idx, blk_iter) = Self::seek_to_first_inner(&table, &options)?;
         let iter = Self {
             blk_iter,
             table,
             blk_idx,
+            options,
+            readahead: Readahead::default(),
         };
         Ok(iter)
     }
 
     /// Seek to the first key-value pair.
     pub fn seek_to_first(&mut self) -> Result<()> {
-        let (blk_idx, blk_iter) = Self::seek_to_first_inner(&self.table)?;
+        let (blk_idx, blk_iter) = Self::seek_to_first_inner(&self.table, &self.options)?;
         self.blk_idx = blk_idx;
         self.blk_iter = blk_iter;
+        self.readahead = Readahead::default();
         Ok(())
     }
 
-    fn seek_to_key_inner(table: &Arc<SsTable>, key: KeySlice) -> Result<(usize, BlockIterator)> {
-        let mut blk_idx = table.find_block_idx(key);
-        let mut blk_iter =
-            BlockIterator::create_and_seek_to_key(table.read_block_cached(blk_idx)?, key);
+    fn seek_to_key_inner(
+        table: &Arc<SsTable>,
+        key: KeySlice,
+        options: &TableReadOptions,
+    ) -> Result<(usize, BlockIterator)> {
+        let mut blk_idx = table.find_block_idx(key)?;
+        let mut blk_iter = BlockIterator::create_and_seek_to_key(
+            table.read_block_with_options(blk_idx, options.fill_cache)?,
+            key,
+        );
         if !blk_iter.is_valid() {
             blk_idx += 1;
             if blk_idx < table.num_of_blocks() {
-                blk_iter =
-                    BlockIterator::create_and_seek_to_first(table.read_block_cached(blk_idx)?);
+                blk_iter = BlockIterator::create_and_seek_to_first(
+                    table.read_block_with_options(blk_idx, options.fill_cache)?,
+                );
             }
         }
         Ok((blk_idx, blk_iter))
@@ -57,20 +146,100 @@ impl SsTableIterator {
 
     /// Create a new iterator and seek to the first key-value pair which >= `key`.
     pub fn create_and_seek_to_key(table: Arc<SsTable>, key: KeySlice) -> Result<Self> {
-        let (blk_idx, blk_iter) = Self::seek_to_key_inner(&table, key)?;
+        Self::create_and_seek_to_key_with_options(table, key, TableReadOptions::default())
+    }
+
+    pub fn create_and_seek_to_key_with_options(
+        table: Arc<SsTable>,
+        key: KeySlice,
+        options: TableReadOptions,
+    ) -> Result<Self> {
+        let (blk_idx, blk_iter) = Self::seek_to_key_inner(&table, key, &options)?;
         let iter = Self {
             blk_iter,
             table,
             blk_idx,
+            options,
+            readahead: Readahead::default(),
         };
         Ok(iter)
     }
 
     /// Seek to the first key-value pair which >= `key`.
     pub fn seek_to_key(&mut self, key: KeySlice) -> Result<()> {
-        let (blk_idx, blk_iter) = Self::seek_to_key_inner(&self.table, key)?;
+        let (blk_idx, blk_iter) = Self::seek_to_key_inner(&self.table, key, &self.options)?;
         self.blk_iter = blk_iter;
         self.blk_idx = blk_idx;
+        self.readahead = Readahead::default();
+        Ok(())
+    }
+
+    /// Read the block at `blk_idx` when moving to it from the previous block.
+    fn next_block(&mut self) -> Result<Arc<Block>> {
+        let readahead = &mut self.readahead;
+        readahead.sequential_reads += 1;
+        if readahead.prefetched.is_empty() {
+            if let Some(pending) = readahead.pending.take() {
+                let blocks = pending
+                    .recv()
+                    .map_err(|_| anyhow!("the background readahead did not complete"))??;
+                readahead.prefetched.extend(blocks);
+            } else if self.options.readahead_size > 0
+                && readahead.sequential_reads > READAHEAD_TRIGGER
+            {
+                let end = self.readahead_end(self.blk_idx)?;
+                let blocks =
+                    self.table
+                        .read_block_range(self.blk_idx, end, self.options.fill_cache)?;
+                self.readahead.prefetched.extend(blocks);
+                self.readahead.next_unread = end;
+            }
+            if self.options.background_readahead && !self.readahead.prefetched.is_empty() {
+                self.start_background_readahead()?;
+            }
+        }
+        match self.readahead.prefetched.pop_front() {
+            Some(block) => Ok(block),
+            None => self
+                .table
+                .read_block_with_options(self.blk_idx, self.options.fill_cache),
+        }
+    }
+
+    /// Grow the readahead window, and return the end of the window starting at block `start`.
+    fn readahead_end(&mut self, start: usize) -> Result<usize> {
+        let window_size = (self.readahead.window_size * 2)
+            .max(INITIAL_READAHEAD_SIZE)
+            .min(self.options.readahead_size);
+        self.readahead.window_size = window_size;
+        let reader = self.table.reader()?;
+        let mut end = start;
+        let mut size = 0;
+        while end < self.table.num_of_blocks() && (end == start || size < window_size) {
+            size += reader.block_range(end).1 as usize;
+            end += 1;
+        }
+        Ok(end)
+    }
+
+    fn start_background_readahead(&mut self) -> Result<()> {
+        let start = self.readahead.next_unread;
+        if start >= self.table.num_of_blocks() {
+            return Ok(());
+        }
+        let end = self.readahead_end(start)?;
+        let (sender, receiver) = crossbeam_channel::bounded(1);
+        let table = self.table.clone();
+        let fill_cache = self.options.fill_cache;
+        let job = Box::new(move || {
+            // the iterator may be gone already
+            let _ = sender.send(table.read_block_range(start, end, fill_cache));
+        });
+        readahead_pool()
+            .send(job)
+            .expect("the readahead threads never exit");
+        self.readahead.pending = Some(receiver);
+        self.readahead.next_unread = end;
         Ok(())
     }
 }
@@ -82,7 +251,7 @@ impl StorageIterator for SsTableIterator {
         self.blk_iter.value()
     }
 
-    fn key(&self) -> KeySlice {
+    fn key(&self) -> KeySlice<'_> {
         self.blk_iter.key()
     }
 
@@ -95,9 +264,8 @@ impl StorageIterator for SsTableIterator {
         if !self.blk_iter.is_valid() {
             self.blk_idx += 1;
             if self.blk_idx < self.table.num_of_blocks() {
-                self.blk_iter = BlockIterator::create_and_seek_to_first(
-                    self.table.read_block_cached(self.blk_idx)?,
-                );
+                let block = self.next_block()?;
+                self.blk_iter = BlockIterator::create_and_seek_to_first(block);
             }
         }
         Ok(())
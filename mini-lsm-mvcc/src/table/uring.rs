@@ -0,0 +1,113 @@
+//! Batched reads with io_uring. Each thread that reads in batches gets its own ring. If the kernel does not support
+//! io_uring, or it is not permitted, the reads fall back to `pread`.
+
+use std::{
+    cell::RefCell,
+    fs::File,
+    io::ErrorKind,
+    os::unix::fs::FileExt,
+    os::unix::io::AsRawFd,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::Result;
+use io_uring::{opcode, types, IoUring};
+
+/// The maximum number of reads in flight on a ring.
+const QUEUE_DEPTH: u32 = 64;
+
+/// Set once io_uring turns out to be unavailable, so that later reads do not try again.
+static UNAVAILABLE: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    static RING: RefCell<Option<IoUring>> = const { RefCell::new(None) };
+}
+
+pub(crate) fn is_available() -> bool {
+    !UNAVAILABLE.load(Ordering::Relaxed)
+}
+
+/// Read `(file, offset, len)` ranges with the ring of the current thread. Returns `None` if io_uring is unavailable,
+/// in which case nothing has been read.
+pub(crate) fn read_batch(reqs: &[(&File, u64, usize)]) -> Option<Result<Vec<Vec<u8>>>> {
+    if !is_available() {
+        return None;
+    }
+    RING.with(|ring| {
+        let mut ring = ring.borrow_mut();
+        if ring.is_none() {
+            match IoUring::new(QUEUE_DEPTH) {
+                Ok(new_ring) => *ring = Some(new_ring),
+                Err(_) => {
+                    UNAVAILABLE.store(true, Ordering::Relaxed);
+                    return None;
+                }
+            }
+        }
+        let ring = ring.as_mut().unwrap();
+        let mut bufs = reqs
+            .iter()
+            .map(|(_, _, len)| vec![0; *len])
+            .collect::<Vec<_>>();
+        let mut read_lens = vec![0; reqs.len()];
+        for start in (0..reqs.len()).step_by(QUEUE_DEPTH as usize) {
+            let end = (start + QUEUE_DEPTH as usize).min(reqs.len());
+            for idx in start..end {
+                let (file, offset, len) = reqs[idx];
+                let entry = opcode::Read::new(
+                    types::Fd(file.as_raw_fd()),
+                    bufs[idx].as_mut_ptr(),
+                    len as u32,
+                )
+                .offset(offset)
+                .build()
+                .user_data(idx as u64);
+                // SAFETY: the buffer outlives the read, as all reads are completed before returning
+                unsafe { ring.submission().push(&entry) }
+                    .expect("the submission queue holds QUEUE_DEPTH entries");
+            }
+            if let Err(e) = ring.submit_and_wait(end - start) {
+                if start == 0 {
+                    // e.g. io_uring is disabled by a seccomp filter
+                    UNAVAILABLE.store(true, Ordering::Relaxed);
+                    return None;
+                }
+                return Some(Err(e.into()));
+            }
+            let mut completed = 0;
+            while completed < end - start {
+                for cqe in ring.completion() {
+                    let idx = cqe.user_data() as usize;
+                    if cqe.result() < 0 {
+                        let e = std::io::Error::from_raw_os_error(-cqe.result());
+                        // the kernel does not support the read opcode
+                        if start == 0 && e.kind() == ErrorKind::Unsupported {
+                            UNAVAILABLE.store(true, Ordering::Relaxed);
+                            return None;
+                        }
+                        return Some(Err(e.into()));
+                    }
+                    read_lens[idx] = cqe.result() as usize;
+                    completed += 1;
+                }
+                if completed < end - start {
+                    if let Err(e) = ring.submit_and_wait(end - start - completed) {
+                        return Some(Err(e.into()));
+                    }
+                }
+            }
+        }
+        // finish short reads synchronously
+        for (idx, (file, offset, len)) in reqs.iter().enumerate() {
+            if read_lens[idx] < *len {
+                if let Err(e) = file.read_exact_at(
+                    &mut bufs[idx][read_lens[idx]..],
+                    offset + read_lens[idx] as u64,
+                ) {
+                    return Some(Err(e.into()));
+                }
+            }
+        }
+        Some(Ok(bufs))
+    })
+}
@@ -0,0 +1,151 @@
+//! A cache of the open SSTs of a storage engine.
+//!
+//! An SST opened through the table cache only keeps its key range in memory. Its file, block index and bloom filter
+//! are opened on first access and kept by the cache until they are evicted in LRU order, either to stay under
+//! `max_open_files` or to keep the memory of the block indexes and bloom filters under the capacity. A reader that is
+//! still in use when it is evicted stays open until the last user drops it.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use parking_lot::Mutex;
+
+use crate::table::{FileObject, TableReader};
+
+/// A point-in-time view of the counters of a table cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TableCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    /// The number of SSTs held open by the cache.
+    pub open_files: usize,
+    /// The bytes of the block indexes and bloom filters held by the cache.
+    pub usage: usize,
+}
+
+struct CachedTable {
+    reader: Arc<TableReader>,
+    max_ts: u64,
+    charge: usize,
+    position: u64,
+}
+
+#[derive(Default)]
+struct TableCacheInner {
+    tables: HashMap<usize, CachedTable>,
+    /// The SST ids from the least to the most recently used.
+    lru: BTreeMap<u64, usize>,
+    usage: usize,
+    next_position: u64,
+}
+
+impl TableCacheInner {
+    fn touch(&mut self, id: usize) -> Option<(Arc<TableReader>, u64)> {
+        self.next_position += 1;
+        let table = self.tables.get_mut(&id)?;
+        self.lru.remove(&table.position);
+        self.lru.insert(self.next_position, id);
+        table.position = self.next_position;
+        Some((table.reader.clone(), table.max_ts))
+    }
+
+    fn remove(&mut self, id: usize) -> Option<CachedTable> {
+        let table = self.tables.remove(&id)?;
+        self.lru.remove(&table.position);
+        self.usage -= table.charge;
+        Some(table)
+    }
+}
+
+pub struct TableCache {
+    max_open_files: usize,
+    capacity: usize,
+    inner: Mutex<TableCacheInner>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl TableCache {
+    /// Create a table cache holding at most `max_open_files` SSTs, whose block indexes and bloom filters take at most
+    /// `capacity` bytes. The most recently used SST is always kept, even if it is larger than the capacity.
+    pub fn new(max_open_files: usize, capacity: usize) -> Self {
+        Self {
+            max_open_files: max_open_files.max(1),
+            capacity,
+            inner: Mutex::new(TableCacheInner::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    /// Get the reader of an SST and its max ts, opening the file at `path` if the SST is not in the cache.
+    pub(crate) fn get_or_open(&self, id: usize, path: &Path) -> Result<(Arc<TableReader>, u64)> {
+        if let Some(table) = self.inner.lock().touch(id) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(table);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let file = FileObject::open(path)
+            .with_context(|| format!("failed to open SST {}", path.display()))?;
+        let (reader, max_ts) = TableReader::open(file)?;
+        Ok((self.insert(id, reader, max_ts), max_ts))
+    }
+
+    /// Add the reader of an SST to the cache and return it. If another thread opened the SST in the meantime, its
+    /// reader is returned instead.
+    pub(crate) fn insert(&self, id: usize, reader: TableReader, max_ts: u64) -> Arc<TableReader> {
+        let mut inner = self.inner.lock();
+        if let Some((reader, _)) = inner.touch(id) {
+            return reader;
+        }
+        let reader = Arc::new(reader);
+        let charge = reader.charge();
+        inner.next_position += 1;
+        let position = inner.next_position;
+        inner.lru.insert(position, id);
+        inner.tables.insert(
+            id,
+            CachedTable {
+                reader: reader.clone(),
+                max_ts,
+                charge,
+                position,
+            },
+        );
+        inner.usage += charge;
+        while inner.tables.len() > 1
+            && (inner.tables.len() > self.max_open_files || inner.usage > self.capacity)
+        {
+            let (_, &victim) = inner.lru.first_key_value().unwrap();
+            inner.remove(victim);
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+        reader
+    }
+
+    /// Drop the reader of an SST, e.g. when its file is deleted.
+    pub(crate) fn evict(&self, id: usize) {
+        self.inner.lock().remove(id);
+    }
+
+    pub fn contains(&self, id: usize) -> bool {
+        self.inner.lock().tables.contains_key(&id)
+    }
+
+    pub fn stats(&self) -> TableCacheStats {
+        let inner = self.inner.lock();
+        TableCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            open_files: inner.tables.len(),
+            usage: inner.usage,
+        }
+    }
+}
@@ -19,3 +19,28 @@ mod week3_day4;
 mod week3_day5;
 mod week3_day6;
 mod week3_day7;
+mod replication;
+mod shared_wal;
+mod snapshot;
+mod history_retention;
+mod named_snapshot;
+mod serializable_conflicts;
+mod pessimistic_txn;
+mod savepoint;
+mod two_phase_commit;
+mod txn_spill;
+mod isolation_level;
+mod async_lsm;
+mod batched_reads;
+mod readahead;
+mod block_cache;
+mod table_cache;
+mod row_cache;
+mod arena_memtable;
+mod memtable_kinds;
+mod write_buffer_manager;
+mod statistics;
+mod metrics;
+mod event_listener;
+mod background_error;
+mod background_scheduler;
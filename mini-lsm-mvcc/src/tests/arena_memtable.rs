@@ -0,0 +1,192 @@
+use std::{ops::Bound, sync::Arc};
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    iterators::StorageIterator,
+    key::KeySlice,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    mem_table::{MemTable, MemTableKind},
+    table::{SsTableBuilder, SsTableIterator},
+};
+
+use super::harness::check_iter_result_by_key_and_ts;
+
+fn key_of(idx: usize) -> Vec<u8> {
+    format!("key_{:05}", idx).into_bytes()
+}
+
+fn value_of(idx: usize) -> Vec<u8> {
+    format!("value_{:010}", idx).into_bytes()
+}
+
+#[test]
+fn test_arena_memtable_get_and_scan() {
+    let memtable = MemTable::new(0, MemTableKind::ArenaSkipList, None);
+    assert!(memtable.is_empty());
+    memtable.put(KeySlice::from_slice(b"b", 1), b"b1").unwrap();
+    memtable.put(KeySlice::from_slice(b"a", 2), b"a2").unwrap();
+    memtable.put(KeySlice::from_slice(b"b", 3), b"b3").unwrap();
+    memtable.put(KeySlice::from_slice(b"c", 1), b"").unwrap();
+    // writing the same key and ts again replaces the value
+    memtable
+        .put(KeySlice::from_slice(b"a", 2), b"a2_new")
+        .unwrap();
+    assert!(!memtable.is_empty());
+
+    assert_eq!(
+        memtable.get(KeySlice::from_slice(b"a", 2)),
+        Some(Bytes::from("a2_new"))
+    );
+    assert_eq!(
+        memtable.get(KeySlice::from_slice(b"b", 1)),
+        Some(Bytes::from("b1"))
+    );
+    assert_eq!(memtable.get(KeySlice::from_slice(b"b", 2)), None);
+    assert_eq!(memtable.get(KeySlice::from_slice(b"d", 1)), None);
+
+    let mut iter = memtable.scan(Bound::Unbounded, Bound::Unbounded);
+    check_iter_result_by_key_and_ts(
+        &mut iter,
+        vec![
+            ((Bytes::from("a"), 2), Bytes::from("a2_new")),
+            ((Bytes::from("b"), 3), Bytes::from("b3")),
+            ((Bytes::from("b"), 1), Bytes::from("b1")),
+            ((Bytes::from("c"), 1), Bytes::new()),
+        ],
+    );
+    let mut iter = memtable.scan(
+        Bound::Excluded(KeySlice::from_slice(b"a", 2)),
+        Bound::Included(KeySlice::from_slice(b"b", 1)),
+    );
+    check_iter_result_by_key_and_ts(
+        &mut iter,
+        vec![
+            ((Bytes::from("b"), 3), Bytes::from("b3")),
+            ((Bytes::from("b"), 1), Bytes::from("b1")),
+        ],
+    );
+    let mut iter = memtable.scan(
+        Bound::Included(KeySlice::from_slice(b"b", 2)),
+        Bound::Excluded(KeySlice::from_slice(b"c", 1)),
+    );
+    check_iter_result_by_key_and_ts(&mut iter, vec![((Bytes::from("b"), 1), Bytes::from("b1"))]);
+}
+
+#[test]
+fn test_arena_memtable_flush_and_memory_usage() {
+    let skipmap = MemTable::new(0, MemTableKind::SkipMap, None);
+    let arena = MemTable::new(1, MemTableKind::ArenaSkipList, None);
+    let mut last_usage = arena.approximate_size();
+    for idx in (0..2000).rev() {
+        for memtable in [&skipmap, &arena] {
+            memtable
+                .put(KeySlice::from_slice(&key_of(idx), 1), &value_of(idx))
+                .unwrap();
+        }
+        // every write takes at least the bytes of its key and value
+        let usage = arena.approximate_size();
+        assert!(usage >= last_usage + key_of(idx).len() + value_of(idx).len() + 4);
+        last_usage = usage;
+    }
+
+    let dir = tempdir().unwrap();
+    let mut tables = Vec::new();
+    for memtable in [&skipmap, &arena] {
+        let mut builder = SsTableBuilder::new(4096);
+        memtable.flush(&mut builder).unwrap();
+        let path = dir.path().join(format!("{}.sst", memtable.id()));
+        tables.push(Arc::new(builder.build_for_test(path).unwrap()));
+    }
+    let mut iters = tables
+        .iter()
+        .map(|table| SsTableIterator::create_and_seek_to_first(table.clone()).unwrap())
+        .collect::<Vec<_>>();
+    for idx in 0..2000 {
+        for iter in &mut iters {
+            assert_eq!(iter.key().key_ref(), key_of(idx));
+            assert_eq!(iter.value(), value_of(idx));
+            iter.next().unwrap();
+        }
+    }
+    assert!(iters.iter().all(|iter| !iter.is_valid()));
+}
+
+#[test]
+fn test_arena_memtable_concurrent_writes() {
+    let memtable = Arc::new(MemTable::new(0, MemTableKind::ArenaSkipList, None));
+    std::thread::scope(|scope| {
+        for thread in 0..4 {
+            let memtable = memtable.clone();
+            scope.spawn(move || {
+                for idx in (thread..4000).step_by(4) {
+                    memtable
+                        .put(KeySlice::from_slice(&key_of(idx), 1), &value_of(idx))
+                        .unwrap();
+                }
+            });
+        }
+        let memtable = memtable.clone();
+        scope.spawn(move || {
+            for _ in 0..20 {
+                // readers see the keys in order while they are being inserted
+                let mut iter = memtable.scan(Bound::Unbounded, Bound::Unbounded);
+                let mut last_key = Vec::new();
+                while iter.is_valid() {
+                    assert!(iter.key().key_ref() > last_key.as_slice());
+                    last_key = iter.key().key_ref().to_vec();
+                    iter.next().unwrap();
+                }
+            }
+        });
+    });
+    let mut iter = memtable.scan(Bound::Unbounded, Bound::Unbounded);
+    for idx in 0..4000 {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key().key_ref(), key_of(idx));
+        assert_eq!(iter.value(), value_of(idx));
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_storage_with_arena_memtable() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.memtable_kind = MemTableKind::ArenaSkipList;
+    options.enable_wal = true;
+    options.target_sst_size = 1 << 16;
+    {
+        let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+        for idx in 0..3000 {
+            storage.put(&key_of(idx), &value_of(idx)).unwrap();
+        }
+        for idx in (0..3000).step_by(3) {
+            storage.delete(&key_of(idx)).unwrap();
+        }
+        storage.close().unwrap();
+    }
+
+    // the memtables are recovered from the WAL into arena skiplists
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert_eq!(
+        storage.inner.state.read().memtable.kind(),
+        MemTableKind::ArenaSkipList
+    );
+    for idx in 0..3000 {
+        let expected = (idx % 3 != 0).then(|| Bytes::from(value_of(idx)));
+        assert_eq!(storage.get(&key_of(idx)).unwrap(), expected);
+    }
+    storage.force_flush().unwrap();
+    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    for idx in (0..3000).filter(|idx| idx % 3 != 0) {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key(), key_of(idx));
+        assert_eq!(iter.value(), value_of(idx));
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
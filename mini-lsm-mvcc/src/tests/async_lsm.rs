@@ -0,0 +1,108 @@
+use std::{
+    future::{poll_fn, Future},
+    ops::Bound,
+    pin::{pin, Pin},
+    sync::Arc,
+    task::{Context, Poll, Wake},
+    thread::Thread,
+};
+
+use anyhow::Result;
+use bytes::Bytes;
+use futures_core::Stream;
+use tempfile::tempdir;
+
+use crate::{
+    async_lsm::{AsyncMiniLsm, ScanStream},
+    compact::CompactionOptions,
+    lsm_storage::{LsmStorageOptions, MiniLsm, WriteBatchRecord},
+};
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let waker = Arc::new(ThreadWaker(std::thread::current())).into();
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => std::thread::park(),
+        }
+    }
+}
+
+fn collect(mut stream: ScanStream) -> Result<Vec<(Bytes, Bytes)>> {
+    let mut entries = Vec::new();
+    while let Some(entry) = block_on(poll_fn(|cx| Pin::new(&mut stream).poll_next(cx))) {
+        entries.push(entry?);
+    }
+    Ok(entries)
+}
+
+#[test]
+fn test_async_get_put_scan() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = AsyncMiniLsm::new(MiniLsm::open(&dir, options).unwrap(), 2).unwrap();
+    block_on(storage.put(b"a", b"1")).unwrap();
+    block_on(storage.write_batch(vec![
+        WriteBatchRecord::Put(Bytes::from("b"), Bytes::from("2")),
+        WriteBatchRecord::Put(Bytes::from("c"), Bytes::from("3")),
+    ]))
+    .unwrap();
+    block_on(storage.delete(b"c")).unwrap();
+    assert_eq!(block_on(storage.get(b"a")).unwrap(), Some(Bytes::from("1")));
+    assert_eq!(block_on(storage.get(b"c")).unwrap(), None);
+    assert_eq!(
+        collect(storage.scan(Bound::Unbounded, Bound::Unbounded)).unwrap(),
+        vec![
+            (Bytes::from("a"), Bytes::from("1")),
+            (Bytes::from("b"), Bytes::from("2")),
+        ]
+    );
+
+    // more entries than a batch of the stream
+    for i in 0..1000 {
+        block_on(storage.put(format!("key{:04}", i).as_bytes(), b"v")).unwrap();
+    }
+    storage.storage().force_flush().unwrap();
+    let entries =
+        collect(storage.scan(Bound::Included(b"key0100"), Bound::Excluded(b"key0900"))).unwrap();
+    assert_eq!(entries.len(), 800);
+    assert_eq!(entries[0].0, Bytes::from("key0100"));
+    assert_eq!(entries[799].0, Bytes::from("key0899"));
+}
+
+#[test]
+fn test_async_txn() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = AsyncMiniLsm::new(MiniLsm::open(&dir, options).unwrap(), 2).unwrap();
+    block_on(storage.put(b"a", b"1")).unwrap();
+    let txn1 = storage.new_txn().unwrap();
+    let txn2 = storage.new_txn().unwrap();
+    block_on(txn1.put(b"a", b"2")).unwrap();
+    block_on(txn1.put(b"b", b"2")).unwrap();
+    block_on(txn2.put(b"a", b"3")).unwrap();
+    assert_eq!(block_on(txn1.get(b"a")).unwrap(), Some(Bytes::from("2")));
+    assert_eq!(
+        collect(txn1.scan(Bound::Unbounded, Bound::Unbounded)).unwrap(),
+        vec![
+            (Bytes::from("a"), Bytes::from("2")),
+            (Bytes::from("b"), Bytes::from("2")),
+        ]
+    );
+    assert_eq!(block_on(storage.get(b"a")).unwrap(), Some(Bytes::from("1")));
+    block_on(txn1.commit()).unwrap();
+    // snapshot isolation, the last writer wins
+    block_on(txn2.commit()).unwrap();
+    assert_eq!(block_on(storage.get(b"a")).unwrap(), Some(Bytes::from("3")));
+    assert_eq!(block_on(storage.get(b"b")).unwrap(), Some(Bytes::from("2")));
+}
@@ -0,0 +1,99 @@
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    background_error::BackgroundErrorSeverity,
+    compact::CompactionOptions,
+    event_listener::BackgroundErrorReason,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+};
+
+#[test]
+fn test_background_error_severity() {
+    let storage_full = anyhow::Error::new(Error::from(ErrorKind::StorageFull));
+    assert_eq!(
+        BackgroundErrorSeverity::of(&storage_full),
+        BackgroundErrorSeverity::Fatal
+    );
+    let interrupted = Err::<(), _>(Error::from(ErrorKind::Interrupted))
+        .context("failed to write SST")
+        .unwrap_err();
+    assert_eq!(
+        BackgroundErrorSeverity::of(&interrupted),
+        BackgroundErrorSeverity::Retryable
+    );
+    assert_eq!(
+        BackgroundErrorSeverity::of(&anyhow!("checksum mismatch")),
+        BackgroundErrorSeverity::Fatal
+    );
+}
+
+#[test]
+fn test_read_only_after_fatal_flush_error() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.num_memtable_limit = 1;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    storage.put(b"a", b"1").unwrap();
+    // the flush thread cannot create the SST in a directory that is gone
+    std::fs::remove_dir_all(&dir).unwrap();
+    storage
+        .inner
+        .force_freeze_memtable(&storage.inner.state_lock.lock())
+        .ok();
+    for _ in 0..100 {
+        if storage.background_error().is_some() {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    let error = storage.background_error().unwrap();
+    assert_eq!(error.reason, BackgroundErrorReason::Flush);
+    assert_eq!(error.severity, BackgroundErrorSeverity::Fatal);
+
+    // reads still work, writes fail until the database is resumed
+    assert_eq!(storage.get(b"a").unwrap(), Some(Bytes::from("1")));
+    let write_error = storage.put(b"b", b"2").unwrap_err();
+    assert!(
+        write_error.to_string().contains("read-only"),
+        "{write_error}"
+    );
+    assert!(storage.resume().is_err());
+    assert!(storage.background_error().is_some());
+
+    std::fs::create_dir_all(&dir).unwrap();
+    storage.resume().unwrap();
+    assert!(storage.background_error().is_none());
+    assert!(storage.inner.state.read().imm_memtables.is_empty());
+    storage.put(b"b", b"2").unwrap();
+    assert_eq!(storage.get(b"a").unwrap(), Some(Bytes::from("1")));
+    assert_eq!(storage.get(b"b").unwrap(), Some(Bytes::from("2")));
+}
+
+#[test]
+fn test_retryable_errors_become_fatal() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    let error = anyhow::Error::new(Error::from(ErrorKind::Interrupted));
+    let mut retries = 0;
+    while storage
+        .inner
+        .record_background_error(BackgroundErrorReason::Flush, &error)
+        == BackgroundErrorSeverity::Retryable
+    {
+        assert!(storage.background_error().is_none());
+        storage.put(b"a", b"1").unwrap();
+        retries += 1;
+    }
+    assert!(retries > 0);
+    assert!(storage.put(b"a", b"2").is_err());
+    // there is nothing to flush, so resuming succeeds right away
+    storage.resume().unwrap();
+    storage.put(b"a", b"2").unwrap();
+    assert_eq!(storage.get(b"a").unwrap(), Some(Bytes::from("2")));
+}
@@ -0,0 +1,123 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    background_scheduler::{BackgroundScheduler, JobPriority},
+    compact::{CompactionOptions, SimpleLeveledCompactionOptions},
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+};
+
+fn wait_until(mut condition: impl FnMut() -> bool) -> bool {
+    for _ in 0..250 {
+        if condition() {
+            return true;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    false
+}
+
+#[test]
+fn test_shared_scheduler() {
+    let scheduler = Arc::new(BackgroundScheduler::new(2, 1));
+    assert_eq!(scheduler.num_threads(JobPriority::High), 2);
+    assert_eq!(scheduler.num_threads(JobPriority::Low), 1);
+    let dirs = (0..2).map(|_| tempdir().unwrap()).collect::<Vec<_>>();
+    let engines = dirs
+        .iter()
+        .map(|dir| {
+            let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::Simple(
+                SimpleLeveledCompactionOptions {
+                    size_ratio_percent: 200,
+                    level0_file_num_compaction_trigger: 2,
+                    max_levels: 3,
+                },
+            ));
+            options.target_sst_size = 4 << 10;
+            options.background_scheduler = Some(scheduler.clone());
+            MiniLsm::open(dir, options).unwrap()
+        })
+        .collect::<Vec<_>>();
+    std::thread::scope(|scope| {
+        for storage in &engines {
+            scope.spawn(move || {
+                for idx in 0..2000 {
+                    storage
+                        .put(
+                            format!("key_{:05}", idx).as_bytes(),
+                            format!("value_{:050}", idx).as_bytes(),
+                        )
+                        .unwrap();
+                }
+            });
+        }
+    });
+
+    for storage in &engines {
+        // the flushes and compactions of both engines run on the shared threads
+        assert!(wait_until(|| {
+            let state = storage.inner.state.read();
+            state.imm_memtables.len() < 2 && state.l0_sstables.len() < 2
+        }));
+        assert!(storage
+            .inner
+            .state
+            .read()
+            .levels
+            .iter()
+            .any(|(_, ssts)| !ssts.is_empty()));
+        for idx in (0..2000).step_by(97) {
+            assert_eq!(
+                storage.get(format!("key_{:05}", idx).as_bytes()).unwrap(),
+                Some(Bytes::from(format!("value_{:050}", idx)))
+            );
+        }
+    }
+    // a running job keeps its engine until it finishes
+    drop(engines);
+    assert!(wait_until(|| Arc::strong_count(&scheduler) == 1));
+}
+
+#[test]
+fn test_pause_background_work() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert!(storage.continue_background_work().is_err());
+
+    storage.pause_background_work();
+    storage.pause_background_work();
+    for idx in 0..4 {
+        storage
+            .put(format!("key_{}", idx).as_bytes(), b"1")
+            .unwrap();
+        storage
+            .inner
+            .force_freeze_memtable(&storage.inner.state_lock.lock())
+            .unwrap();
+    }
+    std::thread::sleep(Duration::from_millis(200));
+    assert_eq!(storage.inner.state.read().imm_memtables.len(), 4);
+    assert!(storage.inner.state.read().l0_sstables.is_empty());
+
+    // the pauses nest
+    storage.continue_background_work().unwrap();
+    std::thread::sleep(Duration::from_millis(200));
+    assert_eq!(storage.inner.state.read().imm_memtables.len(), 4);
+
+    // the flushes skipped during the pause run once it ends
+    storage.continue_background_work().unwrap();
+    assert!(wait_until(|| storage
+        .inner
+        .state
+        .read()
+        .imm_memtables
+        .len()
+        < 2));
+    assert_eq!(storage.inner.state.read().l0_sstables.len(), 3);
+    assert!(storage.continue_background_work().is_err());
+    storage.close().unwrap();
+}
@@ -0,0 +1,106 @@
+use std::{ops::Bound, sync::Arc};
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    iterators::StorageIterator,
+    key::KeySlice,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    table::{FileObject, SsTable, SsTableBuilder, SsTableIterator},
+};
+
+fn key_of(idx: usize) -> Vec<u8> {
+    format!("key_{:05}", idx).into_bytes()
+}
+
+fn value_of(idx: usize) -> Vec<u8> {
+    format!("value_{:010}", idx).into_bytes()
+}
+
+#[test]
+fn test_read_blocks_in_batch() {
+    let dir = tempdir().unwrap();
+    let mut builder = SsTableBuilder::new(128);
+    for idx in 0..1000 {
+        builder.add(
+            KeySlice::for_testing_from_slice_no_ts(&key_of(idx)),
+            &value_of(idx),
+        );
+    }
+    let table = Arc::new(builder.build_for_test(dir.path().join("1.sst")).unwrap());
+    assert!(table.num_of_blocks() > 16);
+
+    let blocks = (0..table.num_of_blocks())
+        .rev()
+        .map(|block_idx| (table.as_ref(), block_idx))
+        .collect::<Vec<_>>();
+    let batch = SsTable::read_blocks_cached(&blocks).unwrap();
+    for ((_, block_idx), block) in blocks.iter().zip(batch) {
+        assert_eq!(block.data, table.read_block(*block_idx).unwrap().data);
+    }
+
+    let reader = table.reader().unwrap();
+    let reqs = [
+        (reader.file(), 0, 16),
+        (reader.file(), 100, 50),
+        (reader.file(), 16, 84),
+    ];
+    let data = FileObject::read_batch(&reqs).unwrap();
+    assert_eq!(data[0], reader.file().read(0, 16).unwrap());
+    assert_eq!(data[1], reader.file().read(100, 50).unwrap());
+    assert_eq!(data[2], reader.file().read(16, 84).unwrap());
+
+    // a scan over many blocks, which reads ahead if the reads can run in parallel
+    let mut iter = SsTableIterator::create_and_seek_to_first(table.clone()).unwrap();
+    for idx in 0..1000 {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key().for_testing_key_ref(), key_of(idx));
+        assert_eq!(iter.value(), value_of(idx));
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_multi_get() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.block_size = 128;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    for idx in 0..300 {
+        storage.put(&key_of(idx), &value_of(idx)).unwrap();
+        if idx % 100 == 99 {
+            storage.force_flush().unwrap();
+        }
+    }
+    storage.delete(&key_of(150)).unwrap();
+    let keys = [
+        key_of(0),
+        key_of(150),
+        key_of(299),
+        key_of(1000),
+        key_of(42),
+    ];
+    let keys = keys.iter().map(|key| key.as_slice()).collect::<Vec<_>>();
+    assert_eq!(
+        storage.multi_get(&keys).unwrap(),
+        vec![
+            Some(Bytes::from(value_of(0))),
+            None,
+            Some(Bytes::from(value_of(299))),
+            None,
+            Some(Bytes::from(value_of(42))),
+        ]
+    );
+    let mut iter = storage
+        .scan(Bound::Included(&key_of(100)), Bound::Excluded(&key_of(200)))
+        .unwrap();
+    let mut count = 0;
+    while iter.is_valid() {
+        count += 1;
+        iter.next().unwrap();
+    }
+    assert_eq!(count, 99);
+}
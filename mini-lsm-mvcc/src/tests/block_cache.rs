@@ -0,0 +1,197 @@
+use std::sync::Arc;
+
+use tempfile::tempdir;
+
+use crate::{
+    block::{Block, BlockBuilder},
+    block_cache::{BlockCacheOptions, CacheEvictionPolicy, CachePriority},
+    compact::CompactionOptions,
+    key::KeySlice,
+    lsm_storage::{BlockCache, LsmStorageOptions, MiniLsm},
+};
+
+fn block_of(value_size: usize) -> Arc<Block> {
+    let mut builder = BlockBuilder::new(4096);
+    assert!(builder.add(
+        KeySlice::for_testing_from_slice_no_ts(b"key"),
+        &vec![b'v'; value_size]
+    ));
+    Arc::new(builder.build())
+}
+
+/// A single-shard cache holding exactly `num_blocks` blocks of `block_of(100)`.
+fn cache_of(num_blocks: usize, eviction_policy: CacheEvictionPolicy) -> BlockCache {
+    let probe = BlockCache::new(1 << 20);
+    probe.insert((0, 0), block_of(100));
+    BlockCache::with_options(BlockCacheOptions {
+        capacity: probe.usage() * num_blocks,
+        num_shards: 1,
+        eviction_policy,
+        high_priority_ratio: 0.5,
+    })
+}
+
+#[test]
+fn test_block_cache_charges_bytes() {
+    let cache = BlockCache::new(1 << 20);
+    cache.insert((1, 0), block_of(100));
+    let small = cache.usage();
+    cache.insert((1, 1), block_of(1000));
+    assert_eq!(cache.usage() - small, small + 900);
+    // replacing a block charges the new block only
+    cache.insert((1, 1), block_of(100));
+    assert_eq!(cache.usage(), small * 2);
+    cache.remove(&(1, 1)).unwrap();
+    assert_eq!(cache.usage(), small);
+
+    // a block larger than a shard is not cached
+    let cache = BlockCache::with_options(BlockCacheOptions {
+        num_shards: 1,
+        ..BlockCacheOptions::new(1024)
+    });
+    cache.insert((1, 0), block_of(2000));
+    assert!(!cache.contains_key(&(1, 0)));
+    assert_eq!(cache.usage(), 0);
+}
+
+#[test]
+fn test_block_cache_lru_eviction() {
+    let cache = cache_of(3, CacheEvictionPolicy::Lru);
+    for idx in 0..3 {
+        cache.insert((1, idx), block_of(100));
+    }
+    assert!(cache.get(&(1, 0)).is_some());
+    cache.insert((1, 3), block_of(100));
+    assert!(cache.contains_key(&(1, 0)));
+    assert!(!cache.contains_key(&(1, 1)));
+    assert!(cache.contains_key(&(1, 2)));
+    assert!(cache.contains_key(&(1, 3)));
+    assert!(cache.usage() <= cache.options().capacity);
+}
+
+#[test]
+fn test_block_cache_clock_eviction() {
+    let cache = cache_of(3, CacheEvictionPolicy::Clock);
+    for idx in 0..3 {
+        cache.insert((1, idx), block_of(100));
+    }
+    assert!(cache.get(&(1, 0)).is_some());
+    assert!(cache.get(&(1, 2)).is_some());
+    cache.insert((1, 3), block_of(100));
+    assert!(cache.contains_key(&(1, 0)));
+    assert!(!cache.contains_key(&(1, 1)));
+    assert!(cache.contains_key(&(1, 2)));
+    // the hand clears the reference bit of block 2 and evicts block 3, which was never read
+    cache.insert((1, 4), block_of(100));
+    assert!(cache.contains_key(&(1, 0)));
+    assert!(cache.contains_key(&(1, 2)));
+    assert!(!cache.contains_key(&(1, 3)));
+    assert!(cache.contains_key(&(1, 4)));
+}
+
+#[test]
+fn test_block_cache_high_priority() {
+    for eviction_policy in [CacheEvictionPolicy::Lru, CacheEvictionPolicy::Clock] {
+        let cache = cache_of(4, eviction_policy);
+        cache.insert_with_priority((1, 0), block_of(100), CachePriority::High);
+        for idx in 1..20 {
+            cache.insert((1, idx), block_of(100));
+        }
+        assert!(cache.contains_key(&(1, 0)));
+        assert_eq!(
+            (1..20).filter(|idx| cache.contains_key(&(1, *idx))).count(),
+            3
+        );
+
+        // high-priority blocks beyond their share of the cache are evicted first
+        for idx in 20..24 {
+            cache.insert_with_priority((1, idx), block_of(100), CachePriority::High);
+        }
+        cache.insert((1, 24), block_of(100));
+        assert!(cache.contains_key(&(1, 24)));
+        assert!(!cache.contains_key(&(1, 0)));
+    }
+}
+
+#[test]
+fn test_block_cache_stats() {
+    let cache = cache_of(2, CacheEvictionPolicy::Lru);
+    assert!(cache.get(&(1, 0)).is_none());
+    let block = cache.try_get_with((1, 0), || Ok(block_of(100))).unwrap();
+    assert!(Arc::ptr_eq(
+        &block,
+        &cache.try_get_with((1, 0), || unreachable!()).unwrap()
+    ));
+    cache.insert((1, 1), block_of(100));
+    cache.insert((1, 2), block_of(100));
+    assert!(cache
+        .try_get_with((1, 3), || anyhow::bail!("io error"))
+        .is_err());
+    assert!(!cache.contains_key(&(1, 3)));
+
+    let stats = cache.stats();
+    assert_eq!(stats.hits, 1);
+    assert_eq!(stats.misses, 3);
+    assert_eq!(stats.inserts, 3);
+    assert_eq!(stats.evictions, 1);
+    assert_eq!(stats.usage, cache.usage());
+    assert_eq!(stats.capacity, cache.options().capacity);
+}
+
+#[test]
+fn test_block_cache_shared_handles() {
+    let cache = BlockCache::new(1 << 20);
+    let other = cache.share();
+    cache.insert((1, 0), block_of(100));
+    other.insert((1, 0), block_of(200));
+    assert_eq!(
+        cache.get(&(1, 0)).unwrap().data.len() + 100,
+        other.get(&(1, 0)).unwrap().data.len()
+    );
+    assert_eq!(cache.usage(), other.usage());
+    assert_eq!(cache.stats(), other.stats());
+}
+
+#[test]
+fn test_block_cache_shared_by_storage_engines() {
+    let block_cache = Arc::new(BlockCache::new(64 << 20));
+    let dirs = [tempdir().unwrap(), tempdir().unwrap()];
+    let storages = dirs
+        .iter()
+        .map(|dir| {
+            let mut options =
+                LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+            options.block_cache = Some(block_cache.clone());
+            MiniLsm::open(dir.path(), options).unwrap()
+        })
+        .collect::<Vec<_>>();
+    // both engines flush their first memtable to SST 1
+    for (idx, storage) in storages.iter().enumerate() {
+        for key in 0..100 {
+            storage
+                .put(
+                    format!("key_{:03}", key).as_bytes(),
+                    format!("value_{}_{}", idx, key).as_bytes(),
+                )
+                .unwrap();
+        }
+        storage.force_flush().unwrap();
+    }
+    for _ in 0..2 {
+        for (idx, storage) in storages.iter().enumerate() {
+            for key in 0..100 {
+                assert_eq!(
+                    storage
+                        .get(format!("key_{:03}", key).as_bytes())
+                        .unwrap()
+                        .unwrap(),
+                    format!("value_{}_{}", idx, key).as_bytes()
+                );
+            }
+        }
+    }
+    let stats = block_cache.stats();
+    assert!(stats.hits > 0);
+    assert!(stats.usage > 0);
+    assert_eq!(stats, storages[0].block_cache().stats());
+}
@@ -0,0 +1,242 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use tempfile::tempdir;
+
+use crate::{
+    background_error::{BackgroundError, BackgroundErrorSeverity},
+    compact::{CompactionOptions, CompactionTask},
+    event_listener::{
+        BackgroundErrorReason, CompactionBeginInfo, CompactionCompletedInfo, EventListener,
+        FlushBeginInfo, FlushCompletedInfo, MemTableSealedInfo, SstDeletedInfo,
+        WriteStallCondition, WriteStallInfo,
+    },
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    write_buffer_manager::WriteBufferManager,
+};
+
+#[derive(Debug, PartialEq)]
+enum Event {
+    Sealed(usize),
+    FlushBegin(usize),
+    FlushCompleted(usize, Vec<u8>, Vec<u8>),
+    CompactionBegin(Vec<usize>),
+    CompactionCompleted(Vec<usize>, Vec<usize>),
+    Deleted(usize),
+    Stall(WriteStallCondition),
+    Error(BackgroundErrorReason),
+    Resumed(BackgroundErrorReason),
+}
+
+#[derive(Default)]
+struct RecordingListener {
+    events: Mutex<Vec<Event>>,
+}
+
+impl RecordingListener {
+    fn take(&self) -> Vec<Event> {
+        std::mem::take(&mut *self.events.lock())
+    }
+}
+
+impl EventListener for RecordingListener {
+    fn on_memtable_sealed(&self, info: &MemTableSealedInfo) {
+        assert!(info.num_imm_memtables > 0);
+        self.events.lock().push(Event::Sealed(info.memtable_id));
+    }
+
+    fn on_flush_begin(&self, info: &FlushBeginInfo) {
+        assert!(info.memtable_size > 0);
+        self.events.lock().push(Event::FlushBegin(info.sst_id));
+    }
+
+    fn on_flush_completed(&self, info: &FlushCompletedInfo) {
+        assert!(info.file_size > 0);
+        self.events.lock().push(Event::FlushCompleted(
+            info.sst_id,
+            info.first_key.to_vec(),
+            info.last_key.to_vec(),
+        ));
+    }
+
+    fn on_compaction_begin(&self, info: &CompactionBeginInfo) {
+        assert!(info.input_bytes > 0);
+        self.events
+            .lock()
+            .push(Event::CompactionBegin(info.input_sst_ids.clone()));
+    }
+
+    fn on_compaction_completed(&self, info: &CompactionCompletedInfo) {
+        assert!(matches!(
+            info.task,
+            CompactionTask::ForceFullCompaction { .. }
+        ));
+        assert!(info.output_bytes > 0);
+        self.events.lock().push(Event::CompactionCompleted(
+            info.input_sst_ids.clone(),
+            info.output_sst_ids.clone(),
+        ));
+    }
+
+    fn on_sst_deleted(&self, info: &SstDeletedInfo) {
+        assert!(!info.path.exists());
+        self.events.lock().push(Event::Deleted(info.sst_id));
+    }
+
+    fn on_stall_conditions_changed(&self, info: &WriteStallInfo) {
+        assert_ne!(info.previous, info.current);
+        self.events.lock().push(Event::Stall(info.current));
+    }
+
+    fn on_background_error(
+        &self,
+        reason: BackgroundErrorReason,
+        _severity: BackgroundErrorSeverity,
+        _error: &anyhow::Error,
+    ) {
+        self.events.lock().push(Event::Error(reason));
+    }
+
+    fn on_background_error_resumed(&self, error: &BackgroundError) {
+        self.events.lock().push(Event::Resumed(error.reason));
+    }
+}
+
+fn options_with(listener: &Arc<RecordingListener>) -> LsmStorageOptions {
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.event_listeners = vec![listener.clone()];
+    options
+}
+
+#[test]
+fn test_event_listener_flush_and_compaction() {
+    let dir = tempdir().unwrap();
+    let listener = Arc::new(RecordingListener::default());
+    let storage = MiniLsm::open(&dir, options_with(&listener)).unwrap();
+    let mut sst_ids = Vec::new();
+    for (first, last) in [(b"a", b"c"), (b"b", b"d")] {
+        storage.put(first, b"1").unwrap();
+        storage.put(last, b"1").unwrap();
+        let sst_id = storage.inner.state.read().memtable.id();
+        storage.force_flush().unwrap();
+        assert_eq!(
+            listener.take(),
+            vec![
+                Event::Sealed(sst_id),
+                Event::FlushBegin(sst_id),
+                Event::FlushCompleted(sst_id, first.to_vec(), last.to_vec()),
+            ]
+        );
+        sst_ids.insert(0, sst_id);
+    }
+
+    storage.force_full_compaction().unwrap();
+    let events = listener.take();
+    let output = storage.inner.state.read().levels[0].1.clone();
+    assert_eq!(
+        events,
+        vec![
+            Event::CompactionBegin(sst_ids.clone()),
+            Event::Deleted(sst_ids[0]),
+            Event::Deleted(sst_ids[1]),
+            Event::CompactionCompleted(sst_ids, output),
+        ]
+    );
+}
+
+#[test]
+fn test_event_listener_background_error() {
+    let dir = tempdir().unwrap();
+    let listener = Arc::new(RecordingListener::default());
+    let mut options = options_with(&listener);
+    options.num_memtable_limit = 1;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    // the flush thread cannot create the SST in a directory that is gone
+    std::fs::remove_dir_all(&dir).unwrap();
+    storage.put(b"a", b"1").unwrap();
+    storage
+        .inner
+        .force_freeze_memtable(&storage.inner.state_lock.lock())
+        .ok();
+    for _ in 0..100 {
+        if listener
+            .events
+            .lock()
+            .contains(&Event::Error(BackgroundErrorReason::Flush))
+        {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    let events = listener.take();
+    assert!(
+        events.contains(&Event::Error(BackgroundErrorReason::Flush)),
+        "the flush error is not reported: {:?}",
+        events
+    );
+
+    std::fs::create_dir_all(&dir).unwrap();
+    storage.resume().unwrap();
+    assert!(listener
+        .take()
+        .contains(&Event::Resumed(BackgroundErrorReason::Flush)));
+}
+
+#[test]
+fn test_event_listener_write_stall() {
+    let manager = Arc::new(WriteBufferManager::new(256 << 10, true));
+    let listeners = (0..3)
+        .map(|_| Arc::new(RecordingListener::default()))
+        .collect::<Vec<_>>();
+    let dirs = (0..3).map(|_| tempdir().unwrap()).collect::<Vec<_>>();
+    let engines = dirs
+        .iter()
+        .zip(&listeners)
+        .map(|(dir, listener)| {
+            let mut options = options_with(listener);
+            options.target_sst_size = 8 << 20;
+            options.num_memtable_limit = 50;
+            options.write_buffer_manager = Some(manager.clone());
+            MiniLsm::open(dir, options).unwrap()
+        })
+        .collect::<Vec<_>>();
+    std::thread::scope(|scope| {
+        for storage in &engines {
+            scope.spawn(move || {
+                for idx in 0..5000 {
+                    storage
+                        .put(
+                            format!("key_{:05}", idx).as_bytes(),
+                            format!("value_{:0100}", idx).as_bytes(),
+                        )
+                        .unwrap();
+                }
+            });
+        }
+    });
+
+    assert!(manager.stats().stalls > 0);
+    let stalls = listeners
+        .iter()
+        .map(|listener| {
+            let stalls = listener
+                .take()
+                .into_iter()
+                .filter_map(|event| match event {
+                    Event::Stall(condition) => Some(condition),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            // every stall ends before the write goes on
+            for pair in stalls.chunks(2) {
+                assert_eq!(
+                    pair,
+                    [WriteStallCondition::Stopped, WriteStallCondition::Normal]
+                );
+            }
+            stalls.len() / 2
+        })
+        .sum::<usize>();
+    assert!(stalls > 0);
+}
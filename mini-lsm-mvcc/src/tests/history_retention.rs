@@ -0,0 +1,95 @@
+use std::{ops::Bound, time::Duration};
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    lsm_storage::{HistoryRetention, LsmStorageOptions, MiniLsm},
+    tests::harness::check_lsm_iter_result_by_key,
+};
+
+#[test]
+fn test_time_travel_with_timestamp_retention() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.history_retention = Some(HistoryRetention::Timestamps(5));
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    let mut commit_ts = Vec::new();
+    for i in 0..10 {
+        storage.put(b"a", format!("{}", i).as_bytes()).unwrap();
+        storage.put(b"b", format!("{}", i).as_bytes()).unwrap();
+        commit_ts.push(storage.inner.mvcc().latest_commit_ts());
+    }
+    storage.force_flush().unwrap();
+    storage.force_full_compaction().unwrap();
+
+    // the last 5 timestamps are still readable after compaction
+    let latest_ts = storage.inner.mvcc().latest_commit_ts();
+    for (i, ts) in commit_ts.iter().enumerate() {
+        if *ts >= latest_ts - 5 {
+            assert_eq!(
+                storage.get_at(b"b", *ts).unwrap(),
+                Some(Bytes::from(format!("{}", i)))
+            );
+        } else {
+            assert!(storage.get_at(b"b", *ts).is_err());
+        }
+    }
+    // `a` was overwritten by the write to `b` at this ts
+    check_lsm_iter_result_by_key(
+        &mut storage
+            .scan_at(Bound::Unbounded, Bound::Unbounded, latest_ts - 1)
+            .unwrap(),
+        vec![
+            (Bytes::from("a"), Bytes::from("9")),
+            (Bytes::from("b"), Bytes::from("8")),
+        ],
+    );
+    assert!(storage.get_at(b"a", latest_ts + 1).is_err());
+}
+
+#[test]
+fn test_time_travel_with_duration_retention() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.history_retention = Some(HistoryRetention::Duration(Duration::from_millis(200)));
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    storage.put(b"a", b"1").unwrap();
+    let ts = storage.inner.mvcc().latest_commit_ts();
+    storage.put(b"a", b"2").unwrap();
+    storage.force_flush().unwrap();
+    storage.force_full_compaction().unwrap();
+    assert_eq!(storage.get_at(b"a", ts).unwrap(), Some(Bytes::from("1")));
+
+    std::thread::sleep(Duration::from_millis(300));
+    assert!(storage.get_at(b"a", ts).is_err());
+    assert_eq!(
+        storage.get_at(b"a", ts + 1).unwrap(),
+        Some(Bytes::from("2"))
+    );
+    storage.put(b"a", b"3").unwrap();
+    storage.force_flush().unwrap();
+    storage.force_full_compaction().unwrap();
+    assert_eq!(
+        storage.get_at(b"a", ts + 1).unwrap(),
+        Some(Bytes::from("2"))
+    );
+}
+
+#[test]
+fn test_duration_retention_forgets_old_commit_times() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.history_retention = Some(HistoryRetention::Duration(Duration::from_millis(200)));
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    for i in 0..100 {
+        storage.put(b"a", format!("{}", i).as_bytes()).unwrap();
+    }
+    std::thread::sleep(Duration::from_millis(300));
+    for i in 0..100 {
+        storage.put(b"b", format!("{}", i).as_bytes()).unwrap();
+    }
+    // nothing reads the watermark without compaction, and the commits before the window are gone anyway
+    assert!(storage.inner.mvcc().num_commit_times() <= 101);
+}
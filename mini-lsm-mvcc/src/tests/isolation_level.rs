@@ -0,0 +1,135 @@
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    mvcc::txn::IsolationLevel,
+};
+
+#[test]
+fn test_read_committed_sees_new_commits() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    storage.put(b"a", b"1").unwrap();
+    let rc = storage
+        .new_txn_with_isolation(IsolationLevel::ReadCommitted)
+        .unwrap();
+    let si = storage
+        .new_txn_with_isolation(IsolationLevel::Snapshot)
+        .unwrap();
+    assert_eq!(rc.get(b"a").unwrap(), Some(Bytes::from("1")));
+    assert_eq!(si.get(b"a").unwrap(), Some(Bytes::from("1")));
+    storage.put(b"a", b"2").unwrap();
+    assert_eq!(rc.get(b"a").unwrap(), Some(Bytes::from("2")));
+    assert_eq!(si.get(b"a").unwrap(), Some(Bytes::from("1")));
+    rc.put(b"a", b"3");
+    assert_eq!(rc.get(b"a").unwrap(), Some(Bytes::from("3")));
+    // no conflict check in read committed
+    rc.commit().unwrap();
+    assert_eq!(storage.get(b"a").unwrap(), Some(Bytes::from("3")));
+}
+
+#[test]
+fn test_snapshot_last_writer_wins() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    let txn1 = storage.new_txn().unwrap();
+    let txn2 = storage.new_txn().unwrap();
+    assert_eq!(txn1.isolation(), IsolationLevel::Snapshot);
+    txn1.put(b"a", b"1");
+    txn2.put(b"a", b"2");
+    // no conflict check, as before the isolation levels
+    txn1.commit().unwrap();
+    txn2.commit().unwrap();
+    assert_eq!(storage.get(b"a").unwrap(), Some(Bytes::from("2")));
+
+    let txn3 = storage.new_txn().unwrap();
+    txn3.put(b"b", b"3");
+    storage.put(b"b", b"4").unwrap();
+    txn3.commit().unwrap();
+    assert_eq!(storage.get(b"b").unwrap(), Some(Bytes::from("3")));
+}
+
+#[test]
+fn test_read_committed_pins_nothing() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    storage.put(b"a", b"1").unwrap();
+    let rc = storage
+        .new_txn_with_isolation(IsolationLevel::ReadCommitted)
+        .unwrap();
+    storage.put(b"a", b"2").unwrap();
+    let latest_ts = storage.inner.mvcc().latest_commit_ts();
+    assert_eq!(storage.inner.mvcc().watermark(), latest_ts);
+    assert_eq!(rc.get(b"a").unwrap(), Some(Bytes::from("2")));
+
+    let si = storage.new_txn().unwrap();
+    storage.put(b"a", b"3").unwrap();
+    assert_eq!(storage.inner.mvcc().watermark(), latest_ts);
+    drop(si);
+    assert_eq!(
+        storage.inner.mvcc().watermark(),
+        storage.inner.mvcc().latest_commit_ts()
+    );
+}
+
+#[test]
+fn test_plain_writes_recorded_for_serializable() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    // no txn checks the writes outside a transaction, so they are not recorded
+    let si = storage.new_txn().unwrap();
+    storage.put(b"a", b"1").unwrap();
+    assert!(storage.inner.mvcc().committed_txns.lock().is_empty());
+    drop(si);
+
+    let txn = storage
+        .new_txn_with_isolation(IsolationLevel::Serializable)
+        .unwrap();
+    assert_eq!(txn.get(b"a").unwrap(), Some(Bytes::from("1")));
+    txn.put(b"b", b"1");
+    storage.put(b"a", b"2").unwrap();
+    assert_eq!(storage.inner.mvcc().committed_txns.lock().len(), 1);
+    assert!(txn.commit().is_err());
+    assert_eq!(storage.get(b"b").unwrap(), None);
+}
+
+#[test]
+fn test_isolation_level_per_txn_write_skew() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    storage.put(b"a", b"1").unwrap();
+    storage.put(b"b", b"1").unwrap();
+
+    // snapshot isolation allows write skew
+    let txn1 = storage.new_txn().unwrap();
+    let txn2 = storage.new_txn().unwrap();
+    txn1.get(b"b").unwrap();
+    txn1.put(b"a", b"0");
+    txn2.get(b"a").unwrap();
+    txn2.put(b"b", b"0");
+    txn1.commit().unwrap();
+    txn2.commit().unwrap();
+
+    // serializable does not, even if the storage is not serializable by default
+    let txn1 = storage
+        .new_txn_with_isolation(IsolationLevel::Serializable)
+        .unwrap();
+    let txn2 = storage
+        .new_txn_with_isolation(IsolationLevel::Serializable)
+        .unwrap();
+    txn1.get(b"b").unwrap();
+    txn1.put(b"a", b"1");
+    txn2.get(b"a").unwrap();
+    txn2.put(b"b", b"1");
+    txn1.commit().unwrap();
+    assert!(txn2.commit().is_err());
+    assert_eq!(storage.get(b"a").unwrap(), Some(Bytes::from("1")));
+    assert_eq!(storage.get(b"b").unwrap(), Some(Bytes::from("0")));
+}
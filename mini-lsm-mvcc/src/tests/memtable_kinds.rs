@@ -0,0 +1,219 @@
+use std::ops::Bound;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    iterators::StorageIterator,
+    key::KeySlice,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    mem_table::{MemTable, MemTableKind},
+};
+
+use super::harness::check_iter_result_by_key_and_ts;
+
+const KINDS: [MemTableKind; 4] = [
+    MemTableKind::SkipMap,
+    MemTableKind::ArenaSkipList,
+    MemTableKind::Vector,
+    MemTableKind::HashPrefix { prefix_len: 2 },
+];
+
+fn key_of(idx: usize) -> Vec<u8> {
+    format!("{:02}_key_{:05}", idx % 7, idx).into_bytes()
+}
+
+fn value_of(idx: usize) -> Vec<u8> {
+    format!("value_{:010}", idx).into_bytes()
+}
+
+fn collect(
+    memtable: &MemTable,
+    lower: Bound<KeySlice>,
+    upper: Bound<KeySlice>,
+) -> Vec<(Vec<u8>, u64)> {
+    let mut iter = memtable.scan(lower, upper);
+    let mut keys = Vec::new();
+    while iter.is_valid() {
+        keys.push((iter.key().key_ref().to_vec(), iter.key().ts()));
+        iter.next().unwrap();
+    }
+    keys
+}
+
+#[test]
+fn test_memtable_kinds_get_and_scan() {
+    for kind in KINDS {
+        let memtable = MemTable::new(0, kind, None);
+        memtable.put(KeySlice::from_slice(b"b", 1), b"b1").unwrap();
+        memtable
+            .put(KeySlice::from_slice(b"abc", 2), b"abc2")
+            .unwrap();
+        memtable.put(KeySlice::from_slice(b"b", 3), b"b3").unwrap();
+        memtable.put(KeySlice::from_slice(b"a", 1), b"a1").unwrap();
+        memtable
+            .put(KeySlice::from_slice(b"abc", 2), b"abc2_new")
+            .unwrap();
+
+        for frozen in [false, true] {
+            if frozen {
+                memtable.freeze();
+            }
+            assert_eq!(
+                memtable.get(KeySlice::from_slice(b"abc", 2)),
+                Some(Bytes::from("abc2_new")),
+                "{kind:?}"
+            );
+            assert_eq!(
+                memtable.get(KeySlice::from_slice(b"b", 2)),
+                None,
+                "{kind:?}"
+            );
+            assert_eq!(
+                memtable.get(KeySlice::from_slice(b"c", 1)),
+                None,
+                "{kind:?}"
+            );
+            let mut iter = memtable.scan(Bound::Unbounded, Bound::Unbounded);
+            check_iter_result_by_key_and_ts(
+                &mut iter,
+                vec![
+                    ((Bytes::from("a"), 1), Bytes::from("a1")),
+                    ((Bytes::from("abc"), 2), Bytes::from("abc2_new")),
+                    ((Bytes::from("b"), 3), Bytes::from("b3")),
+                    ((Bytes::from("b"), 1), Bytes::from("b1")),
+                ],
+            );
+            assert_eq!(
+                collect(
+                    &memtable,
+                    Bound::Excluded(KeySlice::from_slice(b"a", 1)),
+                    Bound::Excluded(KeySlice::from_slice(b"b", 1)),
+                ),
+                vec![(b"abc".to_vec(), 2), (b"b".to_vec(), 3)],
+                "{kind:?}"
+            );
+        }
+    }
+}
+
+#[test]
+fn test_memtable_kinds_flush_order() {
+    let mut expected = None;
+    for kind in KINDS {
+        let memtable = MemTable::new(0, kind, None);
+        for idx in (0..500).rev() {
+            memtable
+                .put(KeySlice::from_slice(&key_of(idx), 1), &value_of(idx))
+                .unwrap();
+        }
+        memtable.freeze();
+        let mut entries = Vec::new();
+        memtable.for_each(|key, value| entries.push((key.key_ref().to_vec(), value.to_vec())));
+        assert_eq!(entries.len(), 500);
+        assert!(
+            entries.windows(2).all(|pair| pair[0].0 < pair[1].0),
+            "{kind:?}"
+        );
+        // every kind holds the same entries in the same order
+        assert_eq!(
+            expected.get_or_insert_with(|| entries.clone()),
+            &entries,
+            "{kind:?}"
+        );
+
+        let lower = key_of(100);
+        let upper = key_of(200);
+        let keys = collect(
+            &memtable,
+            Bound::Included(KeySlice::from_slice(&lower, 1)),
+            Bound::Included(KeySlice::from_slice(&upper, 1)),
+        );
+        assert!(keys.iter().all(|(key, _)| key >= &lower && key <= &upper));
+        assert_eq!(
+            keys.len(),
+            entries
+                .iter()
+                .filter(|(key, _)| key >= &lower && key <= &upper)
+                .count()
+        );
+    }
+}
+
+#[test]
+fn test_memtable_kinds_scans_between_writes() {
+    for kind in KINDS {
+        let memtable = MemTable::new(0, kind, None);
+        for ts in 1..=3 {
+            for idx in 0..50 {
+                memtable
+                    .put(KeySlice::from_slice(&key_of(idx), ts), &value_of(idx))
+                    .unwrap();
+                // the versions of a single key, as read by a point lookup
+                let versions = collect(
+                    &memtable,
+                    Bound::Included(KeySlice::from_slice(&key_of(idx), u64::MAX)),
+                    Bound::Included(KeySlice::from_slice(&key_of(idx), 0)),
+                );
+                let expected = (1..=ts)
+                    .rev()
+                    .map(|ts| (key_of(idx), ts))
+                    .collect::<Vec<_>>();
+                assert_eq!(versions, expected, "{kind:?}");
+            }
+            // a scan sees the writes since the previous scan
+            let keys = collect(&memtable, Bound::Unbounded, Bound::Unbounded);
+            assert_eq!(keys.len(), 50 * ts as usize, "{kind:?}");
+            assert!(keys.contains(&(key_of(49), ts)), "{kind:?}");
+        }
+    }
+}
+
+#[test]
+fn test_storage_with_memtable_kinds() {
+    for kind in KINDS {
+        let dir = tempdir().unwrap();
+        let mut options =
+            LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+        options.memtable_kind = kind;
+        options.enable_wal = true;
+        options.target_sst_size = 1 << 14;
+        {
+            let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+            for idx in 0..1000 {
+                storage.put(&key_of(idx), &value_of(idx)).unwrap();
+            }
+            for idx in (0..1000).step_by(3) {
+                storage.delete(&key_of(idx)).unwrap();
+            }
+            assert_eq!(
+                storage.get(&key_of(1)).unwrap(),
+                Some(Bytes::from(value_of(1)))
+            );
+            assert_eq!(storage.get(&key_of(3)).unwrap(), None);
+            storage.close().unwrap();
+        }
+
+        // the memtables are recovered from the WAL, then flushed
+        let storage = MiniLsm::open(&dir, options).unwrap();
+        assert_eq!(storage.inner.state.read().memtable.kind(), kind);
+        storage.force_flush().unwrap();
+        for idx in 0..1000 {
+            let expected = (idx % 3 != 0).then(|| Bytes::from(value_of(idx)));
+            assert_eq!(storage.get(&key_of(idx)).unwrap(), expected, "{kind:?}");
+        }
+        let mut expected = (0..1000)
+            .filter(|idx| idx % 3 != 0)
+            .map(key_of)
+            .collect::<Vec<_>>();
+        expected.sort();
+        let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+        for key in expected {
+            assert!(iter.is_valid());
+            assert_eq!(iter.key(), key.as_slice(), "{kind:?}");
+            iter.next().unwrap();
+        }
+        assert!(!iter.is_valid());
+    }
+}
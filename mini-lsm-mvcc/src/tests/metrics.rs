@@ -0,0 +1,115 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    statistics::Ticker,
+};
+
+fn http_get(storage: &MiniLsm, path: &str) -> String {
+    let mut stream = TcpStream::connect(storage.metrics_addr().unwrap()).unwrap();
+    write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+fn metric(metrics: &str, sample: &str) -> Option<String> {
+    metrics
+        .lines()
+        .find_map(|line| line.strip_prefix(sample)?.strip_prefix(' '))
+        .map(str::to_string)
+}
+
+#[test]
+fn test_metrics_endpoint() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.metrics_addr = Some("127.0.0.1:0".parse().unwrap());
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    for idx in 0..100 {
+        storage
+            .put(format!("key_{idx:03}").as_bytes(), b"value")
+            .unwrap();
+    }
+    storage.force_flush().unwrap();
+    storage.put(b"key_100", b"value").unwrap();
+    storage.get(b"key_001").unwrap();
+
+    let response = http_get(&storage, "/metrics");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    let (_, metrics) = response.split_once("\r\n\r\n").unwrap();
+    assert_eq!(metrics, storage.prometheus_metrics());
+    assert_eq!(
+        metric(metrics, "mini_lsm_level_ssts{level=\"0\"}").unwrap(),
+        "1"
+    );
+    let l0_bytes = metric(metrics, "mini_lsm_level_bytes{level=\"0\"}").unwrap();
+    assert!(l0_bytes.parse::<u64>().unwrap() > 0);
+    assert_eq!(
+        metric(metrics, "mini_lsm_level_ssts{level=\"1\"}").unwrap(),
+        "0"
+    );
+    assert_eq!(
+        metric(metrics, "mini_lsm_memtables{state=\"immutable\"}").unwrap(),
+        "0"
+    );
+    assert_eq!(metric(metrics, "mini_lsm_latest_commit_ts").unwrap(), "101");
+    assert_eq!(metric(metrics, "mini_lsm_mvcc_watermark").unwrap(), "101");
+    assert_eq!(
+        metric(metrics, "mini_lsm_compaction_pending_ssts").unwrap(),
+        "0"
+    );
+    assert_eq!(
+        metric(metrics, "mini_lsm_keys_written_total").unwrap(),
+        "101"
+    );
+    assert_eq!(metric(metrics, "mini_lsm_get_micros_count").unwrap(), "1");
+    assert!(metric(metrics, "mini_lsm_block_cache_misses_total").is_some());
+    assert!(metrics.contains("# TYPE mini_lsm_get_micros summary\n"));
+    // the row cache is disabled
+    assert!(metric(metrics, "mini_lsm_row_cache_hits_total").is_none());
+
+    let response = http_get(&storage, "/");
+    assert!(
+        response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+        "{response}"
+    );
+}
+
+#[test]
+fn test_metrics_endpoint_disabled() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert!(storage.metrics_addr().is_none());
+    assert!(storage
+        .prometheus_metrics()
+        .contains("mini_lsm_memtables{state=\"mutable\"} 1\n"));
+}
+
+#[test]
+fn test_metrics_endpoint_counts_failed_scrapes() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.metrics_addr = Some("127.0.0.1:0".parse().unwrap());
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    // a scraper that never sends its request times out
+    let _stream = TcpStream::connect(storage.metrics_addr().unwrap()).unwrap();
+    for _ in 0..100 {
+        if storage.inner.statistics.ticker(Ticker::MetricsScrapeErrors) > 0 {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    assert_eq!(
+        storage.inner.statistics.ticker(Ticker::MetricsScrapeErrors),
+        1
+    );
+    let response = http_get(&storage, "/metrics");
+    assert!(response.contains("mini_lsm_metrics_scrape_errors_total 1\n"));
+}
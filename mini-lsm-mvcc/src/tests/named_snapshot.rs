@@ -0,0 +1,85 @@
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    mvcc::txn::IsolationLevel,
+};
+
+#[test]
+fn test_named_snapshot_survives_restart() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"a", b"1").unwrap();
+    let ts = storage.create_named_snapshot("backup").unwrap();
+    assert!(storage.create_named_snapshot("backup").is_err());
+    storage.put(b"a", b"2").unwrap();
+    storage.force_flush().unwrap();
+    storage.close().unwrap();
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    assert_eq!(storage.inner.mvcc().watermark(), ts);
+    // compaction uses the recovered watermark, so it keeps the version at `ts`
+    storage.put(b"a", b"3").unwrap();
+    let snapshots = storage.list_named_snapshots();
+    assert_eq!(snapshots.len(), 1);
+    assert_eq!(snapshots[0].name, "backup");
+    assert_eq!(snapshots[0].ts, ts);
+    assert!(snapshots[0].age() < std::time::Duration::from_secs(60));
+    assert_eq!(
+        storage.named_snapshot("backup").unwrap().get(b"a").unwrap(),
+        Some(Bytes::from("1"))
+    );
+    assert_eq!(storage.get_at(b"a", ts).unwrap(), Some(Bytes::from("1")));
+
+    storage.release_named_snapshot("backup").unwrap();
+    assert!(storage.release_named_snapshot("backup").is_err());
+    assert!(storage.named_snapshot("backup").is_err());
+    assert!(storage.get_at(b"a", ts).is_err());
+    storage.close().unwrap();
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert!(storage.list_named_snapshots().is_empty());
+    assert_eq!(
+        storage.inner.mvcc().watermark(),
+        storage.inner.mvcc().latest_commit_ts()
+    );
+}
+
+#[test]
+fn test_named_snapshot_does_not_pin_conflict_records() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    storage.put(b"a", b"0").unwrap();
+    storage.create_named_snapshot("backup").unwrap();
+    for idx in 0..100 {
+        let txn = storage.new_txn().unwrap();
+        txn.put(format!("key_{}", idx).as_bytes(), b"1");
+        txn.commit().unwrap();
+    }
+    // only the record of the last commit is left, the snapshot does not keep the others
+    assert!(storage.inner.mvcc().committed_txns.lock().len() <= 1);
+
+    // the records a live txn may conflict with are kept
+    let txn = storage
+        .new_txn_with_isolation(IsolationLevel::Serializable)
+        .unwrap();
+    assert_eq!(txn.get(b"key_3").unwrap(), Some(Bytes::from("1")));
+    for idx in 0..10 {
+        let other = storage.new_txn().unwrap();
+        other.put(format!("key_{}", idx).as_bytes(), b"2");
+        other.commit().unwrap();
+    }
+    assert!(storage.inner.mvcc().committed_txns.lock().len() >= 10);
+    txn.put(b"key_3", b"3");
+    assert!(txn.commit().is_err());
+    assert_eq!(
+        storage.named_snapshot("backup").unwrap().get(b"a").unwrap(),
+        Some(Bytes::from("0"))
+    );
+}
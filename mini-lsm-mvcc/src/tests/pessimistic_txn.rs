@@ -0,0 +1,121 @@
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+};
+
+#[test]
+fn test_pessimistic_hot_key() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    storage.put(b"counter", b"0").unwrap();
+    std::thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                for _ in 0..25 {
+                    let txn = storage.new_pessimistic_txn().unwrap();
+                    let value = txn.get_for_update(b"counter").unwrap().unwrap();
+                    let value = std::str::from_utf8(&value).unwrap().parse::<u64>().unwrap();
+                    txn.put(b"counter", (value + 1).to_string().as_bytes());
+                    txn.commit().unwrap();
+                }
+            });
+        }
+    });
+    assert_eq!(storage.get(b"counter").unwrap(), Some(Bytes::from("100")));
+    assert_eq!(storage.inner.mvcc().lock_manager.num_locked_keys(), 0);
+}
+
+#[test]
+fn test_pessimistic_lock_timeout() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.lock_timeout = Duration::from_millis(100);
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    let txn1 = storage.new_pessimistic_txn().unwrap();
+    let txn2 = storage.new_pessimistic_txn().unwrap();
+    txn1.put(b"key", b"1");
+    assert!(txn2.get_for_update(b"key").is_err());
+    txn2.put(b"key", b"2");
+    txn2.put(b"other", b"2");
+    assert!(txn2.commit().is_err());
+    txn1.commit().unwrap();
+    assert_eq!(storage.get(b"key").unwrap(), Some(Bytes::from("1")));
+    assert_eq!(storage.get(b"other").unwrap(), None);
+}
+
+#[test]
+fn test_pessimistic_deadlock() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.lock_timeout = Duration::from_secs(10);
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    let txn1 = storage.new_pessimistic_txn().unwrap();
+    let txn2 = storage.new_pessimistic_txn().unwrap();
+    txn1.put(b"a", b"1");
+    txn2.put(b"b", b"2");
+    std::thread::scope(|s| {
+        let handle = s.spawn(|| {
+            // waits for txn2
+            txn1.put(b"b", b"1");
+            txn1.commit().unwrap();
+        });
+        std::thread::sleep(Duration::from_millis(100));
+        let start = Instant::now();
+        assert!(txn2.get_for_update(b"a").is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+        // abort txn2 to unblock txn1
+        drop(txn2);
+        handle.join().unwrap();
+    });
+    assert_eq!(storage.get(b"a").unwrap(), Some(Bytes::from("1")));
+    assert_eq!(storage.get(b"b").unwrap(), Some(Bytes::from("1")));
+}
+
+#[test]
+fn test_pessimistic_no_lost_update() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    storage.put(b"counter", b"0").unwrap();
+    // a write outside a transaction does not wait for the key lock, so the commit catches it
+    let txn = storage.new_pessimistic_txn().unwrap();
+    assert_eq!(
+        txn.get_for_update(b"counter").unwrap(),
+        Some(Bytes::from("0"))
+    );
+    storage.put(b"counter", b"5").unwrap();
+    txn.put(b"counter", b"1");
+    assert!(txn.commit().is_err());
+    assert_eq!(storage.get(b"counter").unwrap(), Some(Bytes::from("5")));
+
+    // and so does an optimistic txn
+    let txn = storage.new_pessimistic_txn().unwrap();
+    let optimistic = storage.new_txn().unwrap();
+    assert_eq!(
+        txn.get_for_update(b"counter").unwrap(),
+        Some(Bytes::from("5"))
+    );
+    optimistic.put(b"counter", b"7");
+    optimistic.commit().unwrap();
+    txn.put(b"counter", b"6");
+    assert!(txn.commit().is_err());
+    assert_eq!(storage.get(b"counter").unwrap(), Some(Bytes::from("7")));
+
+    // writes before the key was locked are seen by `get_for_update`, so they are no conflict
+    let txn = storage.new_pessimistic_txn().unwrap();
+    storage.put(b"counter", b"8").unwrap();
+    assert_eq!(
+        txn.get_for_update(b"counter").unwrap(),
+        Some(Bytes::from("8"))
+    );
+    txn.put(b"counter", b"9");
+    txn.commit().unwrap();
+    assert_eq!(storage.get(b"counter").unwrap(), Some(Bytes::from("9")));
+    assert_eq!(storage.inner.mvcc().lock_manager.num_locked_keys(), 0);
+}
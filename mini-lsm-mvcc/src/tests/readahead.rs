@@ -0,0 +1,112 @@
+use std::{ops::Bound, sync::Arc};
+
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    iterators::StorageIterator,
+    key::KeySlice,
+    lsm_storage::{BlockCache, LsmStorageOptions, MiniLsm, ReadOptions},
+    table::{SsTable, SsTableBuilder, SsTableIterator, TableReadOptions},
+};
+
+fn key_of(idx: usize) -> Vec<u8> {
+    format!("key_{:05}", idx).into_bytes()
+}
+
+fn value_of(idx: usize) -> Vec<u8> {
+    format!("value_{:010}", idx).into_bytes()
+}
+
+fn build_table(path: &std::path::Path, block_cache: Arc<BlockCache>) -> Arc<SsTable> {
+    let mut builder = SsTableBuilder::new(128);
+    for idx in 0..2000 {
+        builder.add(
+            KeySlice::for_testing_from_slice_no_ts(&key_of(idx)),
+            &value_of(idx),
+        );
+    }
+    Arc::new(builder.build(1, Some(block_cache), path).unwrap())
+}
+
+fn check_scan(mut iter: SsTableIterator, start: usize) {
+    for idx in start..2000 {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key().for_testing_key_ref(), key_of(idx));
+        assert_eq!(iter.value(), value_of(idx));
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_scan_with_readahead() {
+    let dir = tempdir().unwrap();
+    for background_readahead in [false, true] {
+        let block_cache = Arc::new(BlockCache::new(1 << 20));
+        let table = build_table(&dir.path().join("1.sst"), block_cache.clone());
+        let options = TableReadOptions {
+            readahead_size: 4096,
+            fill_cache: false,
+            background_readahead,
+        };
+        check_scan(
+            SsTableIterator::create_and_seek_to_first_with_options(table.clone(), options.clone())
+                .unwrap(),
+            0,
+        );
+        let mut iter = SsTableIterator::create_and_seek_to_key_with_options(
+            table.clone(),
+            KeySlice::for_testing_from_slice_no_ts(&key_of(1500)),
+            options,
+        )
+        .unwrap();
+        // seeking back resets the readahead
+        iter.seek_to_key(KeySlice::for_testing_from_slice_no_ts(&key_of(100)))
+            .unwrap();
+        check_scan(iter, 100);
+        assert!((0..table.num_of_blocks()).all(|idx| !block_cache.contains_key(&(1, idx))));
+
+        let options = TableReadOptions {
+            readahead_size: 4096,
+            fill_cache: true,
+            background_readahead,
+        };
+        check_scan(
+            SsTableIterator::create_and_seek_to_first_with_options(table.clone(), options).unwrap(),
+            0,
+        );
+        assert!((0..table.num_of_blocks()).all(|idx| block_cache.contains_key(&(1, idx))));
+    }
+}
+
+#[test]
+fn test_scan_with_read_options() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.block_size = 128;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    for idx in 0..2000 {
+        storage.put(&key_of(idx), &value_of(idx)).unwrap();
+    }
+    storage.force_flush().unwrap();
+    let read_options = ReadOptions {
+        readahead_size: 8192,
+        fill_cache: false,
+        background_readahead: true,
+        ..Default::default()
+    };
+    let mut iter = storage
+        .scan_with_options(
+            Bound::Included(&key_of(10)),
+            Bound::Unbounded,
+            &read_options,
+        )
+        .unwrap();
+    for idx in 10..2000 {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key(), key_of(idx));
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
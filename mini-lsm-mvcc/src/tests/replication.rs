@@ -0,0 +1,144 @@
+use std::ops::Bound;
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+use tempfile::tempdir;
+
+use crate::{
+    compact::{CompactionOptions, SimpleLeveledCompactionOptions},
+    event_listener::EventListener,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    replication::{MiniLsmFollower, ReplicationAddr},
+    tests::harness::check_lsm_iter_result_by_key,
+};
+
+fn wait_for_ts(follower: &MiniLsmFollower, ts: u64) {
+    let start = Instant::now();
+    while follower.applied_ts() < ts {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "follower did not catch up: {:?}",
+            follower.lag()
+        );
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn test_follower_bootstrap_and_tail() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::Simple(
+        SimpleLeveledCompactionOptions {
+            size_ratio_percent: 200,
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 3,
+        },
+    ));
+    options.enable_wal = true;
+    let leader = MiniLsm::open(dir.path().join("leader"), options.clone()).unwrap();
+    leader.put(b"a", b"1").unwrap();
+    leader.put(b"b", b"1").unwrap();
+    leader.force_flush().unwrap();
+    leader.put(b"a", b"2").unwrap();
+    leader.delete(b"b").unwrap();
+    leader.put(b"c", b"2").unwrap();
+
+    let server = leader
+        .serve_replication(ReplicationAddr::Unix(dir.path().join("leader.sock")))
+        .unwrap();
+    let follower =
+        MiniLsmFollower::connect(dir.path().join("follower"), options, server.addr()).unwrap();
+    let leader_ts = leader.inner.mvcc().latest_commit_ts();
+    assert_eq!(follower.applied_ts(), leader_ts);
+    assert_eq!(follower.get(b"a").unwrap(), Some(Bytes::from("2")));
+    assert_eq!(follower.get(b"b").unwrap(), None);
+
+    leader.put(b"d", b"3").unwrap();
+    leader.delete(b"c").unwrap();
+    wait_for_ts(&follower, leader_ts + 2);
+    check_lsm_iter_result_by_key(
+        &mut follower.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        vec![
+            (Bytes::from("a"), Bytes::from("2")),
+            (Bytes::from("d"), Bytes::from("3")),
+        ],
+    );
+    assert_eq!(follower.lag().lag_ts(), 0);
+    assert_eq!(server.num_followers(), 1);
+    assert!(follower.replication_error().is_none());
+    follower.close().unwrap();
+}
+
+#[test]
+fn test_follower_over_tcp() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let leader = MiniLsm::open(dir.path().join("leader"), options.clone()).unwrap();
+    let server = leader
+        .serve_replication(ReplicationAddr::Tcp("127.0.0.1:0".parse().unwrap()))
+        .unwrap();
+    let follower =
+        MiniLsmFollower::connect(dir.path().join("follower"), options, server.addr()).unwrap();
+    for i in 0..100 {
+        leader
+            .put(format!("key{:03}", i).as_bytes(), b"value")
+            .unwrap();
+    }
+    wait_for_ts(&follower, leader.inner.mvcc().latest_commit_ts());
+    assert_eq!(follower.get(b"key042").unwrap(), Some(Bytes::from("value")));
+    drop(server);
+    follower.close().unwrap();
+}
+
+#[derive(Default)]
+struct ErrorListener {
+    errors: Mutex<Vec<String>>,
+}
+
+impl EventListener for ErrorListener {
+    fn on_replication_error(&self, error: &anyhow::Error) {
+        self.errors.lock().push(format!("{:#}", error));
+    }
+}
+
+#[test]
+fn test_leader_reports_and_joins_streams() {
+    let dir = tempdir().unwrap();
+    let listener = Arc::new(ErrorListener::default());
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.event_listeners = vec![listener.clone()];
+    let leader = MiniLsm::open(dir.path().join("leader"), options).unwrap();
+    leader.put(b"a", b"1").unwrap();
+    let inner_refs = Arc::strong_count(&leader.inner);
+    let server = leader
+        .serve_replication(ReplicationAddr::Unix(dir.path().join("leader.sock")))
+        .unwrap();
+
+    // a follower that goes away, the stream breaks on a later heartbeat
+    let ReplicationAddr::Unix(path) = server.addr() else {
+        unreachable!()
+    };
+    drop(UnixStream::connect(path).unwrap());
+    let start = Instant::now();
+    while listener.errors.lock().is_empty() {
+        assert!(start.elapsed() < Duration::from_secs(10));
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert!(listener.errors.lock()[0].contains("replication stream closed"));
+
+    let follower = MiniLsmFollower::connect(
+        dir.path().join("follower"),
+        LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction),
+        server.addr(),
+    )
+    .unwrap();
+    assert_eq!(follower.get(b"a").unwrap(), Some(Bytes::from("1")));
+    // the stream threads are joined, and the streams stopped by the leader are not errors
+    drop(server);
+    assert_eq!(Arc::strong_count(&leader.inner), inner_refs);
+    assert_eq!(listener.errors.lock().len(), 1);
+    follower.close().unwrap();
+}
@@ -0,0 +1,91 @@
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    row_cache::RowCache,
+};
+
+fn open_with_row_cache(dir: &std::path::Path) -> std::sync::Arc<MiniLsm> {
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.row_cache_size = 1 << 20;
+    MiniLsm::open(dir, options).unwrap()
+}
+
+#[test]
+fn test_row_cache_lookups() {
+    let dir = tempdir().unwrap();
+    let storage = open_with_row_cache(dir.path());
+    storage.put(b"a", b"1").unwrap();
+    storage.force_flush().unwrap();
+
+    assert_eq!(storage.get(b"a").unwrap(), Some(Bytes::from("1")));
+    assert_eq!(storage.get(b"a").unwrap(), Some(Bytes::from("1")));
+    // a key that does not exist is cached as well
+    assert_eq!(storage.get(b"b").unwrap(), None);
+    assert_eq!(storage.get(b"b").unwrap(), None);
+    let stats = storage.row_cache_stats().unwrap();
+    assert_eq!(stats.hits, 2);
+    assert_eq!(stats.misses, 2);
+    assert_eq!(stats.hit_rate(), 0.5);
+    assert!(stats.usage > 0);
+
+    // writes drop the cached lookups
+    storage.put(b"a", b"2").unwrap();
+    storage.put(b"b", b"2").unwrap();
+    assert_eq!(storage.get(b"a").unwrap(), Some(Bytes::from("2")));
+    assert_eq!(storage.get(b"b").unwrap(), Some(Bytes::from("2")));
+    storage.delete(b"a").unwrap();
+    assert_eq!(storage.get(b"a").unwrap(), None);
+    let txn = storage.new_txn().unwrap();
+    txn.put(b"b", b"3");
+    txn.commit().unwrap();
+    assert_eq!(storage.get(b"b").unwrap(), Some(Bytes::from("3")));
+    assert_eq!(storage.row_cache_stats().unwrap().invalidations, 4);
+
+    let storage = MiniLsm::open(
+        dir.path().join("no_cache"),
+        LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction),
+    )
+    .unwrap();
+    assert!(storage.row_cache_stats().is_none());
+}
+
+#[test]
+fn test_row_cache_read_ts() {
+    let dir = tempdir().unwrap();
+    let storage = open_with_row_cache(dir.path());
+    storage.put(b"a", b"1").unwrap();
+    let snapshot = storage.snapshot();
+    let txn = storage.new_txn().unwrap();
+    storage.put(b"a", b"2").unwrap();
+
+    // the lookup cached at the latest ts is not used by older readers
+    assert_eq!(storage.get(b"a").unwrap(), Some(Bytes::from("2")));
+    assert_eq!(storage.get(b"a").unwrap(), Some(Bytes::from("2")));
+    assert_eq!(snapshot.get(b"a").unwrap(), Some(Bytes::from("1")));
+    assert_eq!(txn.get(b"a").unwrap(), Some(Bytes::from("1")));
+}
+
+#[test]
+fn test_row_cache_skips_lookups_missing_writes() {
+    let cache = RowCache::new(1 << 20);
+    // a lookup at ts 4 may have missed the write at ts 5
+    cache.invalidate(b"a", 5);
+    cache.insert(b"a", Some(Bytes::from("1")), 4);
+    assert_eq!(cache.get(b"a", 5), None);
+    cache.insert(b"a", Some(Bytes::from("2")), 5);
+    assert_eq!(cache.get(b"a", 5), Some(Some(Bytes::from("2"))));
+    assert_eq!(cache.get(b"a", 4), None);
+
+    // entries are evicted to stay under the capacity
+    let cache = RowCache::new(16 * 1024);
+    for idx in 0..1000 {
+        cache.insert(format!("key_{}", idx).as_bytes(), None, 0);
+    }
+    let stats = cache.stats();
+    assert!(stats.usage <= stats.capacity);
+    assert!(stats.evictions > 0);
+    assert_eq!(stats.inserts, 1000);
+}
@@ -0,0 +1,107 @@
+use std::ops::Bound;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+};
+
+#[test]
+fn test_rollback_to_savepoint() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    storage.put(b"a", b"0").unwrap();
+    let txn = storage.new_txn().unwrap();
+    txn.put(b"a", b"1");
+    txn.set_savepoint().unwrap();
+    txn.put(b"a", b"2");
+    txn.put(b"b", b"2");
+    txn.set_savepoint().unwrap();
+    txn.delete(b"a");
+    txn.put(b"c", b"3");
+    assert_eq!(txn.get(b"a").unwrap(), None);
+
+    txn.rollback_to_savepoint().unwrap();
+    assert_eq!(txn.get(b"a").unwrap(), Some(Bytes::from("2")));
+    assert_eq!(txn.get(b"c").unwrap(), None);
+    txn.rollback_to_savepoint().unwrap();
+    assert_eq!(txn.get(b"a").unwrap(), Some(Bytes::from("1")));
+    assert_eq!(txn.get(b"b").unwrap(), None);
+    assert!(txn.rollback_to_savepoint().is_err());
+    txn.commit().unwrap();
+
+    assert_eq!(storage.get(b"a").unwrap(), Some(Bytes::from("1")));
+    assert_eq!(storage.get(b"b").unwrap(), None);
+    assert_eq!(storage.get(b"c").unwrap(), None);
+}
+
+#[test]
+fn test_rollback_to_savepoint_restores_key_sets() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.serializable = true;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    let txn1 = storage.new_txn().unwrap();
+    let txn2 = storage.new_txn().unwrap();
+    txn1.put(b"a", b"1");
+    txn1.set_savepoint().unwrap();
+    txn1.get(b"b").unwrap();
+    txn1.rollback_to_savepoint().unwrap();
+    txn2.put(b"b", b"2");
+    txn2.commit().unwrap();
+    // the read of `b` was rolled back
+    txn1.commit().unwrap();
+}
+
+#[test]
+fn test_rollback() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    let txn = storage.new_pessimistic_txn().unwrap();
+    txn.put(b"a", b"1");
+    txn.rollback().unwrap();
+    txn.rollback().unwrap();
+    assert_eq!(storage.inner.mvcc().lock_manager.num_locked_keys(), 0);
+    assert_eq!(storage.get(b"a").unwrap(), None);
+
+    let txn = storage.new_txn().unwrap();
+    txn.put(b"a", b"1");
+    txn.commit().unwrap();
+    assert!(txn.rollback().is_err());
+    assert_eq!(storage.get(b"a").unwrap(), Some(Bytes::from("1")));
+}
+
+#[test]
+fn test_use_after_rollback() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    let txn = storage.new_txn().unwrap();
+    txn.put(b"a", b"1");
+    txn.rollback().unwrap();
+
+    let error = txn.get(b"a").unwrap_err();
+    assert!(error.to_string().contains("rolled back"), "{error}");
+    assert!(txn.get_for_update(b"a").is_err());
+    assert!(txn.scan(Bound::Unbounded, Bound::Unbounded).is_err());
+    assert!(txn.set_savepoint().is_err());
+    assert!(txn.rollback_to_savepoint().is_err());
+    // `put` and `delete` cannot fail, so the error comes back from the commit
+    txn.put(b"b", b"2");
+    txn.delete(b"a");
+    let error = txn.commit().unwrap_err();
+    assert!(error.to_string().contains("rolled back"), "{error}");
+    assert!(txn.prepare("txn").is_err());
+    assert_eq!(storage.get(b"a").unwrap(), None);
+    assert_eq!(storage.get(b"b").unwrap(), None);
+
+    let txn = storage.new_txn().unwrap();
+    txn.commit().unwrap();
+    let error = txn.get(b"a").unwrap_err();
+    assert!(error.to_string().contains("committed"), "{error}");
+    assert!(txn.commit().is_err());
+}
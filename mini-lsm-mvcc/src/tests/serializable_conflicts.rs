@@ -0,0 +1,76 @@
+use std::ops::Bound;
+
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    iterators::StorageIterator,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+};
+
+fn count_keys(iter: &mut impl for<'a> StorageIterator<KeyType<'a> = &'a [u8]>) -> usize {
+    let mut cnt = 0;
+    while iter.is_valid() {
+        cnt += 1;
+        iter.next().unwrap();
+    }
+    cnt
+}
+
+#[test]
+fn test_serializable_phantom() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.serializable = true;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    storage.put(b"item1", b"1").unwrap();
+    storage.put(b"item3", b"1").unwrap();
+
+    // an insert into the scanned range, even though the scan never returned the key
+    let txn1 = storage.new_txn().unwrap();
+    let txn2 = storage.new_txn().unwrap();
+    let cnt = count_keys(
+        &mut txn1
+            .scan(Bound::Included(b"item"), Bound::Excluded(b"itemz"))
+            .unwrap(),
+    );
+    assert_eq!(cnt, 2);
+    txn1.put(b"count", cnt.to_string().as_bytes());
+    txn2.put(b"item2", b"1");
+    txn2.commit().unwrap();
+    assert!(txn1.commit().is_err());
+
+    // an insert outside of the scanned range
+    let txn1 = storage.new_txn().unwrap();
+    let txn2 = storage.new_txn().unwrap();
+    let cnt = count_keys(
+        &mut txn1
+            .scan(Bound::Included(b"item"), Bound::Excluded(b"itemz"))
+            .unwrap(),
+    );
+    assert_eq!(cnt, 3);
+    txn1.put(b"count", cnt.to_string().as_bytes());
+    txn2.put(b"other", b"1");
+    txn2.commit().unwrap();
+    txn1.commit().unwrap();
+}
+
+#[test]
+fn test_serializable_exact_keys() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.serializable = true;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    // many disjoint read and write sets never conflict
+    let readers = (0..1000)
+        .map(|i| {
+            let txn = storage.new_txn().unwrap();
+            txn.get(format!("read{}", i).as_bytes()).unwrap();
+            txn.put(format!("write{}", i).as_bytes(), b"1");
+            txn
+        })
+        .collect::<Vec<_>>();
+    for txn in readers {
+        txn.commit().unwrap();
+    }
+}
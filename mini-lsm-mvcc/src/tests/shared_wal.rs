@@ -0,0 +1,165 @@
+use std::path::Path;
+
+use bytes::{BufMut, Bytes};
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+};
+
+fn num_wal_segments(dir: impl AsRef<Path>) -> usize {
+    std::fs::read_dir(dir)
+        .unwrap()
+        .filter(|entry| {
+            entry
+                .as_ref()
+                .unwrap()
+                .path()
+                .extension()
+                .is_some_and(|ext| ext == "log")
+        })
+        .count()
+}
+
+#[test]
+fn test_many_memtables_share_wal() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    options.num_memtable_limit = 100;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    for i in 0..20 {
+        storage
+            .put(
+                format!("key{:02}", i).as_bytes(),
+                format!("v{}", i).as_bytes(),
+            )
+            .unwrap();
+        storage
+            .inner
+            .force_freeze_memtable(&storage.inner.state_lock.lock())
+            .unwrap();
+    }
+    assert_eq!(num_wal_segments(&dir), 1);
+    storage.close().unwrap();
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert_eq!(storage.inner.state.read().imm_memtables.len(), 20);
+    for i in 0..20 {
+        assert_eq!(
+            storage.get(format!("key{:02}", i).as_bytes()).unwrap(),
+            Some(Bytes::from(format!("v{}", i)))
+        );
+    }
+}
+
+#[test]
+fn test_wal_segments_removed_after_flush() {
+    let dir = tempdir().unwrap();
+    let wal_dir = dir.path().join("wal");
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    options.num_memtable_limit = 100;
+    options.wal_dir = Some(wal_dir.clone());
+    options.wal_segment_size = 1024;
+    let storage = MiniLsm::open(dir.path().join("db"), options.clone()).unwrap();
+    let value = [b'x'; 100];
+    for i in 0..10 {
+        for j in 0..10 {
+            storage
+                .put(format!("key{:02}_{:02}", i, j).as_bytes(), &value)
+                .unwrap();
+        }
+        storage
+            .inner
+            .force_freeze_memtable(&storage.inner.state_lock.lock())
+            .unwrap();
+    }
+    assert_eq!(num_wal_segments(dir.path().join("db")), 0);
+    let segments_before_flush = num_wal_segments(&wal_dir);
+    assert!(segments_before_flush > 5);
+    assert_eq!(
+        storage.inner.wal.as_ref().unwrap().num_segments(),
+        segments_before_flush
+    );
+
+    // flush half of the memtables
+    for _ in 0..5 {
+        storage.inner.force_flush_next_imm_memtable().unwrap();
+    }
+    let segments_after_flush = num_wal_segments(&wal_dir);
+    assert!(segments_after_flush < segments_before_flush);
+    storage.close().unwrap();
+    drop(storage);
+
+    let storage = MiniLsm::open(dir.path().join("db"), options).unwrap();
+    assert_eq!(storage.inner.state.read().imm_memtables.len(), 5);
+    assert_eq!(
+        storage.get(b"key00_00").unwrap(),
+        Some(Bytes::copy_from_slice(&value))
+    );
+    assert_eq!(
+        storage.get(b"key09_09").unwrap(),
+        Some(Bytes::copy_from_slice(&value))
+    );
+    while !storage.inner.state.read().imm_memtables.is_empty() {
+        storage.inner.force_flush_next_imm_memtable().unwrap();
+    }
+    // only the active segment is left
+    assert_eq!(num_wal_segments(&wal_dir), 1);
+}
+
+/// Write a WAL file of a single memtable, as written before the memtables shared a WAL.
+fn write_legacy_wal(path: impl AsRef<Path>, kv_pairs: &[(&[u8], u64, &[u8])]) {
+    let mut buf = Vec::new();
+    for (key, ts, value) in kv_pairs {
+        buf.put_u16(key.len() as u16);
+        buf.put_slice(key);
+        buf.put_u64(*ts);
+        buf.put_u16(value.len() as u16);
+        buf.put_slice(value);
+    }
+    let mut file = Vec::new();
+    file.put_u32(buf.len() as u32);
+    file.put_slice(&buf);
+    file.put_u32(crc32fast::hash(&buf));
+    std::fs::write(path, file).unwrap();
+}
+
+#[test]
+fn test_legacy_wal_files_moved_to_wal() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    let flushed_id = storage.inner.state.read().memtable.id();
+    storage.put(b"a", b"0").unwrap();
+    storage.force_flush().unwrap();
+    let memtable_id = storage.inner.state.read().memtable.id();
+    storage.close().unwrap();
+    drop(storage);
+    write_legacy_wal(
+        dir.path().join(format!("{:05}.wal", memtable_id)),
+        &[(b"b", 2, b"1"), (b"c", 2, b"1")],
+    );
+    // a memtable flushed before the upgrade
+    write_legacy_wal(
+        dir.path().join(format!("{:05}.wal", flushed_id)),
+        &[(b"d", 1, b"1")],
+    );
+
+    for _ in 0..2 {
+        let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+        assert!(!dir.path().join(format!("{:05}.wal", memtable_id)).exists());
+        assert!(!dir.path().join(format!("{:05}.wal", flushed_id)).exists());
+        assert_eq!(storage.get(b"a").unwrap(), Some(Bytes::from("0")));
+        assert_eq!(storage.get(b"b").unwrap(), Some(Bytes::from("1")));
+        assert_eq!(storage.get(b"c").unwrap(), Some(Bytes::from("1")));
+        assert_eq!(storage.get(b"d").unwrap(), None);
+        // the ts of the legacy records are not reused
+        assert!(storage.inner.mvcc().latest_commit_ts() >= 2);
+        storage.close().unwrap();
+    }
+}
@@ -0,0 +1,85 @@
+use std::ops::Bound;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    lsm_storage::{LsmStorageOptions, MiniLsm, ReadOptions},
+    tests::harness::check_lsm_iter_result_by_key,
+};
+
+#[test]
+fn test_snapshot_read() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    storage.put(b"a", b"1").unwrap();
+    storage.put(b"b", b"1").unwrap();
+    let snapshot = storage.snapshot();
+    storage.put(b"a", b"2").unwrap();
+    storage.delete(b"b").unwrap();
+    storage.put(b"c", b"2").unwrap();
+
+    assert_eq!(snapshot.get(b"a").unwrap(), Some(Bytes::from("1")));
+    assert_eq!(snapshot.get(b"c").unwrap(), None);
+    check_lsm_iter_result_by_key(
+        &mut snapshot.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        vec![
+            (Bytes::from("a"), Bytes::from("1")),
+            (Bytes::from("b"), Bytes::from("1")),
+        ],
+    );
+
+    let read_options = ReadOptions {
+        snapshot: Some(snapshot.clone()),
+        ..Default::default()
+    };
+    assert_eq!(
+        storage.get_with_options(b"b", &read_options).unwrap(),
+        Some(Bytes::from("1"))
+    );
+    check_lsm_iter_result_by_key(
+        &mut storage
+            .scan_with_options(Bound::Unbounded, Bound::Unbounded, &read_options)
+            .unwrap(),
+        vec![
+            (Bytes::from("a"), Bytes::from("1")),
+            (Bytes::from("b"), Bytes::from("1")),
+        ],
+    );
+    check_lsm_iter_result_by_key(
+        &mut storage
+            .scan_with_options(Bound::Unbounded, Bound::Unbounded, &ReadOptions::default())
+            .unwrap(),
+        vec![
+            (Bytes::from("a"), Bytes::from("2")),
+            (Bytes::from("c"), Bytes::from("2")),
+        ],
+    );
+}
+
+#[test]
+fn test_snapshot_pins_watermark() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    storage.put(b"a", b"1").unwrap();
+    let snapshot = storage.snapshot();
+    let read_ts = snapshot.read_ts();
+    storage.put(b"a", b"2").unwrap();
+    assert_eq!(storage.inner.mvcc().watermark(), read_ts);
+
+    // an iterator keeps the snapshot alive
+    let mut iter = snapshot.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    drop(snapshot);
+    assert_eq!(storage.inner.mvcc().watermark(), read_ts);
+    storage.force_flush().unwrap();
+    storage.force_full_compaction().unwrap();
+    check_lsm_iter_result_by_key(&mut iter, vec![(Bytes::from("a"), Bytes::from("1"))]);
+    drop(iter);
+    assert_eq!(
+        storage.inner.mvcc().watermark(),
+        storage.inner.mvcc().latest_commit_ts()
+    );
+}
@@ -0,0 +1,145 @@
+use std::ops::Bound;
+
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    iterators::StorageIterator,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    statistics::{HistogramType, Statistics, Ticker},
+};
+
+fn key_of(idx: usize) -> Vec<u8> {
+    format!("key_{:05}", idx).into_bytes()
+}
+
+fn value_of(idx: usize) -> Vec<u8> {
+    format!("value_{:010}", idx).into_bytes()
+}
+
+#[test]
+fn test_statistics_tickers() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    let statistics = storage.statistics();
+
+    for idx in 0..100 {
+        storage.put(&key_of(idx), &value_of(idx)).unwrap();
+    }
+    storage.delete(&key_of(0)).unwrap();
+    let bytes_written = (0..100)
+        .map(|idx| key_of(idx).len() + value_of(idx).len())
+        .sum::<usize>()
+        + key_of(0).len();
+    assert_eq!(statistics.ticker(Ticker::KeysWritten), 101);
+    assert_eq!(
+        statistics.ticker(Ticker::BytesWritten),
+        bytes_written as u64
+    );
+    // every record carries its length, ts and checksum on top of the key and value
+    assert!(statistics.ticker(Ticker::WalBytesWritten) > bytes_written as u64);
+    assert_eq!(statistics.histogram(HistogramType::WriteMicros).count, 101);
+
+    storage.force_flush().unwrap();
+    for idx in 100..200 {
+        storage.put(&key_of(idx), &value_of(idx)).unwrap();
+    }
+    storage.force_flush().unwrap();
+    assert_eq!(statistics.ticker(Ticker::Flushes), 2);
+    assert!(statistics.ticker(Ticker::FlushBytesWritten) > 0);
+    assert_eq!(statistics.histogram(HistogramType::FlushMicros).count, 2);
+
+    storage.force_full_compaction().unwrap();
+    assert_eq!(statistics.ticker(Ticker::Compactions), 1);
+    assert_eq!(
+        statistics.ticker(Ticker::CompactionBytesRead),
+        statistics.ticker(Ticker::FlushBytesWritten)
+    );
+    assert!(statistics.ticker(Ticker::CompactionBytesWritten) > 0);
+    assert_eq!(
+        statistics.histogram(HistogramType::CompactionMicros).count,
+        1
+    );
+
+    assert_eq!(storage.get(&key_of(1)).unwrap().unwrap(), value_of(1));
+    assert_eq!(storage.get(&key_of(0)).unwrap(), None);
+    // within the key range of the SST, but not in it
+    assert_eq!(storage.get(b"key_00001_").unwrap(), None);
+    assert_eq!(statistics.ticker(Ticker::KeysRead), 3);
+    assert_eq!(
+        statistics.ticker(Ticker::BytesRead),
+        value_of(1).len() as u64
+    );
+    // the compaction dropped the delete, so the SST starts after the deleted key and its bloom filter is not checked
+    assert_eq!(statistics.ticker(Ticker::BloomChecked), 2);
+    assert_eq!(statistics.ticker(Ticker::BloomUseful), 1);
+    assert!(statistics.ticker(Ticker::BlockCacheMiss) > 0);
+    let hits = statistics.ticker(Ticker::BlockCacheHit);
+    storage.get(&key_of(1)).unwrap();
+    assert_eq!(statistics.ticker(Ticker::BlockCacheHit), hits + 1);
+    assert_eq!(statistics.histogram(HistogramType::GetMicros).count, 4);
+
+    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    assert_eq!(iter.key(), key_of(1));
+    // a scan is recorded once it is exhausted or dropped
+    assert_eq!(statistics.histogram(HistogramType::ScanMicros).count, 0);
+    while iter.is_valid() {
+        iter.next().unwrap();
+    }
+    assert_eq!(statistics.histogram(HistogramType::ScanMicros).count, 1);
+    drop(iter);
+    let iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    drop(iter);
+    assert_eq!(statistics.histogram(HistogramType::ScanMicros).count, 2);
+
+    statistics.reset();
+    for ticker in Ticker::ALL {
+        assert_eq!(statistics.ticker(ticker), 0, "{}", ticker.name());
+    }
+    for histogram in HistogramType::ALL {
+        assert_eq!(statistics.histogram(histogram).count, 0);
+    }
+}
+
+#[test]
+fn test_statistics_txn() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.serializable = true;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    let statistics = storage.statistics();
+
+    let txn1 = storage.new_txn().unwrap();
+    let txn2 = storage.new_txn().unwrap();
+    txn1.get(b"a").unwrap();
+    txn1.put(b"b", b"1");
+    txn2.get(b"b").unwrap();
+    txn2.put(b"a", b"2");
+    txn1.commit().unwrap();
+    assert!(txn2.commit().is_err());
+    assert_eq!(statistics.ticker(Ticker::TxnConflicts), 1);
+    assert_eq!(statistics.histogram(HistogramType::CommitMicros).count, 1);
+    assert_eq!(statistics.ticker(Ticker::KeysWritten), 1);
+}
+
+#[test]
+fn test_statistics_histogram() {
+    let statistics = Statistics::new();
+    for value in 1..=1000 {
+        statistics.record_value(HistogramType::GetMicros, value);
+    }
+    let data = statistics.histogram(HistogramType::GetMicros);
+    assert_eq!(data.count, 1000);
+    assert_eq!(data.sum, 500500);
+    assert_eq!(data.min, 1);
+    assert_eq!(data.max, 1000);
+    assert_eq!(data.mean(), 500.5);
+    // the buckets are powers of two, so a percentile is off by up to a factor of two
+    assert!((250..=1000).contains(&data.p50), "{}", data.p50);
+    assert!((475..=1000).contains(&data.p95), "{}", data.p95);
+    assert!(data.p50 <= data.p95 && data.p95 <= data.p99 && data.p99 <= data.max);
+    assert!(statistics.to_string().contains("get.micros P50"));
+    assert_eq!(statistics.histogram(HistogramType::ScanMicros).count, 0);
+}
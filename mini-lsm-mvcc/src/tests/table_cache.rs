@@ -0,0 +1,197 @@
+use std::{ops::Bound, sync::Arc};
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    iterators::StorageIterator,
+    key::KeySlice,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    table::{SsTable, SsTableBuilder, SsTableIterator},
+    table_cache::TableCache,
+};
+
+fn key_of(idx: usize) -> Vec<u8> {
+    format!("key_{:05}", idx).into_bytes()
+}
+
+fn value_of(idx: usize) -> Vec<u8> {
+    format!("value_{:010}", idx).into_bytes()
+}
+
+fn build_table(dir: &std::path::Path, id: usize, table_cache: &Arc<TableCache>) -> Arc<SsTable> {
+    let mut builder = SsTableBuilder::new(128);
+    for idx in id * 100..(id + 1) * 100 {
+        builder.add(
+            KeySlice::for_testing_from_slice_no_ts(&key_of(idx)),
+            &value_of(idx),
+        );
+    }
+    Arc::new(
+        builder
+            .build_with_table_cache(
+                id,
+                None,
+                table_cache.clone(),
+                dir.join(format!("{}.sst", id)),
+            )
+            .unwrap(),
+    )
+}
+
+fn check_table(table: &Arc<SsTable>) {
+    let id = table.sst_id();
+    let mut iter = SsTableIterator::create_and_seek_to_first(table.clone()).unwrap();
+    for idx in id * 100..(id + 1) * 100 {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key().for_testing_key_ref(), key_of(idx));
+        assert_eq!(iter.value(), value_of(idx));
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_table_cache_max_open_files() {
+    let dir = tempdir().unwrap();
+    let table_cache = Arc::new(TableCache::new(2, 64 << 20));
+    let tables = (1..=3)
+        .map(|id| build_table(dir.path(), id, &table_cache))
+        .collect::<Vec<_>>();
+    assert_eq!(table_cache.stats().open_files, 2);
+    assert!(!table_cache.contains(1));
+
+    // SST 1 is opened again on demand, evicting the least recently used SST 2
+    check_table(&tables[0]);
+    assert!(table_cache.contains(1));
+    assert!(!table_cache.contains(2));
+    assert!(table_cache.contains(3));
+    for table in &tables {
+        check_table(table);
+        assert!(table_cache.stats().open_files <= 2);
+    }
+    let stats = table_cache.stats();
+    assert_eq!(stats.misses, 3);
+    assert_eq!(stats.evictions, 4);
+    assert!(stats.hits > 0);
+
+    // the key range and the max ts do not need the file
+    assert_eq!(
+        tables[1].first_key().for_testing_key_ref(),
+        key_of(200).as_slice()
+    );
+    assert_eq!(tables[1].num_of_blocks(), tables[2].num_of_blocks());
+}
+
+#[test]
+fn test_table_cache_memory_budget() {
+    let dir = tempdir().unwrap();
+    let table_cache = Arc::new(TableCache::new(100, 1));
+    let tables = (1..=3)
+        .map(|id| build_table(dir.path(), id, &table_cache))
+        .collect::<Vec<_>>();
+    // the most recently used SST is kept even if it is over the budget
+    let stats = table_cache.stats();
+    assert_eq!(stats.open_files, 1);
+    assert!(stats.usage > 1);
+    for table in &tables {
+        check_table(table);
+        assert!(table_cache.contains(table.sst_id()));
+        assert_eq!(table_cache.stats().open_files, 1);
+    }
+}
+
+/// The number of keys flushed to each SST, which spans several blocks.
+const KEYS_PER_SST: usize = 500;
+
+fn put_and_flush(storage: &MiniLsm, num_ssts: usize) {
+    for sst in 0..num_ssts {
+        for idx in sst * KEYS_PER_SST..(sst + 1) * KEYS_PER_SST {
+            storage.put(&key_of(idx), &value_of(idx)).unwrap();
+        }
+        storage.force_flush().unwrap();
+    }
+}
+
+fn check_storage(storage: &MiniLsm, num_keys: usize) {
+    for idx in 0..num_keys {
+        assert_eq!(
+            storage.get(&key_of(idx)).unwrap(),
+            Some(Bytes::from(value_of(idx)))
+        );
+    }
+}
+
+#[test]
+fn test_storage_with_bounded_open_files() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.max_open_files = 2;
+    {
+        let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+        put_and_flush(&storage, 8);
+        check_storage(&storage, 8 * KEYS_PER_SST);
+        assert!(storage.table_cache().stats().open_files <= 2);
+        storage.close().unwrap();
+    }
+
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert!(storage.table_cache().stats().open_files <= 2);
+    check_storage(&storage, 8 * KEYS_PER_SST);
+    let stats = storage.table_cache().stats();
+    assert!(stats.open_files <= 2);
+    assert!(stats.evictions > 0);
+}
+
+#[test]
+fn test_iterator_over_deleted_ssts() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.max_open_files = 1;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    put_and_flush(&storage, 4);
+
+    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    storage.force_full_compaction().unwrap();
+    // the SSTs under the iterator are deleted and evicted from the table cache, but stay readable
+    for idx in 0..4 * KEYS_PER_SST {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key(), key_of(idx));
+        assert_eq!(iter.value(), value_of(idx));
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+    check_storage(&storage, 4 * KEYS_PER_SST);
+}
+
+#[test]
+fn test_recovery_does_not_open_ssts() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.max_open_files = 2;
+    let metas = {
+        let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+        put_and_flush(&storage, 4);
+        let metas = storage
+            .inner
+            .state
+            .read()
+            .sstables
+            .values()
+            .map(|sst| sst.meta())
+            .collect::<Vec<_>>();
+        storage.close().unwrap();
+        metas
+    };
+
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    // the key ranges and the max ts come from the manifest
+    assert_eq!(storage.table_cache().stats().misses, 0);
+    let state = storage.inner.state.read().clone();
+    for meta in metas {
+        assert_eq!(state.sstables[&meta.id].meta(), meta);
+    }
+    check_storage(&storage, 4 * KEYS_PER_SST);
+    assert!(storage.table_cache().stats().misses > 0);
+}
@@ -0,0 +1,220 @@
+use std::ops::Bound;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    iterators::StorageIterator,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    mvcc::txn::IsolationLevel,
+};
+
+#[test]
+fn test_prepared_txn_survives_restart() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"a", b"0").unwrap();
+    let txn1 = storage.new_txn().unwrap();
+    txn1.put(b"a", b"1");
+    txn1.delete(b"b");
+    txn1.prepare("txn1").unwrap();
+    let txn2 = storage.new_txn().unwrap();
+    txn2.put(b"c", b"2");
+    txn2.prepare("txn2").unwrap();
+    let txn3 = storage.new_txn().unwrap();
+    assert!(txn3.prepare("txn1").is_err());
+    storage.put(b"b", b"0").unwrap();
+    assert_eq!(storage.prepared_txns(), vec!["txn1", "txn2"]);
+    // not visible before the decision
+    assert_eq!(storage.get(b"a").unwrap(), Some(Bytes::from("0")));
+    storage.close().unwrap();
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    assert_eq!(storage.prepared_txns(), vec!["txn1", "txn2"]);
+    assert_eq!(storage.get(b"a").unwrap(), Some(Bytes::from("0")));
+    storage.commit_prepared("txn1").unwrap();
+    storage.rollback_prepared("txn2").unwrap();
+    assert!(storage.commit_prepared("txn2").is_err());
+    assert_eq!(storage.get(b"a").unwrap(), Some(Bytes::from("1")));
+    assert_eq!(storage.get(b"b").unwrap(), None);
+    assert_eq!(storage.get(b"c").unwrap(), None);
+    storage.close().unwrap();
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert!(storage.prepared_txns().is_empty());
+    assert_eq!(storage.get(b"a").unwrap(), Some(Bytes::from("1")));
+    assert_eq!(storage.get(b"b").unwrap(), None);
+}
+
+#[test]
+fn test_prepared_txn_pins_wal_segment() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    options.wal_segment_size = 1024;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    let txn = storage.new_pessimistic_txn().unwrap();
+    txn.put(b"prepared", b"1");
+    txn.prepare("txn").unwrap();
+    // the key stays locked until the txn is decided
+    assert_eq!(storage.inner.mvcc().lock_manager.num_locked_keys(), 1);
+    drop(txn);
+    assert_eq!(storage.inner.mvcc().lock_manager.num_locked_keys(), 1);
+    for i in 0..100 {
+        storage
+            .put(format!("key{:03}", i).as_bytes(), &[b'x'; 100])
+            .unwrap();
+    }
+    storage.force_flush().unwrap();
+    storage.close().unwrap();
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert_eq!(storage.prepared_txns(), vec!["txn"]);
+    storage.commit_prepared("txn").unwrap();
+    assert_eq!(storage.inner.mvcc().lock_manager.num_locked_keys(), 0);
+    assert_eq!(storage.get(b"prepared").unwrap(), Some(Bytes::from("1")));
+    storage.force_flush().unwrap();
+    assert_eq!(storage.inner.wal.as_ref().unwrap().num_segments(), 1);
+}
+
+#[test]
+fn test_prepare_requires_wal() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    let txn = storage.new_txn().unwrap();
+    txn.put(b"a", b"1");
+    assert!(txn.prepare("txn").is_err());
+}
+
+#[test]
+fn test_committed_prepared_txn_recovers_from_prepare_record() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    options.wal_segment_size = 1024;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    let txn = storage.new_txn().unwrap();
+    txn.put(b"prepared", b"1");
+    txn.prepare("txn").unwrap();
+    for i in 0..100 {
+        storage
+            .put(format!("key{:03}", i).as_bytes(), &[b'x'; 100])
+            .unwrap();
+    }
+    storage.force_flush().unwrap();
+    // the WAL only has the decision, the data is in the prepare record from many segments ago
+    storage.commit_prepared("txn").unwrap();
+    storage.close().unwrap();
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert!(storage.prepared_txns().is_empty());
+    assert_eq!(storage.get(b"prepared").unwrap(), Some(Bytes::from("1")));
+    storage.force_flush().unwrap();
+    assert_eq!(storage.inner.wal.as_ref().unwrap().num_segments(), 1);
+}
+
+#[test]
+fn test_prepared_write_set_conflicts() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    let txn1 = storage.new_txn().unwrap();
+    txn1.put(b"a", b"1");
+    txn1.prepare("txn1").unwrap();
+
+    // started after the prepare, but committing before the prepared txn would be overwritten by it
+    let txn2 = storage.new_txn().unwrap();
+    txn2.put(b"a", b"2");
+    assert!(txn2.commit().is_err());
+    let txn3 = storage.new_txn().unwrap();
+    txn3.put(b"a", b"3");
+    assert!(txn3.prepare("txn3").is_err());
+    let txn4 = storage.new_pessimistic_txn().unwrap();
+    txn4.put(b"a", b"4");
+    assert!(txn4.commit().is_err());
+    let txn5 = storage.new_txn().unwrap();
+    txn5.put(b"b", b"5");
+    txn5.commit().unwrap();
+
+    storage.commit_prepared("txn1").unwrap();
+    let txn6 = storage.new_txn().unwrap();
+    txn6.put(b"a", b"6");
+    txn6.commit().unwrap();
+    assert_eq!(storage.get(b"a").unwrap(), Some(Bytes::from("6")));
+}
+
+#[test]
+fn test_prepared_read_set_rejects_writes() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"read", b"0").unwrap();
+    let txn = storage
+        .new_txn_with_isolation(IsolationLevel::Serializable)
+        .unwrap();
+    assert_eq!(txn.get(b"read").unwrap(), Some(Bytes::from("0")));
+    let mut iter = txn
+        .scan(Bound::Included(b"scan1"), Bound::Excluded(b"scan3"))
+        .unwrap();
+    while iter.is_valid() {
+        iter.next().unwrap();
+    }
+    txn.put(b"written", b"1");
+    txn.prepare("txn").unwrap();
+    drop(txn);
+
+    // the prepared txn commits as if what it read did not change since the prepare
+    assert!(storage.put(b"read", b"1").is_err());
+    assert!(storage.delete(b"scan2").is_err());
+    let txn = storage
+        .new_txn_with_isolation(IsolationLevel::ReadCommitted)
+        .unwrap();
+    txn.put(b"read", b"2");
+    assert!(txn.commit().is_err());
+    storage.put(b"scan3", b"1").unwrap();
+    storage.close().unwrap();
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert!(storage.put(b"read", b"1").is_err());
+    assert!(storage.put(b"scan1", b"1").is_err());
+    storage.commit_prepared("txn").unwrap();
+    storage.put(b"read", b"1").unwrap();
+    storage.put(b"scan1", b"1").unwrap();
+    assert_eq!(storage.get(b"read").unwrap(), Some(Bytes::from("1")));
+    assert_eq!(storage.get(b"written").unwrap(), Some(Bytes::from("1")));
+}
+
+#[test]
+fn test_failed_prepare_keeps_txn_usable() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    let txn1 = storage.new_txn().unwrap();
+    txn1.put(b"a", b"1");
+    txn1.prepare("txn1").unwrap();
+
+    let txn2 = storage.new_pessimistic_txn().unwrap();
+    txn2.put(b"b", b"2");
+    assert!(txn2.prepare("txn1").is_err());
+    txn2.rollback().unwrap();
+    assert_eq!(storage.inner.mvcc().lock_manager.num_locked_keys(), 0);
+
+    let txn3 = storage.new_txn().unwrap();
+    txn3.put(b"c", b"3");
+    assert!(txn3.prepare("txn1").is_err());
+    txn3.prepare("txn3").unwrap();
+    storage.commit_prepared("txn3").unwrap();
+    assert_eq!(storage.get(b"c").unwrap(), Some(Bytes::from("3")));
+}
@@ -0,0 +1,175 @@
+use std::{ops::Bound, path::Path, sync::atomic::Ordering};
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    lsm_storage::{LsmStorageOptions, MiniLsm, WriteBatchRecord},
+};
+
+use super::harness::check_lsm_iter_result_by_key;
+
+fn num_spilled_files(path: impl AsRef<Path>) -> usize {
+    match std::fs::read_dir(path.as_ref().join("txn_spill")) {
+        Ok(dir) => dir.count(),
+        Err(_) => 0,
+    }
+}
+
+#[test]
+fn test_txn_spill_and_commit() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.txn_spill_threshold = 64;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    storage.put(b"key00", b"v").unwrap();
+    storage.put(b"key01", b"v").unwrap();
+    let txn = storage.new_txn().unwrap();
+    for i in 0..20 {
+        txn.put(format!("key{:02}", i).as_bytes(), b"1");
+    }
+    assert!(num_spilled_files(&dir) > 0);
+    // overwrite and delete keys in the spilled runs
+    txn.put(b"key02", b"2");
+    txn.delete(b"key01");
+    for i in 0..10 {
+        txn.put(format!("key{:02}", i * 2).as_bytes(), b"2");
+    }
+    assert_eq!(txn.get(b"key00").unwrap(), Some(Bytes::from("2")));
+    assert_eq!(txn.get(b"key01").unwrap(), None);
+    assert_eq!(txn.get(b"key03").unwrap(), Some(Bytes::from("1")));
+    check_lsm_iter_result_by_key(
+        &mut txn
+            .scan(Bound::Included(b"key01"), Bound::Included(b"key04"))
+            .unwrap(),
+        vec![
+            (Bytes::from("key02"), Bytes::from("2")),
+            (Bytes::from("key03"), Bytes::from("1")),
+            (Bytes::from("key04"), Bytes::from("2")),
+        ],
+    );
+    // not visible before commit
+    assert_eq!(storage.get(b"key03").unwrap(), None);
+    txn.commit().unwrap();
+    drop(txn);
+    assert_eq!(num_spilled_files(&dir), 0);
+
+    for i in 0..20 {
+        let expected = match i {
+            1 => None,
+            i if i % 2 == 0 => Some(Bytes::from("2")),
+            _ => Some(Bytes::from("1")),
+        };
+        assert_eq!(
+            storage.get(format!("key{:02}", i).as_bytes()).unwrap(),
+            expected
+        );
+    }
+}
+
+#[test]
+fn test_txn_spill_savepoint() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.txn_spill_threshold = 64;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    let txn = storage.new_txn().unwrap();
+    for i in 0..10 {
+        txn.put(format!("key{:02}", i).as_bytes(), b"1");
+    }
+    txn.set_savepoint().unwrap();
+    for i in 5..20 {
+        txn.put(format!("key{:02}", i).as_bytes(), b"2");
+    }
+    assert_eq!(txn.get(b"key05").unwrap(), Some(Bytes::from("2")));
+    assert_eq!(txn.get(b"key15").unwrap(), Some(Bytes::from("2")));
+    txn.rollback_to_savepoint().unwrap();
+    assert_eq!(txn.get(b"key05").unwrap(), Some(Bytes::from("1")));
+    assert_eq!(txn.get(b"key15").unwrap(), None);
+    txn.commit().unwrap();
+    drop(txn);
+
+    assert_eq!(storage.get(b"key05").unwrap(), Some(Bytes::from("1")));
+    assert_eq!(storage.get(b"key15").unwrap(), None);
+
+    let txn = storage.new_txn().unwrap();
+    for i in 0..20 {
+        txn.put(format!("key{:02}", i).as_bytes(), b"3");
+    }
+    assert!(num_spilled_files(&dir) > 0);
+    txn.rollback().unwrap();
+    assert_eq!(num_spilled_files(&dir), 0);
+}
+
+#[test]
+fn test_txn_savepoint_rollback_forgets_undone_size() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.txn_spill_threshold = 64;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    let txn = storage.new_txn().unwrap();
+    txn.put(b"key00", b"1");
+    txn.put(b"key01", b"1");
+    // the txn does not spill while the savepoint keeps an undo log
+    txn.set_savepoint().unwrap();
+    for i in 0..20 {
+        txn.put(format!("key{:02}", i).as_bytes(), b"2");
+    }
+    txn.rollback_to_savepoint().unwrap();
+    assert_eq!(txn.buffered_size.load(Ordering::SeqCst), 12);
+
+    // the undone writes do not count towards the threshold
+    for i in 2..7 {
+        txn.put(format!("key{:02}", i).as_bytes(), b"3");
+    }
+    assert_eq!(txn.spilled.lock().num_runs(), 0);
+    assert_eq!(num_spilled_files(&dir), 0);
+    txn.commit().unwrap();
+    assert_eq!(storage.get(b"key01").unwrap(), Some(Bytes::from("1")));
+    assert_eq!(storage.get(b"key06").unwrap(), Some(Bytes::from("3")));
+    assert_eq!(storage.get(b"key07").unwrap(), None);
+}
+
+#[test]
+fn test_failed_chunked_commit_stays_invisible() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"b", b"0").unwrap();
+    let ts = storage.inner.mvcc().latest_commit_ts();
+
+    let mut chunks = 0;
+    let result = storage.inner.write_batch_chunks(
+        || Ok(()),
+        || {
+            chunks += 1;
+            if chunks == 1 {
+                return Ok(Some(vec![
+                    WriteBatchRecord::Put(b"a", b"1"),
+                    WriteBatchRecord::Del(b"b"),
+                ]));
+            }
+            // the first chunk is in an SST by now
+            let inner = &storage.inner;
+            inner.force_freeze_memtable(&inner.state_lock.lock())?;
+            inner.force_flush_next_imm_memtable()?;
+            anyhow::bail!("failed to read the next chunk")
+        },
+    );
+    assert!(result.is_err());
+    // the ts of the failed batch is not used again
+    storage.put(b"c", b"2").unwrap();
+    assert_eq!(storage.inner.mvcc().latest_commit_ts(), ts + 2);
+    assert_eq!(storage.get(b"a").unwrap(), None);
+    assert_eq!(storage.get(b"b").unwrap(), Some(Bytes::from("0")));
+    assert_eq!(storage.get(b"c").unwrap(), Some(Bytes::from("2")));
+
+    storage.close().unwrap();
+    drop(storage);
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert_eq!(storage.get(b"a").unwrap(), None);
+    assert_eq!(storage.get(b"b").unwrap(), Some(Bytes::from("0")));
+    assert_eq!(storage.get(b"c").unwrap(), Some(Bytes::from("2")));
+}
@@ -0,0 +1,91 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    write_buffer_manager::WriteBufferManager,
+};
+
+fn key_of(idx: usize) -> Vec<u8> {
+    format!("key_{:05}", idx).into_bytes()
+}
+
+fn value_of(idx: usize) -> Vec<u8> {
+    format!("value_{:0100}", idx).into_bytes()
+}
+
+fn options_with(write_buffer_manager: &Arc<WriteBufferManager>) -> LsmStorageOptions {
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    // the engines alone would keep up to 400MB of memtables each
+    options.target_sst_size = 8 << 20;
+    options.num_memtable_limit = 50;
+    options.write_buffer_manager = Some(write_buffer_manager.clone());
+    options
+}
+
+#[test]
+fn test_write_buffer_accounting() {
+    let dir = tempdir().unwrap();
+    let manager = Arc::new(WriteBufferManager::new(64 << 20, true));
+    let storage = MiniLsm::open(&dir, options_with(&manager)).unwrap();
+    for idx in 0..100 {
+        storage.put(&key_of(idx), &value_of(idx)).unwrap();
+    }
+    let usage = storage.inner.state.read().memtable.approximate_size();
+    assert_eq!(manager.usage(), usage);
+    assert_eq!(manager.mutable_usage(), usage);
+
+    // the memtable is charged as immutable until it is flushed and dropped
+    storage
+        .inner
+        .force_freeze_memtable(&storage.inner.state_lock.lock())
+        .unwrap();
+    assert_eq!(manager.usage(), usage);
+    assert_eq!(manager.mutable_usage(), 0);
+    storage.inner.force_flush_next_imm_memtable().unwrap();
+    assert_eq!(manager.usage(), 0);
+
+    storage.put(b"a", b"1").unwrap();
+    assert!(manager.usage() > 0);
+    // closing the engine flushes the memtable
+    storage.close().unwrap();
+    assert_eq!(manager.usage(), 0);
+}
+
+#[test]
+fn test_write_buffer_shared_budget() {
+    let manager = Arc::new(WriteBufferManager::new(256 << 10, true));
+    let dirs = (0..3).map(|_| tempdir().unwrap()).collect::<Vec<_>>();
+    let engines = dirs
+        .iter()
+        .map(|dir| MiniLsm::open(dir, options_with(&manager)).unwrap())
+        .collect::<Vec<_>>();
+    std::thread::scope(|scope| {
+        for storage in &engines {
+            let manager = manager.clone();
+            scope.spawn(move || {
+                for idx in 0..5000 {
+                    storage.put(&key_of(idx), &value_of(idx)).unwrap();
+                    // the usage goes over the budget by at most the writes in flight
+                    assert!(manager.usage() <= manager.buffer_size() + (16 << 10));
+                }
+            });
+        }
+    });
+
+    let stats = manager.stats();
+    assert!(stats.flushes > 0);
+    assert!(stats.stalls > 0);
+    for storage in &engines {
+        assert!(!storage.inner.state.read().l0_sstables.is_empty());
+        for idx in (0..5000).step_by(7) {
+            assert_eq!(
+                storage.get(&key_of(idx)).unwrap(),
+                Some(Bytes::from(value_of(idx)))
+            );
+        }
+    }
+}
@@ -1,7 +1,8 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fs::{File, OpenOptions};
-use std::hash::Hasher;
 use std::io::{BufWriter, Read, Write};
-use std::path::Path;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use anyhow::{bail, Context, Result};
@@ -10,81 +11,421 @@ use crossbeam_skiplist::SkipMap;
 use parking_lot::Mutex;
 
 use crate::key::{KeyBytes, KeySlice};
+use crate::statistics::{Statistics, Ticker};
 
+const RECORD_DATA: u8 = 0;
+const RECORD_PREPARE: u8 = 1;
+const RECORD_DECIDE: u8 = 2;
+
+const BOUND_UNBOUNDED: u8 = 0;
+const BOUND_INCLUDED: u8 = 1;
+const BOUND_EXCLUDED: u8 = 2;
+
+/// A single logical WAL shared by all memtables, split into fixed-size segments.
+///
+/// Each record is `| batch_size (u32) | kind (u8) | body | checksum (u32) |`, where the body is one of:
+///
+/// ```text
+/// data:    | memtable_id (u64) | key_len | key | ts | value_len | value | ... |
+/// prepare: | name_len | name | num_kv_pairs (u32) | key_len | key | value_len | value | ... | reads |
+/// decide:  | name_len | name | committed (u8) | memtable_id (u64) | commit_ts (u64) |
+/// reads:   | num_keys (u32) | key_len | key | ... | lower | upper | ... |
+/// bound:   | kind (u8) | key_len | key |, without the key if it is unbounded
+/// ```
+///
+/// A commit decision carries the commit ts of the prepared transaction and the memtable its write set went to, and
+/// the write set is only replayed from the prepare record into that memtable once the decision is in the WAL. So a
+/// crash before the decision leaves the transaction prepared, with none of its data. A rollback decision, and a
+/// commit decision written before the write set was replayed this way, end after `committed`.
+///
+/// Segments are removed oldest first, once every memtable that has records in them has been flushed and every
+/// prepared transaction in them has been decided. Removing them in order ensures that a decision is never removed
+/// before its prepare record.
 pub struct Wal {
-    file: Arc<Mutex<BufWriter<File>>>,
+    dir: PathBuf,
+    segment_size: usize,
+    inner: Mutex<WalInner>,
+    statistics: Option<Arc<Statistics>>,
+}
+
+struct WalInner {
+    file: BufWriter<File>,
+    segment_id: usize,
+    segment_len: usize,
+    /// Segment id -> ids of the memtables that have records in the segment, including the active segment.
+    segments: BTreeMap<usize, BTreeSet<usize>>,
+    /// Memtables that are not flushed yet.
+    live_memtables: BTreeSet<usize>,
+    /// Name -> segment id of the prepare record, for prepared transactions that are not decided yet.
+    live_prepared: BTreeMap<String, usize>,
+}
+
+/// The data to recover from the WAL.
+#[derive(Default)]
+pub struct WalRecovery {
+    /// Memtable id -> the records of the memtable.
+    pub memtables: HashMap<usize, SkipMap<KeyBytes, Bytes>>,
+    /// Name -> the prepared transactions that are not decided yet.
+    pub prepared: BTreeMap<String, PreparedRecord>,
+}
+
+/// The write set of a prepared transaction, and the keys it has read and the ranges it has scanned if it is
+/// serializable.
+#[derive(Default)]
+pub struct PreparedRecord {
+    /// An empty value is a delete.
+    pub kv_pairs: Vec<(Bytes, Bytes)>,
+    pub read_set: BTreeSet<Bytes>,
+    pub read_ranges: Vec<(Bound<Bytes>, Bound<Bytes>)>,
+}
+
+enum WalRecord {
+    Data {
+        memtable_id: usize,
+        kv_pairs: Vec<(Bytes, u64, Bytes)>,
+    },
+    Prepare {
+        name: String,
+        record: PreparedRecord,
+    },
+    Decide {
+        name: String,
+        /// The memtable id and the commit ts of a committed transaction.
+        committed_to: Option<(usize, u64)>,
+    },
 }
 
 impl Wal {
-    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
-        Ok(Self {
-            file: Arc::new(Mutex::new(BufWriter::new(
-                OpenOptions::new()
-                    .read(true)
-                    .create_new(true)
-                    .write(true)
-                    .open(path)
-                    .context("failed to create WAL")?,
-            ))),
-        })
+    fn path_of_segment(dir: impl AsRef<Path>, id: usize) -> PathBuf {
+        dir.as_ref().join(format!("{:08}.log", id))
+    }
+
+    fn create_segment(dir: impl AsRef<Path>, id: usize) -> Result<BufWriter<File>> {
+        Ok(BufWriter::new(
+            OpenOptions::new()
+                .read(true)
+                .create_new(true)
+                .write(true)
+                .open(Self::path_of_segment(dir, id))
+                .context("failed to create WAL segment")?,
+        ))
+    }
+
+    /// Open the WAL in `dir`, replaying every record whose memtable id satisfies `is_live` into a skiplist of
+    /// that memtable, and collecting the undecided prepared transactions. New records always go to a new segment.
+    pub fn recover(
+        dir: impl AsRef<Path>,
+        segment_size: usize,
+        is_live: impl Fn(usize) -> bool,
+    ) -> Result<(Self, WalRecovery)> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir).context("failed to create WAL dir")?;
+        let mut segment_ids = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "log") {
+                if let Some(id) = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .and_then(|stem| stem.parse::<usize>().ok())
+                {
+                    segment_ids.push(id);
+                }
+            }
+        }
+        segment_ids.sort();
+
+        let mut segments: BTreeMap<usize, BTreeSet<usize>> = BTreeMap::new();
+        let mut recovery = WalRecovery::default();
+        let mut live_prepared = BTreeMap::new();
+        for id in &segment_ids {
+            let mut memtable_ids = BTreeSet::new();
+            Self::replay_segment(&Self::path_of_segment(dir, *id), |record| match record {
+                WalRecord::Data {
+                    memtable_id,
+                    kv_pairs,
+                } => {
+                    memtable_ids.insert(memtable_id);
+                    if is_live(memtable_id) {
+                        let skiplist = recovery.memtables.entry(memtable_id).or_default();
+                        for (key, ts, value) in kv_pairs {
+                            skiplist.insert(KeyBytes::from_bytes_with_ts(key, ts), value);
+                        }
+                    }
+                }
+                WalRecord::Prepare { name, record } => {
+                    live_prepared.insert(name.clone(), *id);
+                    recovery.prepared.insert(name, record);
+                }
+                WalRecord::Decide { name, committed_to } => {
+                    let prepare_segment_id = live_prepared.remove(&name);
+                    let record = recovery.prepared.remove(&name);
+                    if let (Some((memtable_id, commit_ts)), Some(record)) = (committed_to, record) {
+                        // keep the prepare record as long as the decision
+                        if let Some(memtables) =
+                            prepare_segment_id.and_then(|prepare_id| segments.get_mut(&prepare_id))
+                        {
+                            memtables.insert(memtable_id);
+                        }
+                        memtable_ids.insert(memtable_id);
+                        if is_live(memtable_id) {
+                            let skiplist = recovery.memtables.entry(memtable_id).or_default();
+                            for (key, value) in record.kv_pairs {
+                                skiplist
+                                    .insert(KeyBytes::from_bytes_with_ts(key, commit_ts), value);
+                            }
+                        }
+                    }
+                }
+            })?;
+            segments.insert(*id, memtable_ids);
+        }
+
+        let segment_id = segment_ids.last().map_or(0, |id| id + 1);
+        segments.insert(segment_id, BTreeSet::new());
+        let wal = Self {
+            inner: Mutex::new(WalInner {
+                file: Self::create_segment(dir, segment_id)?,
+                segment_id,
+                segment_len: 0,
+                segments,
+                live_memtables: BTreeSet::new(),
+                live_prepared,
+            }),
+            dir: dir.to_path_buf(),
+            segment_size,
+            statistics: None,
+        };
+        File::open(dir)?.sync_all()?;
+        Ok((wal, recovery))
+    }
+
+    /// Move the records of the per-memtable `<id>.wal` files in `dir`, written before the memtables shared a WAL, to
+    /// this WAL. The records of the memtables that satisfy `is_live` are added to `recovery` and appended to this WAL
+    /// before the files are removed. Returns the number of files removed.
+    pub fn import_legacy_wals(
+        &self,
+        dir: impl AsRef<Path>,
+        is_live: impl Fn(usize) -> bool,
+        recovery: &mut WalRecovery,
+    ) -> Result<usize> {
+        let dir = dir.as_ref();
+        let mut legacy_wals = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "wal") {
+                if let Some(id) = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .and_then(|stem| stem.parse::<usize>().ok())
+                {
+                    legacy_wals.push((id, path));
+                }
+            }
+        }
+        if legacy_wals.is_empty() {
+            return Ok(0);
+        }
+        legacy_wals.sort();
+        for (memtable_id, path) in &legacy_wals {
+            if !is_live(*memtable_id) {
+                continue;
+            }
+            let batches = Self::replay_legacy_wal(path)
+                .with_context(|| format!("failed to recover from WAL {}", path.display()))?;
+            let skiplist = recovery.memtables.entry(*memtable_id).or_default();
+            for kv_pairs in batches {
+                let data = kv_pairs
+                    .iter()
+                    .map(|(key, ts, value)| (KeySlice::from_slice(key, *ts), &value[..]))
+                    .collect::<Vec<_>>();
+                self.put_batch(*memtable_id, &data)?;
+                for (key, ts, value) in kv_pairs {
+                    skiplist.insert(KeyBytes::from_bytes_with_ts(key, ts), value);
+                }
+            }
+        }
+        // the records are durable in this WAL before the files that had them are gone
+        self.sync()?;
+        for (_, path) in &legacy_wals {
+            std::fs::remove_file(path)?;
+        }
+        File::open(dir)?.sync_all()?;
+        Ok(legacy_wals.len())
     }
 
-    pub fn recover(path: impl AsRef<Path>, skiplist: &SkipMap<KeyBytes, Bytes>) -> Result<Self> {
-        let path = path.as_ref();
-        let mut file = OpenOptions::new()
-            .read(true)
-            .append(true)
-            .open(path)
-            .context("failed to recover from WAL")?;
+    /// Read the batches of a per-memtable WAL file, `| batch_size (u32) | key_len | key | ts | value_len | value |
+    /// ... | checksum (u32) |`.
+    fn replay_legacy_wal(path: &Path) -> Result<Vec<Vec<(Bytes, u64, Bytes)>>> {
+        let mut file = File::open(path)?;
         let mut buf = Vec::new();
         file.read_to_end(&mut buf)?;
         let mut rbuf: &[u8] = buf.as_slice();
+        let mut batches = Vec::new();
         while rbuf.has_remaining() {
             let batch_size = rbuf.get_u32() as usize;
             if rbuf.remaining() < batch_size {
                 bail!("incomplete WAL");
             }
             let mut batch_buf = &rbuf[..batch_size];
+            let checksum = crc32fast::hash(batch_buf);
+            rbuf.advance(batch_size);
+            if checksum != rbuf.get_u32() {
+                bail!("checksum mismatch");
+            }
             let mut kv_pairs = Vec::new();
-            let mut hasher = crc32fast::Hasher::new();
-            // The checksum computed from the individual components should be the same as a direct checksum on the buffer.
-            // Students' implementation only needs to do a single checksum on the buffer. We compute both for verification purpose.
-            let single_checksum = crc32fast::hash(batch_buf);
             while batch_buf.has_remaining() {
-                let key_len = batch_buf.get_u16() as usize;
-                hasher.write(&(key_len as u16).to_be_bytes());
-                let key = Bytes::copy_from_slice(&batch_buf[..key_len]);
-                hasher.write(&key);
-                batch_buf.advance(key_len);
+                let key = Self::get_bytes(&mut batch_buf);
                 let ts = batch_buf.get_u64();
-                hasher.write(&ts.to_be_bytes());
-                let value_len = batch_buf.get_u16() as usize;
-                hasher.write(&(value_len as u16).to_be_bytes());
-                let value = Bytes::copy_from_slice(&batch_buf[..value_len]);
-                hasher.write(&value);
+                let value = Self::get_bytes(&mut batch_buf);
                 kv_pairs.push((key, ts, value));
-                batch_buf.advance(value_len);
             }
+            batches.push(kv_pairs);
+        }
+        Ok(batches)
+    }
+
+    /// Count the bytes appended to the WAL in the statistics of the engine.
+    pub fn with_statistics(mut self, statistics: Arc<Statistics>) -> Self {
+        self.statistics = Some(statistics);
+        self
+    }
+
+    fn replay_segment(path: &Path, mut f: impl FnMut(WalRecord)) -> Result<()> {
+        let mut file = File::open(path).context("failed to recover from WAL")?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+        let mut rbuf: &[u8] = buf.as_slice();
+        while rbuf.has_remaining() {
+            let batch_size = rbuf.get_u32() as usize;
+            if rbuf.remaining() < batch_size {
+                bail!("incomplete WAL");
+            }
+            let mut batch_buf = &rbuf[..batch_size];
+            let checksum = crc32fast::hash(batch_buf);
             rbuf.advance(batch_size);
-            let expected_checksum = rbuf.get_u32();
-            let component_checksum = hasher.finalize();
-            assert_eq!(component_checksum, single_checksum);
-            if single_checksum != expected_checksum {
+            if checksum != rbuf.get_u32() {
                 bail!("checksum mismatch");
             }
-            for (key, ts, value) in kv_pairs {
-                skiplist.insert(KeyBytes::from_bytes_with_ts(key, ts), value);
-            }
+            let record = match batch_buf.get_u8() {
+                RECORD_DATA => {
+                    let memtable_id = batch_buf.get_u64() as usize;
+                    let mut kv_pairs = Vec::new();
+                    while batch_buf.has_remaining() {
+                        let key = Self::get_bytes(&mut batch_buf);
+                        let ts = batch_buf.get_u64();
+                        let value = Self::get_bytes(&mut batch_buf);
+                        kv_pairs.push((key, ts, value));
+                    }
+                    WalRecord::Data {
+                        memtable_id,
+                        kv_pairs,
+                    }
+                }
+                RECORD_PREPARE => {
+                    let name = Self::get_name(&mut batch_buf)?;
+                    let mut record = PreparedRecord::default();
+                    for _ in 0..batch_buf.get_u32() {
+                        let key = Self::get_bytes(&mut batch_buf);
+                        let value = Self::get_bytes(&mut batch_buf);
+                        record.kv_pairs.push((key, value));
+                    }
+                    for _ in 0..batch_buf.get_u32() {
+                        record.read_set.insert(Self::get_bytes(&mut batch_buf));
+                    }
+                    while batch_buf.has_remaining() {
+                        let lower = Self::get_bound(&mut batch_buf)?;
+                        let upper = Self::get_bound(&mut batch_buf)?;
+                        record.read_ranges.push((lower, upper));
+                    }
+                    WalRecord::Prepare { name, record }
+                }
+                RECORD_DECIDE => {
+                    let name = Self::get_name(&mut batch_buf)?;
+                    let committed = batch_buf.get_u8() != 0;
+                    let committed_to = (committed && batch_buf.has_remaining())
+                        .then(|| (batch_buf.get_u64() as usize, batch_buf.get_u64()));
+                    WalRecord::Decide { name, committed_to }
+                }
+                kind => bail!("unknown WAL record kind {}", kind),
+            };
+            f(record);
         }
-        Ok(Self {
-            file: Arc::new(Mutex::new(BufWriter::new(file))),
+        Ok(())
+    }
+
+    fn get_bytes(buf: &mut &[u8]) -> Bytes {
+        let len = buf.get_u16() as usize;
+        let bytes = Bytes::copy_from_slice(&buf[..len]);
+        buf.advance(len);
+        bytes
+    }
+
+    fn get_name(buf: &mut &[u8]) -> Result<String> {
+        Ok(String::from_utf8(Self::get_bytes(buf).to_vec())?)
+    }
+
+    fn get_bound(buf: &mut &[u8]) -> Result<Bound<Bytes>> {
+        Ok(match buf.get_u8() {
+            BOUND_UNBOUNDED => Bound::Unbounded,
+            BOUND_INCLUDED => Bound::Included(Self::get_bytes(buf)),
+            BOUND_EXCLUDED => Bound::Excluded(Self::get_bytes(buf)),
+            kind => bail!("unknown bound kind {}", kind),
         })
     }
 
+    fn put_bound(buf: &mut Vec<u8>, bound: &Bound<Bytes>) {
+        let key = match bound {
+            Bound::Unbounded => {
+                buf.put_u8(BOUND_UNBOUNDED);
+                return;
+            }
+            Bound::Included(key) => {
+                buf.put_u8(BOUND_INCLUDED);
+                key
+            }
+            Bound::Excluded(key) => {
+                buf.put_u8(BOUND_EXCLUDED);
+                key
+            }
+        };
+        buf.put_u16(key.len() as u16);
+        buf.put_slice(key);
+    }
+
+    /// Append a record and return the id of the segment it is in.
+    fn append(&self, inner: &mut WalInner, kind: u8, body: &[u8]) -> Result<usize> {
+        let record_len = 1 + body.len() + 2 * std::mem::size_of::<u32>();
+        if inner.segment_len > 0 && inner.segment_len + record_len > self.segment_size {
+            self.rotate(inner)?;
+        }
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&[kind]);
+        hasher.update(body);
+        let file = &mut inner.file;
+        // write batch_size header (u32)
+        file.write_all(&((1 + body.len()) as u32).to_be_bytes())?;
+        file.write_all(&[kind])?;
+        file.write_all(body)?;
+        // write checksum (u32)
+        file.write_all(&hasher.finalize().to_be_bytes())?;
+        inner.segment_len += record_len;
+        if let Some(statistics) = &self.statistics {
+            statistics.record(Ticker::WalBytesWritten, record_len as u64);
+        }
+        Ok(inner.segment_id)
+    }
+
+    /// Register a memtable that writes to this WAL. Segments with its records are kept until it is flushed.
+    pub fn add_memtable(&self, memtable_id: usize) {
+        self.inner.lock().live_memtables.insert(memtable_id);
+    }
+
     /// Implement this in week 3, day 5.
-    pub fn put_batch(&self, data: &[(KeySlice, &[u8])]) -> Result<()> {
-        let mut file = self.file.lock();
+    pub fn put_batch(&self, memtable_id: usize, data: &[(KeySlice, &[u8])]) -> Result<()> {
+        let mut inner = self.inner.lock();
         let mut buf = Vec::<u8>::new();
+        buf.put_u64(memtable_id as u64);
         for (key, value) in data {
             buf.put_u16(key.key_len() as u16);
             buf.put_slice(key.key_ref());
@@ -92,23 +433,143 @@ impl Wal {
             buf.put_u16(value.len() as u16);
             buf.put_slice(value);
         }
-        // write batch_size header (u32)
-        file.write_all(&(buf.len() as u32).to_be_bytes())?;
-        // write key-value pairs body
-        file.write_all(&buf)?;
-        // write checksum (u32)
-        file.write_all(&crc32fast::hash(&buf).to_be_bytes())?;
+        let segment_id = self.append(&mut inner, RECORD_DATA, &buf)?;
+        inner
+            .segments
+            .get_mut(&segment_id)
+            .unwrap()
+            .insert(memtable_id);
+        Ok(())
+    }
+
+    /// Durably record a prepared transaction. The segment is kept until the transaction is decided.
+    pub fn prepare(&self, name: &str, record: &PreparedRecord) -> Result<()> {
+        let mut inner = self.inner.lock();
+        if inner.live_prepared.contains_key(name) {
+            bail!("prepared txn {} already exists", name);
+        }
+        let mut buf = Vec::<u8>::new();
+        buf.put_u16(name.len() as u16);
+        buf.put_slice(name.as_bytes());
+        buf.put_u32(record.kv_pairs.len() as u32);
+        for (key, value) in &record.kv_pairs {
+            buf.put_u16(key.len() as u16);
+            buf.put_slice(key);
+            buf.put_u16(value.len() as u16);
+            buf.put_slice(value);
+        }
+        buf.put_u32(record.read_set.len() as u32);
+        for key in &record.read_set {
+            buf.put_u16(key.len() as u16);
+            buf.put_slice(key);
+        }
+        for (lower, upper) in &record.read_ranges {
+            Self::put_bound(&mut buf, lower);
+            Self::put_bound(&mut buf, upper);
+        }
+        let segment_id = self.append(&mut inner, RECORD_PREPARE, &buf)?;
+        inner.live_prepared.insert(name.to_string(), segment_id);
+        inner.file.flush()?;
+        inner.file.get_mut().sync_all()?;
         Ok(())
     }
 
-    pub fn put(&self, key: KeySlice, value: &[u8]) -> Result<()> {
-        self.put_batch(&[(key, value)])
+    /// Durably record that a prepared transaction is committed at `commit_ts`, with its write set put into the
+    /// memtable `memtable_id` without writing it to the WAL again. Recovery replays the write set of the prepare
+    /// record into that memtable, so the segment is kept until the memtable is flushed.
+    pub fn commit_prepared(&self, name: &str, memtable_id: usize, commit_ts: u64) -> Result<()> {
+        let mut inner = self.inner.lock();
+        let mut buf = Vec::<u8>::new();
+        buf.put_u16(name.len() as u16);
+        buf.put_slice(name.as_bytes());
+        buf.put_u8(1);
+        buf.put_u64(memtable_id as u64);
+        buf.put_u64(commit_ts);
+        let segment_id = self.append(&mut inner, RECORD_DECIDE, &buf)?;
+        inner.file.flush()?;
+        inner.file.get_mut().sync_all()?;
+        // both the prepare record and the decision are needed to recover the write set
+        let prepare_segment_id = inner.live_prepared.remove(name);
+        for id in prepare_segment_id.into_iter().chain([segment_id]) {
+            inner.segments.get_mut(&id).unwrap().insert(memtable_id);
+        }
+        self.remove_obsolete_segments(&mut inner)
+    }
+
+    /// Record that a prepared transaction is rolled back.
+    pub fn rollback_prepared(&self, name: &str) -> Result<()> {
+        let mut inner = self.inner.lock();
+        let mut buf = Vec::<u8>::new();
+        buf.put_u16(name.len() as u16);
+        buf.put_slice(name.as_bytes());
+        buf.put_u8(0);
+        self.append(&mut inner, RECORD_DECIDE, &buf)?;
+        inner.live_prepared.remove(name);
+        self.remove_obsolete_segments(&mut inner)
+    }
+
+    pub fn put(&self, memtable_id: usize, key: KeySlice, value: &[u8]) -> Result<()> {
+        self.put_batch(memtable_id, &[(key, value)])
+    }
+
+    /// Seal the active segment and start writing to a new one.
+    fn rotate(&self, inner: &mut WalInner) -> Result<()> {
+        inner.file.flush()?;
+        inner.file.get_mut().sync_all()?;
+        let segment_id = inner.segment_id + 1;
+        inner.file = Self::create_segment(&self.dir, segment_id)?;
+        inner.segment_id = segment_id;
+        inner.segment_len = 0;
+        inner.segments.insert(segment_id, BTreeSet::new());
+        File::open(&self.dir)?.sync_all()?;
+        Ok(())
+    }
+
+    /// Mark a memtable as flushed and remove the sealed segments that no longer contain unflushed data.
+    pub fn memtable_flushed(&self, memtable_id: usize) -> Result<()> {
+        let mut inner = self.inner.lock();
+        inner.live_memtables.remove(&memtable_id);
+        self.remove_obsolete_segments(&mut inner)
+    }
+
+    fn remove_obsolete_segments(&self, inner: &mut WalInner) -> Result<()> {
+        let obsolete = inner
+            .segments
+            .iter()
+            .take_while(|(id, memtables)| {
+                **id != inner.segment_id
+                    && memtables.is_disjoint(&inner.live_memtables)
+                    && !inner.live_prepared.values().any(|segment| segment == *id)
+            })
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        if obsolete.is_empty() {
+            return Ok(());
+        }
+        for id in obsolete {
+            std::fs::remove_file(Self::path_of_segment(&self.dir, id))?;
+            inner.segments.remove(&id);
+        }
+        File::open(&self.dir)?.sync_all()?;
+        Ok(())
+    }
+
+    /// Remove the segments left over from before recovery that only cover flushed memtables. Call this after all
+    /// recovered memtables are registered.
+    pub fn finish_recovery(&self) -> Result<()> {
+        let mut inner = self.inner.lock();
+        self.remove_obsolete_segments(&mut inner)
+    }
+
+    /// Number of segment files, including the active one.
+    pub fn num_segments(&self) -> usize {
+        self.inner.lock().segments.len()
     }
 
     pub fn sync(&self) -> Result<()> {
-        let mut file = self.file.lock();
-        file.flush()?;
-        file.get_mut().sync_all()?;
+        let mut inner = self.inner.lock();
+        inner.file.flush()?;
+        inner.file.get_mut().sync_all()?;
         Ok(())
     }
 }
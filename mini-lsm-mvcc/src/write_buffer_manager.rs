@@ -0,0 +1,202 @@
+//! A budget for the memtables of several storage engines in one process.
+//!
+//! Every memtable created by an engine with a write buffer manager charges its size to the manager until it is
+//! dropped after the flush. Once the memtables that still take writes hold more than 7/8 of the budget, or the
+//! memtables as a whole exceed the budget while at least half of it is in mutable memtables, the largest mutable
+//! memtable across the engines is frozen, and the flush jobs of the engines flush the immutable memtables while the
+//! usage stays over 7/8 of the budget. If the flushes cannot keep up and the usage reaches the budget, writers stall
+//! until it drops again.
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use anyhow::Result;
+use parking_lot::{Condvar, Mutex};
+
+use crate::lsm_storage::LsmStorageInner;
+
+/// How long a stalled writer waits before checking the usage again.
+const STALL_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A point-in-time view of the counters of a write buffer manager.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct WriteBufferManagerStats {
+    /// The bytes charged by all the memtables.
+    pub usage: usize,
+    /// The bytes charged by the memtables that still take writes.
+    pub mutable_usage: usize,
+    pub buffer_size: usize,
+    /// The number of memtables frozen to stay under the budget.
+    pub flushes: u64,
+    /// The number of writes that stalled.
+    pub stalls: u64,
+}
+
+pub struct WriteBufferManager {
+    buffer_size: usize,
+    allow_stall: bool,
+    usage: AtomicUsize,
+    mutable_usage: AtomicUsize,
+    engines: Mutex<Vec<Weak<LsmStorageInner>>>,
+    stall_lock: Mutex<()>,
+    stall_cv: Condvar,
+    flushes: AtomicU64,
+    stalls: AtomicU64,
+}
+
+impl WriteBufferManager {
+    /// Create a write buffer manager keeping the memtables of the engines sharing it under `buffer_size` bytes. If
+    /// `allow_stall` is set, writers stall once the memtables reach the budget.
+    pub fn new(buffer_size: usize, allow_stall: bool) -> Self {
+        Self {
+            buffer_size,
+            allow_stall,
+            usage: AtomicUsize::new(0),
+            mutable_usage: AtomicUsize::new(0),
+            engines: Mutex::new(Vec::new()),
+            stall_lock: Mutex::new(()),
+            stall_cv: Condvar::new(),
+            flushes: AtomicU64::new(0),
+            stalls: AtomicU64::new(0),
+        }
+    }
+
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    pub fn usage(&self) -> usize {
+        self.usage.load(Ordering::Relaxed)
+    }
+
+    pub fn mutable_usage(&self) -> usize {
+        self.mutable_usage.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn register(&self, engine: &Arc<LsmStorageInner>) {
+        let mut engines = self.engines.lock();
+        engines.retain(|engine| engine.strong_count() > 0);
+        engines.push(Arc::downgrade(engine));
+    }
+
+    /// Charge the bytes written to a mutable memtable.
+    pub(crate) fn reserve(&self, bytes: usize) {
+        self.usage.fetch_add(bytes, Ordering::Relaxed);
+        self.mutable_usage.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Move the charge of a memtable that stops taking writes out of the mutable usage.
+    pub(crate) fn mark_immutable(&self, bytes: usize) {
+        self.mutable_usage.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    /// Drop the charge of a memtable.
+    pub(crate) fn free(&self, bytes: usize, mutable: bool) {
+        if mutable {
+            self.mutable_usage.fetch_sub(bytes, Ordering::Relaxed);
+        }
+        self.usage.fetch_sub(bytes, Ordering::Relaxed);
+        if self.allow_stall {
+            let _guard = self.stall_lock.lock();
+            self.stall_cv.notify_all();
+        }
+    }
+
+    fn flush_threshold(&self) -> usize {
+        self.buffer_size - self.buffer_size / 8
+    }
+
+    /// Whether a mutable memtable should be frozen to stay under the budget.
+    pub fn should_flush(&self) -> bool {
+        let mutable_usage = self.mutable_usage();
+        mutable_usage > self.flush_threshold()
+            || (self.usage() >= self.buffer_size && mutable_usage >= self.buffer_size / 2)
+    }
+
+    /// Whether the immutable memtables should be flushed to stay under the budget.
+    pub(crate) fn should_flush_immutable(&self) -> bool {
+        self.usage() > self.flush_threshold()
+    }
+
+    pub(crate) fn should_stall(&self) -> bool {
+        self.allow_stall && self.usage() >= self.buffer_size
+    }
+
+    /// Freeze the largest mutable memtable across the engines sharing the manager.
+    pub(crate) fn flush_largest_memtable(&self) -> Result<()> {
+        let engines = self
+            .engines
+            .lock()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .collect::<Vec<_>>();
+        let largest = engines
+            .iter()
+            .map(|engine| (engine.state.read().memtable.approximate_size(), engine))
+            .filter(|(size, _)| *size > 0)
+            .max_by_key(|(size, _)| *size);
+        if let Some((_, engine)) = largest {
+            if engine.freeze_memtable_for_write_buffer()? {
+                self.flushes.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
+
+    /// Schedule a flush of every engine sharing the manager, so that their immutable memtables go to disk while the
+    /// usage is over the budget.
+    pub(crate) fn schedule_flushes(&self) {
+        let engines = self
+            .engines
+            .lock()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .collect::<Vec<_>>();
+        for engine in engines {
+            engine.schedule_flush();
+        }
+    }
+
+    /// Block the writer while the memtables are at the budget. The memtables are frozen as needed in the meantime,
+    /// and the flush jobs of the engines flush them. The writer gives up once `check_writable` fails, since the
+    /// flushes may never come.
+    pub(crate) fn maybe_stall(&self, check_writable: impl Fn() -> Result<()>) -> Result<()> {
+        if !self.should_stall() {
+            return Ok(());
+        }
+        self.stalls.fetch_add(1, Ordering::Relaxed);
+        while self.should_stall() {
+            check_writable()?;
+            if self.should_flush() {
+                self.flush_largest_memtable()?;
+            }
+            self.schedule_flushes();
+            let mut guard = self.stall_lock.lock();
+            if self.should_stall() {
+                self.stall_cv.wait_for(&mut guard, STALL_CHECK_INTERVAL);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> WriteBufferManagerStats {
+        WriteBufferManagerStats {
+            usage: self.usage(),
+            mutable_usage: self.mutable_usage(),
+            buffer_size: self.buffer_size,
+            flushes: self.flushes.load(Ordering::Relaxed),
+            stalls: self.stalls.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl std::fmt::Debug for WriteBufferManager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WriteBufferManager")
+            .field("buffer_size", &self.buffer_size)
+            .field("allow_stall", &self.allow_stall)
+            .field("usage", &self.usage())
+            .finish()
+    }
+}
@@ -314,6 +314,8 @@ impl ReplBuilder {
 
 fn main() -> Result<()> {
     let args = Args::parse();
+    // The options without a flag keep their defaults, and all of them have one in some of the crates.
+    #[allow(clippy::needless_update)]
     let lsm = MiniLsm::open(
         args.path,
         LsmStorageOptions {
@@ -346,6 +348,7 @@ fn main() -> Result<()> {
             },
             enable_wal: args.enable_wal,
             serializable: args.serializable,
+            ..Default::default()
         },
     )?;
 
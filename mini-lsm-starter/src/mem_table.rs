@@ -144,7 +144,7 @@ impl StorageIterator for MemTableIterator {
         unimplemented!()
     }
 
-    fn key(&self) -> KeySlice {
+    fn key(&self) -> KeySlice<'_> {
         unimplemented!()
     }
 
@@ -81,7 +81,7 @@ impl TieredCompactionController {
         // trying to reduce sorted runs without respecting size ratio
         let num_tiers_to_take = snapshot.levels.len() - self.options.num_tiers + 2;
         println!("compaction triggered by reducing sorted runs");
-        return Some(TieredCompactionTask {
+        Some(TieredCompactionTask {
             tiers: snapshot
                 .levels
                 .iter()
@@ -89,7 +89,7 @@ impl TieredCompactionController {
                 .cloned()
                 .collect::<Vec<_>>(),
             bottom_tier_included: snapshot.levels.len() >= num_tiers_to_take,
-        });
+        })
     }
 
     pub fn apply_compaction_result(
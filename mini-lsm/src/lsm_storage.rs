@@ -117,6 +117,20 @@ impl LsmStorageOptions {
     }
 }
 
+/// The options of an engine outside the tests: a 2MB memtable, up to 3 memtables in memory and no compaction.
+impl Default for LsmStorageOptions {
+    fn default() -> Self {
+        Self {
+            block_size: 4096,
+            target_sst_size: 2 << 20,
+            compaction_options: CompactionOptions::NoCompaction,
+            enable_wal: false,
+            num_memtable_limit: 3,
+            serializable: false,
+        }
+    }
+}
+
 fn range_overlap(
     user_begin: Bound<&[u8]>,
     user_end: Bound<&[u8]>,
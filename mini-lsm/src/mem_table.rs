@@ -186,7 +186,7 @@ impl StorageIterator for MemTableIterator {
         &self.borrow_item().1[..]
     }
 
-    fn key(&self) -> KeySlice {
+    fn key(&self) -> KeySlice<'_> {
         KeySlice::from_slice(&self.borrow_item().0[..])
     }
 